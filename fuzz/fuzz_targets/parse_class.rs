@@ -0,0 +1,13 @@
+#![no_main]
+
+// Feed arbitrary bytes to the class file parser and format checker; both
+// must report malformed input as an error and never panic
+
+use libfuzzer_sys::fuzz_target;
+use rust_jvm::{checker, loader::Class};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(class) = Class::parse(data) {
+        checker::check(&class);
+    }
+});
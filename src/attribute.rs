@@ -4,10 +4,10 @@ use std::rc::Rc;
 use crate::classfile::ConstPool;
 
 pub struct ExceptionTable {
-    start_pc: u16,
-    end_pc: u16,
-    handler_pc: u16,
-    catch_type: u16,
+    pub start_pc: u16,
+    pub end_pc: u16,
+    pub handler_pc: u16,
+    pub catch_type: u16,
 }
 
 impl ExceptionTable {
@@ -22,8 +22,8 @@ impl ExceptionTable {
 }
 
 pub struct LineNumberTableEntry {
-    start_pc: u16,
-    line_number: u16,
+    pub start_pc: u16,
+    pub line_number: u16,
 }
 
 impl LineNumberTableEntry {
@@ -36,19 +36,52 @@ impl LineNumberTableEntry {
 }
 
 pub struct LocalVariableTableEntry {
-    start_pc: u16,
-    line_number: u16,
+    pub start_pc: u16,
+    pub length: u16,
+    pub name_index: u16,
+    pub descriptor_index: u16,
+    pub index: u16,
 }
 
 impl LocalVariableTableEntry {
-    pub fn new(start_pc: u16, line_number: u16) -> Self {
+    pub fn new(
+        start_pc: u16,
+        length: u16,
+        name_index: u16,
+        descriptor_index: u16,
+        index: u16,
+    ) -> Self {
         Self {
             start_pc,
-            line_number,
+            length,
+            name_index,
+            descriptor_index,
+            index,
         }
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerificationTypeInfo {
+    Top,
+    Integer,
+    Float,
+    Double,
+    Long,
+    Null,
+    UninitializedThis,
+    Object(u16),        // constant pool index of the class
+    Uninitialized(u16), // offset of the `new` instruction
+}
+
+// A frame as stored in the class file, relative to the previous one
+pub struct StackMapFrame {
+    pub frame_type: u8,
+    pub offset_delta: u16,
+    pub locals: Vec<VerificationTypeInfo>,
+    pub stack: Vec<VerificationTypeInfo>,
+}
+
 pub enum Attribute {
     ConstantValue(u16),
     Code {
@@ -69,24 +102,67 @@ pub enum Attribute {
     LineNumberTable {
         line_number_table: Vec<LineNumberTableEntry>,
     },
-    LocalVariableTable{
+    LocalVariableTable {
         local_variable_table: Vec<LocalVariableTableEntry>,
     },
-    InnerClasses,
+    // Attributes not decoded yet keep their raw info bytes
+    InnerClasses(Vec<u8>),
     Synthetic,
     Deprecated,
-    EnclosingMethod,
-    Signature,
-    SourceDebugExtension,
-    LocalVariableTypeTable,
-    RuntimeVisibleAnnotations,
-    RuntimeInvisibleAnnotations,
-    RuntimeVisibleParameterAnnotations,
-    RuntimeInvisibleParameterAnnotations,
-    AnnotationDefault,
-    StackMapTable,
-    BootstrapMethods,
-    RuntimeVisibleTypeAnnotations,
-    RuntimeInvisibleTypeAnnotations,
-    MethodParameters,
+    EnclosingMethod(Vec<u8>),
+    Signature(Vec<u8>),
+    SourceDebugExtension(Vec<u8>),
+    LocalVariableTypeTable(Vec<u8>),
+    RuntimeVisibleAnnotations(Vec<u8>),
+    RuntimeInvisibleAnnotations(Vec<u8>),
+    RuntimeVisibleParameterAnnotations(Vec<u8>),
+    RuntimeInvisibleParameterAnnotations(Vec<u8>),
+    AnnotationDefault(Vec<u8>),
+    StackMapTable {
+        entries: Vec<StackMapFrame>,
+    },
+    BootstrapMethods(Vec<u8>),
+    RuntimeVisibleTypeAnnotations(Vec<u8>),
+    RuntimeInvisibleTypeAnnotations(Vec<u8>),
+    MethodParameters(Vec<u8>),
+    // Attributes the JVM does not define are kept as they are
+    Unknown {
+        name: String,
+        info: Vec<u8>,
+    },
+}
+
+impl Attribute {
+    pub fn name(&self) -> &str {
+        match self {
+            Attribute::ConstantValue(_) => "ConstantValue",
+            Attribute::Code { .. } => "Code",
+            Attribute::Exceptions { .. } => "Exceptions",
+            Attribute::SourceFile { .. } => "SourceFile",
+            Attribute::LineNumberTable { .. } => "LineNumberTable",
+            Attribute::LocalVariableTable { .. } => "LocalVariableTable",
+            Attribute::InnerClasses(_) => "InnerClasses",
+            Attribute::Synthetic => "Synthetic",
+            Attribute::Deprecated => "Deprecated",
+            Attribute::EnclosingMethod(_) => "EnclosingMethod",
+            Attribute::Signature(_) => "Signature",
+            Attribute::SourceDebugExtension(_) => "SourceDebugExtension",
+            Attribute::LocalVariableTypeTable(_) => "LocalVariableTypeTable",
+            Attribute::RuntimeVisibleAnnotations(_) => "RuntimeVisibleAnnotations",
+            Attribute::RuntimeInvisibleAnnotations(_) => "RuntimeInvisibleAnnotations",
+            Attribute::RuntimeVisibleParameterAnnotations(_) => {
+                "RuntimeVisibleParameterAnnotations"
+            }
+            Attribute::RuntimeInvisibleParameterAnnotations(_) => {
+                "RuntimeInvisibleParameterAnnotations"
+            }
+            Attribute::AnnotationDefault(_) => "AnnotationDefault",
+            Attribute::StackMapTable { .. } => "StackMapTable",
+            Attribute::BootstrapMethods(_) => "BootstrapMethods",
+            Attribute::RuntimeVisibleTypeAnnotations(_) => "RuntimeVisibleTypeAnnotations",
+            Attribute::RuntimeInvisibleTypeAnnotations(_) => "RuntimeInvisibleTypeAnnotations",
+            Attribute::MethodParameters(_) => "MethodParameters",
+            Attribute::Unknown { name, .. } => name,
+        }
+    }
 }
@@ -0,0 +1,675 @@
+// Opcodes of the JVM instruction set (JVMS chapter 6) and a decoder that
+// turns the bytes of a Code attribute into instructions
+
+pub const NOP: u8 = 0x00;
+pub const ACONST_NULL: u8 = 0x01;
+pub const ICONST_M1: u8 = 0x02;
+pub const ICONST_0: u8 = 0x03;
+pub const ICONST_1: u8 = 0x04;
+pub const ICONST_2: u8 = 0x05;
+pub const ICONST_3: u8 = 0x06;
+pub const ICONST_4: u8 = 0x07;
+pub const ICONST_5: u8 = 0x08;
+pub const LCONST_0: u8 = 0x09;
+pub const LCONST_1: u8 = 0x0a;
+pub const FCONST_0: u8 = 0x0b;
+pub const FCONST_1: u8 = 0x0c;
+pub const FCONST_2: u8 = 0x0d;
+pub const DCONST_0: u8 = 0x0e;
+pub const DCONST_1: u8 = 0x0f;
+pub const BIPUSH: u8 = 0x10;
+pub const SIPUSH: u8 = 0x11;
+pub const LDC: u8 = 0x12;
+pub const LDC_W: u8 = 0x13;
+pub const LDC2_W: u8 = 0x14;
+pub const ILOAD: u8 = 0x15;
+pub const LLOAD: u8 = 0x16;
+pub const FLOAD: u8 = 0x17;
+pub const DLOAD: u8 = 0x18;
+pub const ALOAD: u8 = 0x19;
+pub const ILOAD_0: u8 = 0x1a;
+pub const ILOAD_1: u8 = 0x1b;
+pub const ILOAD_2: u8 = 0x1c;
+pub const ILOAD_3: u8 = 0x1d;
+pub const LLOAD_0: u8 = 0x1e;
+pub const LLOAD_1: u8 = 0x1f;
+pub const LLOAD_2: u8 = 0x20;
+pub const LLOAD_3: u8 = 0x21;
+pub const FLOAD_0: u8 = 0x22;
+pub const FLOAD_1: u8 = 0x23;
+pub const FLOAD_2: u8 = 0x24;
+pub const FLOAD_3: u8 = 0x25;
+pub const DLOAD_0: u8 = 0x26;
+pub const DLOAD_1: u8 = 0x27;
+pub const DLOAD_2: u8 = 0x28;
+pub const DLOAD_3: u8 = 0x29;
+pub const ALOAD_0: u8 = 0x2a;
+pub const ALOAD_1: u8 = 0x2b;
+pub const ALOAD_2: u8 = 0x2c;
+pub const ALOAD_3: u8 = 0x2d;
+pub const IALOAD: u8 = 0x2e;
+pub const LALOAD: u8 = 0x2f;
+pub const FALOAD: u8 = 0x30;
+pub const DALOAD: u8 = 0x31;
+pub const AALOAD: u8 = 0x32;
+pub const BALOAD: u8 = 0x33;
+pub const CALOAD: u8 = 0x34;
+pub const SALOAD: u8 = 0x35;
+pub const ISTORE: u8 = 0x36;
+pub const LSTORE: u8 = 0x37;
+pub const FSTORE: u8 = 0x38;
+pub const DSTORE: u8 = 0x39;
+pub const ASTORE: u8 = 0x3a;
+pub const ISTORE_0: u8 = 0x3b;
+pub const ISTORE_1: u8 = 0x3c;
+pub const ISTORE_2: u8 = 0x3d;
+pub const ISTORE_3: u8 = 0x3e;
+pub const LSTORE_0: u8 = 0x3f;
+pub const LSTORE_1: u8 = 0x40;
+pub const LSTORE_2: u8 = 0x41;
+pub const LSTORE_3: u8 = 0x42;
+pub const FSTORE_0: u8 = 0x43;
+pub const FSTORE_1: u8 = 0x44;
+pub const FSTORE_2: u8 = 0x45;
+pub const FSTORE_3: u8 = 0x46;
+pub const DSTORE_0: u8 = 0x47;
+pub const DSTORE_1: u8 = 0x48;
+pub const DSTORE_2: u8 = 0x49;
+pub const DSTORE_3: u8 = 0x4a;
+pub const ASTORE_0: u8 = 0x4b;
+pub const ASTORE_1: u8 = 0x4c;
+pub const ASTORE_2: u8 = 0x4d;
+pub const ASTORE_3: u8 = 0x4e;
+pub const IASTORE: u8 = 0x4f;
+pub const LASTORE: u8 = 0x50;
+pub const FASTORE: u8 = 0x51;
+pub const DASTORE: u8 = 0x52;
+pub const AASTORE: u8 = 0x53;
+pub const BASTORE: u8 = 0x54;
+pub const CASTORE: u8 = 0x55;
+pub const SASTORE: u8 = 0x56;
+pub const POP: u8 = 0x57;
+pub const POP2: u8 = 0x58;
+pub const DUP: u8 = 0x59;
+pub const DUP_X1: u8 = 0x5a;
+pub const DUP_X2: u8 = 0x5b;
+pub const DUP2: u8 = 0x5c;
+pub const DUP2_X1: u8 = 0x5d;
+pub const DUP2_X2: u8 = 0x5e;
+pub const SWAP: u8 = 0x5f;
+pub const IADD: u8 = 0x60;
+pub const LADD: u8 = 0x61;
+pub const FADD: u8 = 0x62;
+pub const DADD: u8 = 0x63;
+pub const ISUB: u8 = 0x64;
+pub const LSUB: u8 = 0x65;
+pub const FSUB: u8 = 0x66;
+pub const DSUB: u8 = 0x67;
+pub const IMUL: u8 = 0x68;
+pub const LMUL: u8 = 0x69;
+pub const FMUL: u8 = 0x6a;
+pub const DMUL: u8 = 0x6b;
+pub const IDIV: u8 = 0x6c;
+pub const LDIV: u8 = 0x6d;
+pub const FDIV: u8 = 0x6e;
+pub const DDIV: u8 = 0x6f;
+pub const IREM: u8 = 0x70;
+pub const LREM: u8 = 0x71;
+pub const FREM: u8 = 0x72;
+pub const DREM: u8 = 0x73;
+pub const INEG: u8 = 0x74;
+pub const LNEG: u8 = 0x75;
+pub const FNEG: u8 = 0x76;
+pub const DNEG: u8 = 0x77;
+pub const ISHL: u8 = 0x78;
+pub const LSHL: u8 = 0x79;
+pub const ISHR: u8 = 0x7a;
+pub const LSHR: u8 = 0x7b;
+pub const IUSHR: u8 = 0x7c;
+pub const LUSHR: u8 = 0x7d;
+pub const IAND: u8 = 0x7e;
+pub const LAND: u8 = 0x7f;
+pub const IOR: u8 = 0x80;
+pub const LOR: u8 = 0x81;
+pub const IXOR: u8 = 0x82;
+pub const LXOR: u8 = 0x83;
+pub const IINC: u8 = 0x84;
+pub const I2L: u8 = 0x85;
+pub const I2F: u8 = 0x86;
+pub const I2D: u8 = 0x87;
+pub const L2I: u8 = 0x88;
+pub const L2F: u8 = 0x89;
+pub const L2D: u8 = 0x8a;
+pub const F2I: u8 = 0x8b;
+pub const F2L: u8 = 0x8c;
+pub const F2D: u8 = 0x8d;
+pub const D2I: u8 = 0x8e;
+pub const D2L: u8 = 0x8f;
+pub const D2F: u8 = 0x90;
+pub const I2B: u8 = 0x91;
+pub const I2C: u8 = 0x92;
+pub const I2S: u8 = 0x93;
+pub const LCMP: u8 = 0x94;
+pub const FCMPL: u8 = 0x95;
+pub const FCMPG: u8 = 0x96;
+pub const DCMPL: u8 = 0x97;
+pub const DCMPG: u8 = 0x98;
+pub const IFEQ: u8 = 0x99;
+pub const IFNE: u8 = 0x9a;
+pub const IFLT: u8 = 0x9b;
+pub const IFGE: u8 = 0x9c;
+pub const IFGT: u8 = 0x9d;
+pub const IFLE: u8 = 0x9e;
+pub const IF_ICMPEQ: u8 = 0x9f;
+pub const IF_ICMPNE: u8 = 0xa0;
+pub const IF_ICMPLT: u8 = 0xa1;
+pub const IF_ICMPGE: u8 = 0xa2;
+pub const IF_ICMPGT: u8 = 0xa3;
+pub const IF_ICMPLE: u8 = 0xa4;
+pub const IF_ACMPEQ: u8 = 0xa5;
+pub const IF_ACMPNE: u8 = 0xa6;
+pub const GOTO: u8 = 0xa7;
+pub const JSR: u8 = 0xa8;
+pub const RET: u8 = 0xa9;
+pub const TABLESWITCH: u8 = 0xaa;
+pub const LOOKUPSWITCH: u8 = 0xab;
+pub const IRETURN: u8 = 0xac;
+pub const LRETURN: u8 = 0xad;
+pub const FRETURN: u8 = 0xae;
+pub const DRETURN: u8 = 0xaf;
+pub const ARETURN: u8 = 0xb0;
+pub const RETURN: u8 = 0xb1;
+pub const GETSTATIC: u8 = 0xb2;
+pub const PUTSTATIC: u8 = 0xb3;
+pub const GETFIELD: u8 = 0xb4;
+pub const PUTFIELD: u8 = 0xb5;
+pub const INVOKEVIRTUAL: u8 = 0xb6;
+pub const INVOKESPECIAL: u8 = 0xb7;
+pub const INVOKESTATIC: u8 = 0xb8;
+pub const INVOKEINTERFACE: u8 = 0xb9;
+pub const INVOKEDYNAMIC: u8 = 0xba;
+pub const NEW: u8 = 0xbb;
+pub const NEWARRAY: u8 = 0xbc;
+pub const ANEWARRAY: u8 = 0xbd;
+pub const ARRAYLENGTH: u8 = 0xbe;
+pub const ATHROW: u8 = 0xbf;
+pub const CHECKCAST: u8 = 0xc0;
+pub const INSTANCEOF: u8 = 0xc1;
+pub const MONITORENTER: u8 = 0xc2;
+pub const MONITOREXIT: u8 = 0xc3;
+pub const WIDE: u8 = 0xc4;
+pub const MULTIANEWARRAY: u8 = 0xc5;
+pub const IFNULL: u8 = 0xc6;
+pub const IFNONNULL: u8 = 0xc7;
+pub const GOTO_W: u8 = 0xc8;
+pub const JSR_W: u8 = 0xc9;
+
+const NAMES: [&str; 202] = [
+    "nop",
+    "aconst_null",
+    "iconst_m1",
+    "iconst_0",
+    "iconst_1",
+    "iconst_2",
+    "iconst_3",
+    "iconst_4",
+    "iconst_5",
+    "lconst_0",
+    "lconst_1",
+    "fconst_0",
+    "fconst_1",
+    "fconst_2",
+    "dconst_0",
+    "dconst_1",
+    "bipush",
+    "sipush",
+    "ldc",
+    "ldc_w",
+    "ldc2_w",
+    "iload",
+    "lload",
+    "fload",
+    "dload",
+    "aload",
+    "iload_0",
+    "iload_1",
+    "iload_2",
+    "iload_3",
+    "lload_0",
+    "lload_1",
+    "lload_2",
+    "lload_3",
+    "fload_0",
+    "fload_1",
+    "fload_2",
+    "fload_3",
+    "dload_0",
+    "dload_1",
+    "dload_2",
+    "dload_3",
+    "aload_0",
+    "aload_1",
+    "aload_2",
+    "aload_3",
+    "iaload",
+    "laload",
+    "faload",
+    "daload",
+    "aaload",
+    "baload",
+    "caload",
+    "saload",
+    "istore",
+    "lstore",
+    "fstore",
+    "dstore",
+    "astore",
+    "istore_0",
+    "istore_1",
+    "istore_2",
+    "istore_3",
+    "lstore_0",
+    "lstore_1",
+    "lstore_2",
+    "lstore_3",
+    "fstore_0",
+    "fstore_1",
+    "fstore_2",
+    "fstore_3",
+    "dstore_0",
+    "dstore_1",
+    "dstore_2",
+    "dstore_3",
+    "astore_0",
+    "astore_1",
+    "astore_2",
+    "astore_3",
+    "iastore",
+    "lastore",
+    "fastore",
+    "dastore",
+    "aastore",
+    "bastore",
+    "castore",
+    "sastore",
+    "pop",
+    "pop2",
+    "dup",
+    "dup_x1",
+    "dup_x2",
+    "dup2",
+    "dup2_x1",
+    "dup2_x2",
+    "swap",
+    "iadd",
+    "ladd",
+    "fadd",
+    "dadd",
+    "isub",
+    "lsub",
+    "fsub",
+    "dsub",
+    "imul",
+    "lmul",
+    "fmul",
+    "dmul",
+    "idiv",
+    "ldiv",
+    "fdiv",
+    "ddiv",
+    "irem",
+    "lrem",
+    "frem",
+    "drem",
+    "ineg",
+    "lneg",
+    "fneg",
+    "dneg",
+    "ishl",
+    "lshl",
+    "ishr",
+    "lshr",
+    "iushr",
+    "lushr",
+    "iand",
+    "land",
+    "ior",
+    "lor",
+    "ixor",
+    "lxor",
+    "iinc",
+    "i2l",
+    "i2f",
+    "i2d",
+    "l2i",
+    "l2f",
+    "l2d",
+    "f2i",
+    "f2l",
+    "f2d",
+    "d2i",
+    "d2l",
+    "d2f",
+    "i2b",
+    "i2c",
+    "i2s",
+    "lcmp",
+    "fcmpl",
+    "fcmpg",
+    "dcmpl",
+    "dcmpg",
+    "ifeq",
+    "ifne",
+    "iflt",
+    "ifge",
+    "ifgt",
+    "ifle",
+    "if_icmpeq",
+    "if_icmpne",
+    "if_icmplt",
+    "if_icmpge",
+    "if_icmpgt",
+    "if_icmple",
+    "if_acmpeq",
+    "if_acmpne",
+    "goto",
+    "jsr",
+    "ret",
+    "tableswitch",
+    "lookupswitch",
+    "ireturn",
+    "lreturn",
+    "freturn",
+    "dreturn",
+    "areturn",
+    "return",
+    "getstatic",
+    "putstatic",
+    "getfield",
+    "putfield",
+    "invokevirtual",
+    "invokespecial",
+    "invokestatic",
+    "invokeinterface",
+    "invokedynamic",
+    "new",
+    "newarray",
+    "anewarray",
+    "arraylength",
+    "athrow",
+    "checkcast",
+    "instanceof",
+    "monitorenter",
+    "monitorexit",
+    "wide",
+    "multianewarray",
+    "ifnull",
+    "ifnonnull",
+    "goto_w",
+    "jsr_w",
+];
+
+pub fn name(opcode: u8) -> &'static str {
+    NAMES.get(opcode as usize).copied().unwrap_or("<illegal>")
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Operands {
+    None,
+    // Local variable index of a load, store or ret, widened if needed
+    Local(u16),
+    Iinc(u16, i16),
+    // bipush, sipush and the atype of newarray
+    Immediate(i32),
+    // Constant pool index of ldc, field and method instructions, new,
+    // anewarray, checkcast and instanceof
+    Constant(u16),
+    // Absolute target of a branch
+    Branch(i32),
+    TableSwitch {
+        default: i32,
+        low: i32,
+        high: i32,
+        targets: Vec<i32>,
+    },
+    LookupSwitch {
+        default: i32,
+        pairs: Vec<(i32, i32)>,
+    },
+    InvokeInterface(u16, u8),
+    MultiANewArray(u16, u8),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Instruction {
+    pub pc: usize,
+    pub opcode: u8,
+    pub operands: Operands,
+}
+
+impl Instruction {
+    // Every pc this instruction may jump to, not counting fall through
+    pub fn targets(&self) -> Vec<i32> {
+        match &self.operands {
+            Operands::Branch(target) => vec![*target],
+            Operands::TableSwitch {
+                default, targets, ..
+            } => {
+                let mut all = vec![*default];
+                all.extend(targets);
+                all
+            }
+            Operands::LookupSwitch { default, pairs } => {
+                let mut all = vec![*default];
+                all.extend(pairs.iter().map(|(_, target)| *target));
+                all
+            }
+            _ => vec![],
+        }
+    }
+
+    // Whether execution can continue with the next instruction
+    pub fn falls_through(&self) -> bool {
+        !matches!(
+            self.opcode,
+            GOTO | GOTO_W
+                | TABLESWITCH
+                | LOOKUPSWITCH
+                | IRETURN
+                | LRETURN
+                | FRETURN
+                | DRETURN
+                | ARETURN
+                | RETURN
+                | ATHROW
+                | RET
+                | JSR
+                | JSR_W
+        )
+    }
+}
+
+struct Reader<'a> {
+    code: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn u1(&mut self) -> Result<u8, String> {
+        let b = *self
+            .code
+            .get(self.pos)
+            .ok_or_else(|| format!("truncated instruction at {}", self.pos))?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    fn u2(&mut self) -> Result<u16, String> {
+        Ok(u16::from_be_bytes([self.u1()?, self.u1()?]))
+    }
+
+    fn i4(&mut self) -> Result<i32, String> {
+        Ok(i32::from_be_bytes([
+            self.u1()?,
+            self.u1()?,
+            self.u1()?,
+            self.u1()?,
+        ]))
+    }
+}
+
+pub fn decode(code: &[u8]) -> Result<Vec<Instruction>, String> {
+    let mut r = Reader { code, pos: 0 };
+    let mut instructions = vec![];
+    while r.pos < code.len() {
+        let pc = r.pos;
+        let mut opcode = r.u1()?;
+        let operands = match opcode {
+            BIPUSH => Operands::Immediate(r.u1()? as i8 as i32),
+            SIPUSH => Operands::Immediate(r.u2()? as i16 as i32),
+            NEWARRAY => Operands::Immediate(r.u1()? as i32),
+            LDC => Operands::Constant(r.u1()? as u16),
+            LDC_W
+            | LDC2_W
+            | GETSTATIC..=INVOKESTATIC
+            | NEW
+            | ANEWARRAY
+            | CHECKCAST
+            | INSTANCEOF => Operands::Constant(r.u2()?),
+            ILOAD..=ALOAD | ISTORE..=ASTORE | RET => Operands::Local(r.u1()? as u16),
+            IINC => Operands::Iinc(r.u1()? as u16, r.u1()? as i8 as i16),
+            IFEQ..=JSR | IFNULL | IFNONNULL => Operands::Branch(pc as i32 + r.u2()? as i16 as i32),
+            GOTO_W | JSR_W => Operands::Branch(pc as i32 + r.i4()?),
+            TABLESWITCH | LOOKUPSWITCH => {
+                // Operands start at the next multiple of four
+                r.pos = (pc + 4) & !3;
+                let default = pc as i32 + r.i4()?;
+                if opcode == TABLESWITCH {
+                    let low = r.i4()?;
+                    let high = r.i4()?;
+                    if low > high {
+                        return Err(format!("tableswitch at {} has low > high", pc));
+                    }
+                    let mut targets = vec![];
+                    for _ in low..=high {
+                        targets.push(pc as i32 + r.i4()?);
+                    }
+                    Operands::TableSwitch {
+                        default,
+                        low,
+                        high,
+                        targets,
+                    }
+                } else {
+                    let npairs = r.i4()?;
+                    if npairs < 0 {
+                        return Err(format!("lookupswitch at {} has negative npairs", pc));
+                    }
+                    let mut pairs = vec![];
+                    for _ in 0..npairs {
+                        pairs.push((r.i4()?, pc as i32 + r.i4()?));
+                    }
+                    Operands::LookupSwitch { default, pairs }
+                }
+            }
+            INVOKEINTERFACE => {
+                let index = r.u2()?;
+                let count = r.u1()?;
+                r.u1()?;
+                Operands::InvokeInterface(index, count)
+            }
+            INVOKEDYNAMIC => {
+                let index = r.u2()?;
+                r.u2()?;
+                Operands::Constant(index)
+            }
+            MULTIANEWARRAY => Operands::MultiANewArray(r.u2()?, r.u1()?),
+            WIDE => {
+                opcode = r.u1()?;
+                match opcode {
+                    ILOAD..=ALOAD | ISTORE..=ASTORE | RET => Operands::Local(r.u2()?),
+                    IINC => Operands::Iinc(r.u2()?, r.u2()? as i16),
+                    _ => return Err(format!("illegal wide opcode {} at {}", opcode, pc)),
+                }
+            }
+            0..=JSR_W => Operands::None,
+            _ => return Err(format!("illegal opcode {} at {}", opcode, pc)),
+        };
+        instructions.push(Instruction {
+            pc,
+            opcode,
+            operands,
+        });
+    }
+    Ok(instructions)
+}
+
+// Encoding of an instruction without branch targets, choosing the narrow or
+// wide form its operands need. Branches depend on the code layout and are
+// encoded by the caller.
+pub fn encode(opcode: u8, operands: &Operands, out: &mut Vec<u8>) -> Result<(), String> {
+    match (opcode, operands) {
+        (BIPUSH | NEWARRAY, Operands::Immediate(value)) => out.extend([opcode, *value as u8]),
+        (SIPUSH, Operands::Immediate(value)) => {
+            out.push(opcode);
+            out.extend((*value as i16).to_be_bytes());
+        }
+        (LDC | LDC_W, Operands::Constant(index)) if *index > 0xff || opcode == LDC_W => {
+            out.push(LDC_W);
+            out.extend(index.to_be_bytes());
+        }
+        (LDC, Operands::Constant(index)) => out.extend([LDC, *index as u8]),
+        (INVOKEDYNAMIC, Operands::Constant(index)) => {
+            out.push(opcode);
+            out.extend(index.to_be_bytes());
+            out.extend([0, 0]);
+        }
+        (
+            LDC2_W | GETSTATIC..=INVOKESTATIC | NEW | ANEWARRAY | CHECKCAST | INSTANCEOF,
+            Operands::Constant(index),
+        ) => {
+            out.push(opcode);
+            out.extend(index.to_be_bytes());
+        }
+        (ILOAD..=ALOAD | ISTORE..=ASTORE | RET, Operands::Local(index)) => {
+            if *index > 0xff {
+                out.extend([WIDE, opcode]);
+                out.extend(index.to_be_bytes());
+            } else {
+                out.extend([opcode, *index as u8]);
+            }
+        }
+        (IINC, Operands::Iinc(index, value)) => {
+            if *index > 0xff || i8::try_from(*value).is_err() {
+                out.extend([WIDE, opcode]);
+                out.extend(index.to_be_bytes());
+                out.extend(value.to_be_bytes());
+            } else {
+                out.extend([opcode, *index as u8, *value as u8]);
+            }
+        }
+        (INVOKEINTERFACE, Operands::InvokeInterface(index, count)) => {
+            out.push(opcode);
+            out.extend(index.to_be_bytes());
+            out.extend([*count, 0]);
+        }
+        (MULTIANEWARRAY, Operands::MultiANewArray(index, dimensions)) => {
+            out.push(opcode);
+            out.extend(index.to_be_bytes());
+            out.push(*dimensions);
+        }
+        (
+            NOP..=DCONST_1 | ILOAD_0..=SALOAD | ISTORE_0..=LXOR | I2L..=DCMPG | IRETURN..=RETURN,
+            Operands::None,
+        )
+        | (ARRAYLENGTH | ATHROW | MONITORENTER | MONITOREXIT, Operands::None) => out.push(opcode),
+        _ => {
+            return Err(format!(
+                "Cannot encode {} with operands {:?}",
+                name(opcode),
+                operands
+            ))
+        }
+    }
+    Ok(())
+}
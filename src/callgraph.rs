@@ -0,0 +1,749 @@
+// Static call graph of the methods reachable from a set of root classes.
+// Classes are loaded from the class path as calls reach them. Virtual and
+// interface calls are resolved by class hierarchy analysis: a call may
+// reach the implementation selected by any loaded concrete subtype of the
+// class named at the call site, so those call sites are revisited whenever
+// such a subtype gets loaded.
+
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt;
+
+use crate::{
+    attribute::Attribute,
+    bytecode::{self, Operands},
+    classfile::{Const, ConstPool},
+    classpath::ClassPath,
+    loader::Class,
+};
+
+const ACC_STATIC: u16 = 0x0008;
+const ACC_VARARGS: u16 = 0x0080;
+const ACC_INTERFACE: u16 = 0x0200;
+const ACC_NATIVE: u16 = 0x0100;
+const ACC_ABSTRACT: u16 = 0x0400;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MethodId {
+    pub class: String,
+    pub name: String,
+    pub descriptor: String,
+}
+
+impl MethodId {
+    fn new(class: &str, name: &str, descriptor: &str) -> MethodId {
+        MethodId {
+            class: class.to_string(),
+            name: name.to_string(),
+            descriptor: descriptor.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for MethodId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}{}", self.class, self.name, self.descriptor)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum CallKind {
+    Static,
+    Special,
+    Virtual,
+    Interface,
+    // Method handles passed to an invokedynamic bootstrap, as for lambdas
+    Dynamic,
+}
+
+impl CallKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CallKind::Static => "static",
+            CallKind::Special => "special",
+            CallKind::Virtual => "virtual",
+            CallKind::Interface => "interface",
+            CallKind::Dynamic => "dynamic",
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct CallGraph {
+    // Methods whose code was analyzed
+    pub methods: BTreeSet<MethodId>,
+    pub edges: BTreeSet<(MethodId, MethodId, CallKind)>,
+    // Call targets whose class is missing or that do not resolve
+    pub unresolved: BTreeSet<MethodId>,
+}
+
+struct MethodInfo {
+    name: String,
+    descriptor: String,
+    flags: u16,
+    calls: Vec<(CallKind, MethodId)>,
+}
+
+struct ClassInfo {
+    flags: u16,
+    super_name: Option<String>,
+    interfaces: Vec<String>,
+    methods: Vec<MethodInfo>,
+}
+
+struct Builder<'a> {
+    classpath: &'a ClassPath,
+    exclude: &'a [String],
+    // None for classes not found on the class path
+    classes: HashMap<String, Option<ClassInfo>>,
+    // Concrete classes loaded so far, by each of their supertypes
+    subtypes: HashMap<String, Vec<String>>,
+    // Virtual and interface calls seen so far, by the class they name
+    sites: HashMap<String, Vec<(MethodId, MethodId, CallKind)>>,
+    graph: CallGraph,
+    pending: VecDeque<MethodId>,
+    queued: HashSet<MethodId>,
+}
+
+// The graph of calls reachable from every method of the `roots` classes.
+// Classes in the `exclude` packages are used for resolution, but calls made
+// by their methods are not followed.
+pub fn build(classpath: &ClassPath, roots: &[String], exclude: &[String]) -> CallGraph {
+    let mut builder = Builder {
+        classpath,
+        exclude,
+        classes: HashMap::new(),
+        subtypes: HashMap::new(),
+        sites: HashMap::new(),
+        graph: CallGraph::default(),
+        pending: VecDeque::new(),
+        queued: HashSet::new(),
+    };
+    for root in roots {
+        let root = root.replace('.', "/");
+        builder.load(&root);
+        let methods: Vec<MethodId> = match &builder.classes[&root] {
+            Some(class) => class
+                .methods
+                .iter()
+                .map(|m| MethodId::new(&root, &m.name, &m.descriptor))
+                .collect(),
+            None => vec![],
+        };
+        for method in methods {
+            builder.enqueue(method);
+        }
+    }
+    while let Some(method) = builder.pending.pop_front() {
+        builder.visit(method);
+    }
+    builder.graph
+}
+
+impl Builder<'_> {
+    // Load a class and its supertypes, if not done yet
+    fn load(&mut self, name: &str) {
+        if self.classes.contains_key(name) {
+            return;
+        }
+        let class = self
+            .classpath
+            .read_class(name)
+            .ok()
+            .and_then(|data| Class::parse(&data).ok())
+            .map(|class| class_info(&class));
+        let supertypes: Vec<String> = class
+            .iter()
+            .flat_map(|c| c.super_name.iter().chain(&c.interfaces).cloned())
+            .collect();
+        let concrete = class
+            .as_ref()
+            .is_some_and(|c| c.flags & (ACC_INTERFACE | ACC_ABSTRACT) == 0);
+        self.classes.insert(name.to_string(), class);
+        for supertype in supertypes {
+            self.load(&supertype);
+        }
+        if !concrete {
+            return;
+        }
+        for supertype in self.supertypes(name) {
+            self.subtypes
+                .entry(supertype.clone())
+                .or_default()
+                .push(name.to_string());
+            // Calls seen earlier may reach an override in the new class
+            let sites = self.sites.get(&supertype).cloned().unwrap_or_default();
+            for (caller, target, kind) in sites {
+                if let Some(callee) = self.select(name, &target.name, &target.descriptor) {
+                    self.add_call(caller, callee, kind);
+                }
+            }
+        }
+    }
+
+    fn add_call(&mut self, caller: MethodId, callee: MethodId, kind: CallKind) {
+        self.enqueue(callee.clone());
+        self.graph.edges.insert((caller, callee, kind));
+    }
+
+    fn excluded(&self, class: &str) -> bool {
+        self.exclude
+            .iter()
+            .any(|prefix| class.starts_with(prefix.replace('.', "/").as_str()))
+    }
+
+    fn enqueue(&mut self, method: MethodId) {
+        if self.queued.insert(method.clone()) {
+            self.pending.push_back(method);
+        }
+    }
+
+    fn visit(&mut self, method: MethodId) {
+        if self.excluded(&method.class) {
+            return;
+        }
+        let has_code = self
+            .method(&method)
+            .is_some_and(|m| m.flags & (ACC_ABSTRACT | ACC_NATIVE) == 0);
+        if has_code {
+            self.graph.methods.insert(method.clone());
+            self.resolve_calls(&method);
+        }
+    }
+
+    fn method(&self, id: &MethodId) -> Option<&MethodInfo> {
+        self.classes
+            .get(&id.class)?
+            .as_ref()?
+            .methods
+            .iter()
+            .find(|m| m.name == id.name && m.descriptor == id.descriptor)
+    }
+
+    fn resolve_calls(&mut self, method: &MethodId) {
+        let calls = match self.method(method) {
+            Some(m) => m.calls.clone(),
+            None => return,
+        };
+        for (kind, target) in calls {
+            self.load(&target.class);
+            let targets = match kind {
+                CallKind::Virtual | CallKind::Interface => {
+                    self.sites.entry(target.class.clone()).or_default().push((
+                        method.clone(),
+                        target.clone(),
+                        kind,
+                    ));
+                    self.implementations(&target)
+                }
+                _ => self.resolve(&target).into_iter().collect(),
+            };
+            if targets.is_empty() {
+                self.graph.unresolved.insert(target.clone());
+                self.graph.edges.insert((method.clone(), target, kind));
+                continue;
+            }
+            for callee in targets {
+                self.add_call(method.clone(), callee, kind);
+            }
+        }
+    }
+
+    // Method resolution (JVMS 5.4.3.3, 5.4.3.4): the class and its
+    // superclasses, then its superinterfaces
+    fn resolve(&self, target: &MethodId) -> Option<MethodId> {
+        // Methods of arrays are those of Object
+        let class = match target.class.starts_with('[') {
+            true => "java/lang/Object",
+            false => target.class.as_str(),
+        };
+        for (name, info) in self.superclasses(class) {
+            if info
+                .methods
+                .iter()
+                .any(|m| m.name == target.name && m.descriptor == target.descriptor)
+            {
+                return Some(MethodId::new(name, &target.name, &target.descriptor));
+            }
+            // Signature polymorphic methods (JVMS 2.9.3) accept any descriptor
+            if matches!(
+                name,
+                "java/lang/invoke/MethodHandle" | "java/lang/invoke/VarHandle"
+            ) {
+                if let Some(m) = info.methods.iter().find(|m| {
+                    m.name == target.name
+                        && m.descriptor.starts_with("([Ljava/lang/Object;)")
+                        && m.flags & (ACC_VARARGS | ACC_NATIVE) == ACC_VARARGS | ACC_NATIVE
+                }) {
+                    return Some(MethodId::new(name, &m.name, &m.descriptor));
+                }
+            }
+        }
+        self.interface_method(class, &target.name, &target.descriptor, false)
+    }
+
+    // A loaded class followed by its loaded superclasses
+    fn superclasses<'a>(&'a self, class: &'a str) -> Vec<(&'a str, &'a ClassInfo)> {
+        let mut chain: Vec<(&str, &ClassInfo)> = vec![];
+        let mut current = Some(class);
+        while let Some(name) = current {
+            let Some((name, Some(info))) = self.classes.get_key_value(name) else {
+                break;
+            };
+            if chain.iter().any(|(c, _)| *c == name) {
+                break;
+            }
+            chain.push((name, info));
+            current = info.super_name.as_deref();
+        }
+        chain
+    }
+
+    // A method declared by a superinterface of `class`, searched breadth
+    // first; with `concrete` only default methods count
+    fn interface_method(
+        &self,
+        class: &str,
+        name: &str,
+        descriptor: &str,
+        concrete: bool,
+    ) -> Option<MethodId> {
+        let mut queue = VecDeque::from([class.to_string()]);
+        let mut seen = HashSet::new();
+        while let Some(current) = queue.pop_front() {
+            if !seen.insert(current.clone()) {
+                continue;
+            }
+            let Some(Some(info)) = self.classes.get(&current) else {
+                continue;
+            };
+            if info.flags & ACC_INTERFACE != 0
+                && info.methods.iter().any(|m| {
+                    m.name == name
+                        && m.descriptor == descriptor
+                        && m.flags & ACC_STATIC == 0
+                        && (!concrete || m.flags & ACC_ABSTRACT == 0)
+                })
+            {
+                return Some(MethodId::new(&current, name, descriptor));
+            }
+            queue.extend(info.interfaces.iter().cloned());
+            queue.extend(info.super_name.iter().cloned());
+        }
+        None
+    }
+
+    // Class hierarchy analysis: the methods selected by every loaded
+    // concrete subtype of the class named at the call site
+    fn implementations(&self, target: &MethodId) -> BTreeSet<MethodId> {
+        let mut found: BTreeSet<MethodId> = self
+            .subtypes
+            .get(&target.class)
+            .into_iter()
+            .flatten()
+            .filter_map(|class| self.select(class, &target.name, &target.descriptor))
+            .collect();
+        if found.is_empty() {
+            // No implementation loaded, e.g. an interface nothing implements
+            found.extend(self.resolve(target));
+        }
+        found
+    }
+
+    // Method selection (JVMS 5.4.6) for a receiver of class `class`
+    fn select(&self, class: &str, name: &str, descriptor: &str) -> Option<MethodId> {
+        for (c, info) in self.superclasses(class) {
+            if let Some(m) = info
+                .methods
+                .iter()
+                .find(|m| m.name == name && m.descriptor == descriptor && m.flags & ACC_STATIC == 0)
+            {
+                return (m.flags & ACC_ABSTRACT == 0).then(|| MethodId::new(c, name, descriptor));
+            }
+        }
+        self.interface_method(class, name, descriptor, true)
+    }
+
+    // A class and all of its loaded supertypes
+    fn supertypes(&self, class: &str) -> HashSet<String> {
+        // Iterative, as a broken class path may contain cycles
+        let mut pending = vec![class.to_string()];
+        let mut seen = HashSet::new();
+        while let Some(current) = pending.pop() {
+            if !seen.insert(current.clone()) {
+                continue;
+            }
+            if let Some(Some(info)) = self.classes.get(&current) {
+                pending.extend(info.super_name.iter().chain(&info.interfaces).cloned());
+            }
+        }
+        seen
+    }
+}
+
+fn class_info(class: &Class) -> ClassInfo {
+    let cp = class.const_pool.borrow();
+    let bootstrap = class.attributes.iter().find_map(|a| match a {
+        Attribute::BootstrapMethods(raw) => Some(raw.as_slice()),
+        _ => None,
+    });
+    let methods = class
+        .methods
+        .iter()
+        .map(|method| {
+            let code = method.attributes.iter().find_map(|a| match a {
+                Attribute::Code { code, .. } => Some(code.as_slice()),
+                _ => None,
+            });
+            MethodInfo {
+                name: method.name(),
+                descriptor: method.descriptor(),
+                flags: method.flags,
+                calls: code.map_or(vec![], |code| calls(code, &cp, bootstrap)),
+            }
+        })
+        .collect();
+    ClassInfo {
+        flags: class.flags,
+        super_name: class.super_name(),
+        interfaces: class.interface_names(),
+        methods,
+    }
+}
+
+// The methods named by the invoke instructions of `code`
+fn calls(code: &[u8], cp: &ConstPool, bootstrap: Option<&[u8]>) -> Vec<(CallKind, MethodId)> {
+    let Ok(instructions) = bytecode::decode(code) else {
+        return vec![];
+    };
+    let mut calls = vec![];
+    for instr in instructions {
+        let kind = match instr.opcode {
+            bytecode::INVOKESTATIC => CallKind::Static,
+            bytecode::INVOKESPECIAL => CallKind::Special,
+            bytecode::INVOKEVIRTUAL => CallKind::Virtual,
+            bytecode::INVOKEINTERFACE => CallKind::Interface,
+            bytecode::INVOKEDYNAMIC => CallKind::Dynamic,
+            _ => continue,
+        };
+        let (Operands::Constant(index) | Operands::InvokeInterface(index, _)) = instr.operands
+        else {
+            continue;
+        };
+        if kind == CallKind::Dynamic {
+            let Some(Const::InvokeDynamic {
+                bootstrap_method_attr_index,
+                ..
+            }) = cp.get(index)
+            else {
+                continue;
+            };
+            for handle in bootstrap_handles(bootstrap, *bootstrap_method_attr_index) {
+                if let Some(Const::MethodHandle {
+                    reference_index, ..
+                }) = cp.get(handle)
+                {
+                    calls.extend(method_ref(cp, *reference_index).map(|m| (kind, m)));
+                }
+            }
+        } else {
+            calls.extend(method_ref(cp, index).map(|m| (kind, m)));
+        }
+    }
+    calls
+}
+
+fn method_ref(cp: &ConstPool, index: u16) -> Option<MethodId> {
+    match cp.get(index)? {
+        Const::MethodRef {
+            class_index,
+            name_and_type_index,
+            ..
+        }
+        | Const::InterfaceMethodRef {
+            class_index,
+            name_and_type_index,
+            ..
+        } => {
+            let (name, descriptor) = cp.name_and_type(*name_and_type_index);
+            Some(MethodId {
+                class: cp.class_name(*class_index),
+                name,
+                descriptor,
+            })
+        }
+        _ => None,
+    }
+}
+
+// Constant pool indices of the arguments of a bootstrap method entry
+fn bootstrap_handles(raw: Option<&[u8]>, entry: u16) -> Vec<u16> {
+    let Some(raw) = raw else {
+        return vec![];
+    };
+    let u2 = |pos: usize| {
+        raw.get(pos..pos + 2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+    };
+    let mut pos = 2;
+    for i in 0..=entry {
+        let Some(count) = u2(pos + 2) else {
+            return vec![];
+        };
+        if i == entry {
+            return (0..count as usize)
+                .filter_map(|j| u2(pos + 4 + 2 * j))
+                .collect();
+        }
+        pos += 4 + 2 * count as usize;
+    }
+    vec![]
+}
+
+impl CallGraph {
+    // Every method in the graph, callers and callees
+    pub fn nodes(&self) -> BTreeSet<&MethodId> {
+        let mut nodes: BTreeSet<&MethodId> = self.methods.iter().collect();
+        for (from, to, _) in &self.edges {
+            nodes.insert(from);
+            nodes.insert(to);
+        }
+        nodes
+    }
+
+    pub fn to_dot(&self) -> String {
+        let mut out = String::from("digraph calls {\n");
+        for method in &self.unresolved {
+            out.push_str(&format!(
+                "  {} [style=dashed];\n",
+                dot_string(&method.to_string())
+            ));
+        }
+        for (from, to, kind) in &self.edges {
+            out.push_str(&format!(
+                "  {} -> {} [label={}];\n",
+                dot_string(&from.to_string()),
+                dot_string(&to.to_string()),
+                kind.name()
+            ));
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    pub fn to_json(&self) -> String {
+        let list = |methods: Vec<&MethodId>| {
+            methods
+                .iter()
+                .map(|m| format!("\n    {}", json_string(&m.to_string())))
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        let edges = self
+            .edges
+            .iter()
+            .map(|(from, to, kind)| {
+                format!(
+                    "\n    {{\"from\": {}, \"to\": {}, \"kind\": \"{}\"}}",
+                    json_string(&from.to_string()),
+                    json_string(&to.to_string()),
+                    kind.name()
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        format!(
+            "{{\n  \"methods\": [{}\n  ],\n  \"edges\": [{}\n  ],\n  \"unresolved\": [{}\n  ]\n}}\n",
+            list(self.nodes().into_iter().collect()),
+            edges,
+            list(self.unresolved.iter().collect())
+        )
+    }
+}
+
+fn dot_string(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, fs, rc::Rc};
+
+    use super::*;
+    use crate::bytecode::*;
+    use crate::classfile::Constants;
+    use crate::visitor::{ClassVisitor, Header, Insn};
+    use crate::writer::ClassWriter;
+
+    type Call<'a> = (u8, &'a str, &'a str);
+
+    // A class whose `()V` methods make the given calls and return
+    fn class(
+        name: &str,
+        super_name: &str,
+        interfaces: &[&str],
+        flags: u16,
+        methods: &[(&str, u16, &[Call])],
+    ) -> Vec<u8> {
+        let cp = Rc::new(RefCell::new(ConstPool::default()));
+        let mut cw = ClassWriter::new();
+        cw.visit(Header {
+            minor_version: 0,
+            major_version: 52,
+            access: flags,
+            name: name.to_string(),
+            super_name: Some(super_name.to_string()),
+            interfaces: interfaces.iter().map(|i| i.to_string()).collect(),
+            const_pool: cp.clone(),
+        });
+        for (method, access, calls) in methods {
+            let mut mv = cw.visit_method(*access, method, "()V").unwrap();
+            if access & ACC_ABSTRACT != 0 {
+                mv.visit_end();
+                continue;
+            }
+            mv.visit_code();
+            for (opcode, owner, callee) in calls.iter() {
+                mv.visit_insn(Insn::Plain(
+                    *opcode,
+                    match *opcode {
+                        INVOKEINTERFACE => Operands::InvokeInterface(
+                            cp.interface_method_ref(owner, callee, "()V"),
+                            1,
+                        ),
+                        _ => Operands::Constant(cp.method_ref(owner, callee, "()V")),
+                    },
+                ));
+            }
+            mv.visit_insn(Insn::Plain(RETURN, Operands::None));
+            mv.visit_maxs(1, 1);
+            mv.visit_end();
+        }
+        cw.into_bytes().unwrap()
+    }
+
+    fn graph(test: &str) -> CallGraph {
+        let dir = std::env::temp_dir().join(format!("rustjvm-callgraph-{}", test));
+        fs::create_dir_all(dir.join("lib/ext")).unwrap();
+        let object = "java/lang/Object";
+        for (name, data) in [
+            ("I", class("I", object, &[], 0x0601, &[("f", 0x0401, &[])])),
+            (
+                "A",
+                class(
+                    "A",
+                    object,
+                    &["I"],
+                    0x0021,
+                    &[("<init>", 0x0001, &[]), ("f", 0x0001, &[])],
+                ),
+            ),
+            (
+                "B",
+                class(
+                    "B",
+                    "A",
+                    &[],
+                    0x0021,
+                    &[
+                        ("<init>", 0x0001, &[]),
+                        ("f", 0x0001, &[(INVOKESTATIC, "Util", "g")]),
+                    ],
+                ),
+            ),
+            // Never loaded, as nothing reachable uses it
+            ("C", class("C", "A", &[], 0x0021, &[("f", 0x0001, &[])])),
+            (
+                "Util",
+                class(
+                    "Util",
+                    object,
+                    &[],
+                    0x0021,
+                    &[("g", 0x0009, &[(INVOKESTATIC, "Missing", "h")])],
+                ),
+            ),
+            (
+                "Main",
+                class(
+                    "Main",
+                    object,
+                    &[],
+                    0x0021,
+                    &[
+                        (
+                            "main",
+                            0x0009,
+                            &[(INVOKEINTERFACE, "I", "f"), (INVOKESTATIC, "Main", "make")],
+                        ),
+                        ("make", 0x0009, &[(INVOKESPECIAL, "B", "<init>")]),
+                    ],
+                ),
+            ),
+        ] {
+            fs::write(dir.join(format!("{}.class", name)), data).unwrap();
+        }
+        let dir = dir.to_str().unwrap().to_string();
+        build(
+            &ClassPath::new(Some(dir.clone()), dir).unwrap(),
+            &["Main".to_string()],
+            &[],
+        )
+    }
+
+    fn id(s: &str) -> MethodId {
+        let (class, name) = s.split_once('.').unwrap();
+        MethodId::new(class, name, "()V")
+    }
+
+    #[test]
+    fn test_build() {
+        let graph = graph("build");
+        let calls = |from: &str| -> BTreeSet<(String, CallKind)> {
+            graph
+                .edges
+                .iter()
+                .filter(|(caller, _, _)| *caller == id(from))
+                .map(|(_, callee, kind)| (callee.to_string(), *kind))
+                .collect()
+        };
+        // B was loaded after the call to I.f was seen
+        assert!(calls("Main.main").contains(&("A.f()V".to_string(), CallKind::Interface)));
+        assert!(calls("Main.main").contains(&("B.f()V".to_string(), CallKind::Interface)));
+        assert!(!graph.methods.contains(&id("C.f")));
+        assert_eq!(
+            calls("B.f"),
+            BTreeSet::from([("Util.g()V".to_string(), CallKind::Static)])
+        );
+        assert!(graph.methods.contains(&id("Util.g")));
+        assert_eq!(graph.unresolved, BTreeSet::from([id("Missing.h")]));
+    }
+
+    #[test]
+    fn test_export() {
+        let graph = graph("export");
+        let dot = graph.to_dot();
+        assert!(dot.starts_with("digraph calls {\n"));
+        assert!(dot.contains("  \"Main.make()V\" -> \"B.<init>()V\" [label=special];\n"));
+        assert!(dot.contains("  \"Missing.h()V\" [style=dashed];\n"));
+        let json = graph.to_json();
+        assert!(
+            json.contains("{\"from\": \"B.f()V\", \"to\": \"Util.g()V\", \"kind\": \"static\"}")
+        );
+        assert!(json.contains("\"unresolved\": [\n    \"Missing.h()V\"\n  ]"));
+        assert_eq!(json_string("a\"b\\\n"), "\"a\\\"b\\\\\\u000a\"");
+    }
+}
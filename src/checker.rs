@@ -0,0 +1,938 @@
+// Format checking of a loaded class, following the static constraints of
+// JVMS 4.8: constant pool well-formedness, legal names and descriptors,
+// access flag combinations and attribute placement.
+
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::attribute::Attribute;
+use crate::classfile::{Const, ConstPool};
+use crate::descriptor::{self, FieldType};
+use crate::loader::{decode_utf8, Class, Field};
+
+pub const ACC_PUBLIC: u16 = 0x0001;
+pub const ACC_PRIVATE: u16 = 0x0002;
+pub const ACC_PROTECTED: u16 = 0x0004;
+pub const ACC_STATIC: u16 = 0x0008;
+pub const ACC_FINAL: u16 = 0x0010;
+pub const ACC_SUPER: u16 = 0x0020;
+pub const ACC_SYNCHRONIZED: u16 = 0x0020;
+pub const ACC_VOLATILE: u16 = 0x0040;
+pub const ACC_BRIDGE: u16 = 0x0040;
+pub const ACC_TRANSIENT: u16 = 0x0080;
+pub const ACC_VARARGS: u16 = 0x0080;
+pub const ACC_NATIVE: u16 = 0x0100;
+pub const ACC_INTERFACE: u16 = 0x0200;
+pub const ACC_ABSTRACT: u16 = 0x0400;
+pub const ACC_STRICT: u16 = 0x0800;
+pub const ACC_SYNTHETIC: u16 = 0x1000;
+pub const ACC_ANNOTATION: u16 = 0x2000;
+pub const ACC_ENUM: u16 = 0x4000;
+pub const ACC_MODULE: u16 = 0x8000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FormatError {
+    // Where in the class the problem was found, e.g. "method foo(I)V"
+    pub location: String,
+    pub message: String,
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.location, self.message)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Location {
+    Class,
+    Field,
+    Method,
+    Code,
+}
+
+struct Checker<'a> {
+    class: &'a Class,
+    cp: &'a ConstPool,
+    errors: Vec<FormatError>,
+}
+
+// Check a class against JVMS 4.8, returning every problem found
+pub fn check(class: &Class) -> Vec<FormatError> {
+    let cp = class.const_pool.borrow();
+    let mut checker = Checker {
+        class,
+        cp: &cp,
+        errors: vec![],
+    };
+    checker.check_const_pool();
+    checker.check_class();
+    for field in &class.fields {
+        checker.check_field(field);
+    }
+    for method in &class.methods {
+        checker.check_method(method);
+    }
+    checker.check_attributes("class attributes", Location::Class, &class.attributes);
+    checker.errors
+}
+
+impl<'a> Checker<'a> {
+    fn error(&mut self, location: &str, message: String) {
+        self.errors.push(FormatError {
+            location: location.to_string(),
+            message,
+        });
+    }
+
+    fn version(&self) -> u16 {
+        self.class.major_version
+    }
+
+    fn is_interface(&self) -> bool {
+        self.class.flags & ACC_INTERFACE != 0
+    }
+
+    // Look up an index that must refer to a constant of the given kind
+    fn expect(&mut self, location: &str, index: u16, kind: &str) -> Option<&'a Const> {
+        let found = match self.cp.get(index) {
+            None => {
+                let count = self.cp.count();
+                self.error(
+                    location,
+                    format!(
+                        "invalid constant pool index {} (pool count {})",
+                        index, count
+                    ),
+                );
+                return None;
+            }
+            Some(c) => c.kind(),
+        };
+        if found != kind {
+            self.error(
+                location,
+                format!(
+                    "constant pool index {} is {}, expected {}",
+                    index, found, kind
+                ),
+            );
+            return None;
+        }
+        self.cp.get(index)
+    }
+
+    fn expect_utf8(&mut self, location: &str, index: u16) -> Option<String> {
+        match self.expect(location, index, "Utf8") {
+            Some(Const::Utf8(s)) => Some(s.clone()),
+            Some(Const::RawUtf8(bytes)) => decode_utf8(bytes).ok(),
+            _ => None,
+        }
+    }
+
+    fn expect_class(&mut self, location: &str, index: u16) -> Option<String> {
+        match self.expect(location, index, "Class") {
+            Some(Const::Class { name_index, .. }) => Some(self.cp.resolve(*name_index)),
+            _ => None,
+        }
+    }
+
+    fn expect_name_and_type(&mut self, location: &str, index: u16) -> Option<(String, String)> {
+        self.expect(location, index, "NameAndType")?;
+        Some(self.cp.name_and_type(index))
+    }
+
+    fn require_version(&mut self, location: &str, what: &str, major: u16) {
+        if self.version() < major {
+            self.error(
+                location,
+                format!(
+                    "{} requires class file version {}, found {}",
+                    what,
+                    major,
+                    self.version()
+                ),
+            );
+        }
+    }
+
+    fn check_const_pool(&mut self) {
+        let cp = self.cp;
+        for index in 1..cp.count() {
+            let location = format!("constant pool #{}", index);
+            let location = location.as_str();
+            match cp.get(index) {
+                Some(Const::Class { name_index, .. }) => {
+                    if let Some(name) = self.expect_utf8(location, *name_index) {
+                        if !descriptor::is_class_name(&name) {
+                            self.error(location, format!("illegal class name \"{}\"", name));
+                        }
+                    }
+                }
+                Some(Const::String { string_index, .. }) => {
+                    self.expect_utf8(location, *string_index);
+                }
+                Some(Const::FieldRef {
+                    class_index,
+                    name_and_type_index,
+                    ..
+                }) => self.check_member_ref(location, true, *class_index, *name_and_type_index),
+                Some(Const::MethodRef {
+                    class_index,
+                    name_and_type_index,
+                    ..
+                })
+                | Some(Const::InterfaceMethodRef {
+                    class_index,
+                    name_and_type_index,
+                    ..
+                }) => self.check_member_ref(location, false, *class_index, *name_and_type_index),
+                Some(Const::NameAndType {
+                    name_index,
+                    descriptor_index,
+                    ..
+                }) => {
+                    self.expect_utf8(location, *name_index);
+                    self.expect_utf8(location, *descriptor_index);
+                }
+                Some(Const::MethodHandle {
+                    reference_kind,
+                    reference_index,
+                    ..
+                }) => {
+                    self.require_version(location, "CONSTANT_MethodHandle", 51);
+                    self.check_method_handle(location, *reference_kind, *reference_index);
+                }
+                Some(Const::MethodType {
+                    descriptor_index, ..
+                }) => {
+                    self.require_version(location, "CONSTANT_MethodType", 51);
+                    if let Some(desc) = self.expect_utf8(location, *descriptor_index) {
+                        if descriptor::parse_method(&desc).is_none() {
+                            self.error(location, format!("illegal method descriptor \"{}\"", desc));
+                        }
+                    }
+                }
+                Some(Const::Dynamic {
+                    name_and_type_index,
+                    ..
+                }) => {
+                    self.require_version(location, "CONSTANT_Dynamic", 55);
+                    self.check_dynamic(location, *name_and_type_index, true);
+                }
+                Some(Const::InvokeDynamic {
+                    name_and_type_index,
+                    ..
+                }) => {
+                    self.require_version(location, "CONSTANT_InvokeDynamic", 51);
+                    self.check_dynamic(location, *name_and_type_index, false);
+                }
+                Some(Const::Module { name_index, .. })
+                | Some(Const::Package { name_index, .. }) => {
+                    self.require_version(location, "CONSTANT_Module and CONSTANT_Package", 53);
+                    if self.class.flags & ACC_MODULE == 0 {
+                        self.error(
+                            location,
+                            "CONSTANT_Module and CONSTANT_Package are only allowed in module-info"
+                                .to_string(),
+                        );
+                    }
+                    self.expect_utf8(location, *name_index);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn check_dynamic(&mut self, location: &str, nat_index: u16, is_field: bool) {
+        let (name, desc) = match self.expect_name_and_type(location, nat_index) {
+            Some(nat) => nat,
+            None => return,
+        };
+        let valid = if is_field {
+            descriptor::parse_field(&desc).is_some()
+        } else {
+            descriptor::parse_method(&desc).is_some()
+        };
+        if !valid {
+            self.error(location, format!("illegal descriptor \"{}\"", desc));
+        }
+        if !descriptor::is_method_name(&name) || name.starts_with('<') {
+            self.error(location, format!("illegal name \"{}\"", name));
+        }
+    }
+
+    fn check_member_ref(
+        &mut self,
+        location: &str,
+        is_field: bool,
+        class_index: u16,
+        nat_index: u16,
+    ) {
+        self.expect_class(location, class_index);
+        let (name, desc) = match self.expect_name_and_type(location, nat_index) {
+            Some(nat) => nat,
+            None => return,
+        };
+        if is_field {
+            if !descriptor::is_unqualified_name(&name) {
+                self.error(location, format!("illegal field name \"{}\"", name));
+            }
+            if descriptor::parse_field(&desc).is_none() {
+                self.error(location, format!("illegal field descriptor \"{}\"", desc));
+            }
+            return;
+        }
+        if !descriptor::is_method_name(&name) || name == "<clinit>" {
+            self.error(location, format!("illegal method name \"{}\"", name));
+        }
+        match descriptor::parse_method(&desc) {
+            None => self.error(location, format!("illegal method descriptor \"{}\"", desc)),
+            Some(d) if name == "<init>" && d.ret.is_some() => {
+                self.error(location, "method <init> must return void".to_string())
+            }
+            _ => {}
+        }
+    }
+
+    fn check_method_handle(&mut self, location: &str, kind: u8, index: u16) {
+        let target = match self.cp.get(index) {
+            Some(c) => c.kind(),
+            None => {
+                self.error(location, format!("invalid reference index {}", index));
+                return;
+            }
+        };
+        let allowed: &[&str] = match kind {
+            1..=4 => &["Fieldref"],
+            5 | 8 => &["Methodref"],
+            6 | 7 if self.version() >= 52 => &["Methodref", "InterfaceMethodref"],
+            6 | 7 => &["Methodref"],
+            9 => &["InterfaceMethodref"],
+            _ => {
+                self.error(location, format!("illegal reference kind {}", kind));
+                return;
+            }
+        };
+        if !allowed.contains(&target) {
+            self.error(
+                location,
+                format!(
+                    "reference kind {} cannot refer to a {} constant",
+                    kind, target
+                ),
+            );
+            return;
+        }
+        if kind >= 5 {
+            let nat_index = match self.cp.get(index) {
+                Some(Const::MethodRef {
+                    name_and_type_index,
+                    ..
+                })
+                | Some(Const::InterfaceMethodRef {
+                    name_and_type_index,
+                    ..
+                }) => *name_and_type_index,
+                _ => return,
+            };
+            let (name, _) = self.cp.name_and_type(nat_index);
+            if kind == 8 && name != "<init>" {
+                self.error(
+                    location,
+                    "REF_newInvokeSpecial must refer to <init>".to_string(),
+                );
+            } else if kind != 8 && name.starts_with('<') {
+                self.error(
+                    location,
+                    format!("reference kind {} cannot refer to {}", kind, name),
+                );
+            }
+        }
+    }
+
+    fn check_class(&mut self) {
+        let location = "class";
+        let flags = self.class.flags;
+        if flags & ACC_MODULE != 0 {
+            if self.class.name() != "module-info" {
+                self.error(
+                    location,
+                    "ACC_MODULE class must be named module-info".to_string(),
+                );
+            }
+            if self.class.super_class != 0
+                || !self.class.interfaces.is_empty()
+                || !self.class.fields.is_empty()
+                || !self.class.methods.is_empty()
+            {
+                self.error(
+                    location,
+                    "module-info must not have a superclass, interfaces, fields or methods"
+                        .to_string(),
+                );
+            }
+            return;
+        }
+        if flags & ACC_INTERFACE != 0 {
+            if flags & ACC_ABSTRACT == 0 {
+                self.error(location, "interface must be ACC_ABSTRACT".to_string());
+            }
+            if flags & (ACC_FINAL | ACC_SUPER | ACC_ENUM) != 0 {
+                self.error(
+                    location,
+                    format!("illegal class modifiers in interface: 0x{:04x}", flags),
+                );
+            }
+        } else {
+            if flags & ACC_ANNOTATION != 0 {
+                self.error(
+                    location,
+                    "ACC_ANNOTATION is only allowed on interfaces".to_string(),
+                );
+            }
+            if flags & ACC_FINAL != 0 && flags & ACC_ABSTRACT != 0 {
+                self.error(
+                    location,
+                    "class cannot be both final and abstract".to_string(),
+                );
+            }
+        }
+
+        let this_class = self.class.this_class;
+        let name = self
+            .expect_class("this_class", this_class)
+            .unwrap_or_default();
+        if name.starts_with('[') {
+            self.error("this_class", format!("\"{}\" is an array type", name));
+        }
+
+        let super_class = self.class.super_class;
+        if super_class == 0 {
+            if name != "java/lang/Object" {
+                self.error(
+                    "super_class",
+                    "only java/lang/Object may omit a superclass".to_string(),
+                );
+            }
+        } else if let Some(super_name) = self.expect_class("super_class", super_class) {
+            if super_name.starts_with('[') {
+                self.error(
+                    "super_class",
+                    format!("\"{}\" is an array type", super_name),
+                );
+            }
+            if self.is_interface() && super_name != "java/lang/Object" {
+                self.error(
+                    "super_class",
+                    "interface superclass must be java/lang/Object".to_string(),
+                );
+            }
+        }
+
+        let mut seen = HashSet::new();
+        for &interface in &self.class.interfaces {
+            if let Some(interface_name) = self.expect_class("interfaces", interface) {
+                if interface_name.starts_with('[') {
+                    self.error(
+                        "interfaces",
+                        format!("\"{}\" is an array type", interface_name),
+                    );
+                }
+                if !seen.insert(interface_name.clone()) {
+                    self.error(
+                        "interfaces",
+                        format!("duplicate interface {}", interface_name),
+                    );
+                }
+            }
+        }
+    }
+
+    fn check_access(&mut self, location: &str, flags: u16) {
+        let access = flags & (ACC_PUBLIC | ACC_PRIVATE | ACC_PROTECTED);
+        if access.count_ones() > 1 {
+            self.error(
+                location,
+                format!("conflicting access modifiers 0x{:04x}", flags),
+            );
+        }
+    }
+
+    fn check_field(&mut self, field: &Field) {
+        let name = self
+            .expect_utf8("field", field.name_index)
+            .unwrap_or_default();
+        let desc = self
+            .expect_utf8("field", field.descriptor_index)
+            .unwrap_or_default();
+        let location = format!("field {}:{}", name, desc);
+        let location = location.as_str();
+        if !descriptor::is_unqualified_name(&name) {
+            self.error(location, format!("illegal field name \"{}\"", name));
+        }
+        let field_type = descriptor::parse_field(&desc);
+        match &field_type {
+            None => self.error(location, format!("illegal field descriptor \"{}\"", desc)),
+            Some(t) if t.dimensions() > 255 => self.error(
+                location,
+                "array type has more than 255 dimensions".to_string(),
+            ),
+            _ => {}
+        }
+        if self
+            .class
+            .fields
+            .iter()
+            .filter(|f| f.name() == name && f.descriptor() == desc)
+            .count()
+            > 1
+        {
+            self.error(location, "duplicate field name and signature".to_string());
+        }
+
+        let flags = field.flags;
+        self.check_access(location, flags);
+        if flags & ACC_FINAL != 0 && flags & ACC_VOLATILE != 0 {
+            self.error(
+                location,
+                "field cannot be both final and volatile".to_string(),
+            );
+        }
+        if self.is_interface() {
+            let required = ACC_PUBLIC | ACC_STATIC | ACC_FINAL;
+            if flags & required != required || flags & !(required | ACC_SYNTHETIC) != 0 {
+                self.error(
+                    location,
+                    format!("illegal interface field modifiers 0x{:04x}", flags),
+                );
+            }
+        }
+
+        self.check_attributes(location, Location::Field, &field.attributes);
+        for attr in &field.attributes {
+            if let Attribute::ConstantValue(index) = attr {
+                self.check_constant_value(location, *index, field_type.as_ref());
+            }
+        }
+    }
+
+    fn check_constant_value(&mut self, location: &str, index: u16, field_type: Option<&FieldType>) {
+        let expected = match field_type {
+            Some(FieldType::Long) => "Long",
+            Some(FieldType::Float) => "Float",
+            Some(FieldType::Double) => "Double",
+            Some(FieldType::Object(name)) if name == "java/lang/String" => "String",
+            Some(FieldType::Object(_)) | Some(FieldType::Array(_)) => {
+                self.error(
+                    location,
+                    "ConstantValue on a non-String reference field".to_string(),
+                );
+                return;
+            }
+            Some(_) => "Integer",
+            None => return,
+        };
+        self.expect(location, index, expected);
+    }
+
+    fn check_method(&mut self, method: &Field) {
+        let name = self
+            .expect_utf8("method", method.name_index)
+            .unwrap_or_default();
+        let desc = self
+            .expect_utf8("method", method.descriptor_index)
+            .unwrap_or_default();
+        let location = format!("method {}{}", name, desc);
+        let location = location.as_str();
+        let flags = method.flags;
+
+        if !descriptor::is_method_name(&name) {
+            self.error(location, format!("illegal method name \"{}\"", name));
+        }
+        let parsed = descriptor::parse_method(&desc);
+        match &parsed {
+            None => self.error(location, format!("illegal method descriptor \"{}\"", desc)),
+            Some(d) => {
+                let this_slot = if flags & ACC_STATIC == 0 { 1 } else { 0 };
+                if d.param_slots() + this_slot > 255 {
+                    self.error(
+                        location,
+                        "too many arguments in method signature".to_string(),
+                    );
+                }
+                if name == "<init>" && d.ret.is_some() {
+                    self.error(location, "method <init> must return void".to_string());
+                }
+            }
+        }
+        if self
+            .class
+            .methods
+            .iter()
+            .filter(|m| m.name() == name && m.descriptor() == desc)
+            .count()
+            > 1
+        {
+            self.error(location, "duplicate method name and signature".to_string());
+        }
+
+        self.check_access(location, flags);
+        let is_initializer = name == "<clinit>" && (self.version() < 51 || flags & ACC_STATIC != 0);
+        if name == "<clinit>" && self.version() >= 51 && flags & ACC_STATIC == 0 {
+            self.error(location, "<clinit> must be static".to_string());
+        }
+        if !is_initializer {
+            self.check_method_flags(location, &name, flags);
+        }
+
+        self.check_attributes(location, Location::Method, &method.attributes);
+        let code_count = method
+            .attributes
+            .iter()
+            .filter(|a| matches!(a, Attribute::Code { .. }))
+            .count();
+        if flags & (ACC_ABSTRACT | ACC_NATIVE) != 0 {
+            if code_count > 0 {
+                self.error(
+                    location,
+                    "abstract or native method must not have Code".to_string(),
+                );
+            }
+        } else if code_count == 0 {
+            self.error(location, "missing Code attribute".to_string());
+        }
+
+        for attr in &method.attributes {
+            match attr {
+                Attribute::Code { .. } => {
+                    let args = parsed.as_ref().map_or(0, |d| d.param_slots())
+                        + if flags & ACC_STATIC == 0 { 1 } else { 0 };
+                    self.check_code(location, attr, args);
+                }
+                Attribute::Exceptions {
+                    exception_index_table,
+                } => {
+                    for &index in exception_index_table {
+                        self.expect_class(location, index);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn check_method_flags(&mut self, location: &str, name: &str, flags: u16) {
+        if self.is_interface() {
+            let illegal = if self.version() < 52 {
+                flags & (ACC_PUBLIC | ACC_ABSTRACT) != (ACC_PUBLIC | ACC_ABSTRACT)
+                    || flags
+                        & !(ACC_PUBLIC | ACC_ABSTRACT | ACC_BRIDGE | ACC_VARARGS | ACC_SYNTHETIC)
+                        != 0
+            } else {
+                flags & (ACC_PROTECTED | ACC_FINAL | ACC_SYNCHRONIZED | ACC_NATIVE) != 0
+                    || (flags & (ACC_PUBLIC | ACC_PRIVATE)).count_ones() != 1
+            };
+            if illegal {
+                self.error(
+                    location,
+                    format!("illegal interface method modifiers 0x{:04x}", flags),
+                );
+            }
+            if name == "<init>" {
+                self.error(location, "interface cannot declare <init>".to_string());
+            }
+        }
+        if flags & ACC_ABSTRACT != 0 {
+            let mut forbidden =
+                ACC_PRIVATE | ACC_STATIC | ACC_FINAL | ACC_SYNCHRONIZED | ACC_NATIVE;
+            // ACC_STRICT lost its meaning in Java 17
+            if (46..61).contains(&self.version()) {
+                forbidden |= ACC_STRICT;
+            }
+            if flags & forbidden != 0 {
+                self.error(
+                    location,
+                    format!("illegal abstract method modifiers 0x{:04x}", flags),
+                );
+            }
+        }
+        if name == "<init>" {
+            let allowed =
+                ACC_PUBLIC | ACC_PRIVATE | ACC_PROTECTED | ACC_VARARGS | ACC_STRICT | ACC_SYNTHETIC;
+            if flags & !allowed != 0 {
+                self.error(
+                    location,
+                    format!("illegal <init> modifiers 0x{:04x}", flags),
+                );
+            }
+        }
+    }
+
+    fn check_code(&mut self, method: &str, code_attr: &Attribute, args: usize) {
+        let location = format!("{}, Code", method);
+        let location = location.as_str();
+        let Attribute::Code {
+            max_locals,
+            code,
+            exception_table,
+            attributes,
+            ..
+        } = code_attr
+        else {
+            return;
+        };
+        let code_length = code.len();
+        if code_length == 0 || code_length > 65535 {
+            self.error(location, format!("invalid code length {}", code_length));
+        }
+        if (*max_locals as usize) < args {
+            self.error(
+                location,
+                format!(
+                    "max_locals {} is less than the size of the arguments ({})",
+                    max_locals, args
+                ),
+            );
+        }
+        for entry in exception_table {
+            if entry.start_pc >= entry.end_pc || entry.end_pc as usize > code_length {
+                self.error(
+                    location,
+                    format!(
+                        "illegal exception table range [{}, {})",
+                        entry.start_pc, entry.end_pc
+                    ),
+                );
+            }
+            if entry.handler_pc as usize >= code_length {
+                self.error(
+                    location,
+                    format!("illegal exception table handler {}", entry.handler_pc),
+                );
+            }
+            if entry.catch_type != 0 {
+                self.expect_class(location, entry.catch_type);
+            }
+        }
+
+        self.check_attributes(location, Location::Code, attributes);
+        for attr in attributes {
+            match attr {
+                Attribute::LineNumberTable { line_number_table } => {
+                    for entry in line_number_table {
+                        if entry.start_pc as usize >= code_length {
+                            self.error(
+                                location,
+                                format!("illegal start_pc {} in LineNumberTable", entry.start_pc),
+                            );
+                        }
+                    }
+                }
+                Attribute::LocalVariableTable {
+                    local_variable_table,
+                } => {
+                    for entry in local_variable_table {
+                        if entry.start_pc as usize + entry.length as usize > code_length {
+                            self.error(
+                                location,
+                                format!(
+                                    "illegal range [{}, {}) in LocalVariableTable",
+                                    entry.start_pc,
+                                    entry.start_pc as usize + entry.length as usize
+                                ),
+                            );
+                        }
+                        let name = self.expect_utf8(location, entry.name_index);
+                        if let Some(name) = name.filter(|n| !descriptor::is_unqualified_name(n)) {
+                            self.error(
+                                location,
+                                format!("illegal local variable name \"{}\"", name),
+                            );
+                        }
+                        let Some(desc) = self.expect_utf8(location, entry.descriptor_index) else {
+                            continue;
+                        };
+                        match descriptor::parse_field(&desc) {
+                            None => self.error(
+                                location,
+                                format!("illegal local variable descriptor \"{}\"", desc),
+                            ),
+                            Some(t) if entry.index as usize + t.slots() > *max_locals as usize => {
+                                self.error(
+                                    location,
+                                    format!("local variable index {} out of range", entry.index),
+                                )
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn check_attributes(&mut self, location: &str, at: Location, attributes: &[Attribute]) {
+        let mut seen = HashSet::new();
+        for attr in attributes {
+            let name = attr.name();
+            if !allowed(at, attr) {
+                self.error(location, format!("{} attribute is not allowed here", name));
+            }
+            let repeatable = matches!(
+                attr,
+                Attribute::LineNumberTable { .. }
+                    | Attribute::LocalVariableTable { .. }
+                    | Attribute::LocalVariableTypeTable(_)
+            );
+            if !seen.insert(name) && !repeatable {
+                self.error(location, format!("multiple {} attributes", name));
+            }
+        }
+    }
+}
+
+// Where each predefined attribute may appear (JVMS table 4.7-C)
+fn allowed(at: Location, attr: &Attribute) -> bool {
+    match attr {
+        Attribute::SourceFile { .. }
+        | Attribute::InnerClasses(_)
+        | Attribute::EnclosingMethod(_)
+        | Attribute::SourceDebugExtension(_)
+        | Attribute::BootstrapMethods(_) => at == Location::Class,
+        Attribute::ConstantValue(_) => at == Location::Field,
+        Attribute::Code { .. }
+        | Attribute::Exceptions { .. }
+        | Attribute::RuntimeVisibleParameterAnnotations(_)
+        | Attribute::RuntimeInvisibleParameterAnnotations(_)
+        | Attribute::AnnotationDefault(_)
+        | Attribute::MethodParameters(_) => at == Location::Method,
+        Attribute::LineNumberTable { .. }
+        | Attribute::LocalVariableTable { .. }
+        | Attribute::LocalVariableTypeTable(_)
+        | Attribute::StackMapTable { .. } => at == Location::Code,
+        Attribute::Synthetic
+        | Attribute::Deprecated
+        | Attribute::Signature(_)
+        | Attribute::RuntimeVisibleAnnotations(_)
+        | Attribute::RuntimeInvisibleAnnotations(_) => at != Location::Code,
+        Attribute::RuntimeVisibleTypeAnnotations(_)
+        | Attribute::RuntimeInvisibleTypeAnnotations(_)
+        | Attribute::Unknown { .. } => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+
+    // Foo extends java/lang/Object, with the Utf8 entries `x` (#5) and `I` (#6)
+    fn class() -> Class {
+        let cp = Rc::new(RefCell::new(ConstPool::default()));
+        for c in [
+            Const::Utf8("Foo".to_string()),
+            Const::Class {
+                cp: Rc::downgrade(&cp),
+                name_index: 1,
+            },
+            Const::Utf8("java/lang/Object".to_string()),
+            Const::Class {
+                cp: Rc::downgrade(&cp),
+                name_index: 3,
+            },
+            Const::Utf8("x".to_string()),
+            Const::Utf8("I".to_string()),
+        ] {
+            cp.borrow_mut().push(c);
+        }
+        Class {
+            major_version: 52,
+            const_pool: cp,
+            flags: ACC_PUBLIC | ACC_SUPER,
+            this_class: 2,
+            super_class: 4,
+            ..Default::default()
+        }
+    }
+
+    fn field(class: &Class, flags: u16, name_index: u16, descriptor_index: u16) -> Field {
+        Field {
+            cp: class.const_pool.clone(),
+            flags,
+            name_index,
+            descriptor_index,
+            attributes: vec![],
+        }
+    }
+
+    #[test]
+    fn test_valid_class() {
+        let mut c = class();
+        c.fields.push(field(&c, ACC_PRIVATE, 5, 6));
+        assert_eq!(check(&c), vec![]);
+    }
+
+    #[test]
+    fn test_this_class_must_be_class() {
+        let mut c = class();
+        c.this_class = 1;
+        let errors = check(&c);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].location, "this_class");
+        assert_eq!(
+            errors[0].message,
+            "constant pool index 1 is Utf8, expected Class"
+        );
+    }
+
+    #[test]
+    fn test_illegal_field() {
+        let mut c = class();
+        // descriptor `x` and conflicting access flags
+        c.fields.push(field(&c, ACC_PUBLIC | ACC_PRIVATE, 5, 5));
+        let errors: Vec<String> = check(&c).iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            errors,
+            vec![
+                "field x:x: illegal field descriptor \"x\"",
+                "field x:x: conflicting access modifiers 0x0003",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_missing_code() {
+        let mut c = class();
+        c.const_pool
+            .borrow_mut()
+            .push(Const::Utf8("()V".to_string()));
+        c.methods.push(field(&c, ACC_PUBLIC, 5, 7));
+        let errors = check(&c);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].location, "method x()V");
+        assert_eq!(errors[0].message, "missing Code attribute");
+    }
+
+    #[test]
+    fn test_misplaced_attribute() {
+        let mut c = class();
+        let mut f = field(&c, ACC_STATIC, 5, 6);
+        f.attributes.push(Attribute::Exceptions {
+            exception_index_table: vec![],
+        });
+        f.attributes.push(Attribute::Deprecated);
+        f.attributes.push(Attribute::Deprecated);
+        c.fields.push(f);
+        let errors: Vec<String> = check(&c).iter().map(|e| e.message.clone()).collect();
+        assert_eq!(
+            errors,
+            vec![
+                "Exceptions attribute is not allowed here",
+                "multiple Deprecated attributes",
+            ]
+        );
+    }
+}
@@ -1,82 +1,301 @@
-use std::{cell::RefCell, rc::Rc};
+use std::{
+    cell::RefCell,
+    rc::{Rc, Weak},
+};
+
+use crate::loader::decode_utf8;
 
 #[repr(u8)]
 pub enum Const {
     Utf8(String), // 标签值 1
-    Integer(i32), // 标签值 3
-    Float(f32),   // 标签值 4
-    Long(i64),    // 标签值 5
-    Double(f64),  // 标签值 6
+    // A Utf8 entry that a String cannot hold exactly, such as one with an
+    // unpaired surrogate, kept as its modified UTF-8 bytes
+    RawUtf8(Vec<u8>), // 标签值 1
+    Integer(i32),     // 标签值 3
+    Float(f32),       // 标签值 4
+    Long(i64),        // 标签值 5
+    Double(f64),      // 标签值 6
     Class {
-        cp: Rc<RefCell<ConstPool>>,
+        // Weak, as the pool owns its entries
+        cp: Weak<RefCell<ConstPool>>,
         name_index: u16,
     }, // 标签值 7
     String {
-        cp: Rc<RefCell<ConstPool>>,
+        cp: Weak<RefCell<ConstPool>>,
         string_index: u16,
     }, // 标签值 8
     FieldRef {
-        cp: Rc<RefCell<ConstPool>>,
+        cp: Weak<RefCell<ConstPool>>,
         class_index: u16,
         name_and_type_index: u16,
     }, // 标签值 9
     MethodRef {
-        cp: Rc<RefCell<ConstPool>>,
+        cp: Weak<RefCell<ConstPool>>,
         class_index: u16,
         name_and_type_index: u16,
     }, // 标签值 10
     InterfaceMethodRef {
-        cp: Rc<RefCell<ConstPool>>,
+        cp: Weak<RefCell<ConstPool>>,
         class_index: u16,
         name_and_type_index: u16,
     }, // 标签值 11
     NameAndType {
-        cp: Rc<RefCell<ConstPool>>,
+        cp: Weak<RefCell<ConstPool>>,
         name_index: u16,
         descriptor_index: u16,
     }, // 标签值 12
     MethodHandle {
-        cp: Rc<RefCell<ConstPool>>,
+        cp: Weak<RefCell<ConstPool>>,
         reference_kind: u8,
         reference_index: u16,
     }, // 标签值 15
     MethodType {
-        cp: Rc<RefCell<ConstPool>>,
+        cp: Weak<RefCell<ConstPool>>,
         descriptor_index: u16,
     }, // 标签值 16
     Dynamic {
-        cp: Rc<RefCell<ConstPool>>,
+        cp: Weak<RefCell<ConstPool>>,
         bootstrap_method_attr_index: u16,
         name_and_type_index: u16,
     }, // 标签值 17
     InvokeDynamic {
-        cp: Rc<RefCell<ConstPool>>,
+        cp: Weak<RefCell<ConstPool>>,
         bootstrap_method_attr_index: u16,
         name_and_type_index: u16,
     }, // 标签值 18
     Module {
-        cp: Rc<RefCell<ConstPool>>,
+        cp: Weak<RefCell<ConstPool>>,
         name_index: u16,
     }, // 标签值 19
     Package {
-        cp: Rc<RefCell<ConstPool>>,
+        cp: Weak<RefCell<ConstPool>>,
         name_index: u16,
     }, // 标签值 20
+    Unusable,         // long 和 double 之后的槽位
+}
+
+impl Const {
+    pub fn tag(&self) -> u8 {
+        match self {
+            Const::Utf8(_) | Const::RawUtf8(_) => 1,
+            Const::Integer(_) => 3,
+            Const::Float(_) => 4,
+            Const::Long(_) => 5,
+            Const::Double(_) => 6,
+            Const::Class { .. } => 7,
+            Const::String { .. } => 8,
+            Const::FieldRef { .. } => 9,
+            Const::MethodRef { .. } => 10,
+            Const::InterfaceMethodRef { .. } => 11,
+            Const::NameAndType { .. } => 12,
+            Const::MethodHandle { .. } => 15,
+            Const::MethodType { .. } => 16,
+            Const::Dynamic { .. } => 17,
+            Const::InvokeDynamic { .. } => 18,
+            Const::Module { .. } => 19,
+            Const::Package { .. } => 20,
+            Const::Unusable => 0,
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Const::Utf8(_) | Const::RawUtf8(_) => "Utf8",
+            Const::Integer(_) => "Integer",
+            Const::Float(_) => "Float",
+            Const::Long(_) => "Long",
+            Const::Double(_) => "Double",
+            Const::Class { .. } => "Class",
+            Const::String { .. } => "String",
+            Const::FieldRef { .. } => "Fieldref",
+            Const::MethodRef { .. } => "Methodref",
+            Const::InterfaceMethodRef { .. } => "InterfaceMethodref",
+            Const::NameAndType { .. } => "NameAndType",
+            Const::MethodHandle { .. } => "MethodHandle",
+            Const::MethodType { .. } => "MethodType",
+            Const::Dynamic { .. } => "Dynamic",
+            Const::InvokeDynamic { .. } => "InvokeDynamic",
+            Const::Module { .. } => "Module",
+            Const::Package { .. } => "Package",
+            Const::Unusable => "unusable",
+        }
+    }
 }
 
 #[derive(Default)]
 pub struct ConstPool(Vec<Const>);
 
 impl ConstPool {
+    pub fn get(&self, index: u16) -> Option<&Const> {
+        if index == 0 {
+            return None;
+        }
+        self.0.get((index - 1) as usize)
+    }
+
+    pub fn get_mut(&mut self, index: u16) -> Option<&mut Const> {
+        if index == 0 {
+            return None;
+        }
+        self.0.get_mut((index - 1) as usize)
+    }
+
+    // constant_pool_count as it appears in the class file
+    pub fn count(&self) -> u16 {
+        self.0.len() as u16 + 1
+    }
+
     pub fn resolve(&self, index: u16) -> String {
-        let index = (index - 1) as usize;
-        match &self.0[index] {
-            Const::Utf8(s) => s.clone(),
+        match self.get(index) {
+            Some(Const::Utf8(s)) => s.clone(),
+            Some(Const::RawUtf8(bytes)) => decode_utf8(bytes).unwrap_or_default(),
+            _ => String::from(""),
+        }
+    }
+
+    pub fn class_name(&self, index: u16) -> String {
+        match self.get(index) {
+            Some(Const::Class { name_index, .. }) => self.resolve(*name_index),
             _ => String::from(""),
         }
     }
 
+    pub fn name_and_type(&self, index: u16) -> (String, String) {
+        match self.get(index) {
+            Some(Const::NameAndType {
+                name_index,
+                descriptor_index,
+                ..
+            }) => (self.resolve(*name_index), self.resolve(*descriptor_index)),
+            _ => (String::from(""), String::from("")),
+        }
+    }
+
     pub fn push(&mut self, c: Const) {
         self.0.push(c);
     }
+
+    // Index of the first entry matching `f`
+    pub fn find(&self, f: impl Fn(&Const) -> bool) -> Option<u16> {
+        self.0.iter().position(f).map(|i| i as u16 + 1)
+    }
+}
+
+// Adding constants to a shared pool, reusing an equal entry when there is
+// one, for code that generates new references
+pub trait Constants {
+    fn add(
+        &self,
+        f: impl Fn(&Const) -> bool,
+        c: impl FnOnce(Weak<RefCell<ConstPool>>) -> Const,
+    ) -> u16;
+
+    fn utf8(&self, s: &str) -> u16 {
+        self.add(
+            |c| matches!(c, Const::Utf8(x) if x == s),
+            |_| Const::Utf8(s.to_string()),
+        )
+    }
+
+    fn integer(&self, value: i32) -> u16 {
+        self.add(
+            |c| matches!(c, Const::Integer(x) if *x == value),
+            |_| Const::Integer(value),
+        )
+    }
+
+    fn class(&self, name: &str) -> u16 {
+        let name_index = self.utf8(name);
+        self.add(
+            |c| matches!(c, Const::Class { name_index: x, .. } if *x == name_index),
+            |cp| Const::Class { cp, name_index },
+        )
+    }
+
+    fn string(&self, s: &str) -> u16 {
+        let string_index = self.utf8(s);
+        self.add(
+            |c| matches!(c, Const::String { string_index: x, .. } if *x == string_index),
+            |cp| Const::String { cp, string_index },
+        )
+    }
+
+    fn name_and_type(&self, name: &str, descriptor: &str) -> u16 {
+        let name_index = self.utf8(name);
+        let descriptor_index = self.utf8(descriptor);
+        self.add(
+            |c| {
+                matches!(c, Const::NameAndType { name_index: x, descriptor_index: y, .. }
+                    if *x == name_index && *y == descriptor_index)
+            },
+            |cp| Const::NameAndType {
+                cp,
+                name_index,
+                descriptor_index,
+            },
+        )
+    }
+
+    fn field_ref(&self, owner: &str, name: &str, descriptor: &str) -> u16 {
+        let class_index = self.class(owner);
+        let name_and_type_index = self.name_and_type(name, descriptor);
+        self.add(
+            |c| {
+                matches!(c, Const::FieldRef { class_index: x, name_and_type_index: y, .. }
+                    if *x == class_index && *y == name_and_type_index)
+            },
+            |cp| Const::FieldRef {
+                cp,
+                class_index,
+                name_and_type_index,
+            },
+        )
+    }
+
+    fn method_ref(&self, owner: &str, name: &str, descriptor: &str) -> u16 {
+        let class_index = self.class(owner);
+        let name_and_type_index = self.name_and_type(name, descriptor);
+        self.add(
+            |c| {
+                matches!(c, Const::MethodRef { class_index: x, name_and_type_index: y, .. }
+                    if *x == class_index && *y == name_and_type_index)
+            },
+            |cp| Const::MethodRef {
+                cp,
+                class_index,
+                name_and_type_index,
+            },
+        )
+    }
+
+    fn interface_method_ref(&self, owner: &str, name: &str, descriptor: &str) -> u16 {
+        let class_index = self.class(owner);
+        let name_and_type_index = self.name_and_type(name, descriptor);
+        self.add(
+            |c| {
+                matches!(c, Const::InterfaceMethodRef { class_index: x, name_and_type_index: y, .. }
+                    if *x == class_index && *y == name_and_type_index)
+            },
+            |cp| Const::InterfaceMethodRef {
+                cp,
+                class_index,
+                name_and_type_index,
+            },
+        )
+    }
+}
+
+impl Constants for Rc<RefCell<ConstPool>> {
+    fn add(
+        &self,
+        f: impl Fn(&Const) -> bool,
+        c: impl FnOnce(Weak<RefCell<ConstPool>>) -> Const,
+    ) -> u16 {
+        if let Some(index) = self.borrow().find(f) {
+            return index;
+        }
+        let mut cp = self.borrow_mut();
+        cp.push(c(Rc::downgrade(self)));
+        cp.count() - 1
+    }
 }
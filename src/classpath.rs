@@ -1,24 +1,214 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
 use std::fs::{self, File};
 use std::io::prelude::*;
-use std::path::PathBuf;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock, RwLock, Weak};
+use std::time::SystemTime;
 
+use sha2::{Digest, Sha256};
+use zip::result::ZipError;
 use zip::ZipArchive;
 
-pub trait Entry {
-    fn read_class(&self, class_name: &str) -> Result<Vec<u8>, String>;
+use crate::jar::{self, Manifest, Signatures};
+use crate::jimage::JImageEntry;
+
+// Separates class path entries, as File.pathSeparator does in Java
+pub const PATH_SEPARATOR: char = if cfg!(windows) { ';' } else { ':' };
+
+// Why a lookup failed: the resource is in none of the entries searched, or
+// an entry could not be read, as a file or as a zip
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClassPathError {
+    NotFound { name: String, searched: Vec<String> },
+    Io { path: String, message: String },
+    Corrupt { path: String, message: String },
+    // A class of a signed jar not as signed
+    Security { path: String, message: String },
+    // Entries searched in turn failing, not all for want of the resource
+    Multiple(Vec<ClassPathError>),
+}
+
+impl ClassPathError {
+    pub fn not_found(name: &str, path: &str) -> ClassPathError {
+        ClassPathError::NotFound {
+            name: name.to_string(),
+            searched: vec![path.to_string()],
+        }
+    }
+
+    pub fn is_not_found(&self) -> bool {
+        matches!(self, ClassPathError::NotFound { .. })
+    }
+
+    // The failures of entries searched in turn for `name`, as one: not found
+    // in any of them, or what went wrong besides
+    pub fn aggregate(name: &str, errors: Vec<ClassPathError>) -> ClassPathError {
+        let mut searched = vec![];
+        let mut others = vec![];
+        for error in errors {
+            match error {
+                ClassPathError::NotFound { searched: s, .. } => searched.extend(s),
+                ClassPathError::Multiple(errors) => {
+                    for error in errors {
+                        match error {
+                            ClassPathError::NotFound { searched: s, .. } => searched.extend(s),
+                            error => others.push(error),
+                        }
+                    }
+                }
+                error => others.push(error),
+            }
+        }
+        let not_found = ClassPathError::NotFound {
+            name: name.to_string(),
+            searched,
+        };
+        if others.is_empty() {
+            return not_found;
+        }
+        if matches!(&not_found, ClassPathError::NotFound { searched, .. } if !searched.is_empty()) {
+            others.push(not_found);
+        }
+        ClassPathError::Multiple(others)
+    }
+}
+
+impl fmt::Display for ClassPathError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClassPathError::NotFound { name, .. } => write!(f, "Resource not found: {}", name),
+            ClassPathError::Io { path, message } => {
+                write!(f, "Error reading {}: {}", path, message)
+            }
+            ClassPathError::Corrupt { path, message } => {
+                write!(f, "Error reading zip file {}: {}", path, message)
+            }
+            ClassPathError::Security { message, .. } => {
+                write!(f, "java.lang.SecurityException: {}", message)
+            }
+            ClassPathError::Multiple(errors) => {
+                let errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+                write!(f, "{}", errors.join("; "))
+            }
+        }
+    }
+}
+
+impl From<ClassPathError> for String {
+    fn from(e: ClassPathError) -> String {
+        e.to_string()
+    }
+}
+
+// Data that does not decompress is a corrupt zip rather than an I/O error
+fn io_error(path: &str, e: std::io::Error) -> ClassPathError {
+    let (path, message) = (path.to_string(), e.to_string());
+    match e.kind() {
+        std::io::ErrorKind::InvalidData => ClassPathError::Corrupt { path, message },
+        _ => ClassPathError::Io { path, message },
+    }
+}
+
+fn zip_error(path: &str, e: ZipError) -> ClassPathError {
+    match e {
+        ZipError::Io(e) => io_error(path, e),
+        e => ClassPathError::Corrupt {
+            path: path.to_string(),
+            message: e.to_string(),
+        },
+    }
+}
+
+// Told of each innermost entry a lookup searches, and what came of it
+pub type Probe<'a> = dyn FnMut(&str, Result<(), &ClassPathError>) + 'a;
+
+// Entries may be shared between threads
+pub trait Entry: Send + Sync {
+    // A resource named relative to the root of the entry, e.g. p/A.class or
+    // META-INF/services/p.Service
+    fn read_resource(&self, name: &str) -> Result<Vec<u8>, ClassPathError>;
+
+    // The entry as given on the class path
+    fn path(&self) -> String;
+
+    fn read_class(&self, class_name: &str) -> Result<Vec<u8>, ClassPathError> {
+        self.read_resource(class_name)
+    }
+
+    // The resource along with the path of the innermost entry it was found in
+    fn locate_resource(&self, name: &str) -> Result<(Vec<u8>, String), ClassPathError> {
+        Ok((self.read_resource(name)?, self.path()))
+    }
+
+    // As locate_resource, telling `probe` of every entry searched
+    fn probe_resource(
+        &self,
+        name: &str,
+        probe: &mut Probe,
+    ) -> Result<(Vec<u8>, String), ClassPathError> {
+        let found = self.locate_resource(name);
+        probe(&self.path(), found.as_ref().map(|_| ()));
+        found
+    }
+
+    // Every resource of that name, in class path order, as for getResources
+    fn find_resources(&self, name: &str) -> Vec<(Vec<u8>, String)> {
+        self.locate_resource(name).into_iter().collect()
+    }
+
+    // Names of the classes and other resources, sorted, as read_resource
+    // takes them
+    fn list(&self) -> Result<Vec<String>, String>;
+
+    // The names in each innermost entry, in class path order
+    fn list_by_entry(&self) -> Result<Vec<(String, Vec<String>)>, String> {
+        Ok(vec![(self.path(), self.list()?)])
+    }
+
+    // Check what is read from signed jars against their signatures
+    fn set_verify(&mut self, _verify: bool) {}
 }
 
+// The release multi-release jars are read for when there is no runtime to
+// take it from
+pub const DEFAULT_RELEASE: u32 = 17;
+
 pub fn new_entry(path: String) -> Box<dyn Entry> {
-    if path.contains(';') {
-        Box::new(CompositeEntry::new(path))
-    } else if path.ends_with("*") {
-        Box::new(CompositeEntry::new_wildcard(path))
+    new_entry_for_release(path, DEFAULT_RELEASE)
+}
+
+pub fn new_entry_for_release(path: String, release: u32) -> Box<dyn Entry> {
+    if path.contains(PATH_SEPARATOR) {
+        Box::new(CompositeEntry::new(path, release))
+    } else if let Some((outer, inner)) = path.split_once("!/") {
+        if inner.ends_with(".jar") {
+            Box::new(NestedJarEntry::new(
+                outer.to_string(),
+                inner.to_string(),
+                release,
+            ))
+        } else {
+            Box::new(JarDirEntry::new(
+                outer.to_string(),
+                inner.to_string(),
+                release,
+            ))
+        }
+    } else if path == "*" || path.ends_with("/*") || path.ends_with("\\*") {
+        match CompositeEntry::new_wildcard(path.clone(), release) {
+            Ok(entry) => Box::new(entry),
+            Err(error) => Box::new(BadEntry { path, error }),
+        }
+    } else if path.ends_with(".jmod") {
+        Box::new(JmodEntry::new(path, release))
     } else if path.ends_with(".jar")
         || path.ends_with(".JAR")
         || path.ends_with(".zip")
         || path.ends_with(".ZIP")
     {
-        Box::new(ZipEntry::new(path))
+        Box::new(ZipEntry::for_release(path, release))
     } else {
         Box::new(DirEntry::new(path))
     }
@@ -30,142 +220,1682 @@ pub struct DirEntry {
 
 impl DirEntry {
     pub fn new(path: String) -> DirEntry {
-        let path = fs::canonicalize(path).unwrap();
-        DirEntry { abs_dir: path }
+        // A missing directory is kept, and finds nothing
+        let abs_dir = fs::canonicalize(&path).unwrap_or_else(|_| PathBuf::from(path));
+        DirEntry { abs_dir }
     }
 }
 
 impl Entry for DirEntry {
-    fn read_class(&self, class_name: &str) -> Result<Vec<u8>, String> {
-        let file_path = self.abs_dir.join(class_name);
-        match fs::read(file_path) {
+    fn read_resource(&self, name: &str) -> Result<Vec<u8>, ClassPathError> {
+        let file_path = self.abs_dir.join(name);
+        match fs::read(&file_path) {
             Ok(data) => Ok(data),
-            Err(e) => Err(format!("Error reading class file: {}", e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                Err(ClassPathError::not_found(name, &self.path()))
+            }
+            Err(e) => Err(io_error(&file_path.display().to_string(), e)),
+        }
+    }
+
+    fn path(&self) -> String {
+        self.abs_dir.display().to_string()
+    }
+
+    fn list(&self) -> Result<Vec<String>, String> {
+        let mut names = vec![];
+        list_dir(&self.abs_dir, "", &mut names)?;
+        names.sort();
+        Ok(names)
+    }
+}
+
+fn list_dir(dir: &Path, prefix: &str, names: &mut Vec<String>) -> Result<(), String> {
+    let entries = fs::read_dir(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    for entry in entries.filter_map(|e| e.ok()) {
+        let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+        let path = entry.path();
+        if path.is_dir() {
+            list_dir(&path, &format!("{}/", name), names)?;
+        } else {
+            names.push(name);
         }
     }
+    Ok(())
 }
 
 pub struct ZipEntry {
     abs_path: PathBuf,
+    release: u32,
+    verify: bool,
+    // Opened on first lookup
+    jar: OnceLock<Result<Arc<Jar>, ClassPathError>>,
+}
+
+// What a jar is read from: its file, or its bytes when stored in another jar
+trait Source: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> Source for T {}
+
+// An open jar with its central directory indexed by entry name
+struct Jar {
+    path: String,
+    index: HashMap<String, usize>,
+    archive: Mutex<ZipArchive<Box<dyn Source>>>,
+    // For a multi-release jar, the releases with versioned entries, newest
+    // first
+    versions: Vec<u32>,
+    // Read on first verification, None for an unsigned jar
+    signatures: OnceLock<Result<Option<Signatures>, ClassPathError>>,
+}
+
+impl Jar {
+    fn new(source: Box<dyn Source>, path: String) -> Result<Jar, ClassPathError> {
+        let archive = ZipArchive::new(source).map_err(|e| zip_error(&path, e))?;
+        let index = archive
+            .file_names()
+            .map(|name| (name.to_string(), archive.index_for_name(name).unwrap()))
+            .collect();
+        let mut jar = Jar {
+            path,
+            index,
+            archive: Mutex::new(archive),
+            versions: vec![],
+            signatures: OnceLock::new(),
+        };
+        let manifest = jar
+            .read("META-INF/MANIFEST.MF")?
+            .map(|m| Manifest::parse(&m));
+        if manifest.is_some_and(|m| {
+            m.get("Multi-Release")
+                .is_some_and(|v| v.eq_ignore_ascii_case("true"))
+        }) {
+            let mut versions: Vec<u32> = jar
+                .index
+                .keys()
+                .filter_map(|name| name.strip_prefix("META-INF/versions/")?.split_once('/'))
+                .filter_map(|(version, _)| version.parse().ok())
+                .filter(|&version| version >= 9)
+                .collect();
+            versions.sort_unstable_by(|a, b| b.cmp(a));
+            versions.dedup();
+            jar.versions = versions;
+        }
+        Ok(jar)
+    }
+
+    fn read(&self, name: &str) -> Result<Option<Vec<u8>>, ClassPathError> {
+        let Some(&index) = self.index.get(name) else {
+            return Ok(None);
+        };
+        let path = format!("{}!/{}", self.path, name);
+        let mut archive = self.archive.lock().unwrap();
+        let mut file = archive.by_index(index).map_err(|e| zip_error(&path, e))?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)
+            .map_err(|e| io_error(&path, e))?;
+        Ok(Some(data))
+    }
+
+    // The entry for a resource as seen by a runtime of `release`: the newest
+    // versioned one not after it, or the one at the root
+    fn entry_name(&self, name: &str, release: u32) -> Option<String> {
+        self.versions
+            .iter()
+            .filter(|&&version| version <= release)
+            .map(|version| format!("META-INF/versions/{}/{}", version, name))
+            .chain(std::iter::once(name.to_string()))
+            .find(|name| self.index.contains_key(name))
+    }
+
+    fn read_resource(
+        &self,
+        name: &str,
+        release: u32,
+        verify: bool,
+    ) -> Result<Vec<u8>, ClassPathError> {
+        let not_found = || ClassPathError::not_found(name, &self.path);
+        let entry = self.entry_name(name, release).ok_or_else(not_found)?;
+        let data = self.read(&entry)?.ok_or_else(not_found)?;
+        if let Some(signatures) = self.signatures().filter(|_| verify).transpose()? {
+            signatures
+                .check(&entry, &data)
+                .map_err(|message| ClassPathError::Security {
+                    path: self.path.clone(),
+                    message,
+                })?;
+        }
+        Ok(data)
+    }
+
+    // A jar is signed by each signature file with a signature block
+    fn signatures(&self) -> Option<Result<&Signatures, ClassPathError>> {
+        let signatures = self.signatures.get_or_init(|| {
+            let is_block = |stem: &str| {
+                self.index.keys().any(|name| {
+                    ["RSA", "DSA", "EC"]
+                        .iter()
+                        .any(|ext| name.eq_ignore_ascii_case(&format!("META-INF/{}.{}", stem, ext)))
+                })
+            };
+            let is_signature_file = |name: &&String| {
+                let upper = name.to_ascii_uppercase();
+                upper
+                    .strip_prefix("META-INF/")
+                    .and_then(|name| name.strip_suffix(".SF"))
+                    .is_some_and(|stem| !stem.contains('/') && is_block(stem))
+            };
+            let mut files: Vec<&String> = self.index.keys().filter(is_signature_file).collect();
+            if files.is_empty() {
+                return Ok(None);
+            }
+            files.sort();
+            let mut signature_files = vec![];
+            for file in files {
+                signature_files.extend(self.read(file)?);
+            }
+            let manifest = self.read("META-INF/MANIFEST.MF")?.unwrap_or_default();
+            jar::signatures(&manifest, &signature_files)
+                .map(Some)
+                .map_err(|message| ClassPathError::Security {
+                    path: self.path.clone(),
+                    message,
+                })
+        });
+        match signatures {
+            Ok(signatures) => signatures.as_ref().map(Ok),
+            Err(e) => Some(Err(e.clone())),
+        }
+    }
+
+    // Versioned entries under the names they are seen by
+    fn list(&self, release: u32) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .index
+            .keys()
+            .filter(|name| !name.ends_with('/'))
+            .filter_map(|name| {
+                let versioned = name
+                    .strip_prefix("META-INF/versions/")
+                    .and_then(|rest| rest.split_once('/'))
+                    .and_then(|(version, rest)| Some((version.parse::<u32>().ok()?, rest)));
+                match versioned {
+                    Some((version, rest)) if self.versions.contains(&version) => {
+                        (version <= release).then(|| rest.to_string())
+                    }
+                    _ => Some(name.clone()),
+                }
+            })
+            .collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+}
+
+// A jar is opened once however many entries name it, until it changes. The
+// entries own it: once the last is dropped, the jar is closed.
+type JarKey = (PathBuf, u64, Option<SystemTime>);
+
+fn open_jar(path: &PathBuf) -> Result<Arc<Jar>, ClassPathError> {
+    static JARS: OnceLock<Mutex<HashMap<JarKey, Weak<Jar>>>> = OnceLock::new();
+    let name = path.display().to_string();
+    let metadata = fs::metadata(path).map_err(|e| io_error(&name, e))?;
+    let key = (path.clone(), metadata.len(), metadata.modified().ok());
+    let mut jars = JARS.get_or_init(Default::default).lock().unwrap();
+    if let Some(jar) = jars.get(&key).and_then(Weak::upgrade) {
+        return Ok(jar);
+    }
+    let file = File::open(path).map_err(|e| io_error(&name, e))?;
+    let jar = Arc::new(Jar::new(Box::new(file), name)?);
+    jars.retain(|(p, _, _), jar| p != path && jar.strong_count() > 0);
+    jars.insert(key, Arc::downgrade(&jar));
+    Ok(jar)
 }
 
 impl ZipEntry {
     pub fn new(path: String) -> ZipEntry {
-        let path = fs::canonicalize(path).unwrap();
-        ZipEntry { abs_path: path }
+        ZipEntry::for_release(path, DEFAULT_RELEASE)
     }
-}
 
-impl Entry for ZipEntry {
-    fn read_class(&self, class_name: &str) -> Result<Vec<u8>, String> {
+    pub fn for_release(path: String, release: u32) -> ZipEntry {
+        // A missing jar fails its lookups
+        let abs_path = fs::canonicalize(&path).unwrap_or_else(|_| PathBuf::from(path));
+        ZipEntry {
+            abs_path,
+            release,
+            verify: false,
+            jar: OnceLock::new(),
+        }
+    }
+
+    fn jar(&self) -> Result<&Jar, ClassPathError> {
+        self.jar
+            .get_or_init(|| open_jar(&self.abs_path))
+            .as_ref()
+            .map(|jar| jar.as_ref())
+            .map_err(|e| e.clone())
+    }
+
+    pub fn archive(&self) -> Result<ZipArchive<File>, String> {
         let file =
             File::open(self.abs_path.clone()).map_err(|e| format!("Error opening file: {}", e))?;
-        let mut archive =
-            ZipArchive::new(file).map_err(|e| format!("Error reading zip file: {}", e))?;
-        let mut class = match archive.by_name(class_name) {
-            Ok(f) => f,
-            Err(_) => return Err(format!("Class not found: {}", class_name)),
+        ZipArchive::new(file).map_err(|e| format!("Error reading zip file: {}", e))
+    }
+}
+
+impl Entry for ZipEntry {
+    fn read_resource(&self, name: &str) -> Result<Vec<u8>, ClassPathError> {
+        self.jar()?.read_resource(name, self.release, self.verify)
+    }
+
+    fn path(&self) -> String {
+        self.abs_path.display().to_string()
+    }
+
+    fn list(&self) -> Result<Vec<String>, String> {
+        Ok(self.jar()?.list(self.release))
+    }
+
+    fn set_verify(&mut self, verify: bool) {
+        self.verify = verify;
+    }
+}
+
+// A jar stored in another jar, e.g. in BOOT-INF/lib/ of a fat jar, read into
+// memory rather than extracted. Its path is outer.jar!/name.
+pub struct NestedJarEntry {
+    outer: ZipEntry,
+    name: String,
+    release: u32,
+    verify: bool,
+    jar: OnceLock<Result<Jar, ClassPathError>>,
+}
+
+impl NestedJarEntry {
+    pub fn new(outer: String, name: String, release: u32) -> NestedJarEntry {
+        NestedJarEntry {
+            outer: ZipEntry::new(outer),
+            name,
+            release,
+            verify: false,
+            jar: OnceLock::new(),
+        }
+    }
+
+    fn jar(&self) -> Result<&Jar, ClassPathError> {
+        self.jar
+            .get_or_init(|| {
+                let data = self.outer.jar()?.read(&self.name)?;
+                let data = data.ok_or_else(|| ClassPathError::Io {
+                    path: self.path(),
+                    message: "No such file".to_string(),
+                })?;
+                Jar::new(Box::new(Cursor::new(data)), self.path())
+            })
+            .as_ref()
+            .map_err(|e| e.clone())
+    }
+}
+
+impl Entry for NestedJarEntry {
+    fn read_resource(&self, name: &str) -> Result<Vec<u8>, ClassPathError> {
+        self.jar()?.read_resource(name, self.release, self.verify)
+    }
+
+    fn path(&self) -> String {
+        format!("{}!/{}", self.outer.path(), self.name)
+    }
+
+    fn list(&self) -> Result<Vec<String>, String> {
+        Ok(self.jar()?.list(self.release))
+    }
+
+    fn set_verify(&mut self, verify: bool) {
+        self.verify = verify;
+    }
+}
+
+// A directory of a jar, e.g. BOOT-INF/classes/ of a fat jar. Its path is
+// outer.jar!/dir/.
+pub struct JarDirEntry {
+    zip: ZipEntry,
+    dir: String,
+}
+
+impl JarDirEntry {
+    pub fn new(outer: String, dir: String, release: u32) -> JarDirEntry {
+        let dir = match dir.ends_with('/') || dir.is_empty() {
+            true => dir,
+            false => format!("{}/", dir),
         };
-        let mut data = Vec::new();
-        class
-            .read_to_end(&mut data)
-            .map_err(|e| format!("Error reading class file: {}", e))?;
-        Ok(data)
+        JarDirEntry {
+            zip: ZipEntry::for_release(outer, release),
+            dir,
+        }
     }
 }
 
+impl Entry for JarDirEntry {
+    fn read_resource(&self, name: &str) -> Result<Vec<u8>, ClassPathError> {
+        match self.zip.read_resource(&format!("{}{}", self.dir, name)) {
+            Err(e) if e.is_not_found() => Err(ClassPathError::not_found(name, &self.path())),
+            found => found,
+        }
+    }
+
+    fn path(&self) -> String {
+        format!("{}!/{}", self.zip.path(), self.dir)
+    }
+
+    fn list(&self) -> Result<Vec<String>, String> {
+        let names = self.zip.list()?;
+        Ok(names
+            .iter()
+            .filter_map(|name| name.strip_prefix(&self.dir))
+            .map(|name| name.to_string())
+            .collect())
+    }
+
+    fn set_verify(&mut self, verify: bool) {
+        self.zip.set_verify(verify);
+    }
+}
+
+// A jmod file: a magic number, then a zip with the classes of a module
+// under classes/, next to its native libraries, commands and configuration
+pub struct JmodEntry {
+    classes: JarDirEntry,
+    checked: OnceLock<Result<(), ClassPathError>>,
+}
+
+const JMOD_MAGIC: [u8; 4] = [b'J', b'M', 1, 0];
+
+impl JmodEntry {
+    pub fn new(path: String, release: u32) -> JmodEntry {
+        JmodEntry {
+            classes: JarDirEntry::new(path, "classes/".to_string(), release),
+            checked: OnceLock::new(),
+        }
+    }
+
+    fn check(&self) -> Result<(), ClassPathError> {
+        let mut magic = [0; 4];
+        File::open(&self.classes.zip.abs_path)
+            .and_then(|mut f| f.read_exact(&mut magic))
+            .map_err(|e| io_error(&self.path(), e))?;
+        if magic != JMOD_MAGIC {
+            return Err(ClassPathError::Corrupt {
+                path: self.path(),
+                message: "Not a jmod file".to_string(),
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Entry for JmodEntry {
+    fn read_resource(&self, name: &str) -> Result<Vec<u8>, ClassPathError> {
+        self.checked
+            .get_or_init(|| self.check())
+            .as_ref()
+            .map_err(|e| e.clone())?;
+        self.classes.read_resource(name)
+    }
+
+    fn path(&self) -> String {
+        self.classes.zip.path()
+    }
+
+    fn list(&self) -> Result<Vec<String>, String> {
+        self.classes.list()
+    }
+
+    fn set_verify(&mut self, verify: bool) {
+        self.classes.set_verify(verify);
+    }
+}
+
+#[derive(Default)]
 pub struct CompositeEntry {
+    path: String,
     entries: Vec<Box<dyn Entry>>,
 }
 
 impl CompositeEntry {
-    pub fn new(path: String) -> CompositeEntry {
+    pub fn new(path: String, release: u32) -> CompositeEntry {
         let mut entries = Vec::new();
-        for entry in path.split(';') {
-            entries.push(new_entry(entry.to_string()));
+        for entry in path.split(PATH_SEPARATOR) {
+            entries.push(new_entry_for_release(entry.to_string(), release));
         }
-        CompositeEntry { entries }
+        CompositeEntry { path, entries }
     }
 
-    pub fn new_wildcard(path: String) -> Self {
-        let path = fs::canonicalize(path.trim_end_matches("*")).unwrap();
-        let files = fs::read_dir(path).unwrap();
-        let mut ret = Self { entries: vec![] };
+    // The jars of a directory, as dir/*, in the order of their names. Like
+    // java, a directory that does not exist has none.
+    pub fn new_wildcard(path: String, release: u32) -> Result<Self, String> {
+        let dir = match path.trim_end_matches('*') {
+            "" => Path::new("."),
+            dir => Path::new(dir),
+        };
+        let mut ret = Self {
+            path: path.clone(),
+            entries: vec![],
+        };
+        if !dir.is_dir() {
+            return Ok(ret);
+        }
+        let mut files = vec![];
+        for file in fs::read_dir(dir).map_err(|e| format!("{}: {}", path, e))? {
+            let file = file.map_err(|e| format!("{}: {}", path, e))?;
+            files.push(file.path());
+        }
+        files.sort();
         for file in files {
-            let file = file.unwrap();
-            let file_name = file.file_name().into_string().unwrap();
-            if file_name.ends_with(".jar") || file_name.ends_with(".JAR") {
-                ret.entries.push(Box::new(ZipEntry::new(file_name)));
+            let name = file.to_string_lossy().to_string();
+            if !file.is_file() {
+                continue;
+            }
+            if name.ends_with(".jar") || name.ends_with(".JAR") {
+                ret.entries
+                    .push(Box::new(ZipEntry::for_release(name, release)));
+            } else if name.ends_with(".jmod") {
+                ret.entries.push(Box::new(JmodEntry::new(name, release)));
             }
         }
-        ret
+        Ok(ret)
     }
 }
 
 impl Entry for CompositeEntry {
-    fn read_class(&self, class_name: &str) -> Result<Vec<u8>, String> {
+    fn read_resource(&self, name: &str) -> Result<Vec<u8>, ClassPathError> {
+        self.locate_resource(name).map(|(data, _)| data)
+    }
+
+    fn path(&self) -> String {
+        self.path.clone()
+    }
+
+    fn locate_resource(&self, name: &str) -> Result<(Vec<u8>, String), ClassPathError> {
+        self.probe_resource(name, &mut |_, _| {})
+    }
+
+    fn probe_resource(
+        &self,
+        name: &str,
+        probe: &mut Probe,
+    ) -> Result<(Vec<u8>, String), ClassPathError> {
+        let mut errors = vec![];
         for entry in &self.entries {
-            match entry.read_class(class_name) {
-                Ok(data) => return Ok(data),
-                Err(_) => continue,
+            match entry.probe_resource(name, probe) {
+                Ok(found) => return Ok(found),
+                // A tampered class is not passed over for another copy
+                Err(e @ ClassPathError::Security { .. }) => return Err(e),
+                Err(e) => errors.push(e),
             }
         }
-        Err(format!("Class not found: {}", class_name))
+        Err(ClassPathError::aggregate(name, errors))
+    }
+
+    fn find_resources(&self, name: &str) -> Vec<(Vec<u8>, String)> {
+        self.entries
+            .iter()
+            .flat_map(|entry| entry.find_resources(name))
+            .collect()
+    }
+
+    fn list(&self) -> Result<Vec<String>, String> {
+        let mut names = vec![];
+        for entry in &self.entries {
+            names.extend(entry.list()?);
+        }
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+
+    fn list_by_entry(&self) -> Result<Vec<(String, Vec<String>)>, String> {
+        let mut found = vec![];
+        for entry in &self.entries {
+            found.extend(entry.list_by_entry()?);
+        }
+        Ok(found)
+    }
+
+    fn set_verify(&mut self, verify: bool) {
+        for entry in &mut self.entries {
+            entry.set_verify(verify);
+        }
+    }
+}
+
+// Resources held in memory, e.g. classes generated at runtime. Resources
+// may be added after the entry is on a class path, through an Arc of it.
+#[derive(Default)]
+pub struct MemoryEntry {
+    path: String,
+    resources: RwLock<BTreeMap<String, Vec<u8>>>,
+}
+
+impl MemoryEntry {
+    pub fn new(path: String) -> MemoryEntry {
+        MemoryEntry {
+            path,
+            resources: RwLock::default(),
+        }
+    }
+
+    // A resource named as by read_resource, replacing any of that name
+    pub fn insert(&self, name: String, data: Vec<u8>) {
+        self.resources.write().unwrap().insert(name, data);
+    }
+
+    pub fn remove(&self, name: &str) -> Option<Vec<u8>> {
+        self.resources.write().unwrap().remove(name)
+    }
+}
+
+impl Entry for MemoryEntry {
+    fn read_resource(&self, name: &str) -> Result<Vec<u8>, ClassPathError> {
+        let resources = self.resources.read().unwrap();
+        resources
+            .get(name)
+            .cloned()
+            .ok_or_else(|| ClassPathError::not_found(name, &self.path))
+    }
+
+    fn path(&self) -> String {
+        self.path.clone()
+    }
+
+    fn list(&self) -> Result<Vec<String>, String> {
+        Ok(self.resources.read().unwrap().keys().cloned().collect())
+    }
+}
+
+impl<T: Entry + ?Sized> Entry for Arc<T> {
+    fn read_resource(&self, name: &str) -> Result<Vec<u8>, ClassPathError> {
+        self.as_ref().read_resource(name)
+    }
+
+    fn path(&self) -> String {
+        self.as_ref().path()
+    }
+
+    fn locate_resource(&self, name: &str) -> Result<(Vec<u8>, String), ClassPathError> {
+        self.as_ref().locate_resource(name)
+    }
+
+    fn probe_resource(
+        &self,
+        name: &str,
+        probe: &mut Probe,
+    ) -> Result<(Vec<u8>, String), ClassPathError> {
+        self.as_ref().probe_resource(name, probe)
+    }
+
+    fn find_resources(&self, name: &str) -> Vec<(Vec<u8>, String)> {
+        self.as_ref().find_resources(name)
+    }
+
+    fn list(&self) -> Result<Vec<String>, String> {
+        self.as_ref().list()
+    }
+
+    fn list_by_entry(&self) -> Result<Vec<(String, Vec<String>)>, String> {
+        self.as_ref().list_by_entry()
+    }
+}
+
+// An entry that could not be opened, failing every lookup with why
+struct BadEntry {
+    path: String,
+    error: String,
+}
+
+impl Entry for BadEntry {
+    fn read_resource(&self, _name: &str) -> Result<Vec<u8>, ClassPathError> {
+        Err(ClassPathError::Io {
+            path: self.path.clone(),
+            message: self.error.clone(),
+        })
+    }
+
+    fn path(&self) -> String {
+        self.path.clone()
+    }
+
+    fn list(&self) -> Result<Vec<String>, String> {
+        Err(self.error.clone())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tier {
+    Boot,
+    Ext,
+    User,
+    // Entries added in front of or behind the others by the embedder
+    Custom,
+}
+
+// Where a class was found: the class path tier and the jar or directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    pub tier: Tier,
+    pub entry: String,
+}
+
+// A class in more than one entry: every copy in class path order, the first
+// being the one read_class resolves, with the SHA-256 of its bytes
+#[derive(Debug, Clone, PartialEq)]
+pub struct Duplicate {
+    pub class: String,
+    pub copies: Vec<(Location, String)>,
+}
+
+impl Duplicate {
+    // Copies that differ, rather than the same class packaged twice
+    pub fn is_conflict(&self) -> bool {
+        self.copies
+            .iter()
+            .any(|(_, hash)| *hash != self.copies[0].1)
     }
 }
 
 pub struct ClassPath {
+    front: CompositeEntry,
     boot_classpath: Box<dyn Entry>,
     ext_classpath: Box<dyn Entry>,
     user_classpath: Box<dyn Entry>,
+    back: CompositeEntry,
+    release: u32,
+    // A runtime image, without extensions
+    modular: bool,
+    // Print every entry searched, as -verbose:classpath
+    verbose: bool,
+    // Check classes of signed jars against their signatures
+    verify: bool,
+}
+
+// The user class path: -cp, else the CLASSPATH environment variable, else
+// the current directory
+pub fn user_class_path(cp_option: Option<String>) -> String {
+    cp_option
+        .or_else(|| std::env::var("CLASSPATH").ok())
+        .unwrap_or_else(|| ".".to_string())
 }
 
 impl ClassPath {
-    pub fn new(jre_option: String, cp_option: String) -> Self {
-        let jre_dir = ClassPath::get_jre_dir(jre_option);
-        let jre_lib_path = format!("{}/lib/*", jre_dir);
-        let boot_classpath = new_entry(jre_lib_path);
-        let jre_ext_path = format!("{}/lib/ext/*", jre_dir);
-        let ext_classpath = new_entry(jre_ext_path);
+    // A class path reading multi-release jars as the runtime found does
+    pub fn new(jre_option: Option<String>, cp_option: String) -> Result<Self, String> {
+        let jre = ClassPath::find_jre(jre_option)?;
+        let release = runtime_release(Path::new(&jre));
+        ClassPath::for_release(Some(jre), cp_option, release)
+    }
+
+    // A class path reading multi-release jars as a runtime of `release`
+    pub fn for_release(
+        jre_option: Option<String>,
+        cp_option: String,
+        release: u32,
+    ) -> Result<Self, String> {
         let cp_option = if cp_option.is_empty() {
             ".".to_string()
         } else {
             cp_option
         };
-        let user_classpath = new_entry(cp_option);
-        ClassPath {
+        Ok(ClassPath::builder()
+            .jre(ClassPath::find_jre(jre_option)?)
+            .class_path(cp_option)
+            .release(release)
+            .build())
+    }
+
+    pub fn builder() -> ClassPathBuilder {
+        ClassPathBuilder::default()
+    }
+
+    // An entry searched before all others
+    pub fn push_front(&mut self, mut entry: Box<dyn Entry>) {
+        entry.set_verify(self.verify);
+        self.front.entries.insert(0, entry);
+    }
+
+    // An entry searched after all others
+    pub fn push_back(&mut self, mut entry: Box<dyn Entry>) {
+        entry.set_verify(self.verify);
+        self.back.entries.push(entry);
+    }
+
+    // Check classes read from signed jars against their signatures, failing
+    // those that do not match with a SecurityException
+    pub fn set_verify(&mut self, verify: bool) {
+        self.verify = verify;
+        self.front.set_verify(verify);
+        self.boot_classpath.set_verify(verify);
+        self.ext_classpath.set_verify(verify);
+        self.user_classpath.set_verify(verify);
+        self.back.set_verify(verify);
+    }
+
+    // Entries searched after the boot classes, as -Xbootclasspath/a:
+    pub fn append_boot(&mut self, path: String) {
+        let boot = std::mem::replace(
+            &mut self.boot_classpath,
+            Box::new(CompositeEntry::default()),
+        );
+        let mut appended = new_entry_for_release(path.clone(), self.release);
+        appended.set_verify(self.verify);
+        self.boot_classpath = Box::new(CompositeEntry {
+            path: format!("{}{}{}", boot.path(), PATH_SEPARATOR, path),
+            entries: vec![boot, appended],
+        });
+    }
+
+    // Directories whose jars are the extensions instead of lib/ext, as
+    // -Djava.ext.dirs, which runtime images no longer support
+    pub fn set_ext_dirs(&mut self, dirs: &str) -> Result<(), String> {
+        if self.modular {
+            return Err(format!(
+                "-Djava.ext.dirs={} is not supported.  Use -classpath instead.",
+                dirs
+            ));
+        }
+        let entries = dirs
+            .split(PATH_SEPARATOR)
+            .filter(|dir| !dir.is_empty())
+            .map(|dir| new_entry_for_release(format!("{}/*", dir), self.release))
+            .collect();
+        self.ext_classpath = Box::new(CompositeEntry {
+            path: dirs.to_string(),
+            entries,
+        });
+        self.ext_classpath.set_verify(self.verify);
+        Ok(())
+    }
+
+    // The runtime to take the boot classes from: the one given, else ./jre,
+    // JAVA_HOME, the java on PATH, then a JDK under /usr/lib/jvm
+    pub fn find_jre(jre_option: Option<String>) -> Result<String, String> {
+        if let Some(jre) = jre_option {
+            let dir = Path::new(&jre);
+            if !dir.is_dir() {
+                return Err(format!("JRE not found: {}", jre));
+            }
+            return Ok(runtime_dir(dir)
+                .unwrap_or(dir.to_path_buf())
+                .display()
+                .to_string());
+        }
+        let mut candidates = vec![PathBuf::from("./jre")];
+        candidates.extend(std::env::var_os("JAVA_HOME").map(PathBuf::from));
+        // bin/java, its links followed, is in the JDK, or in jre/ for JDK 8
+        let java = if cfg!(windows) { "java.exe" } else { "java" };
+        let java = std::env::var_os("PATH")
+            .iter()
+            .flat_map(std::env::split_paths)
+            .map(|dir| dir.join(java))
+            .find(|java| java.is_file());
+        if let Some(java) = java.and_then(|java| fs::canonicalize(java).ok()) {
+            candidates.extend(
+                java.parent()
+                    .and_then(|bin| bin.parent())
+                    .map(PathBuf::from),
+            );
+        }
+        candidates.push(PathBuf::from("/usr/lib/jvm/default-java"));
+        if let Ok(dirs) = fs::read_dir("/usr/lib/jvm") {
+            let mut dirs: Vec<PathBuf> = dirs.filter_map(|d| d.ok()).map(|d| d.path()).collect();
+            dirs.sort();
+            candidates.extend(dirs);
+        }
+        candidates
+            .iter()
+            .find_map(|dir| runtime_dir(dir))
+            .map(|dir| dir.display().to_string())
+            .ok_or_else(|| "No JRE found: give one with --Xjre or set JAVA_HOME".to_string())
+    }
+
+    pub fn set_verbose(&mut self, verbose: bool) {
+        self.verbose = verbose;
+    }
+
+    pub fn read_class(&self, class_name: &str) -> Result<Vec<u8>, ClassPathError> {
+        self.locate_class(class_name).map(|(data, _)| data)
+    }
+
+    pub fn locate_class(&self, class_name: &str) -> Result<(Vec<u8>, Location), ClassPathError> {
+        self.locate_resource(&format!("{}.class", class_name))
+    }
+
+    fn tiers(&self) -> [(Tier, &dyn Entry); 5] {
+        [
+            (Tier::Custom, &self.front),
+            (Tier::Boot, self.boot_classpath.as_ref()),
+            (Tier::Ext, self.ext_classpath.as_ref()),
+            (Tier::User, self.user_classpath.as_ref()),
+            (Tier::Custom, &self.back),
+        ]
+    }
+
+    // As ClassLoader.getResource: the first copy of a resource
+    pub fn read_resource(&self, name: &str) -> Result<Vec<u8>, ClassPathError> {
+        self.locate_resource(name).map(|(data, _)| data)
+    }
+
+    // Delegation as by the class loaders: bootstrap, then extension, then
+    // application
+    pub fn locate_resource(&self, name: &str) -> Result<(Vec<u8>, Location), ClassPathError> {
+        let mut trace = |path: &str, found: Result<(), &ClassPathError>| {
+            let outcome = match found {
+                Ok(()) => "found".to_string(),
+                Err(e) if e.is_not_found() => "not found".to_string(),
+                Err(e) => e.to_string(),
+            };
+            println!("[classpath] {} in {}: {}", name, path, outcome);
+        };
+        let mut errors = vec![];
+        for (tier, entry) in self.tiers() {
+            let found = match self.verbose {
+                true => entry.probe_resource(name, &mut trace),
+                false => entry.locate_resource(name),
+            };
+            match found {
+                Ok((data, entry)) => return Ok((data, Location { tier, entry })),
+                Err(e @ ClassPathError::Security { .. }) => return Err(e),
+                Err(e) => errors.push(e),
+            }
+        }
+        Err(ClassPathError::aggregate(name, errors))
+    }
+
+    // Every copy of every class, in class path order. Module descriptors and
+    // classes under META-INF/ are not loaded from the class path.
+    fn all_classes(&self) -> Result<Vec<(String, Location)>, String> {
+        let mut found = vec![];
+        for (tier, entry) in self.tiers() {
+            for (entry, names) in entry.list_by_entry()? {
+                for name in names {
+                    let Some(class) = name.strip_suffix(".class") else {
+                        continue;
+                    };
+                    if class.starts_with("META-INF/") || class.ends_with("module-info") {
+                        continue;
+                    }
+                    let location = Location {
+                        tier,
+                        entry: entry.clone(),
+                    };
+                    found.push((class.to_string(), location));
+                }
+            }
+        }
+        Ok(found)
+    }
+
+    // Every class, named as by read_class, with where it is loaded from
+    pub fn classes(&self) -> Result<Vec<(String, Location)>, String> {
+        let mut classes: BTreeMap<String, Location> = BTreeMap::new();
+        for (class, location) in self.all_classes()? {
+            classes.entry(class).or_insert(location);
+        }
+        Ok(classes.into_iter().collect())
+    }
+
+    // Each package with classes, and every entry with classes of it in
+    // class path order; more than one makes a split package
+    pub fn packages(&self) -> Result<BTreeMap<String, Vec<Location>>, String> {
+        let mut packages: BTreeMap<String, Vec<Location>> = BTreeMap::new();
+        for (class, location) in self.all_classes()? {
+            let package = class.rsplit_once('/').map_or("", |(p, _)| p);
+            let locations = packages.entry(package.to_string()).or_default();
+            // The classes of an entry come together
+            if locations.last() != Some(&location) {
+                locations.push(location);
+            }
+        }
+        Ok(packages)
+    }
+
+    // Every class in more than one entry, by name
+    pub fn duplicates(&self) -> Result<Vec<Duplicate>, String> {
+        let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+        for (class, _) in self.all_classes()? {
+            *counts.entry(class).or_default() += 1;
+        }
+        let duplicates = counts
+            .into_iter()
+            .filter(|(_, count)| *count > 1)
+            .map(|(class, _)| {
+                let copies = self
+                    .find_resources(&format!("{}.class", class))
+                    .into_iter()
+                    .map(|(data, location)| (location, format!("{:x}", Sha256::digest(&data))))
+                    .collect();
+                Duplicate { class, copies }
+            })
+            .collect();
+        Ok(duplicates)
+    }
+
+    // As ClassLoader.getResources: every copy, in the order of delegation,
+    // e.g. for the provider files of ServiceLoader
+    pub fn find_resources(&self, name: &str) -> Vec<(Vec<u8>, Location)> {
+        self.tiers()
+            .into_iter()
+            .flat_map(|(tier, entry)| {
+                entry
+                    .find_resources(name)
+                    .into_iter()
+                    .map(move |(data, entry)| (data, Location { tier, entry }))
+            })
+            .collect()
+    }
+}
+
+// The runtime of a JDK or JRE: the directory itself with a runtime image or
+// with lib/, or the jre/ of a JDK 8
+fn runtime_dir(dir: &Path) -> Option<PathBuf> {
+    if dir.join("lib/modules").is_file() {
+        Some(dir.to_path_buf())
+    } else if dir.join("jre/lib").is_dir() {
+        Some(dir.join("jre"))
+    } else if dir.join("lib").is_dir() {
+        Some(dir.to_path_buf())
+    } else {
+        None
+    }
+}
+
+// The Java release of a runtime: JAVA_VERSION from its release file, else 8
+// for a runtime without an image, which predates multi-release jars
+fn runtime_release(dir: &Path) -> u32 {
+    let version = fs::read_to_string(dir.join("release"))
+        .ok()
+        .and_then(|release| {
+            release.lines().find_map(|line| {
+                let version = line.strip_prefix("JAVA_VERSION=")?.trim_matches('"');
+                // 1.8.0_392 for 8, 17.0.2 for 17
+                let version = version.strip_prefix("1.").unwrap_or(version);
+                version.split(['.', '_', '-', '+']).next()?.parse().ok()
+            })
+        });
+    match version {
+        Some(version) => version,
+        None if dir.join("lib/modules").is_file() => DEFAULT_RELEASE,
+        None => 8,
+    }
+}
+
+// A class path from its parts, none of them required: without a JRE there
+// are no boot or extension classes, without a class path no user classes
+#[derive(Default)]
+pub struct ClassPathBuilder {
+    jre_dir: Option<String>,
+    class_path: Option<String>,
+    release: Option<u32>,
+    front: Vec<Box<dyn Entry>>,
+    back: Vec<Box<dyn Entry>>,
+    verify: bool,
+}
+
+impl ClassPathBuilder {
+    // A JDK with lib/modules, or a JRE with lib/*.jar and lib/ext/
+    pub fn jre(mut self, jre_dir: String) -> Self {
+        self.jre_dir = Some(jre_dir);
+        self
+    }
+
+    pub fn class_path(mut self, class_path: String) -> Self {
+        self.class_path = Some(class_path);
+        self
+    }
+
+    // The release multi-release jars are read for, by default the runtime's
+    pub fn release(mut self, release: u32) -> Self {
+        self.release = Some(release);
+        self
+    }
+
+    // Check classes of signed jars against their signatures
+    pub fn verify(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    pub fn push_front(mut self, entry: Box<dyn Entry>) -> Self {
+        self.front.insert(0, entry);
+        self
+    }
+
+    pub fn push_back(mut self, entry: Box<dyn Entry>) -> Self {
+        self.back.push(entry);
+        self
+    }
+
+    pub fn build(self) -> ClassPath {
+        let release = self.release.unwrap_or_else(|| match &self.jre_dir {
+            Some(jre_dir) => runtime_release(Path::new(jre_dir)),
+            None => DEFAULT_RELEASE,
+        });
+        let empty = || -> Box<dyn Entry> { Box::new(CompositeEntry::default()) };
+        let modules = self
+            .jre_dir
+            .as_ref()
+            .map(|dir| format!("{}/lib/modules", dir));
+        let (boot_classpath, ext_classpath, modular) = match (&self.jre_dir, modules) {
+            // JDK 9 and later: a runtime image, and no extension mechanism
+            (Some(_), Some(modules)) if Path::new(&modules).is_file() => (
+                Box::new(JImageEntry::new(modules)) as Box<dyn Entry>,
+                empty(),
+                true,
+            ),
+            (Some(jre_dir), _) => (
+                new_entry_for_release(format!("{}/lib/*", jre_dir), release),
+                new_entry_for_release(format!("{}/lib/ext/*", jre_dir), release),
+                false,
+            ),
+            (None, _) => (empty(), empty(), false),
+        };
+        let user_classpath: Box<dyn Entry> = match self.class_path {
+            Some(class_path) => new_entry_for_release(class_path, release),
+            None => empty(),
+        };
+        let mut cp = ClassPath {
+            front: CompositeEntry {
+                path: String::new(),
+                entries: self.front,
+            },
             boot_classpath,
             ext_classpath,
             user_classpath,
+            back: CompositeEntry {
+                path: String::new(),
+                entries: self.back,
+            },
+            release,
+            modular,
+            verbose: false,
+            verify: false,
+        };
+        cp.set_verify(self.verify);
+        cp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::path::Path;
+
+    use zip::write::SimpleFileOptions;
+    use zip::ZipWriter;
+
+    use super::*;
+
+    // Names without an extension are those of classes
+    fn jar(path: &Path, entries: &[(&str, &[u8])]) {
+        let mut jar = ZipWriter::new(File::create(path).unwrap());
+        for (name, data) in entries {
+            let name = match name.contains('.') {
+                true => name.to_string(),
+                false => format!("{}.class", name),
+            };
+            jar.start_file(name, SimpleFileOptions::default()).unwrap();
+            jar.write_all(data).unwrap();
         }
+        jar.finish().unwrap();
     }
 
-    fn get_jre_dir(jre_option: String) -> String {
-        let jre_option = PathBuf::from(jre_option);
-        if jre_option.exists() {
-            jre_option.to_str().unwrap().to_string()
-        } else if PathBuf::from("./jre").exists() {
-            "./jre".to_string()
-        } else {
-            // TODO: error handling
-            if let Ok(home) = std::env::var("JAVA_HOME") {
-                format!("{}/jre", home)
-            } else {
-                panic!("JAVA_HOME not set");
+    // A JRE with A in its boot jar, A and B in an extension jar, and a user
+    // class path of two directories with A, B, C and D; the extension jar and
+    // the second directory have a service file
+    fn classpath(test: &str) -> (PathBuf, ClassPath) {
+        let root = std::env::temp_dir().join(format!("rustjvm-classpath-{}", test));
+        let _ = fs::remove_dir_all(&root);
+        for dir in ["jre/lib/ext", "user1/p", "user2/p"] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        jar(&root.join("jre/lib/rt.jar"), &[("p/A", b"boot")]);
+        jar(
+            &root.join("jre/lib/ext/ext.jar"),
+            &[
+                ("p/A", b"ext"),
+                ("p/B", b"ext"),
+                ("META-INF/services/p.S", b"ext"),
+            ],
+        );
+        for name in ["A", "B", "C"] {
+            fs::write(root.join(format!("user1/p/{}.class", name)), b"user1").unwrap();
+        }
+        fs::write(root.join("user2/p/D.class"), b"user2").unwrap();
+        fs::create_dir_all(root.join("user2/META-INF/services")).unwrap();
+        fs::write(root.join("user2/META-INF/services/p.S"), b"user2").unwrap();
+        let user = format!(
+            "{}{}{}",
+            root.join("user1").display(),
+            PATH_SEPARATOR,
+            root.join("user2").display()
+        );
+        let jre = root.join("jre").to_str().unwrap().to_string();
+        (
+            fs::canonicalize(root).unwrap(),
+            ClassPath::new(Some(jre), user).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_search_order() {
+        let (root, cp) = classpath("search-order");
+        assert_eq!(cp.read_class("p/A").unwrap(), b"boot");
+        assert_eq!(cp.read_class("p/B").unwrap(), b"ext");
+        assert_eq!(cp.read_class("p/C").unwrap(), b"user1");
+        assert_eq!(cp.read_class("p/D").unwrap(), b"user2");
+        assert!(cp.read_class("p/E").is_err());
+
+        let (_, location) = cp.locate_class("p/A").unwrap();
+        assert_eq!(location.tier, Tier::Boot);
+        assert_eq!(
+            location.entry,
+            root.join("jre/lib/rt.jar").display().to_string()
+        );
+        let (_, location) = cp.locate_class("p/D").unwrap();
+        assert_eq!(
+            location,
+            Location {
+                tier: Tier::User,
+                entry: root.join("user2").display().to_string()
             }
+        );
+    }
+
+    #[test]
+    fn test_wildcard() {
+        let (root, mut cp) = classpath("wildcard");
+        let lib = root.join("lib");
+        fs::create_dir_all(lib.join("dir.jar")).unwrap();
+        jar(&lib.join("b.jar"), &[("p/X", b"b"), ("p/B", b"lib")]);
+        jar(&lib.join("a.jar"), &[("p/X", b"a")]);
+        fs::write(lib.join("notes.txt"), b"").unwrap();
+
+        // Jars only, in the order of their names
+        let entry = new_entry(format!("{}/*", lib.display()));
+        let jars: Vec<String> = entry
+            .list_by_entry()
+            .unwrap()
+            .into_iter()
+            .map(|(jar, _)| jar)
+            .collect();
+        assert_eq!(
+            jars,
+            ["a.jar", "b.jar"].map(|name| lib.join(name).display().to_string())
+        );
+        assert_eq!(entry.read_resource("p/X.class").unwrap(), b"a");
+        let missing = new_entry(format!("{}/*", root.join("missing").display()));
+        assert!(missing.list().unwrap().is_empty());
+
+        cp.set_ext_dirs(&lib.display().to_string()).unwrap();
+        assert_eq!(cp.read_class("p/B").unwrap(), b"lib");
+        assert_eq!(cp.locate_class("p/X").unwrap().1.tier, Tier::Ext);
+        cp.append_boot(lib.join("b.jar").display().to_string());
+        assert_eq!(cp.read_class("p/A").unwrap(), b"boot");
+        let (data, location) = cp.locate_class("p/X").unwrap();
+        assert_eq!((data.as_slice(), location.tier), (&b"b"[..], Tier::Boot));
+    }
+
+    #[test]
+    fn test_errors() {
+        let root = std::env::temp_dir().join("rustjvm-classpath-errors");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("dir/p")).unwrap();
+        fs::write(root.join("dir/p/A.class"), b"dir").unwrap();
+        fs::write(root.join("bad.jar"), b"not a zip").unwrap();
+        let root = fs::canonicalize(root).unwrap();
+        let path = |name: &str| root.join(name).display().to_string();
+        let entry = new_entry([path("bad.jar"), path("dir")].join(&PATH_SEPARATOR.to_string()));
+
+        // Found past an entry that cannot be read, each entry being probed
+        let mut probed = vec![];
+        let (data, _) = entry
+            .probe_resource("p/A.class", &mut |path, found| {
+                probed.push((path.to_string(), found.is_ok()))
+            })
+            .unwrap();
+        assert_eq!(data, b"dir");
+        assert_eq!(probed, [(path("bad.jar"), false), (path("dir"), true)]);
+
+        let ClassPathError::Multiple(errors) = entry.read_class("p/B.class").unwrap_err() else {
+            panic!("expected the failure of each entry");
+        };
+        assert!(
+            matches!(&errors[0], ClassPathError::Corrupt { path: p, .. } if *p == path("bad.jar"))
+        );
+        assert_eq!(
+            errors[1],
+            ClassPathError::NotFound {
+                name: "p/B.class".to_string(),
+                searched: vec![path("dir")],
+            }
+        );
+
+        // Without a broken entry, only not found, with every entry searched
+        let entry = new_entry([path("dir"), path("none")].join(&PATH_SEPARATOR.to_string()));
+        assert_eq!(
+            entry.read_class("p/B.class").unwrap_err(),
+            ClassPathError::NotFound {
+                name: "p/B.class".to_string(),
+                searched: vec![path("dir"), path("none")],
+            }
+        );
+    }
+
+    #[test]
+    fn test_custom_entries() {
+        let (_, mut cp) = classpath("custom-entries");
+        let front = MemoryEntry::new("front".to_string());
+        front.insert("p/A.class".to_string(), b"front".to_vec());
+        front.insert("p/C.class".to_string(), b"front".to_vec());
+        let back = MemoryEntry::new("back".to_string());
+        back.insert("p/C.class".to_string(), b"back".to_vec());
+        back.insert("p/Z.class".to_string(), b"back".to_vec());
+        cp.push_front(Box::new(front));
+        cp.push_back(Box::new(back));
+        assert_eq!(cp.read_class("p/A").unwrap(), b"front");
+        assert_eq!(cp.read_class("p/B").unwrap(), b"ext");
+        let (data, location) = cp.locate_class("p/Z").unwrap();
+        assert_eq!(data, b"back");
+        assert_eq!(
+            location,
+            Location {
+                tier: Tier::Custom,
+                entry: "back".to_string()
+            }
+        );
+
+        // Without a JRE, and with classes generated once the class path is
+        // built
+        let generated = Arc::new(MemoryEntry::new("generated".to_string()));
+        let cp = ClassPath::builder()
+            .push_back(Box::new(generated.clone()))
+            .build();
+        assert!(cp.read_class("g/G").unwrap_err().is_not_found());
+        generated.insert("g/G.class".to_string(), b"g".to_vec());
+        assert_eq!(cp.read_class("g/G").unwrap(), b"g");
+        assert_eq!(
+            cp.classes().unwrap(),
+            [(
+                "g/G".to_string(),
+                Location {
+                    tier: Tier::Custom,
+                    entry: "generated".to_string()
+                }
+            )]
+        );
+    }
+
+    #[test]
+    fn test_find_jre() {
+        let root = std::env::temp_dir().join("rustjvm-classpath-find-jre");
+        let _ = fs::remove_dir_all(&root);
+        for dir in ["jdk8/jre/lib", "jdk17/lib", "jre/lib"] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        fs::write(root.join("jdk17/lib/modules"), b"").unwrap();
+        let find = |dir: &str| ClassPath::find_jre(Some(root.join(dir).display().to_string()));
+        assert_eq!(
+            find("jdk8").unwrap(),
+            root.join("jdk8/jre").display().to_string()
+        );
+        assert_eq!(
+            find("jdk17").unwrap(),
+            root.join("jdk17").display().to_string()
+        );
+        assert_eq!(find("jre").unwrap(), root.join("jre").display().to_string());
+        assert!(find("missing").is_err());
+        let missing = root.join("missing").display().to_string();
+        assert!(ClassPath::new(Some(missing), ".".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_runtime_release() {
+        let root = std::env::temp_dir().join("rustjvm-classpath-runtime-release");
+        let _ = fs::remove_dir_all(&root);
+        for dir in ["jdk8/jre/lib", "jdk11/lib", "jdk17/lib", "jre/lib"] {
+            fs::create_dir_all(root.join(dir)).unwrap();
         }
+        fs::write(root.join("jdk8/release"), "JAVA_VERSION=\"1.8.0_392\"\n").unwrap();
+        fs::write(root.join("jdk11/release"), "JAVA_VERSION=\"11.0.2\"\n").unwrap();
+        fs::write(root.join("jdk11/lib/modules"), b"").unwrap();
+        fs::write(root.join("jdk17/lib/modules"), b"").unwrap();
+        let release = |dir: &str| runtime_release(&root.join(dir));
+        assert_eq!(release("jdk8"), 8);
+        // JDK 8 has its release file beside jre/, not in it
+        assert_eq!(release("jdk8/jre"), 8);
+        assert_eq!(release("jdk11"), 11);
+        assert_eq!(release("jdk17"), DEFAULT_RELEASE);
+        assert_eq!(release("jre"), 8);
+    }
+
+    #[test]
+    fn test_duplicates() {
+        let (root, mut cp) = classpath("duplicates");
+        let copy = MemoryEntry::new("copy".to_string());
+        copy.insert("p/D.class".to_string(), b"user2".to_vec());
+        cp.push_back(Box::new(copy));
+        let duplicates = cp.duplicates().unwrap();
+        let found: Vec<(&str, usize, bool)> = duplicates
+            .iter()
+            .map(|d| (d.class.as_str(), d.copies.len(), d.is_conflict()))
+            .collect();
+        assert_eq!(
+            found,
+            [("p/A", 3, true), ("p/B", 2, true), ("p/D", 2, false)]
+        );
+
+        // The copy read_class resolves comes first
+        let (location, hash) = &duplicates[0].copies[0];
+        assert_eq!(*location, cp.locate_class("p/A").unwrap().1);
+        assert_eq!(
+            location.entry,
+            root.join("jre/lib/rt.jar").display().to_string()
+        );
+        assert_eq!(
+            hash,
+            &format!("{:x}", Sha256::digest(cp.read_class("p/A").unwrap()))
+        );
     }
 
-    pub fn read_class(&self, class_name: &str) -> Result<Vec<u8>, String> {
-        let class_name_str = format!("{}.class", class_name);
-        let class_name = class_name_str.as_str();
-        if let Ok(class) = self.user_classpath.read_class(class_name) {
-            return Ok(class);
-        } else if let Ok(class) = self.ext_classpath.read_class(class_name) {
-            return Ok(class);
-        } else if let Ok(class) = self.user_classpath.read_class(class_name) {
-            return Ok(class);
+    #[test]
+    fn test_signed_jar() {
+        let root = std::env::temp_dir().join("rustjvm-classpath-signed-jar");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("dir/p")).unwrap();
+        fs::write(root.join("dir/p/B.class"), b"b").unwrap();
+        let section = |name: &str, data: &[u8]| {
+            let digest = jar::digest("SHA-256", data).unwrap();
+            format!("Name: {}\r\nSHA-256-Digest: {}\r\n\r\n", name, digest)
+        };
+        let manifest = format!(
+            "Manifest-Version: 1.0\r\n\r\n{}{}",
+            section("p/A.class", b"a"),
+            section("p/B.class", b"b")
+        );
+        let sf = format!(
+            "Signature-Version: 1.0\r\nSHA-256-Digest-Manifest: {}\r\n\r\n",
+            jar::digest("SHA-256", manifest.as_bytes()).unwrap()
+        );
+        jar(
+            &root.join("signed.jar"),
+            &[
+                ("META-INF/MANIFEST.MF", manifest.as_bytes()),
+                ("META-INF/K.SF", sf.as_bytes()),
+                ("META-INF/K.RSA", b""),
+                ("p/A", b"a"),
+                ("p/B", b"tampered"),
+            ],
+        );
+        let class_path = format!(
+            "{}{}{}",
+            root.join("signed.jar").display(),
+            PATH_SEPARATOR,
+            root.join("dir").display()
+        );
+
+        let cp = ClassPath::builder().class_path(class_path.clone()).build();
+        assert_eq!(cp.read_class("p/B").unwrap(), b"tampered");
+        // The tampered class fails rather than the next copy being read
+        let cp = ClassPath::builder()
+            .class_path(class_path)
+            .verify(true)
+            .build();
+        assert_eq!(cp.read_class("p/A").unwrap(), b"a");
+        let e = cp.read_class("p/B").unwrap_err();
+        assert!(matches!(e, ClassPathError::Security { .. }));
+        assert_eq!(
+            e.to_string(),
+            "java.lang.SecurityException: SHA-256 digest error for p/B.class"
+        );
+    }
+
+    #[test]
+    fn test_resources() {
+        let (root, cp) = classpath("resources");
+        let name = "META-INF/services/p.S";
+        assert_eq!(cp.read_resource(name).unwrap(), b"ext");
+        assert_eq!(cp.read_resource("p/C.class").unwrap(), b"user1");
+        assert!(cp.read_resource("p/C.txt").is_err());
+        let found: Vec<_> = cp
+            .find_resources(name)
+            .into_iter()
+            .map(|(data, location)| (data, location.tier))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (b"ext".to_vec(), Tier::Ext),
+                (b"user2".to_vec(), Tier::User)
+            ]
+        );
+        assert_eq!(
+            cp.find_resources(name)[1].1.entry,
+            root.join("user2").display().to_string()
+        );
+        assert!(cp.find_resources("p/E.class").is_empty());
+    }
+
+    #[test]
+    fn test_list() {
+        let (root, cp) = classpath("list");
+        let user2 = new_entry(root.join("user2").to_str().unwrap().to_string());
+        assert_eq!(
+            user2.list().unwrap(),
+            ["META-INF/services/p.S", "p/D.class"]
+        );
+        let classes: Vec<_> = cp
+            .classes()
+            .unwrap()
+            .into_iter()
+            .map(|(class, location)| (class, location.tier))
+            .collect();
+        assert_eq!(
+            classes,
+            [
+                ("p/A".to_string(), Tier::Boot),
+                ("p/B".to_string(), Tier::Ext),
+                ("p/C".to_string(), Tier::User),
+                ("p/D".to_string(), Tier::User),
+            ]
+        );
+        let packages = cp.packages().unwrap();
+        let entries: Vec<_> = packages["p"].iter().map(|l| l.entry.clone()).collect();
+        let path = |name: &str| root.join(name).display().to_string();
+        assert_eq!(
+            entries,
+            [
+                path("jre/lib/rt.jar"),
+                path("jre/lib/ext/ext.jar"),
+                path("user1"),
+                path("user2")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_jar_index() {
+        let root = std::env::temp_dir().join("rustjvm-classpath-jar-test");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        let path = root.join("lib.jar");
+        jar(&path, &[("p/A", b"a"), ("p/B", b"b")]);
+        let path = path.to_str().unwrap().to_string();
+
+        let entry = Arc::new(ZipEntry::new(path.clone()));
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let entry = entry.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..100 {
+                        assert_eq!(entry.read_class("p/A.class").unwrap(), b"a");
+                        assert_eq!(entry.read_class("p/B.class").unwrap(), b"b");
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        // Another entry for the same jar shares the open archive
+        let other = ZipEntry::new(path.clone());
+        assert_eq!(other.read_class("p/B.class").unwrap(), b"b");
+        assert!(Arc::ptr_eq(
+            entry.jar.get().unwrap().as_ref().unwrap(),
+            other.jar.get().unwrap().as_ref().unwrap()
+        ));
+
+        // Misses are answered from the index, without the file
+        fs::remove_file(&path).unwrap();
+        assert!(entry.read_class("p/C.class").is_err());
+        assert_eq!(entry.read_class("p/A.class").unwrap(), b"a");
+
+        // Dropping the entries closes the jar, so a jar rewritten in place,
+        // even with the same size and time, is read afresh
+        let weak = Arc::downgrade(entry.jar.get().unwrap().as_ref().unwrap());
+        drop((entry, other));
+        assert!(weak.upgrade().is_none());
+        jar(Path::new(&path), &[("p/A", b"z")]);
+        let entry = ZipEntry::new(path.clone());
+        assert_eq!(entry.read_class("p/A.class").unwrap(), b"z");
+    }
+
+    #[test]
+    fn test_jmod() {
+        let root = std::env::temp_dir().join("rustjvm-classpath-jmod-test");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        let mut zip = ZipWriter::new(std::io::Cursor::new(vec![]));
+        for (name, data) in [("classes/p/A.class", "a"), ("lib/p/B.class", "b")] {
+            zip.start_file(name, SimpleFileOptions::default()).unwrap();
+            zip.write_all(data.as_bytes()).unwrap();
+        }
+        let mut jmod = JMOD_MAGIC.to_vec();
+        jmod.extend(zip.finish().unwrap().into_inner());
+        fs::write(root.join("m.jmod"), &jmod).unwrap();
+        fs::write(root.join("bad.jmod"), &jmod[4..]).unwrap();
+
+        let entry = new_entry(root.join("*").to_str().unwrap().to_string());
+        assert_eq!(entry.read_class("p/A.class").unwrap(), b"a");
+        assert!(entry.read_class("p/B.class").is_err());
+        let bad = new_entry(root.join("bad.jmod").to_str().unwrap().to_string());
+        assert!(bad
+            .read_class("p/A.class")
+            .unwrap_err()
+            .to_string()
+            .contains("Not a jmod"));
+    }
+
+    #[test]
+    fn test_multi_release() {
+        let root = std::env::temp_dir().join("rustjvm-classpath-mr-test");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        let classes: [(&str, &[u8]); 5] = [
+            ("p/A", b"8"),
+            ("META-INF/versions/9/p/A", b"9"),
+            ("META-INF/versions/11/p/A", b"11"),
+            ("META-INF/versions/21/p/A", b"21"),
+            ("META-INF/versions/11/p/B", b"11"),
+        ];
+        jar(&root.join("plain.jar"), &classes);
+        let mut manifest = vec![("META-INF/MANIFEST.MF", &b"Multi-Release: true\n"[..])];
+        manifest.extend(classes);
+        jar(&root.join("mr.jar"), &manifest);
+        let path = |name: &str| root.join(name).to_str().unwrap().to_string();
+
+        let read =
+            |name, release| ZipEntry::for_release(path(name), release).read_class("p/A.class");
+        assert_eq!(read("mr.jar", 8).unwrap(), b"8");
+        assert_eq!(read("mr.jar", 10).unwrap(), b"9");
+        assert_eq!(read("mr.jar", 17).unwrap(), b"11");
+        assert_eq!(read("mr.jar", 21).unwrap(), b"21");
+        // Without the manifest attribute, versioned entries are not seen
+        assert_eq!(read("plain.jar", 21).unwrap(), b"8");
+        // A class only in a versioned directory exists from that release on
+        let entry = ZipEntry::for_release(path("mr.jar"), 8);
+        assert!(entry.read_class("p/B.class").is_err());
+        assert_eq!(
+            ZipEntry::new(path("mr.jar"))
+                .read_class("p/B.class")
+                .unwrap(),
+            b"11"
+        );
+        assert_eq!(
+            ZipEntry::for_release(path("mr.jar"), 10).list().unwrap(),
+            ["META-INF/MANIFEST.MF", "p/A.class"]
+        );
+        assert_eq!(
+            ZipEntry::new(path("mr.jar")).list().unwrap(),
+            ["META-INF/MANIFEST.MF", "p/A.class", "p/B.class"]
+        );
+
+        // The release is the runtime's, also for directories of a jar
+        fs::create_dir_all(root.join("jre8/lib")).unwrap();
+        let cp = ClassPath::builder()
+            .jre(path("jre8"))
+            .class_path(format!("{}!/", path("mr.jar")))
+            .build();
+        assert_eq!(cp.read_class("p/A").unwrap(), b"8");
+        let cp = ClassPath::builder()
+            .jre(path("jre8"))
+            .class_path(path("mr.jar"))
+            .build();
+        assert_eq!(cp.read_class("p/A").unwrap(), b"8");
+        assert!(cp.read_class("p/B").is_err());
+    }
+
+    #[test]
+    fn test_nested() {
+        let root = std::env::temp_dir().join("rustjvm-classpath-nested-test");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        let inner = root.join("inner.jar");
+        jar(&inner, &[("q/B", b"b")]);
+        let inner = fs::read(inner).unwrap();
+        let mut zip = ZipWriter::new(File::create(root.join("fat.jar")).unwrap());
+        let stored =
+            SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+        for (name, data, options) in [
+            ("classes/p/A.class", &b"a"[..], SimpleFileOptions::default()),
+            ("lib/stored.jar", &inner, stored),
+            ("lib/deflated.jar", &inner, SimpleFileOptions::default()),
+        ] {
+            zip.start_file(name, options).unwrap();
+            zip.write_all(data).unwrap();
+        }
+        zip.finish().unwrap();
+
+        let fat = fs::canonicalize(root.join("fat.jar")).unwrap();
+        let fat = fat.to_str().unwrap();
+        let cp = [
+            format!("{}!/classes", fat),
+            format!("{}!/lib/stored.jar", fat),
+            format!("{}!/lib/deflated.jar", fat),
+        ];
+        for path in &cp {
+            let entry = new_entry(path.clone());
+            assert_eq!(entry.list().unwrap().len(), 1);
         }
-        Err(format!("Class not found: {}", class_name))
+        let entry = new_entry(cp.join(&PATH_SEPARATOR.to_string()));
+        assert_eq!(entry.read_class("p/A.class").unwrap(), b"a");
+        let found = entry.find_resources("q/B.class");
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0], (b"b".to_vec(), cp[1].clone()));
+        assert_eq!(found[1].1, cp[2]);
+        assert_eq!(
+            entry.locate_resource("p/A.class").unwrap().1,
+            format!("{}!/classes/", fat)
+        );
+        let missing = new_entry(format!("{}!/lib/missing.jar", fat));
+        assert!(missing.read_class("q/B.class").is_err());
     }
 }
@@ -0,0 +1,271 @@
+// Dependency report in the style of jdeps: which classes, packages and
+// class path entries the classes of a jar or directory depend on. The
+// classes a class depends on are those named by its constant pool, its
+// descriptors, signatures and annotations.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::Path;
+
+use crate::{
+    classfile::Const,
+    classpath::ClassPath,
+    descriptor,
+    loader::Class,
+    relocate::{self, Role},
+};
+
+#[derive(Debug, Default)]
+pub struct Report {
+    pub source: String,
+    // For each class of the source, the classes it depends on, with the
+    // entry each was found in, or None when it is missing
+    pub classes: BTreeMap<String, BTreeMap<String, Option<String>>>,
+    // Packages with classes in more than one entry, and those entries
+    pub split_packages: BTreeMap<String, BTreeSet<String>>,
+    // Classes that could not be parsed
+    pub skipped: Vec<String>,
+}
+
+// Analyze the classes of `source`, looking for their dependencies in
+// `source` itself and then in `class_path`, in the order of delegation
+pub fn analyze(source: &str, class_path: &ClassPath) -> Result<Report, String> {
+    if !Path::new(source.strip_suffix('*').unwrap_or(source)).exists() {
+        return Err(format!("{}: no such file or directory", source));
+    }
+    let mut report = Report {
+        source: source.to_string(),
+        ..Default::default()
+    };
+    let source_path = ClassPath::builder().class_path(source.to_string()).build();
+    let names: Vec<String> = source_path
+        .classes()?
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+
+    // Where each class is found first, and every entry with each package
+    let mut origins: HashMap<String, String> = HashMap::new();
+    let mut packages: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+    for name in &names {
+        origins.insert(name.clone(), source.to_string());
+        packages
+            .entry(package(name).to_string())
+            .or_default()
+            .insert(source.to_string());
+    }
+    for (class, location) in class_path.classes()? {
+        origins.entry(class).or_insert(location.entry);
+    }
+    for (package, locations) in class_path.packages()? {
+        let entries = packages.entry(package).or_default();
+        entries.extend(locations.into_iter().map(|location| location.entry));
+    }
+    report.split_packages = packages
+        .into_iter()
+        .filter(|(_, entries)| entries.len() > 1)
+        .collect();
+
+    for name in names {
+        let data = source_path.read_class(&name)?;
+        let Ok(mut class) = Class::parse(&data) else {
+            report.skipped.push(name);
+            continue;
+        };
+        let Ok(dependencies) = dependencies(&mut class) else {
+            report.skipped.push(name);
+            continue;
+        };
+        let dependencies = dependencies
+            .into_iter()
+            .filter(|d| *d != name)
+            .map(|d| {
+                let origin = origins.get(&d).cloned();
+                (d, origin)
+            })
+            .collect();
+        report.classes.insert(name, dependencies);
+    }
+    Ok(report)
+}
+
+// Classes named anywhere in `class`, the same references relocation follows
+pub fn dependencies(class: &mut Class) -> Result<BTreeSet<String>, String> {
+    let strings: HashMap<u16, String> = {
+        let cp = class.const_pool.borrow();
+        (1..cp.count())
+            .filter_map(|index| match cp.get(index) {
+                Some(Const::Utf8(s)) => Some((index, s.clone())),
+                _ => None,
+            })
+            .collect()
+    };
+    let mut found = BTreeSet::new();
+    relocate::walk(class, &mut |index, role| {
+        let Some(s) = strings.get(index) else {
+            return;
+        };
+        match role {
+            Role::ClassName if !s.starts_with('[') => {
+                found.insert(s.clone());
+            }
+            Role::ClassName | Role::Descriptor => found.extend(descriptor_classes(s)),
+            _ => {}
+        }
+    })?;
+    Ok(found)
+}
+
+// Class names in a descriptor or signature
+fn descriptor_classes(s: &str) -> Vec<String> {
+    descriptor::class_names(s)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|range| s[range].to_string())
+        .collect()
+}
+
+pub fn package(class: &str) -> &str {
+    class.rsplit_once('/').map_or("", |(package, _)| package)
+}
+
+impl Report {
+    // Entries the source depends on, "not found" standing for missing classes
+    pub fn summary(&self) -> BTreeSet<String> {
+        self.classes
+            .values()
+            .flat_map(|deps| deps.values())
+            .map(|origin| origin.clone().unwrap_or_else(|| "not found".to_string()))
+            .filter(|origin| *origin != self.source)
+            .collect()
+    }
+
+    // Dependencies between packages, with the entry providing the target
+    pub fn packages(&self) -> BTreeSet<(String, String, String)> {
+        let mut found = BTreeSet::new();
+        for (class, deps) in &self.classes {
+            for (dep, origin) in deps {
+                if package(dep) != package(class) {
+                    found.insert((
+                        package(class).to_string(),
+                        package(dep).to_string(),
+                        origin.clone().unwrap_or_else(|| "not found".to_string()),
+                    ));
+                }
+            }
+        }
+        found
+    }
+
+    // Classes not found anywhere, with the classes that depend on them
+    pub fn missing(&self) -> BTreeMap<String, BTreeSet<String>> {
+        let mut missing: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+        for (class, deps) in &self.classes {
+            for (dep, origin) in deps {
+                if origin.is_none() {
+                    missing
+                        .entry(dep.clone())
+                        .or_default()
+                        .insert(class.clone());
+                }
+            }
+        }
+        missing
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, fs, rc::Rc};
+
+    use super::*;
+    use crate::classfile::ConstPool;
+    use crate::visitor::{ClassVisitor, Header};
+    use crate::writer::ClassWriter;
+
+    // A class extending `super_name` with a field of type `field`
+    fn class(name: &str, super_name: &str, field: &str) -> Vec<u8> {
+        let mut cw = ClassWriter::new();
+        cw.visit(Header {
+            minor_version: 0,
+            major_version: 52,
+            access: 0x0021,
+            name: name.to_string(),
+            super_name: Some(super_name.to_string()),
+            interfaces: vec![],
+            const_pool: Rc::new(RefCell::new(ConstPool::default())),
+        });
+        cw.visit_field(0x0001, "f", field, vec![]);
+        cw.into_bytes().unwrap()
+    }
+
+    fn write(dir: &Path, name: &str, data: Vec<u8>) {
+        let path = dir.join(format!("{}.class", name));
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, data).unwrap();
+    }
+
+    #[test]
+    fn test_descriptor_classes() {
+        assert_eq!(
+            descriptor_classes("(I[La/B;)Ljava/util/List<+Lc/D;>;"),
+            ["a/B", "java/util/List", "c/D"]
+        );
+        // Type variables are not classes
+        assert!(descriptor_classes("<TLIST:Ljava/lang/Object;>()TTLIST;")
+            .iter()
+            .all(|c| c == "java/lang/Object"));
+        // Nor are type parameters, even those named like a class type starts
+        assert_eq!(
+            descriptor_classes("<L:Ljava/lang/Object;>(TL;ZLa/B;)Ljava/util/List<TL;>;"),
+            ["java/lang/Object", "a/B", "java/util/List"]
+        );
+        assert_eq!(
+            descriptor_classes("<K::Ljava/lang/Comparable<TK;>;>La/Base<TK;>.Inner;Lc/D;"),
+            ["java/lang/Comparable", "a/Base", "c/D"]
+        );
+        assert!(descriptor_classes("(La/B").is_empty());
+    }
+
+    #[test]
+    fn test_analyze() {
+        let root = std::env::temp_dir().join("rustjvm-deps-test");
+        let (app, lib) = (root.join("app"), root.join("lib"));
+        let _ = fs::remove_dir_all(&root);
+        write(&app, "a/Main", class("a/Main", "b/Base", "[Lc/Gone;"));
+        write(&app, "b/Local", class("b/Local", "java/lang/Object", "I"));
+        write(
+            &lib,
+            "b/Base",
+            class("b/Base", "java/lang/Object", "Ljava/util/List;"),
+        );
+        let (app, lib) = (app.to_str().unwrap(), lib.to_str().unwrap());
+
+        let class_path = ClassPath::builder().class_path(lib.to_string()).build();
+        let report = analyze(app, &class_path).unwrap();
+        assert_eq!(
+            report.classes["a/Main"],
+            BTreeMap::from([
+                ("b/Base".to_string(), Some(lib.to_string())),
+                ("c/Gone".to_string(), None),
+            ])
+        );
+        assert_eq!(
+            report.summary(),
+            BTreeSet::from([lib.to_string(), "not found".to_string()])
+        );
+        assert!(report
+            .packages()
+            .contains(&("a".to_string(), "b".to_string(), lib.to_string())));
+        assert_eq!(
+            report.split_packages,
+            BTreeMap::from([(
+                "b".to_string(),
+                BTreeSet::from([app.to_string(), lib.to_string()])
+            )])
+        );
+        // java/lang/Object is missing too, as no JRE was given
+        let missing = report.missing();
+        assert_eq!(missing["c/Gone"], BTreeSet::from(["a/Main".to_string()]));
+        assert!(missing.contains_key("java/lang/Object"));
+    }
+}
@@ -0,0 +1,338 @@
+// Field and method descriptors (JVMS 4.3) and the name rules of JVMS 4.2
+
+use std::ops::Range;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FieldType {
+    Byte,
+    Char,
+    Double,
+    Float,
+    Int,
+    Long,
+    Short,
+    Boolean,
+    Object(String),
+    Array(Box<FieldType>),
+}
+
+impl FieldType {
+    // Number of local variable / operand stack slots a value of this type uses
+    pub fn slots(&self) -> usize {
+        match self {
+            FieldType::Long | FieldType::Double => 2,
+            _ => 1,
+        }
+    }
+
+    pub fn dimensions(&self) -> usize {
+        match self {
+            FieldType::Array(component) => 1 + component.dimensions(),
+            _ => 0,
+        }
+    }
+
+    pub fn descriptor(&self) -> String {
+        match self {
+            FieldType::Byte => "B".to_string(),
+            FieldType::Char => "C".to_string(),
+            FieldType::Double => "D".to_string(),
+            FieldType::Float => "F".to_string(),
+            FieldType::Int => "I".to_string(),
+            FieldType::Long => "J".to_string(),
+            FieldType::Short => "S".to_string(),
+            FieldType::Boolean => "Z".to_string(),
+            FieldType::Object(name) => format!("L{};", name),
+            FieldType::Array(component) => format!("[{}", component.descriptor()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MethodDescriptor {
+    pub params: Vec<FieldType>,
+    // None stands for void
+    pub ret: Option<FieldType>,
+}
+
+impl MethodDescriptor {
+    // Slots taken by the parameters, not counting `this`
+    pub fn param_slots(&self) -> usize {
+        self.params.iter().map(|p| p.slots()).sum()
+    }
+}
+
+fn parse_one(s: &str, pos: usize) -> Option<(FieldType, usize)> {
+    let t = match s.as_bytes().get(pos)? {
+        b'B' => FieldType::Byte,
+        b'C' => FieldType::Char,
+        b'D' => FieldType::Double,
+        b'F' => FieldType::Float,
+        b'I' => FieldType::Int,
+        b'J' => FieldType::Long,
+        b'S' => FieldType::Short,
+        b'Z' => FieldType::Boolean,
+        b'L' => {
+            let end = pos + s[pos..].find(';')?;
+            let name = &s[pos + 1..end];
+            if !is_binary_name(name) {
+                return None;
+            }
+            return Some((FieldType::Object(name.to_string()), end + 1));
+        }
+        b'[' => {
+            let (component, next) = parse_one(s, pos + 1)?;
+            return Some((FieldType::Array(Box::new(component)), next));
+        }
+        _ => return None,
+    };
+    Some((t, pos + 1))
+}
+
+pub fn parse_field(s: &str) -> Option<FieldType> {
+    match parse_one(s, 0)? {
+        (t, end) if end == s.len() => Some(t),
+        _ => None,
+    }
+}
+
+pub fn parse_method(s: &str) -> Option<MethodDescriptor> {
+    if !s.starts_with('(') {
+        return None;
+    }
+    let mut pos = 1;
+    let mut params = vec![];
+    while s.as_bytes().get(pos)? != &b')' {
+        let (t, next) = parse_one(s, pos)?;
+        params.push(t);
+        pos = next;
+    }
+    let ret = match &s[pos + 1..] {
+        "V" => None,
+        r => Some(parse_field(r)?),
+    };
+    Some(MethodDescriptor { params, ret })
+}
+
+// Unqualified names of fields, methods, locals and formal parameters
+pub fn is_unqualified_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains(['.', ';', '[', '/'])
+}
+
+pub fn is_method_name(name: &str) -> bool {
+    name == "<init>"
+        || name == "<clinit>"
+        || (is_unqualified_name(name) && !name.contains(['<', '>']))
+}
+
+// Binary class or interface name in internal form, e.g. java/lang/Object
+pub fn is_binary_name(name: &str) -> bool {
+    name.split('/').all(is_unqualified_name)
+}
+
+// The name of a CONSTANT_Class_info may also be an array type descriptor
+pub fn is_class_name(name: &str) -> bool {
+    if name.starts_with('[') {
+        matches!(parse_field(name), Some(t) if t.dimensions() <= 255)
+    } else {
+        is_binary_name(name)
+    }
+}
+
+// Where the class names are in a descriptor or in a class, method or field
+// signature (JVMS 4.7.9.1). Only the outermost class of a nested class type
+// such as Lp/Outer<TT;>.Inner; is named, as p/Outer. None if `s` is none of
+// these.
+pub fn class_names(s: &str) -> Option<Vec<Range<usize>>> {
+    let parse = |f: &dyn Fn(&mut Signatures) -> Option<()>| {
+        let mut signatures = Signatures {
+            s: s.as_bytes(),
+            pos: 0,
+            names: vec![],
+        };
+        match f(&mut signatures) {
+            Some(()) if signatures.pos == s.len() => Some(signatures.names),
+            _ => None,
+        }
+    };
+    parse(&|p| p.method())
+        .or_else(|| parse(&|p| p.class()))
+        .or_else(|| parse(&|p| p.java_type()))
+}
+
+struct Signatures<'s> {
+    s: &'s [u8],
+    pos: usize,
+    names: Vec<Range<usize>>,
+}
+
+impl Signatures<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.s.get(self.pos).copied()
+    }
+
+    fn expect(&mut self, c: u8) -> Option<()> {
+        if self.peek()? != c {
+            return None;
+        }
+        self.pos += 1;
+        Some(())
+    }
+
+    // Up to the next character an identifier cannot hold, `/` too unless
+    // it is a class name
+    fn identifier(&mut self, class_name: bool) -> Option<Range<usize>> {
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            if b".;[<>:".contains(&c) || (c == b'/' && !class_name) {
+                break;
+            }
+            self.pos += 1;
+        }
+        (self.pos > start).then_some(start..self.pos)
+    }
+
+    fn class(&mut self) -> Option<()> {
+        if self.peek() == Some(b'<') {
+            self.type_parameters()?;
+        }
+        self.class_type()?;
+        while self.pos < self.s.len() {
+            self.class_type()?;
+        }
+        Some(())
+    }
+
+    fn method(&mut self) -> Option<()> {
+        if self.peek() == Some(b'<') {
+            self.type_parameters()?;
+        }
+        self.expect(b'(')?;
+        while self.peek()? != b')' {
+            self.java_type()?;
+        }
+        self.pos += 1;
+        if self.peek() == Some(b'V') {
+            self.pos += 1;
+        } else {
+            self.java_type()?;
+        }
+        while self.peek() == Some(b'^') {
+            self.pos += 1;
+            self.reference_type()?;
+        }
+        Some(())
+    }
+
+    fn type_parameters(&mut self) -> Option<()> {
+        self.expect(b'<')?;
+        loop {
+            // The name of a type parameter is not a class, whatever it is
+            self.identifier(false)?;
+            self.expect(b':')?;
+            if self.peek()? != b':' && self.peek()? != b'>' {
+                self.reference_type()?;
+            }
+            while self.peek()? == b':' {
+                self.pos += 1;
+                self.reference_type()?;
+            }
+            if self.peek()? == b'>' {
+                self.pos += 1;
+                return Some(());
+            }
+        }
+    }
+
+    fn java_type(&mut self) -> Option<()> {
+        match self.peek()? {
+            b'B' | b'C' | b'D' | b'F' | b'I' | b'J' | b'S' | b'Z' => {
+                self.pos += 1;
+                Some(())
+            }
+            _ => self.reference_type(),
+        }
+    }
+
+    fn reference_type(&mut self) -> Option<()> {
+        match self.peek()? {
+            b'L' => self.class_type(),
+            b'T' => {
+                self.pos += 1;
+                self.identifier(false)?;
+                self.expect(b';')
+            }
+            b'[' => {
+                self.pos += 1;
+                self.java_type()
+            }
+            _ => None,
+        }
+    }
+
+    fn class_type(&mut self) -> Option<()> {
+        self.expect(b'L')?;
+        let name = self.identifier(true)?;
+        self.names.push(name);
+        self.type_arguments()?;
+        while self.peek()? == b'.' {
+            self.pos += 1;
+            self.identifier(false)?;
+            self.type_arguments()?;
+        }
+        self.expect(b';')
+    }
+
+    fn type_arguments(&mut self) -> Option<()> {
+        if self.peek() != Some(b'<') {
+            return Some(());
+        }
+        self.pos += 1;
+        loop {
+            match self.peek()? {
+                b'*' => self.pos += 1,
+                b'+' | b'-' => {
+                    self.pos += 1;
+                    self.reference_type()?;
+                }
+                _ => self.reference_type()?,
+            }
+            if self.peek()? == b'>' {
+                self.pos += 1;
+                return Some(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_method() {
+        let d = parse_method("(IJ[Ljava/lang/String;)V").unwrap();
+        assert_eq!(
+            d.params,
+            vec![
+                FieldType::Int,
+                FieldType::Long,
+                FieldType::Array(Box::new(FieldType::Object("java/lang/String".to_string()))),
+            ]
+        );
+        assert_eq!(d.ret, None);
+        assert_eq!(d.param_slots(), 4);
+        assert!(parse_method("(I)").is_none());
+        assert!(parse_method("(Ljava.lang.String;)V").is_none());
+    }
+
+    #[test]
+    fn test_names() {
+        assert!(is_binary_name("java/lang/Object"));
+        assert!(!is_binary_name("java//Object"));
+        assert!(is_method_name("<init>"));
+        assert!(!is_method_name("<foo>"));
+        assert!(is_class_name("[[I"));
+        assert!(!is_class_name("[V"));
+    }
+}
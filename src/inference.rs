@@ -0,0 +1,296 @@
+// Verification by type inference (JVMS 4.10.2) for class files older than
+// version 50, which carry no StackMapTable. Frames are computed by data
+// flow analysis, merging the states that reach each instruction until a
+// fixed point is found. Subroutines called with jsr and left with ret are
+// handled by restoring, after each ret, the locals the subroutine did not
+// touch from the frame at the matching jsr.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use crate::bytecode::{self, Instruction, Operands};
+use crate::verifier::{Frame, MethodContext, VType, VerifyError};
+
+struct Analyzer<'a, 'b> {
+    ctx: &'a MethodContext<'b>,
+    instructions: Vec<Instruction>,
+    index_of: HashMap<usize, usize>,
+    frames: Vec<Option<Frame>>,
+    changed: BTreeSet<usize>,
+    // Locals written by each subroutine, keyed by its first instruction
+    modified: HashMap<usize, HashSet<usize>>,
+    // jsr instructions calling each subroutine, with their incoming frame
+    callers: HashMap<usize, Vec<(usize, Frame)>>,
+    // ret instructions seen for each subroutine, by instruction index
+    rets: HashMap<usize, HashSet<usize>>,
+}
+
+pub fn infer(ctx: &MethodContext) -> Result<(), VerifyError> {
+    let instructions = bytecode::decode(ctx.code).map_err(|e| ctx.error(None, e, None))?;
+    let initial = ctx.initial_frame().map_err(|e| ctx.error(None, e, None))?;
+    let mut analyzer = Analyzer {
+        ctx,
+        index_of: instructions
+            .iter()
+            .enumerate()
+            .map(|(i, instr)| (instr.pc, i))
+            .collect(),
+        frames: vec![None; instructions.len()],
+        instructions,
+        changed: BTreeSet::new(),
+        modified: HashMap::new(),
+        callers: HashMap::new(),
+        rets: HashMap::new(),
+    };
+    if analyzer.instructions.is_empty() {
+        return Err(ctx.error(None, "Code is empty".to_string(), None));
+    }
+    analyzer.frames[0] = Some(initial);
+    analyzer.changed.insert(0);
+    while let Some(i) = analyzer.changed.pop_first() {
+        analyzer.step(i)?;
+    }
+    Ok(())
+}
+
+impl Analyzer<'_, '_> {
+    fn step(&mut self, i: usize) -> Result<(), VerifyError> {
+        let ctx = self.ctx;
+        let instr = self.instructions[i].clone();
+        let pc = instr.pc;
+        let frame = self.frames[i].clone().unwrap();
+        let error = |reason: String| ctx.error(Some(pc), reason, Some(&frame));
+
+        for (handler, handler_frame) in ctx.handler_frames(pc, &frame).map_err(error)? {
+            self.merge_into(handler as i32, handler_frame, pc, &frame)?;
+        }
+
+        match instr.opcode {
+            bytecode::JSR | bytecode::JSR_W => {
+                let target = instr.targets()[0];
+                let mut next = frame.clone();
+                next.stack.push(VType::ReturnAddress(target as usize));
+                if next.stack_size() > ctx.max_stack {
+                    return Err(error("Operand stack overflow".to_string()));
+                }
+                let callers = self.callers.entry(target as usize).or_default();
+                if !callers.iter().any(|(caller, _)| *caller == i) {
+                    callers.push((i, frame.clone()));
+                    // Returns already analyzed now have one more place to go
+                    let rets = self
+                        .rets
+                        .get(&(target as usize))
+                        .cloned()
+                        .unwrap_or_default();
+                    self.changed.extend(rets);
+                }
+                if !self.modified.contains_key(&(target as usize)) {
+                    let modified = self.subroutine_locals(target as usize, &mut HashSet::new());
+                    self.modified.insert(target as usize, modified);
+                }
+                self.merge_into(target, next, pc, &frame)?;
+            }
+            bytecode::RET => {
+                let Operands::Local(index) = instr.operands else {
+                    unreachable!()
+                };
+                let subroutine = match frame.locals.get(index as usize) {
+                    Some(VType::ReturnAddress(subroutine)) => *subroutine,
+                    _ => {
+                        return Err(error(format!(
+                            "Expecting a returnAddress in local {}",
+                            index
+                        )))
+                    }
+                };
+                self.rets.entry(subroutine).or_default().insert(i);
+                let modified = self.modified[&subroutine].clone();
+                let callers = self.callers.get(&subroutine).cloned().unwrap_or_default();
+                for (caller, caller_frame) in callers {
+                    let Some(next) = self.instructions.get(caller + 1) else {
+                        return Err(error("Falling off the end of the code".to_string()));
+                    };
+                    let locals = (0..frame.locals.len())
+                        .map(|j| {
+                            if modified.contains(&j) {
+                                frame.locals[j].clone()
+                            } else {
+                                caller_frame.locals[j].clone()
+                            }
+                        })
+                        .collect();
+                    let returned = Frame {
+                        locals,
+                        stack: frame.stack.clone(),
+                        this_uninit: frame.this_uninit,
+                    };
+                    self.merge_into(next.pc as i32, returned, pc, &frame)?;
+                }
+            }
+            _ => {
+                let mut next = frame.clone();
+                ctx.execute(&mut next, &instr).map_err(error)?;
+                for target in instr.targets() {
+                    if target <= pc as i32 && has_uninitialized(&next) {
+                        return Err(error(
+                            "Uninitialized object exists on backward branch".to_string(),
+                        ));
+                    }
+                    self.merge_into(target, next.clone(), pc, &frame)?;
+                }
+                if instr.falls_through() {
+                    let Some(following) = self.instructions.get(i + 1) else {
+                        return Err(error("Falling off the end of the code".to_string()));
+                    };
+                    self.merge_into(following.pc as i32, next, pc, &frame)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Merge `incoming` into the frame recorded at `target`, queueing the
+    // target again if that frame changed
+    fn merge_into(
+        &mut self,
+        target: i32,
+        incoming: Frame,
+        pc: usize,
+        frame: &Frame,
+    ) -> Result<(), VerifyError> {
+        let ctx = self.ctx;
+        let error = |reason: String| ctx.error(Some(pc), reason, Some(frame));
+        let index = usize::try_from(target)
+            .ok()
+            .and_then(|t| self.index_of.get(&t).copied())
+            .ok_or_else(|| error("Illegal target of jump or branch".to_string()))?;
+        let merged = match &self.frames[index] {
+            None => incoming,
+            Some(existing) => {
+                let merged = self.merge(existing, &incoming, target).map_err(error)?;
+                if merged == *existing {
+                    return Ok(());
+                }
+                merged
+            }
+        };
+        self.frames[index] = Some(merged);
+        self.changed.insert(index);
+        Ok(())
+    }
+
+    fn merge(&self, a: &Frame, b: &Frame, target: i32) -> Result<Frame, String> {
+        if a.stack.len() != b.stack.len() {
+            return Err(format!(
+                "Inconsistent stack height {} != {} at {}",
+                a.stack.len(),
+                b.stack.len(),
+                target
+            ));
+        }
+        let mut stack = vec![];
+        for (x, y) in a.stack.iter().zip(&b.stack) {
+            let t = self.merge_types(x, y)?;
+            if t == VType::Top {
+                return Err(format!("Mismatched stack types at {}", target));
+            }
+            stack.push(t);
+        }
+        let mut locals = vec![];
+        for (x, y) in a.locals.iter().zip(&b.locals) {
+            locals.push(self.merge_types(x, y)?);
+        }
+        // A long or double whose second half was lost is unusable
+        for j in 0..locals.len() {
+            if locals[j].is_wide() && locals.get(j + 1) != Some(&VType::Top) {
+                locals[j] = VType::Top;
+            }
+        }
+        Ok(Frame {
+            locals,
+            stack,
+            this_uninit: a.this_uninit || b.this_uninit,
+        })
+    }
+
+    fn merge_types(&self, a: &VType, b: &VType) -> Result<VType, String> {
+        Ok(match (a, b) {
+            _ if a == b => a.clone(),
+            (VType::Null, VType::Reference(_)) => b.clone(),
+            (VType::Reference(_), VType::Null) => a.clone(),
+            (VType::Reference(x), VType::Reference(y)) => {
+                VType::Reference(self.ctx.hierarchy.common_super(x, y)?)
+            }
+            _ => VType::Top,
+        })
+    }
+
+    // Locals a subroutine may write, including those of nested subroutines
+    fn subroutine_locals(&self, start: usize, active: &mut HashSet<usize>) -> HashSet<usize> {
+        let mut modified = HashSet::new();
+        if !active.insert(start) {
+            return modified;
+        }
+        let mut visited = HashSet::new();
+        let mut pending = vec![start];
+        while let Some(pc) = pending.pop() {
+            let Some(&i) = self.index_of.get(&pc) else {
+                continue;
+            };
+            if !visited.insert(i) {
+                continue;
+            }
+            let instr = &self.instructions[i];
+            if let Some((index, wide)) = stored_local(instr) {
+                modified.insert(index);
+                if wide {
+                    modified.insert(index + 1);
+                }
+            }
+            match instr.opcode {
+                bytecode::RET => continue,
+                bytecode::JSR | bytecode::JSR_W => {
+                    let nested = instr.targets()[0] as usize;
+                    modified.extend(self.subroutine_locals(nested, active));
+                    if let Some(next) = self.instructions.get(i + 1) {
+                        pending.push(next.pc);
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+            pending.extend(instr.targets().iter().map(|t| *t as usize));
+            if instr.falls_through() {
+                if let Some(next) = self.instructions.get(i + 1) {
+                    pending.push(next.pc);
+                }
+            }
+        }
+        active.remove(&start);
+        modified
+    }
+}
+
+// The local written by a store or iinc, and whether it takes two slots
+fn stored_local(instr: &Instruction) -> Option<(usize, bool)> {
+    use bytecode::*;
+    let opcode = instr.opcode;
+    match (opcode, &instr.operands) {
+        (ISTORE..=ASTORE, Operands::Local(index)) => {
+            Some((*index as usize, opcode == LSTORE || opcode == DSTORE))
+        }
+        (ISTORE_0..=ASTORE_3, _) => {
+            let kind = (opcode - ISTORE_0) / 4;
+            Some((((opcode - ISTORE_0) % 4) as usize, kind == 1 || kind == 3))
+        }
+        (IINC, Operands::Iinc(index, _)) => Some((*index as usize, false)),
+        _ => None,
+    }
+}
+
+fn has_uninitialized(frame: &Frame) -> bool {
+    frame
+        .locals
+        .iter()
+        .chain(&frame.stack)
+        .any(|t| matches!(t, VType::Uninitialized(_)))
+}
@@ -0,0 +1,545 @@
+// Rewriting of jar files entry by entry, shared by the class transforms
+
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use zip::write::SimpleFileOptions;
+use zip::ZipWriter;
+
+use crate::classpath::ZipEntry;
+
+// Copy `input` to `output`, passing each entry's name and data to `f`, which
+// returns the new name and data, or None to keep the entry as it is.
+// Signature files are dropped, as they would no longer match the contents.
+pub fn rewrite(
+    input: &str,
+    output: &str,
+    mut f: impl FnMut(&str, &[u8]) -> Option<(String, Vec<u8>)>,
+) -> Result<(), String> {
+    if !Path::new(input).is_file() {
+        return Err(format!("{}: no such file", input));
+    }
+    let mut archive = ZipEntry::new(input.to_string()).archive()?;
+    let file = File::create(output).map_err(|e| format!("{}: {}", output, e))?;
+    let mut jar = ZipWriter::new(file);
+    let read_error = |e: zip::result::ZipError| format!("Error reading zip file: {}", e);
+    let write_error = |e: zip::result::ZipError| format!("Error writing {}: {}", output, e);
+    // Renamed entries may collide, e.g. directories created by a move
+    let mut written = HashSet::new();
+
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i).map_err(read_error)?;
+        let name = entry.name().to_string();
+        if is_signature_file(&name) {
+            continue;
+        }
+        let mut data = vec![];
+        entry
+            .read_to_end(&mut data)
+            .map_err(|e| format!("Error reading {}: {}", name, e))?;
+        let Some((new_name, new_data)) = f(&name, &data) else {
+            drop(entry);
+            if written.insert(name) {
+                let raw = archive.by_index_raw(i).map_err(read_error)?;
+                jar.raw_copy_file(raw).map_err(write_error)?;
+            }
+            continue;
+        };
+        if !written.insert(new_name.clone()) {
+            continue;
+        }
+        let mut options = SimpleFileOptions::default().compression_method(entry.compression());
+        if let Some(time) = entry.last_modified() {
+            options = options.last_modified_time(time);
+        }
+        if entry.is_dir() {
+            jar.add_directory(new_name, options).map_err(write_error)?;
+        } else {
+            jar.start_file(new_name, options).map_err(write_error)?;
+            jar.write_all(&new_data)
+                .map_err(|e| format!("Error writing {}: {}", output, e))?;
+        }
+    }
+    jar.finish().map_err(write_error)?;
+    Ok(())
+}
+
+// The main attributes and per-entry sections of a jar manifest. Names of
+// attributes are case insensitive; lines longer than 72 bytes continue on
+// lines starting with a space.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Manifest {
+    pub main: Vec<(String, String)>,
+    pub entries: Vec<(String, Vec<(String, String)>)>,
+}
+
+impl Manifest {
+    pub fn parse(data: &[u8]) -> Manifest {
+        let text = String::from_utf8_lossy(data);
+        // Sections are separated by empty lines, the first being the main one
+        let mut sections: Vec<Vec<(String, String)>> = vec![vec![]];
+        let mut lines = text
+            .split('\n')
+            .map(|l| l.strip_suffix('\r').unwrap_or(l))
+            .peekable();
+        while let Some(line) = lines.next() {
+            let section = sections.last_mut().unwrap();
+            if line.is_empty() {
+                if !section.is_empty() {
+                    sections.push(vec![]);
+                }
+                continue;
+            }
+            let mut line = line.to_string();
+            while let Some(next) = lines.next_if(|l| l.starts_with(' ')) {
+                line.push_str(&next[1..]);
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                section.push((name.trim().to_string(), value.trim_start().to_string()));
+            }
+        }
+        let mut sections = sections.into_iter();
+        Manifest {
+            main: sections.next().unwrap(),
+            entries: sections
+                .filter_map(|mut section| {
+                    let (name, value) = section.first()?;
+                    if !name.eq_ignore_ascii_case("Name") {
+                        return None;
+                    }
+                    let value = value.clone();
+                    section.remove(0);
+                    Some((value, section))
+                })
+                .collect(),
+        }
+    }
+
+    // A main attribute
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.main
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+// The manifest of a jar, if it has one
+pub fn read_manifest(path: &str) -> Result<Option<Manifest>, String> {
+    if !Path::new(path).is_file() {
+        return Err(format!("{}: no such file", path));
+    }
+    let mut archive = ZipEntry::new(path.to_string()).archive()?;
+    let Ok(mut file) = archive.by_name("META-INF/MANIFEST.MF") else {
+        return Ok(None);
+    };
+    let mut data = vec![];
+    file.read_to_end(&mut data)
+        .map_err(|e| format!("Error reading manifest of {}: {}", path, e))?;
+    Ok(Some(Manifest::parse(&data)))
+}
+
+// What `java -jar` runs: the Main-Class of the manifest, from a class path
+// of the jar followed by the entries of its Class-Path, or for a fat jar the
+// application inside it
+#[derive(Debug)]
+pub struct Executable {
+    pub main_class: String,
+    pub class_path: Vec<String>,
+}
+
+pub fn executable(path: &str) -> Result<Executable, String> {
+    let manifest = read_manifest(path)?;
+    let main_class = manifest
+        .as_ref()
+        .and_then(|m| m.get("Main-Class"))
+        .ok_or_else(|| format!("no main manifest attribute, in {}", path))?;
+    if let Some(start_class) = manifest.as_ref().and_then(|m| m.get("Start-Class")) {
+        return fat_jar(path, manifest.as_ref().unwrap(), start_class);
+    }
+    let mut class_path = vec![path.to_string()];
+    // Relative URLs, separated by spaces, resolved against the directory of
+    // the jar; entries that do not exist are ignored
+    let dir = Path::new(path).parent().unwrap_or(Path::new(""));
+    for url in manifest.iter().filter_map(|m| m.get("Class-Path")) {
+        for entry in url.split_whitespace() {
+            let entry = percent_decode(entry.strip_prefix("file:").unwrap_or(entry));
+            let entry = dir.join(entry);
+            if entry.exists() {
+                class_path.push(entry.to_string_lossy().to_string());
+            }
+        }
+    }
+    Ok(Executable {
+        main_class: main_class.to_string(),
+        class_path,
+    })
+}
+
+// A Spring Boot fat jar, whose Main-Class is the launcher of Spring Boot and
+// Start-Class that of the application. Its classes are in BOOT-INF/classes/
+// and its libraries in BOOT-INF/lib/, in the order of BOOT-INF/classpath.idx
+// if there is one, else in the order of the jar.
+fn fat_jar(path: &str, manifest: &Manifest, start_class: &str) -> Result<Executable, String> {
+    let classes = manifest
+        .get("Spring-Boot-Classes")
+        .unwrap_or("BOOT-INF/classes/");
+    let lib = manifest.get("Spring-Boot-Lib").unwrap_or("BOOT-INF/lib/");
+    let index = manifest
+        .get("Spring-Boot-Classpath-Index")
+        .unwrap_or("BOOT-INF/classpath.idx");
+    let mut archive = ZipEntry::new(path.to_string()).archive()?;
+    let mut jars: Vec<String> = archive
+        .file_names()
+        .filter(|name| {
+            name.strip_prefix(lib)
+                .is_some_and(|jar| jar.ends_with(".jar") && !jar.contains('/'))
+        })
+        .map(|name| name.to_string())
+        .collect();
+    if let Ok(mut file) = archive.by_name(index) {
+        let mut text = String::new();
+        file.read_to_string(&mut text)
+            .map_err(|e| format!("Error reading {}: {}", index, e))?;
+        // Lines of - "BOOT-INF/lib/a.jar", or of - "a.jar" in early versions
+        let listed: Vec<String> = text
+            .lines()
+            .filter_map(|line| line.strip_prefix("- "))
+            .map(|jar| jar.trim().trim_matches('"'))
+            .map(|jar| match jar.starts_with(lib) {
+                true => jar.to_string(),
+                false => format!("{}{}", lib, jar),
+            })
+            .collect();
+        jars.sort_by_key(|jar| listed.iter().position(|l| l == jar).unwrap_or(listed.len()));
+    }
+    let mut class_path = vec![format!("{}!/{}", path, classes)];
+    class_path.extend(jars.iter().map(|jar| format!("{}!/{}", path, jar)));
+    Ok(Executable {
+        main_class: start_class.to_string(),
+        class_path,
+    })
+}
+
+fn percent_decode(s: &str) -> String {
+    let mut bytes = vec![];
+    let mut rest = s.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        let hex = tail.get(..2).and_then(|h| std::str::from_utf8(h).ok());
+        match hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+            Some(decoded) if b == b'%' => {
+                bytes.push(decoded);
+                rest = &tail[2..];
+            }
+            _ => {
+                bytes.push(b);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).to_string()
+}
+
+// What the signature files of a signed jar vouch for: the digests the
+// manifest gives its entries, less the entries whose manifest sections no
+// longer match them. The signature blocks are not checked cryptographically.
+#[derive(Debug, Default)]
+pub struct Signatures {
+    digests: HashMap<String, Vec<(String, String)>>,
+    tampered: HashMap<String, String>,
+}
+
+impl Signatures {
+    // Whether `data` is what was signed as the entry `name`; entries the
+    // manifest has no digest for are not signed, and pass
+    pub fn check(&self, name: &str, data: &[u8]) -> Result<(), String> {
+        if let Some(message) = self.tampered.get(name) {
+            return Err(message.clone());
+        }
+        for (algorithm, expected) in self.digests.get(name).into_iter().flatten() {
+            if digest(algorithm, data).is_some_and(|actual| actual != *expected) {
+                return Err(format!("{} digest error for {}", algorithm, name));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Check the manifest of a jar against its signature files: as a whole, or
+// failing that, its main attributes and each section
+pub fn signatures(manifest: &[u8], signature_files: &[Vec<u8>]) -> Result<Signatures, String> {
+    let sections = raw_sections(manifest);
+    let raw_section = |name: &str| {
+        sections
+            .iter()
+            .skip(1)
+            .find(|section| Manifest::parse(section).get("Name") == Some(name))
+    };
+    let mut signatures = Signatures::default();
+    for (name, attributes) in &Manifest::parse(manifest).entries {
+        signatures
+            .digests
+            .insert(name.clone(), digests(attributes, "-Digest"));
+    }
+    for file in signature_files {
+        let signature = Manifest::parse(file);
+        let matches = |attributes: &[(String, String)], suffix: &str, data: &[u8]| {
+            digests(attributes, suffix)
+                .into_iter()
+                .find_map(|(algorithm, expected)| Some(digest(&algorithm, data)? == expected))
+        };
+        if matches(&signature.main, "-Digest-Manifest", manifest) == Some(true) {
+            continue;
+        }
+        let main = sections.first().copied().unwrap_or_default();
+        if matches(&signature.main, "-Digest-Manifest-Main-Attributes", main) == Some(false) {
+            return Err("Invalid signature file digest for Manifest main attributes".to_string());
+        }
+        for (name, attributes) in &signature.entries {
+            let section = raw_section(name).copied().unwrap_or_default();
+            if matches(attributes, "-Digest", section) == Some(false) {
+                let algorithm = digests(attributes, "-Digest").remove(0).0;
+                signatures.tampered.insert(
+                    name.clone(),
+                    format!("invalid {} signature file digest for {}", algorithm, name),
+                );
+            }
+        }
+    }
+    Ok(signatures)
+}
+
+// The algorithms and values of attributes such as SHA-256-Digest
+fn digests(attributes: &[(String, String)], suffix: &str) -> Vec<(String, String)> {
+    attributes
+        .iter()
+        .filter_map(|(name, value)| {
+            let algorithm = name.get(..name.len().checked_sub(suffix.len())?)?;
+            name[algorithm.len()..]
+                .eq_ignore_ascii_case(suffix)
+                .then(|| (algorithm.to_string(), value.clone()))
+        })
+        .filter(|(algorithm, _)| digest(algorithm, b"").is_some())
+        .collect()
+}
+
+// The digest of `data` in base64, by an algorithm as named in manifests
+pub fn digest(algorithm: &str, data: &[u8]) -> Option<String> {
+    match algorithm.to_ascii_uppercase().as_str() {
+        "SHA-256" => Some(base64(&Sha256::digest(data))),
+        "SHA1" | "SHA-1" => Some(base64(&Sha1::digest(data))),
+        _ => None,
+    }
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut text = String::new();
+    for chunk in data.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            text.push(match i <= chunk.len() {
+                true => ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char,
+                false => '=',
+            });
+        }
+    }
+    text
+}
+
+// The bytes of each section of a manifest, with the empty line ending it,
+// as the digests of signature files are taken over
+fn raw_sections(data: &[u8]) -> Vec<&[u8]> {
+    let mut sections = vec![];
+    let (mut start, mut line) = (0, 0);
+    for (i, b) in data.iter().enumerate() {
+        if *b != b'\n' {
+            continue;
+        }
+        if data[line..i]
+            .strip_suffix(b"\r")
+            .unwrap_or(&data[line..i])
+            .is_empty()
+        {
+            if line > start {
+                sections.push(&data[start..=i]);
+            }
+            start = i + 1;
+        }
+        line = i + 1;
+    }
+    if start < data.len() {
+        sections.push(&data[start..]);
+    }
+    sections
+}
+
+fn is_signature_file(name: &str) -> bool {
+    let upper = name.to_ascii_uppercase();
+    upper.starts_with("META-INF/")
+        && !upper["META-INF/".len()..].contains('/')
+        && [".SF", ".RSA", ".DSA", ".EC"]
+            .iter()
+            .any(|ext| upper.ends_with(ext))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn test_manifest() {
+        let manifest = Manifest::parse(
+            b"Manifest-Version: 1.0\r\nmulti-release: true\r\nClass-Path: a.jar\r\n  b.jar\r\n\r\n\
+              Name: p/A.class\r\nSHA-256-Digest: abc=\r\n\r\n",
+        );
+        assert_eq!(manifest.get("Multi-Release"), Some("true"));
+        assert_eq!(manifest.get("Class-Path"), Some("a.jar b.jar"));
+        assert_eq!(manifest.get("Main-Class"), None);
+        assert_eq!(
+            manifest.entries,
+            [(
+                "p/A.class".to_string(),
+                vec![("SHA-256-Digest".to_string(), "abc=".to_string())]
+            )]
+        );
+    }
+
+    #[test]
+    fn test_signatures() {
+        assert_eq!(
+            ["", "f", "fo", "foo", "foobar"].map(|s| base64(s.as_bytes())),
+            ["", "Zg==", "Zm8=", "Zm9v", "Zm9vYmFy"]
+        );
+        assert_eq!(
+            digest("SHA-256", b"").unwrap(),
+            "47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU="
+        );
+        let main = "Manifest-Version: 1.0\r\n\r\n".to_string();
+        let a = format!(
+            "Name: p/A.class\r\nSHA-256-Digest: {}\r\n\r\n",
+            digest("SHA-256", b"a").unwrap()
+        );
+        let b = format!(
+            "Name: p/B.class\r\nSHA1-Digest: {}\r\n\r\n",
+            digest("SHA1", b"b").unwrap()
+        );
+        let manifest = format!("{}{}{}", main, a, b);
+        let sf = format!(
+            "Signature-Version: 1.0\r\nSHA-256-Digest-Manifest: {}\r\n\r\n",
+            digest("SHA-256", manifest.as_bytes()).unwrap()
+        );
+        let signatures = signatures(manifest.as_bytes(), &[sf.into_bytes()]).unwrap();
+        assert!(signatures.check("p/A.class", b"a").is_ok());
+        assert!(signatures.check("p/B.class", b"b").is_ok());
+        assert!(signatures.check("p/C.class", b"unsigned").is_ok());
+        assert_eq!(
+            signatures.check("p/A.class", b"x").unwrap_err(),
+            "SHA-256 digest error for p/A.class"
+        );
+
+        // Once the manifest changes, the signature file vouches for its
+        // main attributes and sections one by one
+        let section = |name: &str, data: &str| {
+            let digest = digest("SHA-256", data.as_bytes()).unwrap();
+            format!("Name: {}\r\nSHA-256-Digest: {}\r\n\r\n", name, digest)
+        };
+        let sf = format!(
+            "Signature-Version: 1.0\r\nSHA-256-Digest-Manifest-Main-Attributes: {}\r\n\r\n{}{}",
+            digest("SHA-256", main.as_bytes()).unwrap(),
+            section("p/A.class", &a),
+            section("p/B.class", "signed before the manifest changed"),
+        );
+        let signatures =
+            super::signatures(manifest.as_bytes(), &[sf.clone().into_bytes()]).unwrap();
+        assert!(signatures.check("p/A.class", b"a").is_ok());
+        assert_eq!(
+            signatures.check("p/B.class", b"b").unwrap_err(),
+            "invalid SHA-256 signature file digest for p/B.class"
+        );
+        let changed = format!("Manifest-Version: 2.0\r\n\r\n{}{}", a, b);
+        assert_eq!(
+            super::signatures(changed.as_bytes(), &[sf.into_bytes()]).unwrap_err(),
+            "Invalid signature file digest for Manifest main attributes"
+        );
+    }
+
+    #[test]
+    fn test_executable() {
+        let root = std::env::temp_dir().join("rustjvm-jar-test");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("lib dir")).unwrap();
+        fs::write(root.join("lib dir/dep.jar"), b"").unwrap();
+        let app = root.join("app.jar");
+        let mut jar = ZipWriter::new(File::create(&app).unwrap());
+        jar.start_file("META-INF/MANIFEST.MF", SimpleFileOptions::default())
+            .unwrap();
+        jar.write_all(
+            b"Main-Class: app.Main\r\nClass-Path: missing.jar lib%20dir/de\r\n p.jar\r\n",
+        )
+        .unwrap();
+        jar.finish().unwrap();
+
+        let app = app.to_str().unwrap();
+        let launch = executable(app).unwrap();
+        assert_eq!(launch.main_class, "app.Main");
+        assert_eq!(
+            launch.class_path,
+            [
+                app.to_string(),
+                root.join("lib dir/dep.jar").to_str().unwrap().to_string()
+            ]
+        );
+        let plain = root.join("lib dir/dep.jar");
+        assert!(executable(plain.to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_fat_jar() {
+        let root = std::env::temp_dir().join("rustjvm-jar-fat-test");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        let fat = root.join("fat.jar");
+        let mut jar = ZipWriter::new(File::create(&fat).unwrap());
+        for (name, data) in [
+            (
+                "META-INF/MANIFEST.MF",
+                "Main-Class: org.springframework.boot.loader.JarLauncher\nStart-Class: app.Main\n",
+            ),
+            ("BOOT-INF/lib/a.jar", ""),
+            ("BOOT-INF/lib/b.jar", ""),
+            ("BOOT-INF/lib/c.jar", ""),
+            (
+                "BOOT-INF/classpath.idx",
+                "- \"BOOT-INF/lib/c.jar\"\n- \"BOOT-INF/lib/a.jar\"\n",
+            ),
+        ] {
+            jar.start_file(name, SimpleFileOptions::default()).unwrap();
+            jar.write_all(data.as_bytes()).unwrap();
+        }
+        jar.finish().unwrap();
+
+        let fat = fat.to_str().unwrap();
+        let launch = executable(fat).unwrap();
+        assert_eq!(launch.main_class, "app.Main");
+        let nested = |name| format!("{}!/BOOT-INF/{}", fat, name);
+        assert_eq!(
+            launch.class_path,
+            [
+                nested("classes/"),
+                nested("lib/c.jar"),
+                nested("lib/a.jar"),
+                nested("lib/b.jar")
+            ]
+        );
+    }
+}
@@ -0,0 +1,667 @@
+// Reader for the runtime image of JDK 9 and later, lib/modules, in the jimage
+// format. The image starts with a header and an index: a perfect hash table
+// of resource names (a redirect table and a table of location offsets), the
+// locations themselves and a table of strings. Resources, possibly
+// compressed, follow the index. Names are of the form
+// /module/parent/base.extension, e.g. /java.base/java/lang/Object.class,
+// and the module of each package is recorded under /packages/<package>.
+
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+
+use flate2::read::ZlibDecoder;
+
+use crate::classpath::{ClassPathError, Entry};
+
+const IMAGE_MAGIC: u32 = 0xCAFEDADA;
+const MAJOR_VERSION: u32 = 1;
+const HEADER_SIZE: usize = 28;
+const HASH_MULTIPLIER: i32 = 0x01000193;
+
+const RESOURCE_MAGIC: u32 = 0xCAFEFAFA;
+const RESOURCE_HEADER_SIZE: usize = 29;
+
+// Location attributes
+const ATTRIBUTE_END: usize = 0;
+const ATTRIBUTE_MODULE: usize = 1;
+const ATTRIBUTE_PARENT: usize = 2;
+const ATTRIBUTE_BASE: usize = 3;
+const ATTRIBUTE_EXTENSION: usize = 4;
+const ATTRIBUTE_OFFSET: usize = 5;
+const ATTRIBUTE_COMPRESSED: usize = 6;
+const ATTRIBUTE_UNCOMPRESSED: usize = 7;
+const ATTRIBUTE_COUNT: usize = 8;
+
+// Constant pool tags of the string sharing compression, for Utf8 constants
+// held in the image strings table, whole or as a descriptor
+const EXTERNALIZED_STRING: u8 = 23;
+const EXTERNALIZED_STRING_DESCRIPTOR: u8 = 25;
+
+pub struct JImageEntry {
+    abs_path: PathBuf,
+    // Opened on first lookup
+    image: OnceLock<Result<Image, String>>,
+}
+
+struct Image {
+    big_endian: bool,
+    redirect: Vec<i32>,
+    offsets: Vec<u32>,
+    locations: Vec<u8>,
+    strings: Vec<u8>,
+    index_size: u64,
+    file: Mutex<File>,
+}
+
+impl JImageEntry {
+    pub fn new(path: String) -> JImageEntry {
+        let abs_path = fs::canonicalize(&path).unwrap_or_else(|_| PathBuf::from(path));
+        JImageEntry {
+            abs_path,
+            image: OnceLock::new(),
+        }
+    }
+
+    fn image(&self) -> Result<&Image, String> {
+        self.image
+            .get_or_init(|| Image::open(&self.abs_path))
+            .as_ref()
+            .map_err(|e| e.clone())
+    }
+
+    // A resource by its full name, e.g. /java.base/java/lang/Object.class
+    pub fn read_module_resource(&self, name: &str) -> Result<Vec<u8>, String> {
+        let image = self.image()?;
+        match image.find_location(name)? {
+            Some(location) => image.read(&location),
+            None => Err(format!("Resource not found: {}", name)),
+        }
+    }
+
+    // The names of the modules in the image, those with a module-info.class
+    pub fn modules(&self) -> Result<Vec<String>, String> {
+        let image = self.image()?;
+        let mut modules = vec![];
+        for &offset in &image.offsets {
+            let location = image.attributes(offset as usize)?;
+            if location[ATTRIBUTE_PARENT] != 0
+                || image.string(location[ATTRIBUTE_BASE] as usize)? != b"module-info"
+                || image.string(location[ATTRIBUTE_EXTENSION] as usize)? != b"class"
+            {
+                continue;
+            }
+            let module = image.string(location[ATTRIBUTE_MODULE] as usize)?;
+            modules.push(String::from_utf8_lossy(module).to_string());
+        }
+        modules.sort();
+        Ok(modules)
+    }
+
+    // An image that is there but cannot be read is taken to be corrupt
+    fn lookup_error(&self, message: String) -> ClassPathError {
+        let path = self.path();
+        match fs::metadata(&self.abs_path) {
+            Ok(_) => ClassPathError::Corrupt { path, message },
+            Err(_) => ClassPathError::Io { path, message },
+        }
+    }
+
+    // The module of a package, given with slashes as in class names
+    pub fn module_of(&self, package: &str) -> Result<Option<String>, String> {
+        let image = self.image()?;
+        let name = format!("/packages/{}", package.replace('/', "."));
+        let Some(location) = image.find_location(&name)? else {
+            return Ok(None);
+        };
+        // Pairs of an is-empty flag and a module name, the first module with
+        // classes in the package providing it
+        let content = image.read(&location)?;
+        for pair in content.chunks_exact(8) {
+            if image.u32(pair, 0) == 0 {
+                let module = image.string(image.u32(pair, 4) as usize)?;
+                return Ok(Some(String::from_utf8_lossy(module).to_string()));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Entry for JImageEntry {
+    // Found in the module of its package, as the boot loader does
+    fn read_resource(&self, name: &str) -> Result<Vec<u8>, ClassPathError> {
+        let package = name.rsplit_once('/').map_or("", |(p, _)| p);
+        let error = |message| self.lookup_error(message);
+        let Some(module) = self.module_of(package).map_err(error)? else {
+            return Err(ClassPathError::not_found(name, &self.path()));
+        };
+        let image = self.image().map_err(error)?;
+        match image
+            .find_location(&format!("/{}/{}", module, name))
+            .map_err(error)?
+        {
+            Some(location) => image.read(&location).map_err(error),
+            None => Err(ClassPathError::not_found(name, &self.path())),
+        }
+    }
+
+    fn path(&self) -> String {
+        self.abs_path.display().to_string()
+    }
+
+    // The resources of all modules, without the module, as read_resource
+    // takes them
+    fn list(&self) -> Result<Vec<String>, String> {
+        let image = self.image()?;
+        let mut names = vec![];
+        for &offset in &image.offsets {
+            let location = image.attributes(offset as usize)?;
+            let name = image.name(&location)?;
+            let name = String::from_utf8_lossy(&name);
+            // The directories of packages and modules have locations too
+            let Some((module, rest)) = name.strip_prefix('/').and_then(|n| n.split_once('/'))
+            else {
+                continue;
+            };
+            if module != "packages" && module != "modules" {
+                names.push(rest.to_string());
+            }
+        }
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+}
+
+fn hash(name: &[u8], seed: i32) -> i32 {
+    let mut h = seed;
+    for b in name {
+        h = h.wrapping_mul(HASH_MULTIPLIER) ^ *b as i32;
+    }
+    h & 0x7FFFFFFF
+}
+
+fn read_error(e: std::io::Error) -> String {
+    format!("Error reading image: {}", e)
+}
+
+impl Image {
+    fn open(path: &PathBuf) -> Result<Image, String> {
+        let mut file = File::open(path).map_err(|e| format!("Error opening file: {}", e))?;
+        let mut header = [0; HEADER_SIZE];
+        file.read_exact(&mut header).map_err(read_error)?;
+        let big_endian = if header[..4] == IMAGE_MAGIC.to_le_bytes() {
+            false
+        } else if header[..4] == IMAGE_MAGIC.to_be_bytes() {
+            true
+        } else {
+            return Err(format!("Not a jimage file: {}", path.display()));
+        };
+        let mut image = Image {
+            big_endian,
+            redirect: vec![],
+            offsets: vec![],
+            locations: vec![],
+            strings: vec![],
+            index_size: 0,
+            file: Mutex::new(file),
+        };
+        let version = image.u32(&header, 4);
+        if version >> 16 != MAJOR_VERSION {
+            return Err(format!(
+                "Unsupported jimage version {}.{}",
+                version >> 16,
+                version & 0xFFFF
+            ));
+        }
+        let table_length = image.u32(&header, 16) as usize;
+        let locations_size = image.u32(&header, 20) as usize;
+        let strings_size = image.u32(&header, 24) as usize;
+
+        let mut index = vec![0; table_length * 8 + locations_size + strings_size];
+        image
+            .file
+            .get_mut()
+            .unwrap()
+            .read_exact(&mut index)
+            .map_err(read_error)?;
+        let (redirect, rest) = index.split_at(table_length * 4);
+        let (offsets, rest) = rest.split_at(table_length * 4);
+        let (locations, strings) = rest.split_at(locations_size);
+        image.redirect = (0..table_length)
+            .map(|i| image.u32(redirect, i * 4) as i32)
+            .collect();
+        image.offsets = (0..table_length)
+            .map(|i| image.u32(offsets, i * 4))
+            .collect();
+        image.locations = locations.to_vec();
+        image.strings = strings.to_vec();
+        image.index_size = (HEADER_SIZE + index.len()) as u64;
+        Ok(image)
+    }
+
+    fn u32(&self, bytes: &[u8], at: usize) -> u32 {
+        let b = bytes[at..at + 4].try_into().unwrap();
+        if self.big_endian {
+            u32::from_be_bytes(b)
+        } else {
+            u32::from_le_bytes(b)
+        }
+    }
+
+    fn u64(&self, bytes: &[u8], at: usize) -> u64 {
+        let b = bytes[at..at + 8].try_into().unwrap();
+        if self.big_endian {
+            u64::from_be_bytes(b)
+        } else {
+            u64::from_le_bytes(b)
+        }
+    }
+
+    // A string of the strings table, in modified UTF-8
+    fn string(&self, offset: usize) -> Result<&[u8], String> {
+        let rest = self
+            .strings
+            .get(offset..)
+            .ok_or_else(|| format!("Bad string offset {}", offset))?;
+        let end = rest.iter().position(|&b| b == 0).unwrap_or(rest.len());
+        Ok(&rest[..end])
+    }
+
+    fn find_location(&self, name: &str) -> Result<Option<[u64; ATTRIBUTE_COUNT]>, String> {
+        let length = self.redirect.len() as i32;
+        if length == 0 {
+            return Ok(None);
+        }
+        let index = hash(name.as_bytes(), HASH_MULTIPLIER) % length;
+        let index = match self.redirect[index as usize] {
+            0 => return Ok(None),
+            value if value < 0 => -1 - value,
+            seed => hash(name.as_bytes(), seed) % length,
+        };
+        let Some(&offset) = self.offsets.get(index as usize) else {
+            return Err(format!("Bad redirect for {}", name));
+        };
+        let location = self.attributes(offset as usize)?;
+        // The hash only says where the name would be
+        if self.name(&location)? == name.as_bytes() {
+            Ok(Some(location))
+        } else {
+            Ok(None)
+        }
+    }
+
+    // Attributes are a byte of kind and length, followed by a big endian
+    // value of that length, up to the end attribute
+    fn attributes(&self, offset: usize) -> Result<[u64; ATTRIBUTE_COUNT], String> {
+        let mut attributes = [0; ATTRIBUTE_COUNT];
+        let mut pos = offset;
+        loop {
+            let Some(&byte) = self.locations.get(pos) else {
+                return Err(format!("Bad location offset {}", offset));
+            };
+            let kind = (byte >> 3) as usize;
+            if kind == ATTRIBUTE_END {
+                return Ok(attributes);
+            }
+            if kind >= ATTRIBUTE_COUNT {
+                return Err(format!("Bad location attribute {}", kind));
+            }
+            let length = (byte & 7) as usize + 1;
+            let Some(value) = self.locations.get(pos + 1..pos + 1 + length) else {
+                return Err(format!("Bad location offset {}", offset));
+            };
+            attributes[kind] = value.iter().fold(0, |v, b| v << 8 | *b as u64);
+            pos += 1 + length;
+        }
+    }
+
+    fn name(&self, location: &[u64; ATTRIBUTE_COUNT]) -> Result<Vec<u8>, String> {
+        let mut name = vec![];
+        let module = location[ATTRIBUTE_MODULE] as usize;
+        if module != 0 {
+            name.push(b'/');
+            name.extend(self.string(module)?);
+            name.push(b'/');
+        }
+        let parent = location[ATTRIBUTE_PARENT] as usize;
+        if parent != 0 {
+            name.extend(self.string(parent)?);
+            name.push(b'/');
+        }
+        name.extend(self.string(location[ATTRIBUTE_BASE] as usize)?);
+        let extension = location[ATTRIBUTE_EXTENSION] as usize;
+        if extension != 0 {
+            name.push(b'.');
+            name.extend(self.string(extension)?);
+        }
+        Ok(name)
+    }
+
+    fn read(&self, location: &[u64; ATTRIBUTE_COUNT]) -> Result<Vec<u8>, String> {
+        let compressed = location[ATTRIBUTE_COMPRESSED];
+        let size = if compressed != 0 {
+            compressed
+        } else {
+            location[ATTRIBUTE_UNCOMPRESSED]
+        };
+        let mut data = vec![0; size as usize];
+        {
+            let mut file = self.file.lock().unwrap();
+            file.seek(SeekFrom::Start(
+                self.index_size + location[ATTRIBUTE_OFFSET],
+            ))
+            .map_err(read_error)?;
+            file.read_exact(&mut data).map_err(read_error)?;
+        }
+        if compressed != 0 {
+            data = self.decompress(data)?;
+        }
+        Ok(data)
+    }
+
+    // Compressions may be stacked, each with its header naming the
+    // decompressor
+    fn decompress(&self, mut data: Vec<u8>) -> Result<Vec<u8>, String> {
+        while data.len() >= RESOURCE_HEADER_SIZE && self.u32(&data, 0) == RESOURCE_MAGIC {
+            let compressed_size = self.u64(&data, 4) as usize;
+            let uncompressed_size = self.u64(&data, 12) as usize;
+            let decompressor = self.string(self.u32(&data, 20) as usize)?;
+            let content = data
+                .get(RESOURCE_HEADER_SIZE..RESOURCE_HEADER_SIZE + compressed_size)
+                .ok_or("Truncated compressed resource")?;
+            data = match decompressor {
+                b"zip" => {
+                    let mut out = Vec::with_capacity(uncompressed_size);
+                    ZlibDecoder::new(content)
+                        .read_to_end(&mut out)
+                        .map_err(|e| format!("Error decompressing resource: {}", e))?;
+                    out
+                }
+                b"compact-cp" => self.expand_strings(content)?,
+                _ => {
+                    return Err(format!(
+                        "Unknown decompressor: {}",
+                        String::from_utf8_lossy(decompressor)
+                    ))
+                }
+            };
+            if data.len() != uncompressed_size {
+                return Err("Bad size of decompressed resource".to_string());
+            }
+        }
+        Ok(data)
+    }
+
+    // Undo string sharing: put back in the constant pool of a class the
+    // Utf8 constants moved to the image strings table
+    fn expand_strings(&self, data: &[u8]) -> Result<Vec<u8>, String> {
+        let mut reader = Reader { data, pos: 0 };
+        let mut out = Vec::with_capacity(data.len() * 2);
+        // Magic and version
+        out.extend(reader.bytes(8)?);
+        let count = reader.u2()?;
+        out.extend(count.to_be_bytes());
+        let mut i = 1;
+        while i < count {
+            let tag = reader.u1()?;
+            let utf8 = match tag {
+                1 => {
+                    let length = reader.u2()?;
+                    reader.bytes(length as usize)?.to_vec()
+                }
+                EXTERNALIZED_STRING => self.string(reader.index()?)?.to_vec(),
+                EXTERNALIZED_STRING_DESCRIPTOR => self.descriptor(&mut reader)?,
+                _ => {
+                    let size = match tag {
+                        5 | 6 => 8,
+                        3 | 4 | 9 | 10 | 11 | 12 | 17 | 18 => 4,
+                        15 => 3,
+                        7 | 8 | 16 | 19 | 20 => 2,
+                        _ => return Err(format!("Bad constant pool tag {}", tag)),
+                    };
+                    out.push(tag);
+                    out.extend(reader.bytes(size)?);
+                    i += if size == 8 { 2 } else { 1 };
+                    continue;
+                }
+            };
+            out.push(1);
+            out.extend((utf8.len() as u16).to_be_bytes());
+            out.extend(utf8);
+            i += 1;
+        }
+        out.extend(&data[reader.pos..]);
+        Ok(out)
+    }
+
+    // A descriptor stored with its class names taken out, each L followed
+    // in a separate list by the strings of a package and a simple name
+    fn descriptor(&self, reader: &mut Reader) -> Result<Vec<u8>, String> {
+        let skeleton = self.string(reader.index()?)?;
+        let length = reader.index()?;
+        let mut names = Reader {
+            data: reader.bytes(length)?,
+            pos: 0,
+        };
+        let mut out = vec![];
+        for &c in skeleton {
+            out.push(c);
+            if c == b'L' {
+                let package = self.string(names.index()?)?;
+                let class = self.string(names.index()?)?;
+                if !package.is_empty() {
+                    out.extend(package);
+                    out.push(b'/');
+                }
+                out.extend(class);
+            }
+        }
+        Ok(out)
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], String> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + n)
+            .ok_or("Truncated compressed resource")?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u1(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u2(&mut self) -> Result<u16, String> {
+        Ok(u16::from_be_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    // A compressed index: with the high bit set, bits 5 and 6 give the
+    // length in bytes and the low 5 bits start the value, otherwise it is a
+    // plain 4 byte value
+    fn index(&mut self) -> Result<usize, String> {
+        let header = self.u1()?;
+        let (length, mut value) = if header & 0x80 != 0 {
+            ((header >> 5) & 3, (header & 0x1F) as usize)
+        } else {
+            (4, header as usize)
+        };
+        for _ in 1..length {
+            value = value << 8 | self.u1()? as usize;
+        }
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{write::ZlibEncoder, Compression};
+
+    use super::*;
+
+    fn attribute(out: &mut Vec<u8>, kind: usize, value: u64) {
+        if value == 0 {
+            return;
+        }
+        let bytes = value.to_be_bytes();
+        let skip = bytes.iter().take_while(|&&b| b == 0).count();
+        out.push((kind << 3) as u8 | (7 - skip) as u8);
+        out.extend(&bytes[skip..]);
+    }
+
+    // A little endian image of (module, package, class, content, zip)
+    // resources, with the /packages entries for them
+    fn image(path: &std::path::Path, classes: &[(&str, &str, &str, &[u8], bool)]) {
+        let mut strings = vec![0];
+        let mut string = |s: &str| {
+            strings.extend(s.as_bytes());
+            strings.push(0);
+            (strings.len() - s.len() - 1) as u64
+        };
+        let zip = string("zip");
+        let mut names = vec![];
+        let (mut locations, mut offsets, mut resources): (Vec<u8>, Vec<u32>, Vec<u8>) =
+            (vec![0], vec![], vec![]);
+        let mut add = |names: &mut Vec<String>, name, parts: [u64; 4], data: &[u8], size| {
+            names.push(name);
+            offsets.push(locations.len() as u32);
+            for (kind, value) in parts.into_iter().enumerate() {
+                attribute(&mut locations, kind + 1, value);
+            }
+            attribute(&mut locations, ATTRIBUTE_OFFSET, resources.len() as u64);
+            if size != data.len() {
+                attribute(&mut locations, ATTRIBUTE_COMPRESSED, data.len() as u64);
+            }
+            attribute(&mut locations, ATTRIBUTE_UNCOMPRESSED, size as u64);
+            locations.push(0);
+            resources.extend(data);
+        };
+        for (module, package, class, content, compress) in classes {
+            let name = format!("/{}/{}/{}.class", module, package, class);
+            let parts = [
+                string(module),
+                string(package),
+                string(class),
+                string("class"),
+            ];
+            let mut data = content.to_vec();
+            if *compress {
+                let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+                encoder.write_all(content).unwrap();
+                let compressed = encoder.finish().unwrap();
+                data = RESOURCE_MAGIC.to_le_bytes().to_vec();
+                data.extend((compressed.len() as u64).to_le_bytes());
+                data.extend((content.len() as u64).to_le_bytes());
+                data.extend((zip as u32).to_le_bytes());
+                data.extend([0, 0, 0, 0, 1]);
+                data.extend(compressed);
+            }
+            add(&mut names, name, parts, &data, content.len());
+            let package = package.replace('/', ".");
+            let data = [0u32.to_le_bytes(), (parts[0] as u32).to_le_bytes()].concat();
+            let parts = [string("packages"), 0, string(&package), 0];
+            add(
+                &mut names,
+                format!("/packages/{}", package),
+                parts,
+                &data,
+                8,
+            );
+        }
+
+        // Perfect hash: names alone in their bucket are placed directly, the
+        // others by a seed found for their bucket
+        let n = names.len();
+        let mut buckets = vec![vec![]; n];
+        for (i, name) in names.iter().enumerate() {
+            buckets[(hash(name.as_bytes(), HASH_MULTIPLIER) as usize) % n].push(i);
+        }
+        let (mut redirect, mut table) = (vec![0i32; n], vec![None; n]);
+        let mut order: Vec<_> = (0..n).collect();
+        order.sort_by_key(|&b| std::cmp::Reverse(buckets[b].len()));
+        for b in order {
+            match buckets[b].len() {
+                0 => {}
+                1 => {
+                    let slot = table.iter().position(|s| s.is_none()).unwrap();
+                    table[slot] = Some(buckets[b][0]);
+                    redirect[b] = -1 - slot as i32;
+                }
+                _ => {
+                    let slots = (1..)
+                        .map(|seed| {
+                            let slots: Vec<_> = buckets[b]
+                                .iter()
+                                .map(|&i| hash(names[i].as_bytes(), seed) as usize % n)
+                                .collect();
+                            (seed, slots)
+                        })
+                        .find(|(_, slots)| {
+                            slots.iter().all(|&s| table[s].is_none())
+                                && (1..slots.len()).all(|i| !slots[..i].contains(&slots[i]))
+                        })
+                        .unwrap();
+                    for (&i, &slot) in buckets[b].iter().zip(&slots.1) {
+                        table[slot] = Some(i);
+                    }
+                    redirect[b] = slots.0;
+                }
+            }
+        }
+
+        let mut out = vec![];
+        let fields = [IMAGE_MAGIC, MAJOR_VERSION << 16, 0, n as u32, n as u32];
+        for field in fields
+            .into_iter()
+            .chain([locations.len() as u32, strings.len() as u32])
+        {
+            out.extend(field.to_le_bytes());
+        }
+        out.extend(redirect.iter().flat_map(|r| r.to_le_bytes()));
+        out.extend(table.iter().flat_map(|i| offsets[i.unwrap()].to_le_bytes()));
+        out.extend(locations);
+        out.extend(strings);
+        out.extend(resources);
+        fs::write(path, out).unwrap();
+    }
+
+    #[test]
+    fn test_read_class() {
+        let root = std::env::temp_dir().join("rustjvm-jimage-test");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        let path = root.join("modules");
+        let content = b"class content, repeated, repeated, repeated";
+        image(
+            &path,
+            &[
+                ("m.one", "p", "A", b"a", false),
+                ("m.one", "p/q", "B", content, true),
+                ("m.two", "r", "C", b"c", false),
+            ],
+        );
+        let entry = JImageEntry::new(path.to_str().unwrap().to_string());
+        assert_eq!(entry.read_class("p/A.class").unwrap(), b"a");
+        assert_eq!(entry.read_class("p/q/B.class").unwrap(), content);
+        assert_eq!(entry.read_class("r/C.class").unwrap(), b"c");
+        assert!(entry.read_class("r/A.class").is_err());
+        assert!(entry.read_class("s/A.class").is_err());
+        assert_eq!(entry.module_of("p/q").unwrap().unwrap(), "m.one");
+        assert_eq!(
+            entry.read_module_resource("/m.two/r/C.class").unwrap(),
+            entry.read_class("r/C.class").unwrap()
+        );
+    }
+}
@@ -0,0 +1,18 @@
+pub mod attribute;
+pub mod bytecode;
+pub mod callgraph;
+pub mod checker;
+pub mod classfile;
+pub mod classpath;
+pub mod deps;
+pub mod descriptor;
+pub mod inference;
+pub mod jar;
+pub mod jimage;
+pub mod loader;
+pub mod module;
+pub mod relocate;
+pub mod strip;
+pub mod verifier;
+pub mod visitor;
+pub mod writer;
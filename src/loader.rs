@@ -1,248 +1,513 @@
 use std::{cell::RefCell, fs::File, io::Read, rc::Rc};
 
 use crate::{
-    attribute::{Attribute, ExceptionTable, LineNumberTableEntry, LocalVariableTableEntry},
+    attribute::{
+        Attribute, ExceptionTable, LineNumberTableEntry, LocalVariableTableEntry, StackMapFrame,
+        VerificationTypeInfo,
+    },
     classfile::{Const, ConstPool},
+    writer::encode_utf8,
 };
 
-pub struct Loader {
-    file: File,
+// Bounds applied while parsing, so that a crafted class file cannot make
+// the loader allocate or recurse without limit
+#[derive(Debug, Clone)]
+pub struct Limits {
+    // Total bytes the class file may take
+    pub max_size: usize,
+    // Largest constant_pool_count accepted
+    pub max_constants: usize,
+    // How deep attributes may be nested inside one another
+    pub max_attribute_depth: usize,
+    pub max_code_length: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_size: 64 * 1024 * 1024,
+            max_constants: 65535,
+            max_attribute_depth: 4,
+            max_code_length: 65535,
+        }
+    }
+}
+
+pub struct Loader<R: Read = File> {
+    reader: R,
+    limits: Limits,
+    // Bytes consumed so far
+    read: usize,
+    // Nesting level of the attributes being read
+    depth: usize,
 }
 
 impl Loader {
-    fn new(path: String) -> Self {
+    fn new(path: String) -> Result<Self, String> {
+        let reader = File::open(&path).map_err(|e| format!("{}: {}", path, e))?;
+        Ok(Loader::with_limits(reader, Limits::default()))
+    }
+}
+
+impl<'a> Loader<&'a [u8]> {
+    fn from_bytes(data: &'a [u8]) -> Self {
+        Loader::with_limits(data, Limits::default())
+    }
+
+    // Parse the body of attribute `name`
+    fn attr(
+        &mut self,
+        name: &str,
+        const_pool: Rc<RefCell<ConstPool>>,
+    ) -> Result<Attribute, String> {
+        let attr = match name {
+            "Code" => {
+                let max_stack = self.u2()?;
+                let max_locals = self.u2()?;
+                let code_length = self.u4()? as usize;
+                if code_length > self.limits.max_code_length {
+                    return Err(format!(
+                        "Code length {} exceeds limit {}",
+                        code_length, self.limits.max_code_length
+                    ));
+                }
+                let code = self.bytes(code_length)?;
+                let exception_table_length = self.u2()?;
+                let mut exception_table = Vec::new();
+                for _ in 0..exception_table_length {
+                    let start_pc = self.u2()?;
+                    let end_pc = self.u2()?;
+                    let handler_pc = self.u2()?;
+                    let catch_type = self.u2()?;
+                    exception_table.push(ExceptionTable::new(
+                        start_pc, end_pc, handler_pc, catch_type,
+                    ));
+                }
+                let attributes = self.attrs(const_pool.clone())?;
+                Attribute::Code {
+                    cp: const_pool.clone(),
+                    max_stack,
+                    max_locals,
+                    code,
+                    exception_table,
+                    attributes,
+                }
+            }
+            "ConstantValue" => Attribute::ConstantValue(self.u2()?),
+            "Deprecated" => Attribute::Deprecated,
+            "Exceptions" => {
+                let number_of_exceptions = self.u2()?;
+                let mut exception_index_table = vec![];
+                for _ in 0..number_of_exceptions {
+                    exception_index_table.push(self.u2()?);
+                }
+                Attribute::Exceptions {
+                    exception_index_table,
+                }
+            }
+            "LineNumberTable" => {
+                let line_number_table_length = self.u2()?;
+                let mut line_number_table = vec![];
+                for _ in 0..line_number_table_length {
+                    let start_pc = self.u2()?;
+                    let line_number = self.u2()?;
+                    line_number_table.push(LineNumberTableEntry::new(start_pc, line_number));
+                }
+                Attribute::LineNumberTable { line_number_table }
+            }
+            "LocalVariableTable" => {
+                let local_variable_table_length = self.u2()?;
+                let mut local_variable_table = vec![];
+                for _ in 0..local_variable_table_length {
+                    let start_pc = self.u2()?;
+                    let length = self.u2()?;
+                    let name_index = self.u2()?;
+                    let descriptor_index = self.u2()?;
+                    let index = self.u2()?;
+                    local_variable_table.push(LocalVariableTableEntry::new(
+                        start_pc,
+                        length,
+                        name_index,
+                        descriptor_index,
+                        index,
+                    ));
+                }
+                Attribute::LocalVariableTable {
+                    local_variable_table,
+                }
+            }
+            "SourceFile" => {
+                let index = self.u2()?;
+                Attribute::SourceFile {
+                    cp: const_pool.clone(),
+                    index,
+                }
+            }
+            "Synthetic" => Attribute::Synthetic,
+            "StackMapTable" => {
+                let number_of_entries = self.u2()?;
+                let mut entries = vec![];
+                for _ in 0..number_of_entries {
+                    entries.push(self.stack_map_frame()?);
+                }
+                Attribute::StackMapTable { entries }
+            }
+            _ => {
+                // Attributes we do not decode yet are kept as raw bytes, so
+                // their placement can be checked and the class written back
+                let info = std::mem::take(&mut self.reader).to_vec();
+                match name {
+                    "InnerClasses" => Attribute::InnerClasses(info),
+                    "EnclosingMethod" => Attribute::EnclosingMethod(info),
+                    "Signature" => Attribute::Signature(info),
+                    "SourceDebugExtension" => Attribute::SourceDebugExtension(info),
+                    "LocalVariableTypeTable" => Attribute::LocalVariableTypeTable(info),
+                    "RuntimeVisibleAnnotations" => Attribute::RuntimeVisibleAnnotations(info),
+                    "RuntimeInvisibleAnnotations" => Attribute::RuntimeInvisibleAnnotations(info),
+                    "RuntimeVisibleParameterAnnotations" => {
+                        Attribute::RuntimeVisibleParameterAnnotations(info)
+                    }
+                    "RuntimeInvisibleParameterAnnotations" => {
+                        Attribute::RuntimeInvisibleParameterAnnotations(info)
+                    }
+                    "AnnotationDefault" => Attribute::AnnotationDefault(info),
+                    "BootstrapMethods" => Attribute::BootstrapMethods(info),
+                    "RuntimeVisibleTypeAnnotations" => {
+                        Attribute::RuntimeVisibleTypeAnnotations(info)
+                    }
+                    "RuntimeInvisibleTypeAnnotations" => {
+                        Attribute::RuntimeInvisibleTypeAnnotations(info)
+                    }
+                    "MethodParameters" => Attribute::MethodParameters(info),
+                    _ => Attribute::Unknown {
+                        name: name.to_string(),
+                        info,
+                    },
+                }
+            }
+        };
+        Ok(attr)
+    }
+}
+
+impl<R: Read> Loader<R> {
+    fn with_limits(reader: R, limits: Limits) -> Self {
         Loader {
-            file: File::open(path).unwrap(),
+            reader,
+            limits,
+            read: 0,
+            depth: 0,
         }
     }
 
-    fn bytes(&mut self, n: usize) -> Vec<u8> {
-        let mut bytes = vec![0u8; n];
-        match self.file.read_exact(&mut bytes) {
-            Ok(_) => bytes,
-            Err(_) => panic!("Failed to read bytes"),
+    fn bytes(&mut self, n: usize) -> Result<Vec<u8>, String> {
+        if n > self.limits.max_size - self.read {
+            return Err(format!(
+                "Class file larger than {} bytes",
+                self.limits.max_size
+            ));
         }
+        // Grow the buffer as data arrives instead of trusting `n` up front
+        let mut bytes = Vec::new();
+        self.reader
+            .by_ref()
+            .take(n as u64)
+            .read_to_end(&mut bytes)
+            .map_err(|e| e.to_string())?;
+        if bytes.len() < n {
+            return Err("Truncated class file".to_string());
+        }
+        self.read += n;
+        Ok(bytes)
     }
 
-    fn u1(&mut self) -> u8 {
-        self.bytes(1)[0]
+    fn u1(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
     }
 
-    fn u2(&mut self) -> u16 {
-        u16::from_be_bytes(self.bytes(2).try_into().unwrap())
+    fn u2(&mut self) -> Result<u16, String> {
+        Ok(u16::from_be_bytes(self.bytes(2)?.try_into().unwrap()))
     }
 
-    fn u4(&mut self) -> u32 {
-        u32::from_be_bytes(self.bytes(4).try_into().unwrap())
+    fn u4(&mut self) -> Result<u32, String> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
     }
 
-    fn u8(&mut self) -> u64 {
-        u64::from_be_bytes(self.bytes(8).try_into().unwrap())
+    fn u8(&mut self) -> Result<u64, String> {
+        Ok(u64::from_be_bytes(self.bytes(8)?.try_into().unwrap()))
     }
 
-    fn cpinfo<'a>(&mut self, const_pool: Rc<RefCell<ConstPool>>) {
-        let const_pool_count = self.u2();
+    fn cpinfo(&mut self, const_pool: Rc<RefCell<ConstPool>>) -> Result<(), String> {
+        let const_pool_count = self.u2()?;
+        let pool = Rc::downgrade(&const_pool);
+        if const_pool_count as usize > self.limits.max_constants {
+            return Err(format!(
+                "Constant pool count {} exceeds limit {}",
+                const_pool_count, self.limits.max_constants
+            ));
+        }
         // Valid constant pool indices start from 1
-        for _ in 1..const_pool_count {
-            let tag = self.u1();
+        while const_pool.borrow().count() < const_pool_count {
+            let tag = self.u1()?;
             let c = match tag {
                 0x01 => {
                     // UTF8 string literal, 2 bytes length + data
-                    let size = self.u2() as usize;
-                    Const::Utf8(String::from_utf8(self.bytes(size)).unwrap())
+                    let size = self.u2()? as usize;
+                    let bytes = self.bytes(size)?;
+                    let s = decode_utf8(&bytes)?;
+                    if encode_utf8(&s) == bytes {
+                        Const::Utf8(s)
+                    } else {
+                        Const::RawUtf8(bytes)
+                    }
                 }
-                0x03 => Const::Integer(self.u4() as i32),
-                0x04 => Const::Float(f32::from_bits(self.u4())),
-                0x05 => Const::Long(self.u8() as i64),
-                0x06 => Const::Double(f64::from_bits(self.u8())),
+                0x03 => Const::Integer(self.u4()? as i32),
+                0x04 => Const::Float(f32::from_bits(self.u4()?)),
+                0x05 => Const::Long(self.u8()? as i64),
+                0x06 => Const::Double(f64::from_bits(self.u8()?)),
                 0x07 => {
                     Const::Class {
-                        cp: const_pool.clone(),
-                        name_index: self.u2(), // Class index
+                        cp: pool.clone(),
+                        name_index: self.u2()?, // Class index
                     }
                 }
                 0x08 => {
                     Const::String {
-                        cp: const_pool.clone(),
-                        string_index: self.u2(), // String reference index
+                        cp: pool.clone(),
+                        string_index: self.u2()?, // String reference index
                     }
                 }
                 0x09 => Const::FieldRef {
-                    cp: const_pool.clone(),
-                    class_index: self.u2(),
-                    name_and_type_index: self.u2(),
+                    cp: pool.clone(),
+                    class_index: self.u2()?,
+                    name_and_type_index: self.u2()?,
                 },
                 0x0a => Const::MethodRef {
-                    cp: const_pool.clone(),
-                    class_index: self.u2(),
-                    name_and_type_index: self.u2(),
+                    cp: pool.clone(),
+                    class_index: self.u2()?,
+                    name_and_type_index: self.u2()?,
                 },
                 0x0b => Const::InterfaceMethodRef {
-                    cp: const_pool.clone(),
-                    class_index: self.u2(),
-                    name_and_type_index: self.u2(),
+                    cp: pool.clone(),
+                    class_index: self.u2()?,
+                    name_and_type_index: self.u2()?,
                 },
                 0x0c => Const::NameAndType {
-                    cp: const_pool.clone(),
-                    name_index: self.u2(),
-                    descriptor_index: self.u2(),
+                    cp: pool.clone(),
+                    name_index: self.u2()?,
+                    descriptor_index: self.u2()?,
                 },
                 0x0f => Const::MethodHandle {
-                    cp: const_pool.clone(),
-                    reference_kind: self.u1(),
-                    reference_index: self.u2(),
+                    cp: pool.clone(),
+                    reference_kind: self.u1()?,
+                    reference_index: self.u2()?,
                 },
                 0x10 => Const::MethodType {
-                    cp: const_pool.clone(),
-                    descriptor_index: self.u2(),
+                    cp: pool.clone(),
+                    descriptor_index: self.u2()?,
                 },
                 0x11 => Const::Dynamic {
-                    cp: const_pool.clone(),
-                    bootstrap_method_attr_index: self.u2(),
-                    name_and_type_index: self.u2(),
+                    cp: pool.clone(),
+                    bootstrap_method_attr_index: self.u2()?,
+                    name_and_type_index: self.u2()?,
                 },
                 0x12 => Const::InvokeDynamic {
-                    cp: const_pool.clone(),
-                    bootstrap_method_attr_index: self.u2(),
-                    name_and_type_index: self.u2(),
+                    cp: pool.clone(),
+                    bootstrap_method_attr_index: self.u2()?,
+                    name_and_type_index: self.u2()?,
                 },
                 0x13 => Const::Module {
-                    cp: const_pool.clone(),
-                    name_index: self.u2(),
+                    cp: pool.clone(),
+                    name_index: self.u2()?,
                 },
                 0x14 => Const::Package {
-                    cp: const_pool.clone(),
-                    name_index: self.u2(),
+                    cp: pool.clone(),
+                    name_index: self.u2()?,
                 },
-                _ => {
-                    println!("unsupported tag: {}", tag);
-                    continue;
-                }
+                _ => return Err(format!("Unknown constant tag {}", tag)),
             };
-            const_pool.borrow_mut().push(c)
+            // Long and double constants take up two entries in the pool
+            let wide = matches!(c, Const::Long(_) | Const::Double(_));
+            let index = const_pool.borrow().count();
+            if wide && index == const_pool_count - 1 {
+                return Err(format!(
+                    "Constant pool index {} has no room for a long or double",
+                    index
+                ));
+            }
+            const_pool.borrow_mut().push(c);
+            if wide {
+                const_pool.borrow_mut().push(Const::Unusable);
+            }
         }
+        Ok(())
     }
 
-    fn interfaces(&mut self, const_pool: Rc<RefCell<ConstPool>>) -> Vec<String> {
+    fn interfaces(&mut self) -> Result<Vec<u16>, String> {
         let mut interfaces = vec![];
-        let interface_count = self.u2();
+        let interface_count = self.u2()?;
         for _ in 0..interface_count {
-            let c = const_pool.borrow().resolve(self.u2());
-            interfaces.push(c);
+            interfaces.push(self.u2()?);
         }
-        interfaces
+        Ok(interfaces)
     }
 
-    fn fields(&mut self, const_pool: Rc<RefCell<ConstPool>>) -> Vec<Field> {
+    fn fields(&mut self, const_pool: Rc<RefCell<ConstPool>>) -> Result<Vec<Field>, String> {
         let mut fields = vec![];
-        let fields_count = self.u2();
+        let fields_count = self.u2()?;
         for _ in 0..fields_count {
-            let name = const_pool.borrow().resolve(self.u2());
-            let descriptor = const_pool.borrow().resolve(self.u2());
+            let flags = self.u2()?;
+            let name_index = self.u2()?;
+            let descriptor_index = self.u2()?;
             fields.push(Field {
-                flags: self.u2(),
-                name,
-                descriptor,
-                attributes: self.attrs(const_pool.clone()),
+                cp: const_pool.clone(),
+                flags,
+                name_index,
+                descriptor_index,
+                attributes: self.attrs(const_pool.clone())?,
             })
         }
-        return fields;
+        Ok(fields)
     }
 
-    fn attrs(&mut self, const_pool: Rc<RefCell<ConstPool>>) -> Vec<Attribute> {
+    fn attrs(&mut self, const_pool: Rc<RefCell<ConstPool>>) -> Result<Vec<Attribute>, String> {
+        if self.depth >= self.limits.max_attribute_depth {
+            return Err(format!(
+                "Attributes nested deeper than {}",
+                self.limits.max_attribute_depth
+            ));
+        }
         let mut attrs = vec![];
-        let attributes_count = self.u2();
+        let attributes_count = self.u2()?;
         for _ in 0..attributes_count {
-            let name = const_pool.borrow().resolve(self.u2());
-            let size = self.u4() as usize;
-            // attrs.push(Attribute {
-            //     name,
-            //     data: self.bytes(size),
-            // })
-            let attr = match name.as_str() {
-                "Code" => {
-                    let max_stack = self.u2();
-                    let max_locals = self.u2();
-                    let code_length = self.u4() as usize;
-                    let code = self.bytes(code_length);
-                    let exception_table_length = self.u2();
-                    let mut exception_table = Vec::new();
-                    for _ in 0..exception_table_length {
-                        let start_pc = self.u2();
-                        let end_pc = self.u2();
-                        let handler_pc = self.u2();
-                        let catch_type = self.u2();
-                        exception_table.push(ExceptionTable::new(
-                            start_pc, end_pc, handler_pc, catch_type,
-                        ));
-                    }
-                    let attributes = self.attrs(const_pool.clone());
-                    Attribute::Code {
-                        cp: const_pool.clone(),
-                        max_stack,
-                        max_locals,
-                        code,
-                        exception_table,
-                        attributes,
-                    }
-                }
-                "ConstantValue" => Attribute::ConstantValue(self.u2()),
-                "Deprecated" => Attribute::Deprecated,
-                "Exceptions" => {
-                    let number_of_exceptions = self.u2();
-                    let mut exception_index_table = vec![];
-                    for _ in 0..number_of_exceptions {
-                        exception_index_table.push(self.u2());
-                    }
-                    Attribute::Exceptions {
-                        exception_index_table,
-                    }
-                }
-                "LineNumberTable" => {
-                    let line_number_table_length = self.u2();
-                    let mut line_number_table = vec![];
-                    for _ in 0..line_number_table_length {
-                        let start_pc = self.u2();
-                        let line_number = self.u2();
-                        line_number_table.push(LineNumberTableEntry::new(start_pc, line_number));
-                    }
-                    Attribute::LineNumberTable { line_number_table }
+            let name = const_pool.borrow().resolve(self.u2()?);
+            let size = self.u4()? as usize;
+            // The body is read whole and parsed on its own, so that its
+            // declared length is checked and cannot be overrun
+            let body = self.bytes(size)?;
+            let mut loader = Loader {
+                reader: body.as_slice(),
+                limits: self.limits.clone(),
+                read: 0,
+                depth: self.depth + 1,
+            };
+            let attr = loader.attr(&name, const_pool.clone())?;
+            if !loader.reader.is_empty() {
+                return Err(format!("{} attribute has wrong length {}", name, size));
+            }
+            attrs.push(attr);
+        }
+        Ok(attrs)
+    }
+
+    fn stack_map_frame(&mut self) -> Result<StackMapFrame, String> {
+        let frame_type = self.u1()?;
+        let mut frame = StackMapFrame {
+            frame_type,
+            offset_delta: 0,
+            locals: vec![],
+            stack: vec![],
+        };
+        match frame_type {
+            0..=63 => frame.offset_delta = frame_type as u16,
+            64..=127 => {
+                frame.offset_delta = frame_type as u16 - 64;
+                frame.stack.push(self.verification_type()?);
+            }
+            247 => {
+                frame.offset_delta = self.u2()?;
+                frame.stack.push(self.verification_type()?);
+            }
+            248..=251 => frame.offset_delta = self.u2()?,
+            252..=254 => {
+                frame.offset_delta = self.u2()?;
+                for _ in 251..frame_type {
+                    frame.locals.push(self.verification_type()?);
                 }
-                "LocalVariableTable" => {
-                    let local_variable_table_length = self.u2();
-                    let mut local_variable_table = vec![];
-                    for _ in 0..local_variable_table_length {
-                        let start_pc = self.u2();
-                        let line_number = self.u2();
-                        local_variable_table
-                            .push(LocalVariableTableEntry::new(start_pc, line_number));
-                    }
-                    Attribute::LocalVariableTable {
-                        local_variable_table,
-                    }
+            }
+            255 => {
+                frame.offset_delta = self.u2()?;
+                let number_of_locals = self.u2()?;
+                for _ in 0..number_of_locals {
+                    frame.locals.push(self.verification_type()?);
                 }
-                "SourceFile" => {
-                    let index = self.u2();
-                    Attribute::SourceFile {
-                        cp: const_pool.clone(),
-                        index,
-                    }
+                let number_of_stack_items = self.u2()?;
+                for _ in 0..number_of_stack_items {
+                    frame.stack.push(self.verification_type()?);
                 }
-                "Synthetic" => Attribute::Synthetic,
-                _ => continue,
-            };
-            attrs.push(attr);
+            }
+            _ => return Err(format!("Invalid stack map frame type {}", frame_type)),
+        }
+        Ok(frame)
+    }
+
+    fn verification_type(&mut self) -> Result<VerificationTypeInfo, String> {
+        Ok(match self.u1()? {
+            0 => VerificationTypeInfo::Top,
+            1 => VerificationTypeInfo::Integer,
+            2 => VerificationTypeInfo::Float,
+            3 => VerificationTypeInfo::Double,
+            4 => VerificationTypeInfo::Long,
+            5 => VerificationTypeInfo::Null,
+            6 => VerificationTypeInfo::UninitializedThis,
+            7 => VerificationTypeInfo::Object(self.u2()?),
+            8 => VerificationTypeInfo::Uninitialized(self.u2()?),
+            tag => return Err(format!("Invalid verification type tag {}", tag)),
+        })
+    }
+}
+
+// Decode the modified UTF-8 of CONSTANT_Utf8_info (JVMS 4.4.7): NUL is
+// written as C0 80 and supplementary characters as surrogate pairs. An
+// unpaired surrogate becomes U+FFFD, so the loader keeps such entries as bytes.
+pub(crate) fn decode_utf8(bytes: &[u8]) -> Result<String, String> {
+    let error = || "Illegal UTF8 string in constant pool".to_string();
+    let mut units = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        let b = bytes[i] as u16;
+        let continuation = |j: usize| match bytes.get(j) {
+            Some(c) if c & 0xc0 == 0x80 => Ok((c & 0x3f) as u16),
+            _ => Err(error()),
+        };
+        match b {
+            0x01..=0x7f => {
+                units.push(b);
+                i += 1;
+            }
+            0xc0..=0xdf => {
+                units.push((b & 0x1f) << 6 | continuation(i + 1)?);
+                i += 2;
+            }
+            0xe0..=0xef => {
+                units.push((b & 0x0f) << 12 | continuation(i + 1)? << 6 | continuation(i + 2)?);
+                i += 3;
+            }
+            _ => return Err(error()),
         }
-        return attrs;
     }
+    Ok(char::decode_utf16(units)
+        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+        .collect())
 }
 
 // Field type is used for both, fields and methods
-struct Field {
-    flags: u16,
-    name: String,
-    descriptor: String,
-    attributes: Vec<Attribute>,
+pub struct Field {
+    pub cp: Rc<RefCell<ConstPool>>,
+    pub flags: u16,
+    pub name_index: u16,
+    pub descriptor_index: u16,
+    pub attributes: Vec<Attribute>,
+}
+
+impl Field {
+    pub fn name(&self) -> String {
+        self.cp.borrow().resolve(self.name_index)
+    }
+
+    pub fn descriptor(&self) -> String {
+        self.cp.borrow().resolve(self.descriptor_index)
+    }
 }
 
 // Attributes contain addition information about fields and classes
@@ -250,97 +515,253 @@ struct Field {
 
 #[derive(Default)]
 pub struct Class {
-    major_version: u16,
-    minor_version: u16,
-    const_pool: Rc<RefCell<ConstPool>>,
-    flags: u16,
-    this_class: String,
-    super_class: String,
-    interfaces: Vec<String>,
-    fields: Vec<Field>,
-    methods: Vec<Field>,
-    attributes: Vec<Attribute>,
+    pub minor_version: u16,
+    pub major_version: u16,
+    pub const_pool: Rc<RefCell<ConstPool>>,
+    pub flags: u16,
+    pub this_class: u16,
+    pub super_class: u16,
+    pub interfaces: Vec<u16>,
+    pub fields: Vec<Field>,
+    pub methods: Vec<Field>,
+    pub attributes: Vec<Attribute>,
 }
 
 impl Class {
-    pub fn load(path: String) -> Class {
-        let mut loader = Loader::new(path);
+    pub fn load(path: String) -> Result<Class, String> {
+        Class::read(Loader::new(path)?)
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Class, String> {
+        Class::read(Loader::from_bytes(data))
+    }
+
+    pub fn parse_with_limits(data: &[u8], limits: &Limits) -> Result<Class, String> {
+        Class::read(Loader::with_limits(data, limits.clone()))
+    }
+
+    fn read<R: Read>(mut loader: Loader<R>) -> Result<Class, String> {
         let mut c = Class::default();
-        let magic = loader.u4();
-        assert_eq!(magic, 0xcafebabe, "Error: Invalid magic number");
-        c.major_version = loader.u2();
-        c.minor_version = loader.u2();
+        let magic = loader.u4()?;
+        if magic != 0xcafebabe {
+            return Err(format!("Incompatible magic value {:#x}", magic));
+        }
+        c.minor_version = loader.u2()?;
+        c.major_version = loader.u2()?;
 
         let cp = Rc::new(RefCell::new(ConstPool::default()));
-        loader.cpinfo(cp.clone()); // const pool info
-        c.flags = loader.u2(); // access flags
-        c.this_class = cp.borrow_mut().resolve(loader.u2()); // this class
-        c.super_class = cp.borrow_mut().resolve(loader.u2()); // super class
-        c.interfaces = loader.interfaces(cp.clone());
-        c.fields = loader.fields(cp.clone()); // fields
-        c.methods = loader.fields(cp.clone()); // methods
-        c.attributes = loader.attrs(cp.clone()); // methods
+        loader.cpinfo(cp.clone())?; // const pool info
+        c.flags = loader.u2()?; // access flags
+        c.this_class = loader.u2()?; // this class
+        c.super_class = loader.u2()?; // super class
+        c.interfaces = loader.interfaces()?;
+        c.fields = loader.fields(cp.clone())?; // fields
+        c.methods = loader.fields(cp.clone())?; // methods
+        c.attributes = loader.attrs(cp.clone())?; // methods
+        if loader.u1().is_ok() {
+            return Err("Extra bytes at the end of class file".to_string());
+        }
         c.const_pool = cp;
-        return c;
+        Ok(c)
+    }
+
+    pub fn name(&self) -> String {
+        self.const_pool.borrow().class_name(self.this_class)
+    }
+
+    pub fn super_name(&self) -> Option<String> {
+        match self.super_class {
+            0 => None,
+            index => Some(self.const_pool.borrow().class_name(index)),
+        }
+    }
+
+    pub fn interface_names(&self) -> Vec<String> {
+        let cp = self.const_pool.borrow();
+        self.interfaces.iter().map(|&i| cp.class_name(i)).collect()
     }
 }
 
+#[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
     fn test_loader_bytes() {
         let path = "test_file.bin"; // 确保这个文件存在于文件系统上并且至少有5个字节长
-        let mut loader = Loader::new(path.to_string());
-        let bytes = loader.bytes(5);
+        let mut loader = Loader::new(path.to_string()).unwrap();
+        let bytes = loader.bytes(5).unwrap();
         assert_eq!(bytes.len(), 5);
     }
 
     #[test]
     fn test_loader_u1() {
         let path = "test_file.bin"; // 确保这个文件存在于文件系统上并且至少有一个字节长
-        let mut loader = Loader::new(path.to_string());
-        let byte = loader.u1();
+        let mut loader = Loader::new(path.to_string()).unwrap();
+        let byte = loader.u1().unwrap();
         assert!(byte == 0x31);
     }
 
     #[test]
     fn test_loader_u2() {
         let path = "test_file.bin"; // 确保这个文件存在于文件系统上并且至少有两个字节长
-        let mut loader = Loader::new(path.to_string());
-        let word = loader.u2();
+        let mut loader = Loader::new(path.to_string()).unwrap();
+        let word = loader.u2().unwrap();
         assert!(word == 0x3132);
     }
 
     #[test]
     fn test_loader_u4() {
         let path = "test_file.bin"; // 确保这个文件存在于文件系统上并且至少有四个字节长
-        let mut loader = Loader::new(path.to_string());
-        let dword = loader.u4();
+        let mut loader = Loader::new(path.to_string()).unwrap();
+        let dword = loader.u4().unwrap();
         assert!(dword == 0x31323334);
     }
 
     #[test]
     fn test_loader_u8() {
         let path = "test_file.bin"; // 确保这个文件存在于文件系统上并且至少有八个字节长
-        let mut loader = Loader::new(path.to_string());
-        let qword = loader.u8();
+        let mut loader = Loader::new(path.to_string()).unwrap();
+        let qword = loader.u8().unwrap();
         assert!(qword == 0x3132333435363738);
     }
 
     #[test]
     fn test_loader_sequential_read() {
         let path = "test_file.bin"; // 确保这个文件存在于文件系统上并且至少有八个字节长
-        let mut loader = Loader::new(path.to_string());
-        let dword = loader.u4();
+        let mut loader = Loader::new(path.to_string()).unwrap();
+        let dword = loader.u4().unwrap();
         // print dword as hexadecimal
         println!("dword: {:x}", dword);
         assert!(dword == 0x31323334);
-        let dword = loader.u4();
+        let dword = loader.u4().unwrap();
         println!("dword: {:x}", dword);
         assert!(dword == 0x35363738);
-        let dword = loader.u1();
+        let dword = loader.u1().unwrap();
         println!("dword: {:x}", dword);
         assert!(dword == 0x39);
     }
+
+    // Foo with a method `static f()V` whose Code attribute has body `code`
+    fn class_bytes(code: &[u8]) -> Vec<u8> {
+        let mut data = vec![0xca, 0xfe, 0xba, 0xbe, 0, 0, 0, 52, 0, 8];
+        for s in ["Foo", "java/lang/Object", "f", "()V", "Code"] {
+            data.push(1);
+            data.extend((s.len() as u16).to_be_bytes());
+            data.extend(s.as_bytes());
+            if s.starts_with(['F', 'j']) {
+                // Class entry naming the Utf8 just added
+                data.extend([7, 0, if s == "Foo" { 1 } else { 3 }]);
+            }
+        }
+        data.extend([0, 0x21, 0, 2, 0, 4, 0, 0, 0, 0, 0, 1]);
+        data.extend([0, 0x09, 0, 5, 0, 6, 0, 1, 0, 7]);
+        data.extend((code.len() as u32).to_be_bytes());
+        data.extend(code);
+        data.extend([0, 0]);
+        data
+    }
+
+    // max_stack 0, max_locals 0, code `return`, no handlers or attributes
+    const CODE: [u8; 13] = [0, 0, 0, 0, 0, 0, 0, 1, 0xb1, 0, 0, 0, 0];
+
+    #[test]
+    fn test_parse_class() {
+        let class = Class::parse(&class_bytes(&CODE)).unwrap();
+        assert_eq!(class.name(), "Foo");
+        assert_eq!(class.methods[0].name(), "f");
+    }
+
+    #[test]
+    fn test_limits() {
+        let data = class_bytes(&CODE);
+        let limits = Limits {
+            max_code_length: 0,
+            ..Default::default()
+        };
+        assert_eq!(
+            Class::parse_with_limits(&data, &limits).err().unwrap(),
+            "Code length 1 exceeds limit 0"
+        );
+        let limits = Limits {
+            max_attribute_depth: 1,
+            ..Default::default()
+        };
+        assert_eq!(
+            Class::parse_with_limits(&data, &limits).err().unwrap(),
+            "Attributes nested deeper than 1"
+        );
+        let limits = Limits {
+            max_constants: 4,
+            ..Default::default()
+        };
+        assert_eq!(
+            Class::parse_with_limits(&data, &limits).err().unwrap(),
+            "Constant pool count 8 exceeds limit 4"
+        );
+        // A huge attribute length is refused before anything is allocated.
+        // The Code attribute's length comes before its body and the class's
+        // empty attribute count.
+        let mut data = data;
+        let at = data.len() - 2 - CODE.len() - 4;
+        assert_eq!(data[at..at + 4], (CODE.len() as u32).to_be_bytes());
+        data[at..at + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert_eq!(
+            Class::parse(&data).err().unwrap(),
+            format!(
+                "Class file larger than {} bytes",
+                Limits::default().max_size
+            )
+        );
+        let limits = Limits {
+            max_size: 1024,
+            ..Default::default()
+        };
+        assert_eq!(
+            Class::parse_with_limits(&data, &limits).err().unwrap(),
+            "Class file larger than 1024 bytes"
+        );
+        // A long in the last entry would run past the constant pool count
+        let mut data = vec![0xca, 0xfe, 0xba, 0xbe, 0, 0, 0, 52, 0, 2, 5];
+        data.extend(1_i64.to_be_bytes());
+        assert_eq!(
+            Class::parse(&data).err().unwrap(),
+            "Constant pool index 1 has no room for a long or double"
+        );
+    }
+
+    #[test]
+    fn test_attribute_length_mismatch() {
+        let mut code = CODE.to_vec();
+        code.push(0);
+        assert_eq!(
+            Class::parse(&class_bytes(&code)).err().unwrap(),
+            "Code attribute has wrong length 14"
+        );
+    }
+
+    #[test]
+    fn test_modified_utf8() {
+        assert_eq!(decode_utf8(b"a\xc0\x80b").unwrap(), "a\0b");
+        // U+1F600 as a surrogate pair
+        let emoji = [0xed, 0xa0, 0xbd, 0xed, 0xb8, 0x80];
+        assert_eq!(decode_utf8(&emoji).unwrap(), "\u{1f600}");
+        assert!(decode_utf8(&[0]).is_err());
+        assert!(decode_utf8(&[0xe0, 0x80]).is_err());
+    }
+
+    #[test]
+    fn test_malformed_input_does_not_panic() {
+        let data = class_bytes(&CODE);
+        for len in 0..data.len() {
+            assert!(Class::parse(&data[..len]).is_err());
+        }
+        for i in 0..data.len() {
+            for b in [0x00, 0x01, 0x7f, 0x80, 0xff] {
+                let mut mutated = data.clone();
+                mutated[i] = b;
+                let _ = Class::parse(&mutated);
+            }
+        }
+    }
 }
@@ -1,43 +1,583 @@
-pub mod attribute;
-pub mod classpath;
-pub mod classfile;
-pub mod loader;
-
 // use clap to handle command line arguments
-use clap::{arg, Parser};
-use classpath::ClassPath;
+use clap::{CommandFactory, Parser, Subcommand};
+use rust_jvm::{
+    callgraph, checker,
+    classpath::{self, ClassPath, ClassPathError},
+    deps, jar,
+    loader::Class,
+    module, relocate, strip, verifier,
+};
 
 #[derive(Parser, Debug)]
-#[command(version)]
+#[command(version, args_conflicts_with_subcommands = true)]
 struct Cmd {
-    #[arg(short, long)]
+    #[arg(short, long, conflicts_with = "jar")]
     classpath: Option<String>,
+    /// The JDK or JRE of the boot classes, found from JAVA_HOME or PATH if not given
     #[arg(long = "Xjre")]
-    xjre: String,
-    class: String,
+    xjre: Option<String>,
+    /// Entries searched after the boot classes, as -Xbootclasspath/a:<path>
+    #[arg(long = "Xbootclasspath-a")]
+    xbootclasspath_a: Vec<String>,
+    /// System properties, as -Dname=value; java.ext.dirs replaces lib/ext
+    #[arg(short = 'D', value_name = "NAME=VALUE")]
+    properties: Vec<String>,
+    /// What to trace, as -verbose:classpath for every class path entry searched
+    #[arg(long, value_delimiter = ',')]
+    verbose: Vec<String>,
+    /// Check the classes of signed jars against their signatures
+    #[arg(long)]
+    verify_signatures: bool,
+    /// Run the Main-Class of a jar, from the jar and its manifest Class-Path
+    #[arg(long)]
+    jar: Option<String>,
+    /// List the classes of the class path, with where each is loaded from
+    #[arg(long)]
+    list_classes: bool,
+    /// Directories of modules, and modules, to find the modules to run with in
+    #[arg(short = 'p', long)]
+    module_path: Option<String>,
+    /// Modules to resolve besides the main module, or ALL-MODULE-PATH
+    #[arg(long, value_delimiter = ',')]
+    add_modules: Vec<String>,
+    /// The main module, followed by /main.Class unless it names its own
+    #[arg(short = 'm', long, conflicts_with = "jar")]
+    module: Option<String>,
+    /// List the classes found in more than one class path entry
+    #[arg(long)]
+    duplicate_classes: bool,
+    /// Refuse to start if entries have different classes of the same name
+    #[arg(long)]
+    strict_classpath: bool,
+    #[arg(required_unless_present_any = ["jar", "list_classes", "duplicate_classes", "module"])]
+    class: Option<String>,
     args: Vec<String>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Remove debug information and other optional attributes from a jar
+    Strip {
+        input: String,
+        #[arg(short, long)]
+        output: String,
+        /// Attributes to strip besides the debug ones
+        #[arg(long, value_delimiter = ',')]
+        strip: Vec<String>,
+        /// Debug attributes to keep
+        #[arg(long, value_delimiter = ',')]
+        keep: Vec<String>,
+    },
+    /// Move packages of a jar under new names, rewriting references to them
+    Relocate {
+        input: String,
+        #[arg(short, long)]
+        output: String,
+        /// Package to move, as from=to, e.g. com.google=shaded.com.google
+        #[arg(short, long = "package", required = true)]
+        packages: Vec<String>,
+    },
+    /// Build the static call graph of the methods reachable from some classes
+    Callgraph {
+        #[arg(long = "Xjre")]
+        xjre: Option<String>,
+        #[arg(short, long)]
+        classpath: Option<String>,
+        /// Classes whose methods are the entry points
+        #[arg(required = true)]
+        roots: Vec<String>,
+        /// Packages whose methods are not followed, e.g. java.,javax.
+        #[arg(long, value_delimiter = ',')]
+        exclude: Vec<String>,
+        #[arg(long, default_value = "dot", value_parser = ["dot", "json"])]
+        format: String,
+        /// File to write the graph to instead of standard output
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+    /// Report what the classes of a jar or directory depend on
+    Deps {
+        input: String,
+        /// Entries to look for dependencies in, separated by : (; on Windows)
+        #[arg(short, long)]
+        classpath: Option<String>,
+        /// The JRE to look for dependencies in, found as when running if not given
+        #[arg(long = "Xjre")]
+        xjre: Option<String>,
+        /// Only list the entries depended on
+        #[arg(short, long)]
+        summary: bool,
+        /// List dependencies between classes instead of packages
+        #[arg(short, long)]
+        verbose: bool,
+        /// List packages found in more than one entry
+        #[arg(long)]
+        split_packages: bool,
+        /// List the classes not found anywhere
+        #[arg(long)]
+        missing: bool,
+    },
 }
 
 fn start_jvm(cmd: &Cmd) {
-    let classpath = match &cmd.classpath {
-        Some(cp) => cp.clone(),
-        None => ".".to_string(),
+    let jre = match ClassPath::find_jre(cmd.xjre.clone()) {
+        Ok(jre) => jre,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+    // With a jar, the arguments after it are all arguments of its main class
+    let (class_name, classpath) = match &cmd.jar {
+        Some(jar) => match jar::executable(jar) {
+            Ok(executable) => (
+                Some(executable.main_class),
+                executable
+                    .class_path
+                    .join(&classpath::PATH_SEPARATOR.to_string()),
+            ),
+            Err(e) => {
+                println!("{}", e);
+                return;
+            }
+        },
+        None => (
+            cmd.class.clone(),
+            classpath::user_class_path(cmd.classpath.clone()),
+        ),
+    };
+    // Modules on the module path go ahead of the class path
+    let (class_name, classpath) =
+        if cmd.module.is_some() || cmd.module_path.is_some() || !cmd.add_modules.is_empty() {
+            match boot_layer(cmd, &jre) {
+                Ok((main_class, modules)) => (
+                    main_class.or(class_name),
+                    modules
+                        .into_iter()
+                        .chain([classpath])
+                        .collect::<Vec<_>>()
+                        .join(&classpath::PATH_SEPARATOR.to_string()),
+                ),
+                Err(e) => {
+                    println!("Error occurred during initialization of boot layer");
+                    println!("{}", e);
+                    return;
+                }
+            }
+        } else {
+            (class_name, classpath)
+        };
+    let mut cp = match ClassPath::new(Some(jre), classpath) {
+        Ok(cp) => cp,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
     };
-    let cp = ClassPath::new(cmd.xjre.clone(), classpath);
+    cp.set_verbose(cmd.verbose.iter().any(|v| v == "classpath"));
+    cp.set_verify(cmd.verify_signatures);
+    for path in &cmd.xbootclasspath_a {
+        cp.append_boot(path.clone());
+    }
+    let ext_dirs = cmd
+        .properties
+        .iter()
+        .filter_map(|p| p.strip_prefix("java.ext.dirs="))
+        .next_back();
+    if let Some(Err(e)) = ext_dirs.map(|dirs| cp.set_ext_dirs(dirs)) {
+        println!("{}", e);
+        println!("Error: Could not create the Java Virtual Machine.");
+        return;
+    }
     println!("{:?}", cmd);
-    let class_name = cmd.class.replace(".", "/");
-    if let Ok(class_data) = cp.read_class(class_name.as_str()) {
-        println!("{:?}", class_data);
+    if cmd.list_classes {
+        list_classes(&cp);
+        return;
+    }
+    if cmd.duplicate_classes || cmd.strict_classpath {
+        let duplicates = match cp.duplicates() {
+            Ok(duplicates) => duplicates,
+            Err(e) => {
+                println!("{}", e);
+                return;
+            }
+        };
+        if cmd.duplicate_classes {
+            duplicates.iter().for_each(print_duplicate);
+            return;
+        }
+        let conflicts: Vec<_> = duplicates.iter().filter(|d| d.is_conflict()).collect();
+        if !conflicts.is_empty() {
+            conflicts.into_iter().for_each(print_duplicate);
+            println!("Error: conflicting classes on the class path");
+            return;
+        }
+    }
+    let class_name = class_name.unwrap().replace(".", "/");
+    match cp.read_class(class_name.as_str()) {
+        Ok(class_data) => {
+            let class = match Class::parse(&class_data) {
+                Ok(class) => class,
+                Err(e) => {
+                    println!("ClassFormatError: {} ({})", e, class_name);
+                    return;
+                }
+            };
+            let errors = checker::check(&class);
+            for error in &errors {
+                println!("ClassFormatError: {} ({})", error, class_name);
+            }
+            if errors.is_empty() {
+                if let Err(e) = verifier::verify(&class, &cp) {
+                    println!("{}", e);
+                }
+            }
+        }
+        Err(e) if e.is_not_found() => println!("class not found"),
+        Err(e @ ClassPathError::Security { .. }) => {
+            println!("Exception in thread \"main\" {}", e)
+        }
+        Err(e) => println!("class not found: {}", e),
+    }
+}
+
+// A class in more than one entry, with the copy loaded marked by *
+fn print_duplicate(duplicate: &classpath::Duplicate) {
+    let kind = match duplicate.is_conflict() {
+        true => "conflict",
+        false => "duplicate",
+    };
+    println!("{}: {}", kind, duplicate.class.replace('/', "."));
+    for (i, (location, hash)) in duplicate.copies.iter().enumerate() {
+        let mark = if i == 0 { '*' } else { ' ' };
+        println!("  {} {} {}", mark, &hash[..16], location.entry);
+    }
+}
+
+// Resolve the main module and --add-modules, for the main class of the main
+// module and the locations of the modules resolved from the module path
+fn boot_layer(cmd: &Cmd, jre: &str) -> Result<(Option<String>, Vec<String>), String> {
+    let system = module::system_modules(jre)?;
+    let module_path = module::find_modules(cmd.module_path.as_deref().unwrap_or(""))?;
+    let (main_module, main_class) = match cmd.module.as_deref().map(|m| m.split_once('/')) {
+        Some(Some((module, class))) => (Some(module), Some(class.to_string())),
+        Some(None) => (cmd.module.as_deref(), None),
+        None => (None, None),
+    };
+    let roots: Vec<String> = main_module
+        .iter()
+        .map(|m| m.to_string())
+        .chain(cmd.add_modules.iter().cloned())
+        .collect();
+    let configuration = module::resolve(&system, &module_path, &roots)?;
+    let main_class = match main_module {
+        Some(module) => Some(
+            main_class
+                .or_else(|| configuration.modules[module].descriptor.main_class.clone())
+                .ok_or(format!(
+                    "Module {} does not have a ModuleMainClass attribute, use -m <module>/<main-class>",
+                    module
+                ))?,
+        ),
+        None => None,
+    };
+    let locations = configuration
+        .modules
+        .values()
+        .filter(|r| module_path.contains(r))
+        .map(|r| r.location.clone())
+        .collect();
+    Ok((main_class, locations))
+}
+
+// The classes of the user class path, as the JRE would list thousands
+fn list_classes(cp: &ClassPath) {
+    match cp.classes() {
+        Ok(classes) => {
+            for (class, location) in classes {
+                if location.tier == classpath::Tier::User {
+                    println!("{} {}", class.replace('/', "."), location.entry);
+                }
+            }
+        }
+        Err(e) => println!("{}", e),
+    }
+}
+
+fn strip_jar(input: &str, output: &str, strip: &[String], keep: &[String]) {
+    let summary =
+        strip::attribute_set(strip, keep).and_then(|names| strip::strip_jar(input, output, &names));
+    match summary {
+        Ok(summary) => {
+            println!(
+                "Stripped {} classes: {} -> {} bytes",
+                summary.classes, summary.size_before, summary.size_after
+            );
+            for name in summary.skipped {
+                println!("Copied unchanged: {}", name);
+            }
+        }
+        Err(e) => println!("{}", e),
+    }
+}
+
+fn relocate_jar(input: &str, output: &str, packages: &[String]) {
+    let summary = packages
+        .iter()
+        .map(|p| relocate::Relocation::parse(p))
+        .collect::<Result<Vec<_>, _>>()
+        .and_then(|relocations| relocate::relocate_jar(input, output, &relocations));
+    match summary {
+        Ok(summary) => {
+            println!(
+                "Relocated {} classes, moved {} entries",
+                summary.classes, summary.moved
+            );
+            for name in summary.skipped {
+                println!("Copied unchanged: {}", name);
+            }
+        }
+        Err(e) => println!("{}", e),
+    }
+}
+
+fn call_graph(
+    xjre: &Option<String>,
+    classpath: &Option<String>,
+    roots: &[String],
+    exclude: &[String],
+    format: &str,
+    output: &Option<String>,
+) {
+    let classpath = classpath::user_class_path(classpath.clone());
+    let cp = match ClassPath::new(xjre.clone(), classpath) {
+        Ok(cp) => cp,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+    let graph = callgraph::build(&cp, roots, exclude);
+    let text = match format {
+        "json" => graph.to_json(),
+        _ => graph.to_dot(),
+    };
+    match output {
+        Some(path) => {
+            if let Err(e) = std::fs::write(path, text) {
+                println!("{}: {}", path, e);
+                return;
+            }
+            println!(
+                "{} methods, {} calls, {} unresolved",
+                graph.methods.len(),
+                graph.edges.len(),
+                graph.unresolved.len()
+            );
+        }
+        None => print!("{}", text),
+    }
+}
+
+fn dependency_report(
+    input: &str,
+    classpath: &Option<String>,
+    xjre: &Option<String>,
+    summary: bool,
+    verbose: bool,
+    split_packages: bool,
+    missing: bool,
+) {
+    let jre = match ClassPath::find_jre(xjre.clone()) {
+        Ok(jre) => jre,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+    let mut builder = ClassPath::builder().jre(jre);
+    if let Some(classpath) = classpath {
+        builder = builder.class_path(classpath.clone());
+    }
+    let report = match deps::analyze(input, &builder.build()) {
+        Ok(report) => report,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+    let dotted = |name: &str| name.replace('/', ".");
+    if split_packages || missing {
+        if split_packages {
+            for (package, entries) in &report.split_packages {
+                let entries: Vec<&str> = entries.iter().map(|e| e.as_str()).collect();
+                println!(
+                    "split package: {} [{}]",
+                    dotted(package),
+                    entries.join(", ")
+                );
+            }
+        }
+        if missing {
+            for (class, dependents) in report.missing() {
+                for dependent in dependents {
+                    println!(
+                        "   {:<40} -> {:<40} not found",
+                        dotted(&dependent),
+                        dotted(&class)
+                    );
+                }
+            }
+        }
     } else {
-        println!("class not found");
+        for entry in report.summary() {
+            println!("{} -> {}", input, entry);
+        }
+        if !summary && verbose {
+            for (class, dependencies) in &report.classes {
+                for (dependency, origin) in dependencies {
+                    let origin = origin.as_deref().unwrap_or("not found");
+                    println!(
+                        "   {:<40} -> {:<40} {}",
+                        dotted(class),
+                        dotted(dependency),
+                        origin
+                    );
+                }
+            }
+        } else if !summary {
+            for (from, to, origin) in report.packages() {
+                println!("   {:<40} -> {:<40} {}", dotted(&from), dotted(&to), origin);
+            }
+        }
+    }
+    for name in &report.skipped {
+        println!("Could not parse: {}", name);
+    }
+}
+
+// The launcher's arguments and the program's. As with java, options end at
+// the main class or the jar of -jar, and everything after is passed on as it
+// is, whatever it looks like.
+fn split_args(mut args: Vec<String>) -> (Vec<String>, Vec<String>) {
+    let command = Cmd::command();
+    let takes_value = |arg: &str| {
+        command.get_arguments().any(|a| {
+            a.get_action().takes_values()
+                && (a.get_short().is_some_and(|s| arg == format!("-{}", s))
+                    || a.get_long().is_some_and(|l| arg == format!("--{}", l)))
+        })
     };
+    let mut i = 1;
+    while i < args.len() {
+        let arg = args[i].as_str();
+        if arg == "-jar" || arg == "--jar" {
+            i += 2;
+            break;
+        }
+        if arg.starts_with("--jar=") {
+            i += 1;
+            break;
+        }
+        if !arg.starts_with('-') {
+            // A subcommand parses all of its arguments
+            if command.get_subcommands().any(|c| c.get_name() == arg) {
+                return (args, vec![]);
+            }
+            i += 1;
+            break;
+        }
+        i += if takes_value(arg) { 2 } else { 1 };
+    }
+    let program_args = args.split_off(i.min(args.len()));
+    (args, program_args)
 }
 
 fn main() {
-    println!("Hello, world!");
     // loader::load("./test.class".to_string());
-    let cmd = Cmd::parse();
-    println!("{:?}", cmd);
+    let (args, program_args) = split_args(std::env::args().collect());
+    // -jar, -Xbootclasspath/a: and -verbose: as spelled by java
+    let mut cmd = Cmd::parse_from(args.into_iter().map(|arg| {
+        if arg == "-jar" {
+            return "--jar".to_string();
+        }
+        if let Some(path) = arg.strip_prefix("-Xbootclasspath/a:") {
+            return format!("--Xbootclasspath-a={}", path);
+        }
+        if let Some(what) = arg.strip_prefix("-verbose:") {
+            return format!("--verbose={}", what);
+        }
+        arg
+    }));
+    if cmd.command.is_none() {
+        cmd.args = program_args;
+    }
+
+    match &cmd.command {
+        Some(Command::Strip {
+            input,
+            output,
+            strip,
+            keep,
+        }) => strip_jar(input, output, strip, keep),
+        Some(Command::Relocate {
+            input,
+            output,
+            packages,
+        }) => relocate_jar(input, output, packages),
+        Some(Command::Callgraph {
+            xjre,
+            classpath,
+            roots,
+            exclude,
+            format,
+            output,
+        }) => call_graph(xjre, classpath, roots, exclude, format, output),
+        Some(Command::Deps {
+            input,
+            classpath,
+            xjre,
+            summary,
+            verbose,
+            split_packages,
+            missing,
+        }) => dependency_report(
+            input,
+            classpath,
+            xjre,
+            *summary,
+            *verbose,
+            *split_packages,
+            *missing,
+        ),
+        None => start_jvm(&cmd),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(args: &[&str]) -> (Vec<String>, Vec<String>) {
+        split_args(args.iter().map(|a| a.to_string()).collect())
+    }
 
-    start_jvm(&cmd);
+    #[test]
+    fn test_split_args() {
+        // -jar after the main class is the program's
+        let (launcher, program) = split(&["rustJVM", "--Xjre", "X", "Main", "-jar", "a.jar"]);
+        assert_eq!(launcher, ["rustJVM", "--Xjre", "X", "Main"]);
+        assert_eq!(program, ["-jar", "a.jar"]);
+        let (launcher, program) = split(&["rustJVM", "-jar", "app.jar", "-verbose:classpath", "x"]);
+        assert_eq!(launcher, ["rustJVM", "-jar", "app.jar"]);
+        assert_eq!(program, ["-verbose:classpath", "x"]);
+        let (launcher, program) = split(&["rustJVM", "-c", "Main", "-verbose:gc", "Main"]);
+        assert_eq!(launcher, ["rustJVM", "-c", "Main", "-verbose:gc", "Main"]);
+        assert!(program.is_empty());
+        let (launcher, program) = split(&["rustJVM", "deps", "-s", "-jar"]);
+        assert_eq!(launcher.len(), 4);
+        assert!(program.is_empty());
+    }
 }
@@ -0,0 +1,745 @@
+// Modules: descriptors read from module-info.class or derived for automatic
+// modules, discovery on a module path, and resolution of root modules into a
+// graph of which module reads which, as ModuleFinder and Configuration do.
+// Errors start with the exception the launcher would report them with.
+
+use std::collections::{BTreeMap, BTreeSet, HashSet, VecDeque};
+use std::fs;
+use std::path::Path;
+
+use crate::{
+    attribute::Attribute,
+    classfile::{Const, ConstPool},
+    classpath::{self, Entry, PATH_SEPARATOR},
+    jar::Manifest,
+    jimage::JImageEntry,
+    loader::Class,
+};
+
+const ACC_OPEN: u16 = 0x0020;
+const ACC_TRANSITIVE: u16 = 0x0020;
+const ACC_STATIC_PHASE: u16 = 0x0040;
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Descriptor {
+    pub name: String,
+    pub open: bool,
+    // Automatic modules read every module and export all their packages
+    pub automatic: bool,
+    pub requires: Vec<Requires>,
+    // Exported packages, with the modules they are exported to if qualified
+    pub exports: Vec<(String, Vec<String>)>,
+    pub packages: BTreeSet<String>,
+    pub main_class: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Requires {
+    pub name: String,
+    pub transitive: bool,
+    // Required at compile time only
+    pub is_static: bool,
+}
+
+// A module and where it was found
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reference {
+    pub descriptor: Descriptor,
+    pub location: String,
+}
+
+impl Descriptor {
+    // From the Module, ModulePackages and ModuleMainClass attributes of a
+    // module-info.class
+    pub fn parse(data: &[u8]) -> Result<Descriptor, String> {
+        let class = Class::parse(data)?;
+        let cp = class.const_pool.borrow();
+        let mut descriptor = None;
+        let mut packages = BTreeSet::new();
+        let mut main_class = None;
+        for attribute in &class.attributes {
+            let Attribute::Unknown { name, info } = attribute else {
+                continue;
+            };
+            let mut reader = Reader { info, pos: 0 };
+            match name.as_str() {
+                "Module" => descriptor = Some(module_attribute(&cp, &mut reader)?),
+                "ModulePackages" => {
+                    for _ in 0..reader.u2()? {
+                        packages.insert(constant_name(&cp, reader.u2()?)?);
+                    }
+                }
+                "ModuleMainClass" => {
+                    main_class = Some(cp.class_name(reader.u2()?).replace('/', "."));
+                }
+                _ => {}
+            }
+        }
+        let mut descriptor = descriptor.ok_or("No Module attribute")?;
+        descriptor.packages.extend(packages);
+        descriptor.main_class = main_class;
+        Ok(descriptor)
+    }
+
+    // Whether `package` is exported to `module`, all packages of automatic
+    // modules being
+    fn exports_to(&self, package: &str, module: &str) -> bool {
+        if self.automatic {
+            return self.packages.contains(package);
+        }
+        self.exports
+            .iter()
+            .any(|(p, to)| p == package && (to.is_empty() || to.iter().any(|m| m == module)))
+    }
+}
+
+struct Reader<'a> {
+    info: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn u2(&mut self) -> Result<u16, String> {
+        let bytes = self
+            .info
+            .get(self.pos..self.pos + 2)
+            .ok_or("Truncated Module attribute")?;
+        self.pos += 2;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+}
+
+// The name of a CONSTANT_Module or CONSTANT_Package
+fn constant_name(cp: &ConstPool, index: u16) -> Result<String, String> {
+    match cp.get(index) {
+        Some(Const::Module { name_index, .. }) | Some(Const::Package { name_index, .. }) => {
+            Ok(cp.resolve(*name_index))
+        }
+        _ => Err(format!("Bad module or package constant {}", index)),
+    }
+}
+
+fn module_attribute(cp: &ConstPool, reader: &mut Reader) -> Result<Descriptor, String> {
+    let mut descriptor = Descriptor {
+        name: constant_name(cp, reader.u2()?)?,
+        open: reader.u2()? & ACC_OPEN != 0,
+        ..Default::default()
+    };
+    reader.u2()?;
+    for _ in 0..reader.u2()? {
+        let name = constant_name(cp, reader.u2()?)?;
+        let flags = reader.u2()?;
+        reader.u2()?;
+        descriptor.requires.push(Requires {
+            name,
+            transitive: flags & ACC_TRANSITIVE != 0,
+            is_static: flags & ACC_STATIC_PHASE != 0,
+        });
+    }
+    for _ in 0..reader.u2()? {
+        let package = constant_name(cp, reader.u2()?)?;
+        reader.u2()?;
+        let to = (0..reader.u2()?)
+            .map(|_| constant_name(cp, reader.u2()?))
+            .collect::<Result<_, _>>()?;
+        descriptor.packages.insert(package.clone());
+        descriptor.exports.push((package, to));
+    }
+    // Opens, uses and provides do not take part in resolution
+    for _ in 0..reader.u2()? {
+        descriptor.packages.insert(constant_name(cp, reader.u2()?)?);
+        reader.u2()?;
+        for _ in 0..reader.u2()? {
+            reader.u2()?;
+        }
+    }
+    Ok(descriptor)
+}
+
+// The name of an automatic module from its file name: without the version
+// that follows the first hyphen and digit, with every run of characters
+// other than letters and digits replaced by a dot
+pub fn automatic_name(file_name: &str) -> Result<String, String> {
+    let stem = file_name
+        .strip_suffix(".jar")
+        .or_else(|| file_name.strip_suffix(".JAR"))
+        .unwrap_or(file_name);
+    let bytes = stem.as_bytes();
+    let version = (0..bytes.len()).find(|&i| {
+        bytes[i] == b'-' && {
+            let digits = bytes[i + 1..]
+                .iter()
+                .take_while(|b| b.is_ascii_digit())
+                .count();
+            digits > 0 && matches!(bytes.get(i + 1 + digits), None | Some(b'.'))
+        }
+    });
+    let stem = &stem[..version.unwrap_or(stem.len())];
+    let name = stem
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join(".");
+    check_name(&name)?;
+    Ok(name)
+}
+
+fn check_name(name: &str) -> Result<(), String> {
+    for part in name.split('.') {
+        let valid = part
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_alphabetic() || c == '_' || c == '$')
+            && part
+                .chars()
+                .all(|c| c.is_alphanumeric() || c == '_' || c == '$');
+        if !valid {
+            return Err(format!(
+                "{}: Invalid module name: '{}' is not a Java identifier",
+                name, part
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Packages of the classes among the names of an entry
+fn packages(names: &[String]) -> Result<BTreeSet<String>, String> {
+    let mut packages = BTreeSet::new();
+    for name in names {
+        let Some(class) = name.strip_suffix(".class") else {
+            continue;
+        };
+        if class.starts_with("META-INF/") || class == "module-info" {
+            continue;
+        }
+        match class.rsplit_once('/') {
+            Some((package, _)) => packages.insert(package.to_string()),
+            None => return Err(format!("{}.class found in top-level directory", class)),
+        };
+    }
+    Ok(packages)
+}
+
+fn automatic(entry: &dyn Entry, path: &Path, names: &[String]) -> Result<Descriptor, String> {
+    let manifest = entry
+        .read_resource("META-INF/MANIFEST.MF")
+        .ok()
+        .map(|m| Manifest::parse(&m));
+    let name = match manifest
+        .as_ref()
+        .and_then(|m| m.get("Automatic-Module-Name"))
+    {
+        Some(name) => {
+            check_name(name)?;
+            name.to_string()
+        }
+        None => automatic_name(&path.file_name().unwrap_or_default().to_string_lossy())?,
+    };
+    let packages =
+        packages(names).map_err(|e| format!("{} (unnamed package not allowed in module)", e))?;
+    let main_class = manifest
+        .as_ref()
+        .and_then(|m| m.get("Main-Class"))
+        .map(|c| c.to_string());
+    Ok(Descriptor {
+        name,
+        automatic: true,
+        packages,
+        main_class,
+        ..Default::default()
+    })
+}
+
+// The module of a modular or plain jar, a jmod or an exploded directory;
+// None for anything else
+fn read_module(path: &Path) -> Result<Option<Reference>, String> {
+    let exploded = path.is_dir() && path.join("module-info.class").is_file();
+    let archive = path.is_file()
+        && path
+            .extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case("jar") || e == "jmod");
+    if !exploded && !archive {
+        return Ok(None);
+    }
+    let location = path.to_string_lossy().to_string();
+    let error = |e: String| {
+        format!(
+            "java.lang.module.FindException: Unable to derive module descriptor for {}: {}",
+            location, e
+        )
+    };
+    let entry = classpath::new_entry(location.clone());
+    let names = entry.list().map_err(error)?;
+    let descriptor = match entry.read_resource("module-info.class") {
+        Ok(data) => {
+            let mut descriptor = Descriptor::parse(&data).map_err(error)?;
+            descriptor
+                .packages
+                .extend(packages(&names).unwrap_or_default());
+            descriptor
+        }
+        Err(_) if path.extension().is_some_and(|e| e == "jmod") => {
+            return Err(error("no module-info.class".to_string()))
+        }
+        Err(_) => automatic(entry.as_ref(), path, &names).map_err(error)?,
+    };
+    Ok(Some(Reference {
+        descriptor,
+        location,
+    }))
+}
+
+// The modules of a module path, in order: each element a module, or a
+// directory of modules. The first module of a name hides those after it,
+// but two in the same directory are an error.
+pub fn find_modules(module_path: &str) -> Result<Vec<Reference>, String> {
+    let mut found: Vec<Reference> = vec![];
+    for element in module_path.split(PATH_SEPARATOR).filter(|e| !e.is_empty()) {
+        let path = Path::new(element);
+        let mut modules: Vec<Reference> = vec![];
+        if path.is_dir() && !path.join("module-info.class").is_file() {
+            let mut children: Vec<_> = fs::read_dir(path)
+                .map_err(|e| format!("java.lang.module.FindException: {}: {}", element, e))?
+                .filter_map(|f| f.ok())
+                .map(|f| f.path())
+                .collect();
+            children.sort();
+            for child in children {
+                let Some(module) = read_module(&child)? else {
+                    continue;
+                };
+                let name = &module.descriptor.name;
+                if let Some(other) = modules.iter().find(|m| m.descriptor.name == *name) {
+                    return Err(format!(
+                        "java.lang.module.FindException: Two versions of module {} found in {} ({} and {})",
+                        name,
+                        element,
+                        file_name(&other.location),
+                        file_name(&module.location)
+                    ));
+                }
+                modules.push(module);
+            }
+        } else {
+            modules.extend(read_module(path)?);
+        }
+        for module in modules {
+            if !found
+                .iter()
+                .any(|m| m.descriptor.name == module.descriptor.name)
+            {
+                found.push(module);
+            }
+        }
+    }
+    Ok(found)
+}
+
+fn file_name(location: &str) -> String {
+    Path::new(location)
+        .file_name()
+        .map_or(location.to_string(), |n| n.to_string_lossy().to_string())
+}
+
+// The modules of the runtime image of a JDK, if it has one
+pub fn system_modules(jre_dir: &str) -> Result<Vec<Reference>, String> {
+    let path = format!("{}/lib/modules", jre_dir);
+    if !Path::new(&path).is_file() {
+        return Ok(vec![]);
+    }
+    let image = JImageEntry::new(path);
+    let mut modules = vec![];
+    for name in image.modules()? {
+        let data = image.read_module_resource(&format!("/{}/module-info.class", name))?;
+        modules.push(Reference {
+            descriptor: Descriptor::parse(&data)?,
+            location: format!("jrt:/{}", name),
+        });
+    }
+    Ok(modules)
+}
+
+// Resolved modules, with the modules each reads
+#[derive(Debug, Default)]
+pub struct Configuration {
+    pub modules: BTreeMap<String, Reference>,
+    pub reads: BTreeMap<String, BTreeSet<String>>,
+}
+
+// Resolve `roots`, and the modules they require in turn, from the system
+// modules and then the module path. ALL-MODULE-PATH and ALL-SYSTEM stand for
+// every module of either, as for --add-modules.
+pub fn resolve(
+    system: &[Reference],
+    module_path: &[Reference],
+    roots: &[String],
+) -> Result<Configuration, String> {
+    let find = |name: &str| {
+        system
+            .iter()
+            .chain(module_path)
+            .find(|r| r.descriptor.name == name)
+    };
+    let mut queue: VecDeque<(String, Option<String>)> = VecDeque::new();
+    for root in roots {
+        let all = match root.as_str() {
+            "ALL-MODULE-PATH" => module_path,
+            "ALL-SYSTEM" => system,
+            _ => {
+                queue.push_back((root.clone(), None));
+                continue;
+            }
+        };
+        queue.extend(all.iter().map(|r| (r.descriptor.name.clone(), None)));
+    }
+    let mut configuration = Configuration::default();
+    while let Some((name, required_by)) = queue.pop_front() {
+        if configuration.modules.contains_key(&name) {
+            continue;
+        }
+        let Some(reference) = find(&name) else {
+            return Err(match required_by {
+                Some(by) => format!(
+                    "java.lang.module.FindException: Module {} not found, required by {}",
+                    name, by
+                ),
+                None => format!("java.lang.module.FindException: Module {} not found", name),
+            });
+        };
+        for requires in &reference.descriptor.requires {
+            if !requires.is_static {
+                queue.push_back((requires.name.clone(), Some(name.clone())));
+            }
+        }
+        // Automatic modules are resolved all together
+        if reference.descriptor.automatic {
+            for other in module_path.iter().filter(|r| r.descriptor.automatic) {
+                queue.push_back((other.descriptor.name.clone(), None));
+            }
+        }
+        configuration.modules.insert(name, reference.clone());
+    }
+    check_cycles(&configuration.modules)?;
+    configuration.reads = reads(&configuration.modules);
+    check_packages(&configuration)?;
+    Ok(configuration)
+}
+
+fn check_cycles(modules: &BTreeMap<String, Reference>) -> Result<(), String> {
+    // Modules done with, and the path of requires being followed
+    let mut done: HashSet<&str> = HashSet::new();
+    for root in modules.keys() {
+        let mut path: Vec<(&str, usize)> = vec![(root, 0)];
+        while let Some((module, next)) = path.last_mut() {
+            let requires = &modules[*module].descriptor.requires;
+            let Some(target) = requires.get(*next).map(|r| r.name.as_str()) else {
+                done.insert(module);
+                path.pop();
+                continue;
+            };
+            *next += 1;
+            if done.contains(target) || !modules.contains_key(target) {
+                continue;
+            }
+            if let Some(start) = path.iter().position(|(m, _)| *m == target) {
+                let cycle: Vec<&str> = path[start..]
+                    .iter()
+                    .map(|(m, _)| *m)
+                    .chain([target])
+                    .collect();
+                return Err(format!(
+                    "java.lang.module.ResolutionException: Cycle detected: {}",
+                    cycle.join(" -> ")
+                ));
+            }
+            path.push((target, 0));
+        }
+    }
+    Ok(())
+}
+
+// Each module reads the modules it requires, and those they require
+// transitively. Automatic modules read every module, and as they require
+// each other transitively, reading one reads them all.
+fn reads(modules: &BTreeMap<String, Reference>) -> BTreeMap<String, BTreeSet<String>> {
+    let implied = |name: &str| {
+        let mut found = BTreeSet::new();
+        let mut stack = vec![name];
+        while let Some(module) = stack.pop() {
+            let descriptor = &modules[module].descriptor;
+            let targets: Vec<&str> = if descriptor.automatic {
+                modules
+                    .values()
+                    .filter(|r| r.descriptor.automatic)
+                    .map(|r| r.descriptor.name.as_str())
+                    .collect()
+            } else {
+                descriptor
+                    .requires
+                    .iter()
+                    .filter(|r| r.transitive && modules.contains_key(&r.name))
+                    .map(|r| r.name.as_str())
+                    .collect()
+            };
+            for target in targets {
+                if found.insert(target.to_string()) {
+                    stack.push(target);
+                }
+            }
+        }
+        found
+    };
+    let mut reads = BTreeMap::new();
+    for (name, reference) in modules {
+        let descriptor = &reference.descriptor;
+        let mut read = BTreeSet::new();
+        if descriptor.automatic {
+            read.extend(modules.keys().cloned());
+        } else {
+            for requires in &descriptor.requires {
+                if modules.contains_key(&requires.name) {
+                    read.insert(requires.name.clone());
+                    read.extend(implied(&requires.name));
+                }
+            }
+        }
+        read.remove(name);
+        reads.insert(name.clone(), read);
+    }
+    reads
+}
+
+// A module may not read two modules exporting the same package to it, nor
+// one exporting a package it contains
+fn check_packages(configuration: &Configuration) -> Result<(), String> {
+    for (name, reference) in &configuration.modules {
+        if reference.descriptor.automatic {
+            continue;
+        }
+        let mut suppliers: BTreeMap<&str, &str> = reference
+            .descriptor
+            .packages
+            .iter()
+            .map(|p| (p.as_str(), name.as_str()))
+            .collect();
+        for other in &configuration.reads[name] {
+            let descriptor = &configuration.modules[other].descriptor;
+            for package in &descriptor.packages {
+                if !descriptor.exports_to(package, name) {
+                    continue;
+                }
+                let dotted = package.replace('/', ".");
+                match suppliers.insert(package, other) {
+                    Some(supplier) if supplier == name => {
+                        return Err(format!(
+                            "java.lang.module.ResolutionException: Module {} contains package {}, module {} exports package {} to {}",
+                            name, dotted, other, dotted, name
+                        ))
+                    }
+                    Some(supplier) => {
+                        return Err(format!(
+                            "java.lang.module.ResolutionException: Modules {} and {} export package {} to module {}",
+                            supplier, other, dotted, name
+                        ))
+                    }
+                    None => {}
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::io::Write;
+    use std::rc::Rc;
+
+    use zip::write::SimpleFileOptions;
+    use zip::ZipWriter;
+
+    use super::*;
+    use crate::classfile::Constants;
+    use crate::writer;
+
+    // A module-info.class requiring modules, with flags, and exporting packages
+    fn module_info(name: &str, requires: &[(&str, u16)], exports: &[&str]) -> Vec<u8> {
+        let cp = Rc::new(RefCell::new(ConstPool::default()));
+        let module = |name: &str| {
+            let name_index = cp.utf8(name);
+            cp.add(
+                |c| matches!(c, Const::Module { name_index: x, .. } if *x == name_index),
+                |cp| Const::Module { cp, name_index },
+            )
+        };
+        let package = |name: &str| {
+            let name_index = cp.utf8(name);
+            cp.add(
+                |c| matches!(c, Const::Package { name_index: x, .. } if *x == name_index),
+                |cp| Const::Package { cp, name_index },
+            )
+        };
+        let mut info = vec![module(name), 0, 0, requires.len() as u16];
+        for (name, flags) in requires {
+            info.extend([module(name), *flags, 0]);
+        }
+        info.push(exports.len() as u16);
+        for name in exports {
+            info.extend([package(name), 0, 0]);
+        }
+        info.extend([0, 0, 0]);
+        let class = Class {
+            major_version: 53,
+            flags: 0x8000,
+            this_class: cp.class("module-info"),
+            attributes: vec![Attribute::Unknown {
+                name: "Module".to_string(),
+                info: info.iter().flat_map(|u| u.to_be_bytes()).collect(),
+            }],
+            const_pool: cp,
+            ..Default::default()
+        };
+        writer::write(&class).unwrap()
+    }
+
+    fn jar(path: &Path, entries: &[(&str, &[u8])]) {
+        let mut jar = ZipWriter::new(fs::File::create(path).unwrap());
+        for (name, data) in entries {
+            jar.start_file(*name, SimpleFileOptions::default()).unwrap();
+            jar.write_all(data).unwrap();
+        }
+        jar.finish().unwrap();
+    }
+
+    fn exploded(dir: &Path, info: Vec<u8>, classes: &[&str]) {
+        fs::create_dir_all(dir).unwrap();
+        fs::write(dir.join("module-info.class"), info).unwrap();
+        for class in classes {
+            let path = dir.join(format!("{}.class", class));
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, b"").unwrap();
+        }
+    }
+
+    fn roots(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn test_automatic_name() {
+        assert_eq!(automatic_name("foo-bar-1.2.3.jar").unwrap(), "foo.bar");
+        assert_eq!(
+            automatic_name("commons-lang3-3.12.0.jar").unwrap(),
+            "commons.lang3"
+        );
+        assert_eq!(
+            automatic_name("a--b_c-SNAPSHOT.jar").unwrap(),
+            "a.b.c.SNAPSHOT"
+        );
+        assert_eq!(
+            automatic_name("1x-2.jar").unwrap_err(),
+            "1x: Invalid module name: '1x' is not a Java identifier"
+        );
+    }
+
+    #[test]
+    fn test_resolve() {
+        let root = std::env::temp_dir().join("rustjvm-module-resolve");
+        let _ = fs::remove_dir_all(&root);
+        let mods = root.join("mods");
+        exploded(
+            &mods.join("a"),
+            module_info("a", &[("b", 0)], &[]),
+            &["pa/A"],
+        );
+        exploded(
+            &mods.join("b"),
+            module_info(
+                "b",
+                &[("c", ACC_TRANSITIVE), ("x", ACC_STATIC_PHASE)],
+                &["pb"],
+            ),
+            &["pb/B"],
+        );
+        exploded(&mods.join("c"), module_info("c", &[], &["pc"]), &["pc/C"]);
+        jar(
+            &mods.join("util-1.0.jar"),
+            &[
+                ("u/U.class", b""),
+                ("META-INF/MANIFEST.MF", b"Main-Class: u.U\n"),
+            ],
+        );
+        let found = find_modules(mods.to_str().unwrap()).unwrap();
+        let names: Vec<&str> = found.iter().map(|r| r.descriptor.name.as_str()).collect();
+        assert_eq!(names, ["a", "b", "c", "util"]);
+        assert_eq!(
+            found[0].descriptor.packages,
+            BTreeSet::from(["pa".to_string()])
+        );
+        assert!(found[1].descriptor.requires[1].is_static);
+        assert_eq!(found[3].descriptor.main_class.as_deref(), Some("u.U"));
+
+        // Static requires are not resolved, automatic modules only if asked for
+        let configuration = resolve(&[], &found, &roots(&["a"])).unwrap();
+        assert_eq!(configuration.modules.len(), 3);
+        assert_eq!(
+            configuration.reads["a"],
+            BTreeSet::from(["b".into(), "c".into()])
+        );
+        assert_eq!(configuration.reads["b"], BTreeSet::from(["c".into()]));
+        let configuration = resolve(&[], &found, &roots(&["ALL-MODULE-PATH"])).unwrap();
+        assert_eq!(configuration.reads["util"].len(), 3);
+
+        // The first of two modules of a name on the module path wins
+        let other = root.join("other");
+        exploded(&other.join("c"), module_info("c", &[("a", 0)], &[]), &[]);
+        let module_path = format!("{}{}{}", mods.display(), PATH_SEPARATOR, other.display());
+        let found = find_modules(&module_path).unwrap();
+        assert_eq!(found.len(), 4);
+
+        let found = find_modules(other.to_str().unwrap()).unwrap();
+        assert_eq!(
+            resolve(&[], &found, &roots(&["c"])).unwrap_err(),
+            "java.lang.module.FindException: Module a not found, required by c"
+        );
+        let module_path = format!("{}{}{}", other.display(), PATH_SEPARATOR, mods.display());
+        let found = find_modules(&module_path).unwrap();
+        assert_eq!(
+            resolve(&[], &found, &roots(&["a"])).unwrap_err(),
+            "java.lang.module.ResolutionException: Cycle detected: a -> b -> c -> a"
+        );
+        assert_eq!(
+            resolve(&[], &found, &roots(&["d"])).unwrap_err(),
+            "java.lang.module.FindException: Module d not found"
+        );
+
+        jar(&mods.join("util-2.0.jar"), &[("u/U.class", b"")]);
+        assert_eq!(
+            find_modules(mods.to_str().unwrap()).unwrap_err(),
+            format!(
+                "java.lang.module.FindException: Two versions of module util found in {} (util-1.0.jar and util-2.0.jar)",
+                mods.display()
+            )
+        );
+    }
+
+    #[test]
+    fn test_split_package() {
+        let root = std::env::temp_dir().join("rustjvm-module-split-package");
+        let _ = fs::remove_dir_all(&root);
+        exploded(
+            &root.join("a"),
+            module_info("a", &[("b", 0), ("c", 0)], &[]),
+            &[],
+        );
+        exploded(&root.join("b"), module_info("b", &[], &["p/q"]), &["p/q/B"]);
+        exploded(&root.join("c"), module_info("c", &[], &["p/q"]), &["p/q/C"]);
+        let found = find_modules(root.to_str().unwrap()).unwrap();
+        assert_eq!(
+            resolve(&[], &found, &roots(&["a"])).unwrap_err(),
+            "java.lang.module.ResolutionException: Modules b and c export package p.q to module a"
+        );
+    }
+}
@@ -0,0 +1,770 @@
+// Package relocation ("shading"): classes under one package prefix are moved
+// under another, and every reference to them is rewritten, so that a library
+// can be bundled without clashing with other copies of it
+
+use std::collections::HashMap;
+
+use crate::{
+    attribute::{Attribute, VerificationTypeInfo},
+    bytecode::{self, Operands, LDC},
+    classfile::{Const, ConstPool},
+    descriptor, jar,
+    loader::{Class, Limits},
+    writer,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Relocation {
+    // Internal form with a trailing slash, e.g. com/google/
+    from: String,
+    to: String,
+}
+
+impl Relocation {
+    // Packages may be written com.google or com/google
+    pub fn new(from: &str, to: &str) -> Result<Relocation, String> {
+        let package = |p: &str| {
+            let p = p.replace('.', "/");
+            let p = p.trim_end_matches('/');
+            if descriptor::is_binary_name(p) {
+                Ok(format!("{}/", p))
+            } else {
+                Err(format!("Invalid package name {}", p))
+            }
+        };
+        Ok(Relocation {
+            from: package(from)?,
+            to: package(to)?,
+        })
+    }
+
+    // A relocation given as from=to
+    pub fn parse(spec: &str) -> Result<Relocation, String> {
+        match spec.split_once('=') {
+            Some((from, to)) => Relocation::new(from, to),
+            None => Err(format!("Expected from=to, found {}", spec)),
+        }
+    }
+}
+
+// How a Utf8 entry is used decides how it is rewritten
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Role {
+    ClassName,
+    // Field and method descriptors, and signatures
+    Descriptor,
+    Package,
+    // String constants, which may name classes for reflection
+    Text,
+    // Left as it is; visited so a shared entry is not rewritten under it
+    Name,
+    // A reference to an entry other than a Utf8 one
+    Constant,
+}
+
+fn relocate_name(relocations: &[Relocation], name: &str) -> Option<String> {
+    relocations
+        .iter()
+        .find(|r| name.starts_with(&r.from))
+        .map(|r| format!("{}{}", r.to, &name[r.from.len()..]))
+}
+
+// Class names in binary form, as used by reflection and service files
+fn relocate_binary_name(relocations: &[Relocation], name: &str) -> Option<String> {
+    relocations.iter().find_map(|r| {
+        let from = r.from.replace('/', ".");
+        name.strip_prefix(&from)
+            .map(|rest| format!("{}{}", r.to.replace('/', "."), rest))
+    })
+}
+
+// Rewrite the class types of a descriptor or signature, leaving one that
+// does not parse as it is
+fn relocate_descriptor(relocations: &[Relocation], s: &str) -> String {
+    let Some(names) = descriptor::class_names(s) else {
+        return s.to_string();
+    };
+    let mut out = String::with_capacity(s.len());
+    let mut last = 0;
+    for range in names {
+        out.push_str(&s[last..range.start]);
+        let name = &s[range.clone()];
+        out.push_str(&relocate_name(relocations, name).unwrap_or_else(|| name.to_string()));
+        last = range.end;
+    }
+    out.push_str(&s[last..]);
+    out
+}
+
+fn relocate(relocations: &[Relocation], role: Role, s: &str) -> String {
+    let relocated = match role {
+        Role::ClassName if s.starts_with('[') => Some(relocate_descriptor(relocations, s)),
+        Role::ClassName => relocate_name(relocations, s),
+        Role::Descriptor => Some(relocate_descriptor(relocations, s)),
+        Role::Package => relocate_name(relocations, &format!("{}/", s))
+            .map(|p| p.trim_end_matches('/').to_string()),
+        // Resource paths may be absolute, as for Class.getResource
+        Role::Text => match s.strip_prefix('/') {
+            Some(path) => relocate_name(relocations, path).map(|path| format!("/{}", path)),
+            None => relocate_name(relocations, s),
+        }
+        .or_else(|| relocate_binary_name(relocations, s)),
+        Role::Name | Role::Constant => None,
+    };
+    relocated.unwrap_or_else(|| s.to_string())
+}
+
+pub fn relocate_class(class: &mut Class, relocations: &[Relocation]) -> Result<(), String> {
+    let original: Vec<Option<String>> = {
+        let cp = class.const_pool.borrow();
+        (0..cp.count())
+            .map(|index| match cp.get(index) {
+                Some(Const::Utf8(s)) => Some(s.clone()),
+                _ => None,
+            })
+            .collect()
+    };
+    let mut uses: HashMap<u16, Vec<String>> = HashMap::new();
+    walk(class, &mut |index, role| {
+        if let Some(Some(s)) = original.get(*index as usize) {
+            let values = uses.entry(*index).or_default();
+            let value = relocate(relocations, role, s);
+            if !values.contains(&value) {
+                values.push(value);
+            }
+        }
+    })?;
+
+    // An entry whose uses all agree is rewritten in place. Otherwise it keeps
+    // serving the uses it still fits, and the others get entries of their own.
+    let mut added: HashMap<(u16, String), u16> = HashMap::new();
+    {
+        let mut cp = class.const_pool.borrow_mut();
+        let mut indices: Vec<u16> = uses.keys().copied().collect();
+        indices.sort();
+        for index in indices {
+            let values = &uses[&index];
+            let s = original[index as usize].as_ref().unwrap();
+            let kept = if values.contains(s) { s } else { &values[0] };
+            *cp.get_mut(index).unwrap() = Const::Utf8(kept.clone());
+            for value in values.iter().filter(|v| *v != kept) {
+                if cp.count() == u16::MAX {
+                    return Err("Too many constants after relocation".to_string());
+                }
+                added.insert((index, value.clone()), cp.count());
+                cp.push(Const::Utf8(value.clone()));
+            }
+        }
+    }
+    if added.is_empty() {
+        return Ok(());
+    }
+    walk(class, &mut |index, role| {
+        if let Some(Some(s)) = original.get(*index as usize) {
+            let value = relocate(relocations, role, s);
+            if let Some(new_index) = added.get(&(*index, value)) {
+                *index = *new_index;
+            }
+        }
+    })
+}
+
+pub(crate) type Visit<'v> = dyn FnMut(&mut u16, Role) + 'v;
+
+// Call `visit` on every constant pool index in `class`. Those of Utf8 entries
+// that may name a class say how the name is used.
+pub(crate) fn walk(class: &mut Class, visit: &mut Visit) -> Result<(), String> {
+    {
+        let mut cp = class.const_pool.borrow_mut();
+        for index in 1..cp.count() {
+            if let Some(c) = cp.get_mut(index) {
+                walk_const(c, visit);
+            }
+        }
+    }
+    walk_members(class, visit).map(|_| ())
+}
+
+// The indices held by a constant pool entry
+pub(crate) fn walk_const(c: &mut Const, visit: &mut Visit) {
+    match c {
+        Const::Class { name_index, .. } => visit(name_index, Role::ClassName),
+        Const::String { string_index, .. } => visit(string_index, Role::Text),
+        Const::FieldRef {
+            class_index,
+            name_and_type_index,
+            ..
+        }
+        | Const::MethodRef {
+            class_index,
+            name_and_type_index,
+            ..
+        }
+        | Const::InterfaceMethodRef {
+            class_index,
+            name_and_type_index,
+            ..
+        } => {
+            visit(class_index, Role::Constant);
+            visit(name_and_type_index, Role::Constant);
+        }
+        Const::NameAndType {
+            name_index,
+            descriptor_index,
+            ..
+        } => {
+            visit(name_index, Role::Name);
+            visit(descriptor_index, Role::Descriptor);
+        }
+        Const::MethodHandle {
+            reference_index, ..
+        } => visit(reference_index, Role::Constant),
+        Const::MethodType {
+            descriptor_index, ..
+        } => visit(descriptor_index, Role::Descriptor),
+        Const::Dynamic {
+            name_and_type_index,
+            ..
+        }
+        | Const::InvokeDynamic {
+            name_and_type_index,
+            ..
+        } => visit(name_and_type_index, Role::Constant),
+        Const::Module { name_index, .. } => visit(name_index, Role::Name),
+        Const::Package { name_index, .. } => visit(name_index, Role::Package),
+        _ => {}
+    }
+}
+
+// The indices held outside the constant pool. False when an attribute
+// whose layout we do not know may hold more.
+pub(crate) fn walk_members(class: &mut Class, visit: &mut Visit) -> Result<bool, String> {
+    visit(&mut class.this_class, Role::Constant);
+    if class.super_class != 0 {
+        visit(&mut class.super_class, Role::Constant);
+    }
+    for interface in class.interfaces.iter_mut() {
+        visit(interface, Role::Constant);
+    }
+    let pool = class.const_pool.clone();
+    let cp = pool.borrow();
+    let mut known = true;
+    for field in class.fields.iter_mut().chain(class.methods.iter_mut()) {
+        visit(&mut field.name_index, Role::Name);
+        visit(&mut field.descriptor_index, Role::Descriptor);
+        known &= walk_attributes(&cp, &mut field.attributes, visit)?;
+    }
+    known &= walk_attributes(&cp, &mut class.attributes, visit)?;
+    Ok(known)
+}
+
+fn walk_attributes(
+    cp: &ConstPool,
+    attributes: &mut [Attribute],
+    visit: &mut Visit,
+) -> Result<bool, String> {
+    let mut known = true;
+    for attr in attributes {
+        let name = attr.name().to_string();
+        match attr {
+            Attribute::ConstantValue(index) => visit(index, Role::Constant),
+            Attribute::Code {
+                code,
+                exception_table,
+                attributes,
+                ..
+            } => {
+                walk_code(code, visit)?;
+                for entry in exception_table {
+                    if entry.catch_type != 0 {
+                        visit(&mut entry.catch_type, Role::Constant);
+                    }
+                }
+                known &= walk_attributes(cp, attributes, visit)?;
+            }
+            Attribute::Exceptions {
+                exception_index_table,
+            } => {
+                for index in exception_index_table {
+                    visit(index, Role::Constant);
+                }
+            }
+            Attribute::SourceFile { index, .. } => visit(index, Role::Name),
+            Attribute::LocalVariableTable {
+                local_variable_table,
+            } => {
+                for entry in local_variable_table {
+                    visit(&mut entry.name_index, Role::Name);
+                    visit(&mut entry.descriptor_index, Role::Descriptor);
+                }
+            }
+            Attribute::StackMapTable { entries } => {
+                for frame in entries {
+                    for info in frame.locals.iter_mut().chain(frame.stack.iter_mut()) {
+                        if let VerificationTypeInfo::Object(index) = info {
+                            visit(index, Role::Constant);
+                        }
+                    }
+                }
+            }
+            Attribute::InnerClasses(info)
+            | Attribute::EnclosingMethod(info)
+            | Attribute::Signature(info)
+            | Attribute::LocalVariableTypeTable(info)
+            | Attribute::RuntimeVisibleAnnotations(info)
+            | Attribute::RuntimeInvisibleAnnotations(info)
+            | Attribute::RuntimeVisibleParameterAnnotations(info)
+            | Attribute::RuntimeInvisibleParameterAnnotations(info)
+            | Attribute::AnnotationDefault(info)
+            | Attribute::BootstrapMethods(info)
+            | Attribute::RuntimeVisibleTypeAnnotations(info)
+            | Attribute::RuntimeInvisibleTypeAnnotations(info)
+            | Attribute::MethodParameters(info)
+            | Attribute::Unknown { info, .. } => {
+                let mut raw = Raw { data: info, pos: 0 };
+                known &= raw
+                    .attribute(cp, &name, visit)
+                    .map_err(|_| format!("Malformed {} attribute", name))?;
+            }
+            _ => {}
+        }
+    }
+    Ok(known)
+}
+
+// The constant operands of ldc, field and method instructions and the like
+fn walk_code(code: &mut [u8], visit: &mut Visit) -> Result<(), String> {
+    for insn in bytecode::decode(code)? {
+        let (Operands::Constant(index)
+        | Operands::InvokeInterface(index, _)
+        | Operands::MultiANewArray(index, _)) = insn.operands
+        else {
+            continue;
+        };
+        let mut new_index = index;
+        visit(&mut new_index, Role::Constant);
+        if new_index == index {
+            continue;
+        }
+        let at = insn.pc + 1;
+        if insn.opcode == LDC {
+            code[at] = u8::try_from(new_index)
+                .map_err(|_| format!("Constant {} out of reach of ldc", new_index))?;
+        } else {
+            code[at..at + 2].copy_from_slice(&new_index.to_be_bytes());
+        }
+    }
+    Ok(())
+}
+
+// Cursor over the bytes of an attribute we do not model, rewriting the
+// constant pool indices found in it
+struct Raw<'d> {
+    data: &'d mut [u8],
+    pos: usize,
+}
+
+impl Raw<'_> {
+    fn skip(&mut self, n: usize) -> Result<(), ()> {
+        if self.pos + n > self.data.len() {
+            return Err(());
+        }
+        self.pos += n;
+        Ok(())
+    }
+
+    fn u1(&mut self) -> Result<u8, ()> {
+        self.skip(1)?;
+        Ok(self.data[self.pos - 1])
+    }
+
+    fn u2(&mut self) -> Result<u16, ()> {
+        self.skip(2)?;
+        Ok(u16::from_be_bytes([
+            self.data[self.pos - 2],
+            self.data[self.pos - 1],
+        ]))
+    }
+
+    fn u4(&mut self) -> Result<u32, ()> {
+        Ok((self.u2()? as u32) << 16 | self.u2()? as u32)
+    }
+
+    // The index as rewritten
+    fn index(&mut self, role: Role, visit: &mut Visit) -> Result<u16, ()> {
+        let mut index = self.u2()?;
+        visit(&mut index, role);
+        self.data[self.pos - 2..self.pos].copy_from_slice(&index.to_be_bytes());
+        Ok(index)
+    }
+
+    // An index that may be zero for none
+    fn optional_index(&mut self, role: Role, visit: &mut Visit) -> Result<(), ()> {
+        if self.u2()? != 0 {
+            self.pos -= 2;
+            self.index(role, visit)?;
+        }
+        Ok(())
+    }
+
+    // False for an attribute whose layout we do not know
+    fn attribute(&mut self, cp: &ConstPool, name: &str, visit: &mut Visit) -> Result<bool, ()> {
+        let mut known = true;
+        match name {
+            "Signature" => {
+                self.index(Role::Descriptor, visit)?;
+            }
+            "NestHost" | "ModuleMainClass" => {
+                self.index(Role::Constant, visit)?;
+            }
+            "NestMembers" | "PermittedSubclasses" | "ModulePackages" => {
+                for _ in 0..self.u2()? {
+                    self.index(Role::Constant, visit)?;
+                }
+            }
+            "InnerClasses" => {
+                for _ in 0..self.u2()? {
+                    self.index(Role::Constant, visit)?;
+                    self.optional_index(Role::Constant, visit)?;
+                    self.optional_index(Role::Name, visit)?;
+                    self.skip(2)?;
+                }
+            }
+            "EnclosingMethod" => {
+                self.index(Role::Constant, visit)?;
+                self.optional_index(Role::Constant, visit)?;
+            }
+            "BootstrapMethods" => {
+                for _ in 0..self.u2()? {
+                    self.index(Role::Constant, visit)?;
+                    for _ in 0..self.u2()? {
+                        self.index(Role::Constant, visit)?;
+                    }
+                }
+            }
+            "MethodParameters" => {
+                for _ in 0..self.u1()? {
+                    self.optional_index(Role::Name, visit)?;
+                    self.skip(2)?;
+                }
+            }
+            "LocalVariableTypeTable" => {
+                for _ in 0..self.u2()? {
+                    self.skip(4)?;
+                    self.index(Role::Name, visit)?;
+                    self.index(Role::Descriptor, visit)?;
+                    self.skip(2)?;
+                }
+            }
+            "RuntimeVisibleAnnotations" | "RuntimeInvisibleAnnotations" => {
+                for _ in 0..self.u2()? {
+                    self.annotation(visit, 0)?;
+                }
+            }
+            "RuntimeVisibleParameterAnnotations" | "RuntimeInvisibleParameterAnnotations" => {
+                for _ in 0..self.u1()? {
+                    for _ in 0..self.u2()? {
+                        self.annotation(visit, 0)?;
+                    }
+                }
+            }
+            "AnnotationDefault" => self.element_value(visit, 0)?,
+            "RuntimeVisibleTypeAnnotations" | "RuntimeInvisibleTypeAnnotations" => {
+                for _ in 0..self.u2()? {
+                    self.type_annotation(visit)?;
+                }
+            }
+            "Record" => {
+                for _ in 0..self.u2()? {
+                    self.index(Role::Name, visit)?;
+                    self.index(Role::Descriptor, visit)?;
+                    for _ in 0..self.u2()? {
+                        let name = cp.resolve(self.index(Role::Name, visit)?);
+                        let length = self.u4()? as usize;
+                        let end = self.pos + length;
+                        if end > self.data.len() {
+                            return Err(());
+                        }
+                        let mut component = Raw {
+                            data: &mut self.data[self.pos..end],
+                            pos: 0,
+                        };
+                        known &= component.attribute(cp, &name, visit)?;
+                        self.pos = end;
+                    }
+                }
+            }
+            _ => return Ok(false),
+        }
+        // The attributes we walk must be consumed exactly
+        if self.pos != self.data.len() {
+            return Err(());
+        }
+        Ok(known)
+    }
+
+    fn annotation(&mut self, visit: &mut Visit, depth: usize) -> Result<(), ()> {
+        self.index(Role::Descriptor, visit)?;
+        for _ in 0..self.u2()? {
+            self.index(Role::Name, visit)?;
+            self.element_value(visit, depth)?;
+        }
+        Ok(())
+    }
+
+    fn element_value(&mut self, visit: &mut Visit, depth: usize) -> Result<(), ()> {
+        // Nesting is bounded by the data, but keep the stack in check too
+        if depth > 64 {
+            return Err(());
+        }
+        match self.u1()? {
+            b'B' | b'C' | b'D' | b'F' | b'I' | b'J' | b'S' | b'Z' => {
+                self.index(Role::Constant, visit)?;
+            }
+            b's' => {
+                self.index(Role::Text, visit)?;
+            }
+            b'e' => {
+                self.index(Role::Descriptor, visit)?;
+                self.index(Role::Name, visit)?;
+            }
+            b'c' => {
+                self.index(Role::Descriptor, visit)?;
+            }
+            b'@' => self.annotation(visit, depth + 1)?,
+            b'[' => {
+                for _ in 0..self.u2()? {
+                    self.element_value(visit, depth + 1)?;
+                }
+            }
+            _ => return Err(()),
+        }
+        Ok(())
+    }
+
+    // JVMS 4.7.20
+    fn type_annotation(&mut self, visit: &mut Visit) -> Result<(), ()> {
+        match self.u1()? {
+            0x00 | 0x01 | 0x16 => self.skip(1)?,
+            0x10 | 0x11 | 0x12 | 0x17 | 0x42..=0x46 => self.skip(2)?,
+            0x13..=0x15 => {}
+            0x40 | 0x41 => {
+                let length = self.u2()? as usize;
+                self.skip(6 * length)?;
+            }
+            0x47..=0x4b => self.skip(3)?,
+            _ => return Err(()),
+        }
+        let path_length = self.u1()? as usize;
+        self.skip(2 * path_length)?;
+        self.annotation(visit, 0)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub classes: usize,
+    // Entries given a new name
+    pub moved: usize,
+    // Class entries copied unchanged because they could not be relocated
+    pub skipped: Vec<String>,
+}
+
+pub fn relocate_jar(
+    input: &str,
+    output: &str,
+    relocations: &[Relocation],
+) -> Result<Summary, String> {
+    let mut summary = Summary::default();
+    jar::rewrite(input, output, |name, data| {
+        // Multi-release jars keep versioned classes under META-INF/versions/N/
+        let (prefix, path) = match name.strip_prefix("META-INF/versions/") {
+            Some(rest) => match rest.find('/') {
+                Some(slash) => name.split_at("META-INF/versions/".len() + slash + 1),
+                None => ("", name),
+            },
+            None => ("", name),
+        };
+        let new_name = relocate_name(relocations, path).map(|p| format!("{}{}", prefix, p));
+
+        if name.ends_with(".class") {
+            let relocated = Class::parse_with_limits(data, &Limits::default()).and_then(|mut c| {
+                relocate_class(&mut c, relocations)?;
+                writer::write(&c)
+            });
+            let Ok(relocated) = relocated else {
+                summary.skipped.push(name.to_string());
+                return None;
+            };
+            summary.classes += 1;
+            if new_name.is_some() {
+                summary.moved += 1;
+            }
+            return Some((new_name.unwrap_or_else(|| name.to_string()), relocated));
+        }
+        if let Some(service) = name.strip_prefix("META-INF/services/") {
+            // Service files are named after a class and list implementations
+            let text = String::from_utf8_lossy(data);
+            let lines: Vec<String> = text
+                .lines()
+                .map(|line| relocate_binary_name(relocations, line).unwrap_or(line.to_string()))
+                .collect();
+            let service = relocate_binary_name(relocations, service).unwrap_or(service.to_string());
+            return Some((
+                format!("META-INF/services/{}", service),
+                (lines.join("\n") + "\n").into_bytes(),
+            ));
+        }
+        new_name.map(|new_name| {
+            summary.moved += 1;
+            (new_name, data.to_vec())
+        })
+    })?;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::fs::File;
+    use std::io::prelude::*;
+    use std::{cell::RefCell, rc::Rc};
+
+    use zip::write::SimpleFileOptions;
+    use zip::ZipWriter;
+
+    use super::*;
+    use crate::classpath::ZipEntry;
+    use crate::loader::Field;
+
+    fn relocations() -> Vec<Relocation> {
+        vec![Relocation::parse("com.foo=shaded.com.foo").unwrap()]
+    }
+
+    #[test]
+    fn test_relocate_descriptor() {
+        let r = relocations();
+        assert_eq!(
+            relocate_descriptor(&r, "(Lcom/foo/A;[Lcom/foo/B;Lcom/foobar/C;)V"),
+            "(Lshaded/com/foo/A;[Lshaded/com/foo/B;Lcom/foobar/C;)V"
+        );
+        assert_eq!(
+            relocate_descriptor(&r, "<T:Lcom/foo/A;>Ljava/util/List<+Lcom/foo/B;>;"),
+            "<T:Lshaded/com/foo/A;>Ljava/util/List<+Lshaded/com/foo/B;>;"
+        );
+        // Class types right after a primitive, and a type variable named L
+        assert_eq!(
+            relocate_descriptor(&r, "<L:Ljava/lang/Object;>(ZLcom/foo/A;[[CLcom/foo/B;TL;)V"),
+            "<L:Ljava/lang/Object;>(ZLshaded/com/foo/A;[[CLshaded/com/foo/B;TL;)V"
+        );
+        assert_eq!(
+            relocate(&r, Role::Text, "com.foo.impl.Service"),
+            "shaded.com.foo.impl.Service"
+        );
+        assert_eq!(
+            relocate(&r, Role::Text, "/com/foo/data.txt"),
+            "/shaded/com/foo/data.txt"
+        );
+        assert_eq!(relocate(&r, Role::Text, "/com/foobar"), "/com/foobar");
+        assert!(Relocation::parse("com.foo").is_err());
+    }
+
+    #[test]
+    fn test_relocate_class() {
+        let cp = Rc::new(RefCell::new(ConstPool::default()));
+        for c in [
+            Const::Utf8("com/foo/Bar".to_string()),
+            Const::Class {
+                cp: Rc::downgrade(&cp),
+                name_index: 1,
+            },
+            Const::Utf8("java/lang/Object".to_string()),
+            Const::Class {
+                cp: Rc::downgrade(&cp),
+                name_index: 3,
+            },
+            Const::Utf8("Lcom/foo/Baz;".to_string()),
+            Const::Utf8("baz".to_string()),
+        ] {
+            cp.borrow_mut().push(c);
+        }
+        let field = Field {
+            cp: cp.clone(),
+            flags: 0,
+            name_index: 6,
+            descriptor_index: 5,
+            // @Baz on the field
+            attributes: vec![Attribute::RuntimeVisibleAnnotations(vec![0, 1, 0, 5, 0, 0])],
+        };
+        let mut class = Class {
+            major_version: 52,
+            const_pool: cp.clone(),
+            this_class: 2,
+            super_class: 4,
+            fields: vec![field],
+            // A source file name that happens to equal the class name
+            attributes: vec![Attribute::SourceFile {
+                cp: cp.clone(),
+                index: 1,
+            }],
+            ..Default::default()
+        };
+        relocate_class(&mut class, &relocations()).unwrap();
+
+        assert_eq!(class.name(), "shaded/com/foo/Bar");
+        assert_eq!(class.super_name().unwrap(), "java/lang/Object");
+        let field = &class.fields[0];
+        assert_eq!(field.descriptor(), "Lshaded/com/foo/Baz;");
+        // The class name moved to a new entry, as the source file kept #1
+        assert_eq!(class.const_pool.borrow().resolve(1), "com/foo/Bar");
+        assert_eq!(class.const_pool.borrow().count(), 8);
+        assert!(matches!(
+            &field.attributes[0],
+            Attribute::RuntimeVisibleAnnotations(info) if info[2..4] == [0, 5]
+        ));
+    }
+
+    #[test]
+    fn test_relocate_jar() {
+        let dir = std::env::temp_dir().join("rustjvm-relocate-test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("in.jar");
+        let output = dir.join("out.jar");
+        let mut jar = ZipWriter::new(File::create(&input).unwrap());
+        let options = SimpleFileOptions::default();
+        jar.start_file("META-INF/services/com.foo.Spi", options)
+            .unwrap();
+        jar.write_all(b"com.foo.impl.SpiImpl\n").unwrap();
+        jar.start_file("com/foo/messages.properties", options)
+            .unwrap();
+        jar.start_file("org/other/Keep.txt", options).unwrap();
+        jar.finish().unwrap();
+
+        let summary = relocate_jar(
+            input.to_str().unwrap(),
+            output.to_str().unwrap(),
+            &relocations(),
+        )
+        .unwrap();
+        assert_eq!(summary.moved, 1);
+
+        let mut archive = ZipEntry::new(output.to_str().unwrap().to_string())
+            .archive()
+            .unwrap();
+        assert_eq!(
+            archive.file_names().collect::<HashSet<_>>(),
+            HashSet::from([
+                "META-INF/services/shaded.com.foo.Spi",
+                "shaded/com/foo/messages.properties",
+                "org/other/Keep.txt",
+            ])
+        );
+        let mut text = String::new();
+        archive
+            .by_name("META-INF/services/shaded.com.foo.Spi")
+            .unwrap()
+            .read_to_string(&mut text)
+            .unwrap();
+        assert_eq!(text, "shaded.com.foo.impl.SpiImpl\n");
+    }
+}
@@ -0,0 +1,269 @@
+// Removal of debug information and other optional attributes, to make
+// smaller builds of classes and jars
+
+use std::collections::HashSet;
+
+use crate::{
+    attribute::Attribute,
+    classfile::Const,
+    jar,
+    loader::{Class, Limits},
+    relocate, writer,
+};
+
+// Stripped unless asked to keep them
+pub const DEBUG_ATTRIBUTES: [&str; 8] = [
+    "LineNumberTable",
+    "LocalVariableTable",
+    "LocalVariableTypeTable",
+    "SourceFile",
+    "SourceDebugExtension",
+    "RuntimeInvisibleAnnotations",
+    "RuntimeInvisibleParameterAnnotations",
+    "RuntimeInvisibleTypeAnnotations",
+];
+
+// Without these the classes would no longer load or link
+const REQUIRED_ATTRIBUTES: [&str; 4] =
+    ["Code", "ConstantValue", "StackMapTable", "BootstrapMethods"];
+
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub classes: usize,
+    // Class entries copied unchanged because they could not be parsed
+    pub skipped: Vec<String>,
+    pub size_before: usize,
+    pub size_after: usize,
+}
+
+// The debug attributes, plus those in `strip`, minus those in `keep`
+pub fn attribute_set(strip: &[String], keep: &[String]) -> Result<HashSet<String>, String> {
+    let mut names: HashSet<String> = DEBUG_ATTRIBUTES.iter().map(|s| s.to_string()).collect();
+    for name in strip {
+        if REQUIRED_ATTRIBUTES.contains(&name.as_str()) {
+            return Err(format!("{} attributes cannot be stripped", name));
+        }
+        names.insert(name.clone());
+    }
+    for name in keep {
+        names.remove(name);
+    }
+    Ok(names)
+}
+
+pub fn strip_class(class: &mut Class, names: &HashSet<String>) -> Result<(), String> {
+    strip_attributes(&mut class.attributes, names);
+    for field in class.fields.iter_mut().chain(class.methods.iter_mut()) {
+        strip_attributes(&mut field.attributes, names);
+    }
+    compact(class)
+}
+
+fn strip_attributes(attributes: &mut Vec<Attribute>, names: &HashSet<String>) {
+    attributes.retain(|attr| !names.contains(attr.name()));
+    for attr in attributes {
+        if let Attribute::Code { attributes, .. } = attr {
+            strip_attributes(attributes, names);
+        }
+    }
+}
+
+// Drop the constants nothing refers to any more, such as the names the
+// stripped attributes used. Left alone if an attribute we cannot walk may
+// still refer to some.
+fn compact(class: &mut Class) -> Result<(), String> {
+    let mut pending = vec![];
+    if !relocate::walk_members(class, &mut |index, _| pending.push(*index))? {
+        return Ok(());
+    }
+    let mut cp = class.const_pool.borrow_mut();
+    let count = cp.count();
+    let mut used = vec![false; count as usize];
+    while let Some(index) = pending.pop() {
+        if index == 0 || index >= count || used[index as usize] {
+            continue;
+        }
+        used[index as usize] = true;
+        if let Some(c) = cp.get_mut(index) {
+            relocate::walk_const(c, &mut |index, _| pending.push(*index));
+        }
+    }
+
+    let mut old = std::mem::take(&mut *cp);
+    let mut new_index = vec![0; count as usize];
+    for index in 1..count {
+        if !used[index as usize] {
+            continue;
+        }
+        new_index[index as usize] = cp.count();
+        let c = std::mem::replace(old.get_mut(index).unwrap(), Const::Unusable);
+        let wide = matches!(c, Const::Long(_) | Const::Double(_));
+        cp.push(c);
+        if wide {
+            cp.push(Const::Unusable);
+        }
+    }
+    drop(cp);
+    relocate::walk(class, &mut |index, _| {
+        if let Some(&new) = new_index.get(*index as usize) {
+            if new != 0 {
+                *index = new;
+            }
+        }
+    })
+}
+
+pub fn strip_jar(input: &str, output: &str, names: &HashSet<String>) -> Result<Summary, String> {
+    let mut summary = Summary::default();
+    jar::rewrite(input, output, |name, data| {
+        if !name.ends_with(".class") {
+            return None;
+        }
+        let stripped = Class::parse_with_limits(data, &Limits::default()).and_then(|mut class| {
+            strip_class(&mut class, names)?;
+            writer::write(&class)
+        });
+        match stripped {
+            Ok(stripped) => {
+                summary.classes += 1;
+                summary.size_before += data.len();
+                summary.size_after += stripped.len();
+                Some((name.to_string(), stripped))
+            }
+            Err(_) => {
+                summary.skipped.push(name.to_string());
+                None
+            }
+        }
+    })?;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::prelude::*;
+    use std::{cell::RefCell, rc::Rc};
+
+    use zip::write::SimpleFileOptions;
+    use zip::ZipWriter;
+
+    use super::*;
+    use crate::attribute::LineNumberTableEntry;
+    use crate::classfile::{Const, ConstPool};
+    use crate::classpath::ZipEntry;
+    use crate::loader::Field;
+
+    // Foo with a SourceFile and a method whose code has line numbers
+    fn class() -> Class {
+        let cp = Rc::new(RefCell::new(ConstPool::default()));
+        for c in [
+            Const::Utf8("Foo".to_string()),
+            Const::Class {
+                cp: Rc::downgrade(&cp),
+                name_index: 1,
+            },
+            Const::Utf8("java/lang/Object".to_string()),
+            Const::Class {
+                cp: Rc::downgrade(&cp),
+                name_index: 3,
+            },
+            Const::Utf8("f".to_string()),
+            Const::Utf8("()V".to_string()),
+            Const::Utf8("Foo.java".to_string()),
+        ] {
+            cp.borrow_mut().push(c);
+        }
+        let method = Field {
+            cp: cp.clone(),
+            flags: 0x0001,
+            name_index: 5,
+            descriptor_index: 6,
+            attributes: vec![Attribute::Code {
+                cp: cp.clone(),
+                max_stack: 0,
+                max_locals: 1,
+                code: vec![0xb1],
+                exception_table: vec![],
+                attributes: vec![Attribute::LineNumberTable {
+                    line_number_table: vec![LineNumberTableEntry::new(0, 3)],
+                }],
+            }],
+        };
+        Class {
+            major_version: 52,
+            const_pool: cp.clone(),
+            this_class: 2,
+            super_class: 4,
+            methods: vec![method],
+            attributes: vec![Attribute::SourceFile { cp, index: 7 }],
+            ..Default::default()
+        }
+    }
+
+    fn code_attributes(class: &Class) -> Vec<String> {
+        match &class.methods[0].attributes[0] {
+            Attribute::Code { attributes, .. } => {
+                attributes.iter().map(|a| a.name().to_string()).collect()
+            }
+            _ => panic!("expected Code"),
+        }
+    }
+
+    #[test]
+    fn test_strip_class() {
+        let mut c = class();
+        strip_class(
+            &mut c,
+            &attribute_set(&[], &["SourceFile".to_string()]).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(c.attributes[0].name(), "SourceFile");
+        assert!(code_attributes(&c).is_empty());
+        assert!(attribute_set(&["Code".to_string()], &[]).is_err());
+    }
+
+    #[test]
+    fn test_strip_jar() {
+        let dir = std::env::temp_dir().join("rustjvm-strip-test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("in.jar");
+        let output = dir.join("out.jar");
+        let mut jar = ZipWriter::new(File::create(&input).unwrap());
+        let options = SimpleFileOptions::default();
+        jar.start_file("META-INF/MANIFEST.MF", options).unwrap();
+        jar.write_all(b"Manifest-Version: 1.0\r\n").unwrap();
+        jar.start_file("META-INF/FOO.SF", options).unwrap();
+        jar.start_file("Foo.class", options).unwrap();
+        jar.write_all(&writer::write(&class()).unwrap()).unwrap();
+        jar.finish().unwrap();
+
+        let names = attribute_set(&[], &[]).unwrap();
+        let summary = strip_jar(input.to_str().unwrap(), output.to_str().unwrap(), &names).unwrap();
+        assert_eq!(summary.classes, 1);
+        assert!(summary.size_after < summary.size_before);
+
+        let mut archive = ZipEntry::new(output.to_str().unwrap().to_string())
+            .archive()
+            .unwrap();
+        assert_eq!(
+            archive.file_names().collect::<HashSet<_>>(),
+            HashSet::from(["META-INF/MANIFEST.MF", "Foo.class"])
+        );
+        let mut data = vec![];
+        archive
+            .by_name("Foo.class")
+            .unwrap()
+            .read_to_end(&mut data)
+            .unwrap();
+        let c = Class::parse(&data).unwrap();
+        assert!(c.attributes.is_empty());
+        assert!(code_attributes(&c).is_empty());
+        // The constants only the stripped attributes used go with them
+        assert!(!data.windows(8).any(|w| w == b"Foo.java"));
+        let cp = c.const_pool.borrow();
+        assert_eq!(cp.class_name(c.this_class), "Foo");
+        assert_eq!(cp.class_name(c.super_class), "java/lang/Object");
+        assert_eq!(cp.resolve(c.methods[0].name_index), "f");
+    }
+}
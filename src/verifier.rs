@@ -0,0 +1,1679 @@
+// Bytecode verification by type checking (JVMS 4.10.1). Every method is
+// checked instruction by instruction against the frames recorded in its
+// StackMapTable. Subtype questions are answered by loading the classes
+// involved through the class path. Class files older than version 50 go
+// through the type-inference verifier in `inference` instead.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::attribute::{Attribute, ExceptionTable, VerificationTypeInfo};
+use crate::bytecode::{self, Instruction, Operands};
+use crate::checker::{ACC_ABSTRACT, ACC_INTERFACE, ACC_NATIVE, ACC_PROTECTED, ACC_STATIC};
+use crate::classfile::{Const, ConstPool};
+use crate::classpath::ClassPath;
+use crate::descriptor::{self, FieldType};
+use crate::inference;
+use crate::loader::Class;
+
+const OBJECT: &str = "java/lang/Object";
+const THROWABLE: &str = "java/lang/Throwable";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VType {
+    Top,
+    Integer,
+    Float,
+    Long,
+    Double,
+    Null,
+    UninitializedThis,
+    // Result of the `new` instruction at the given offset
+    Uninitialized(usize),
+    // Class name or array descriptor, e.g. java/lang/String or [I
+    Reference(String),
+    // Pushed by jsr, identified by the subroutine it returns from
+    ReturnAddress(usize),
+}
+
+impl VType {
+    fn from_field_type(t: &FieldType) -> VType {
+        match t {
+            FieldType::Float => VType::Float,
+            FieldType::Long => VType::Long,
+            FieldType::Double => VType::Double,
+            FieldType::Object(name) => VType::Reference(name.clone()),
+            FieldType::Array(_) => VType::Reference(t.descriptor()),
+            _ => VType::Integer,
+        }
+    }
+
+    fn from_descriptor(desc: &str) -> Option<VType> {
+        descriptor::parse_field(desc).map(|t| VType::from_field_type(&t))
+    }
+
+    pub fn is_wide(&self) -> bool {
+        matches!(self, VType::Long | VType::Double)
+    }
+
+    fn is_reference(&self) -> bool {
+        matches!(
+            self,
+            VType::Null | VType::UninitializedThis | VType::Uninitialized(_) | VType::Reference(_)
+        )
+    }
+}
+
+impl fmt::Display for VType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VType::Top => write!(f, "top"),
+            VType::Integer => write!(f, "integer"),
+            VType::Float => write!(f, "float"),
+            VType::Long => write!(f, "long"),
+            VType::Double => write!(f, "double"),
+            VType::Null => write!(f, "null"),
+            VType::UninitializedThis => write!(f, "uninitializedThis"),
+            VType::Uninitialized(pc) => write!(f, "uninitialized({})", pc),
+            VType::Reference(name) => write!(f, "'{}'", name),
+            VType::ReturnAddress(_) => write!(f, "returnAddress"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    // One entry per slot; long and double are followed by Top
+    pub locals: Vec<VType>,
+    // One entry per value, whatever its size
+    pub stack: Vec<VType>,
+    // Set in a constructor until this() or super() has been called
+    pub this_uninit: bool,
+}
+
+impl Frame {
+    pub fn stack_size(&self) -> usize {
+        self.stack
+            .iter()
+            .map(|t| if t.is_wide() { 2 } else { 1 })
+            .sum()
+    }
+
+    fn pop(&mut self) -> Result<VType, String> {
+        self.stack
+            .pop()
+            .ok_or_else(|| "Attempt to pop empty stack.".to_string())
+    }
+
+    fn push(&mut self, t: VType) {
+        self.stack.push(t);
+    }
+
+    fn pop_category1(&mut self) -> Result<VType, String> {
+        let t = self.pop()?;
+        if t.is_wide() {
+            return Err(format!(
+                "Type {} (current frame, stack[{}]) is not a category 1 type",
+                t,
+                self.stack.len()
+            ));
+        }
+        Ok(t)
+    }
+
+    fn local(&self, index: usize) -> Result<&VType, String> {
+        self.locals
+            .get(index)
+            .ok_or_else(|| format!("Illegal local variable number {}", index))
+    }
+
+    fn store(&mut self, index: usize, t: VType) -> Result<(), String> {
+        let wide = t.is_wide();
+        if index + if wide { 1 } else { 0 } >= self.locals.len() {
+            return Err(format!("Illegal local variable number {}", index));
+        }
+        // Overwriting the second half of a long or double kills the first
+        if index > 0 && self.locals[index - 1].is_wide() {
+            self.locals[index - 1] = VType::Top;
+        }
+        self.locals[index] = t;
+        if wide {
+            self.locals[index + 1] = VType::Top;
+        }
+        Ok(())
+    }
+
+    // Replace an uninitialized type once its constructor has been called
+    fn initialize(&mut self, from: &VType, to: &VType) {
+        for t in self.locals.iter_mut().chain(self.stack.iter_mut()) {
+            if t == from {
+                *t = to.clone();
+            }
+        }
+        if *from == VType::UninitializedThis {
+            self.this_uninit = false;
+        }
+    }
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flags = if self.this_uninit {
+            " flagThisUninit "
+        } else {
+            " "
+        };
+        writeln!(f, "    flags: {{{}}}", flags)?;
+        // Trailing Top slots carry no information
+        let used = self
+            .locals
+            .iter()
+            .rposition(|t| *t != VType::Top)
+            .map_or(0, |i| i + 1);
+        let mut locals = vec![];
+        let mut i = 0;
+        while i < used {
+            let t = &self.locals[i];
+            locals.push(t.to_string());
+            if t.is_wide() {
+                locals.push(format!("{}_2nd", t));
+                i += 1;
+            }
+            i += 1;
+        }
+        let stack: Vec<String> = self.stack.iter().map(|t| t.to_string()).collect();
+        writeln!(f, "    locals: {{ {} }}", locals.join(", "))?;
+        write!(f, "    stack: {{ {} }}", stack.join(", "))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyError {
+    pub class: String,
+    // Method name and descriptor, e.g. main([Ljava/lang/String;)V
+    pub method: String,
+    pub pc: Option<usize>,
+    pub opcode: Option<u8>,
+    pub reason: String,
+    pub frame: Option<Box<Frame>>,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "java.lang.VerifyError: {}", self.reason)?;
+        writeln!(f, "  Location:")?;
+        write!(f, "    {}.{}", self.class.replace('/', "."), self.method)?;
+        if let Some(pc) = self.pc {
+            write!(f, " @{}", pc)?;
+        }
+        if let Some(opcode) = self.opcode {
+            write!(f, ": {}", bytecode::name(opcode))?;
+        }
+        if let Some(frame) = &self.frame {
+            writeln!(f)?;
+            writeln!(f, "  Current Frame:")?;
+            if let Some(pc) = self.pc {
+                writeln!(f, "    bci: @{}", pc)?;
+            }
+            write!(f, "{}", frame)?;
+        }
+        Ok(())
+    }
+}
+
+struct ClassInfo {
+    super_name: Option<String>,
+    interface: bool,
+    // Whether each field and method declared, by name and descriptor, is
+    // protected
+    members: HashMap<(String, String), bool>,
+}
+
+impl ClassInfo {
+    fn new(class: &Class) -> ClassInfo {
+        ClassInfo {
+            super_name: class.super_name(),
+            interface: class.flags & ACC_INTERFACE != 0,
+            members: class
+                .fields
+                .iter()
+                .chain(class.methods.iter())
+                .map(|m| {
+                    let protected = m.flags & ACC_PROTECTED != 0;
+                    ((m.name(), m.descriptor()), protected)
+                })
+                .collect(),
+        }
+    }
+}
+
+// Answers subtype queries, loading classes through the class path on demand
+pub struct Hierarchy<'a> {
+    classpath: &'a ClassPath,
+    cache: RefCell<HashMap<String, Option<ClassInfo>>>,
+}
+
+impl<'a> Hierarchy<'a> {
+    pub fn new(classpath: &'a ClassPath) -> Self {
+        Hierarchy {
+            classpath,
+            cache: RefCell::new(HashMap::new()),
+        }
+    }
+
+    fn add(&self, class: &Class) {
+        self.cache
+            .borrow_mut()
+            .insert(class.name(), Some(ClassInfo::new(class)));
+    }
+
+    fn with_info<T>(&self, name: &str, f: impl FnOnce(&ClassInfo) -> T) -> Result<T, String> {
+        if !self.cache.borrow().contains_key(name) {
+            let info = self
+                .classpath
+                .read_class(name)
+                .ok()
+                .and_then(|data| Class::parse(&data).ok())
+                .map(|class| ClassInfo::new(&class));
+            self.cache.borrow_mut().insert(name.to_string(), info);
+        }
+        match self.cache.borrow().get(name) {
+            Some(Some(info)) => Ok(f(info)),
+            _ => Err(format!(
+                "Could not load class {} to check assignability",
+                name
+            )),
+        }
+    }
+
+    pub fn is_interface(&self, name: &str) -> Result<bool, String> {
+        self.with_info(name, |info| info.interface)
+    }
+
+    pub fn super_name(&self, name: &str) -> Result<Option<String>, String> {
+        // No need to load the root of every hierarchy to know that
+        if name == OBJECT {
+            return Ok(None);
+        }
+        self.with_info(name, |info| info.super_name.clone())
+    }
+
+    // Whether `class` declares the member protected, None if it does not
+    // declare it
+    pub fn is_protected(
+        &self,
+        class: &str,
+        name: &str,
+        desc: &str,
+    ) -> Result<Option<bool>, String> {
+        self.with_info(class, |info| {
+            info.members
+                .get(&(name.to_string(), desc.to_string()))
+                .copied()
+        })
+    }
+
+    // Whether a value of class `from` may be used where `to` is expected
+    pub fn is_java_assignable(&self, from: &str, to: &str) -> Result<bool, String> {
+        if from == to || to == OBJECT {
+            return Ok(true);
+        }
+        if let Some(to_component) = to.strip_prefix('[') {
+            let Some(from_component) = from.strip_prefix('[') else {
+                return Ok(false);
+            };
+            return match (element_name(from_component), element_name(to_component)) {
+                (Some(f), Some(t)) => self.is_java_assignable(f, t),
+                _ => Ok(from_component == to_component),
+            };
+        }
+        if from.starts_with('[') {
+            return Ok(to == "java/lang/Cloneable" || to == "java/io/Serializable");
+        }
+        if self.is_interface(to)? {
+            return Ok(true);
+        }
+        let mut current = from.to_string();
+        while let Some(super_name) = self.super_name(&current)? {
+            if super_name == to {
+                return Ok(true);
+            }
+            current = super_name;
+        }
+        Ok(false)
+    }
+
+    pub fn is_assignable(&self, from: &VType, to: &VType) -> Result<bool, String> {
+        Ok(match (from, to) {
+            _ if from == to => true,
+            (_, VType::Top) => true,
+            (VType::Null, VType::Reference(_)) => true,
+            (VType::Reference(f), VType::Reference(t)) => self.is_java_assignable(f, t)?,
+            _ => false,
+        })
+    }
+
+    // Closest common superclass of two class names or array descriptors.
+    // Interfaces are treated like java/lang/Object, as the old verifier does.
+    pub fn common_super(&self, a: &str, b: &str) -> Result<String, String> {
+        if a == b {
+            return Ok(a.to_string());
+        }
+        if let (Some(ca), Some(cb)) = (a.strip_prefix('['), b.strip_prefix('[')) {
+            return Ok(match (element_name(ca), element_name(cb)) {
+                (Some(ea), Some(eb)) => {
+                    let element = self.common_super(ea, eb)?;
+                    if element.starts_with('[') {
+                        format!("[{}", element)
+                    } else {
+                        format!("[L{};", element)
+                    }
+                }
+                _ => OBJECT.to_string(),
+            });
+        }
+        if a.starts_with('[')
+            || b.starts_with('[')
+            || self.is_interface(a)?
+            || self.is_interface(b)?
+        {
+            return Ok(OBJECT.to_string());
+        }
+        let mut chain = vec![a.to_string()];
+        while let Some(super_name) = self.super_name(chain.last().unwrap())? {
+            chain.push(super_name);
+        }
+        let mut current = b.to_string();
+        loop {
+            if chain.contains(&current) {
+                return Ok(current);
+            }
+            match self.super_name(&current)? {
+                Some(super_name) => current = super_name,
+                None => return Ok(OBJECT.to_string()),
+            }
+        }
+    }
+
+    pub fn is_frame_assignable(&self, from: &Frame, to: &Frame) -> Result<bool, String> {
+        if from.locals.len() != to.locals.len()
+            || from.stack.len() != to.stack.len()
+            || (from.this_uninit && !to.this_uninit)
+        {
+            return Ok(false);
+        }
+        for (f, t) in from.locals.iter().zip(&to.locals) {
+            if !self.is_assignable(f, t)? {
+                return Ok(false);
+            }
+        }
+        for (f, t) in from.stack.iter().zip(&to.stack) {
+            if !self.is_assignable(f, t)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+fn package(class: &str) -> &str {
+    class.rsplit_once('/').map_or("", |(package, _)| package)
+}
+
+// The class name of a reference component descriptor, None for primitives
+fn element_name(component: &str) -> Option<&str> {
+    if component.starts_with('[') {
+        Some(component)
+    } else {
+        component.strip_prefix('L')?.strip_suffix(';')
+    }
+}
+
+// Everything the instruction rules need to know about the method
+pub struct MethodContext<'a> {
+    pub hierarchy: &'a Hierarchy<'a>,
+    pub cp: &'a ConstPool,
+    pub class_name: String,
+    pub super_name: Option<String>,
+    pub major_version: u16,
+    pub name: String,
+    pub descriptor: String,
+    pub is_static: bool,
+    pub code: &'a [u8],
+    pub max_stack: usize,
+    pub max_locals: usize,
+    pub exception_table: &'a [ExceptionTable],
+}
+
+impl MethodContext<'_> {
+    pub fn error(&self, pc: Option<usize>, reason: String, frame: Option<&Frame>) -> VerifyError {
+        VerifyError {
+            class: self.class_name.clone(),
+            method: format!("{}{}", self.name, self.descriptor),
+            pc,
+            opcode: pc.and_then(|pc| self.code.get(pc).copied()),
+            reason,
+            frame: frame.map(|f| Box::new(f.clone())),
+        }
+    }
+
+    pub fn initial_frame(&self) -> Result<Frame, String> {
+        let desc = descriptor::parse_method(&self.descriptor)
+            .ok_or_else(|| format!("Illegal method descriptor {}", self.descriptor))?;
+        let mut frame = Frame {
+            locals: vec![],
+            stack: vec![],
+            this_uninit: false,
+        };
+        if !self.is_static {
+            if self.name == "<init>" && self.class_name != OBJECT {
+                frame.locals.push(VType::UninitializedThis);
+                frame.this_uninit = true;
+            } else {
+                frame.locals.push(VType::Reference(self.class_name.clone()));
+            }
+        }
+        for param in &desc.params {
+            let t = VType::from_field_type(param);
+            let wide = t.is_wide();
+            frame.locals.push(t);
+            if wide {
+                frame.locals.push(VType::Top);
+            }
+        }
+        if frame.locals.len() > self.max_locals {
+            return Err("Arguments can't fit into locals".to_string());
+        }
+        frame.locals.resize(self.max_locals, VType::Top);
+        Ok(frame)
+    }
+
+    fn return_type(&self) -> Option<VType> {
+        descriptor::parse_method(&self.descriptor)?
+            .ret
+            .map(|t| VType::from_field_type(&t))
+    }
+
+    pub fn assignable(&self, from: &VType, to: &VType) -> Result<bool, String> {
+        self.hierarchy.is_assignable(from, to)
+    }
+
+    fn pop_expect(&self, frame: &mut Frame, expected: &VType) -> Result<VType, String> {
+        let t = frame.pop()?;
+        if !self.assignable(&t, expected)? {
+            return Err(format!(
+                "Type {} (current frame, stack[{}]) is not assignable to {}",
+                t,
+                frame.stack.len(),
+                expected
+            ));
+        }
+        Ok(t)
+    }
+
+    fn pop_reference(&self, frame: &mut Frame) -> Result<VType, String> {
+        let t = frame.pop()?;
+        if !t.is_reference() {
+            return Err(format!(
+                "Type {} (current frame, stack[{}]) is not a reference type",
+                t,
+                frame.stack.len()
+            ));
+        }
+        Ok(t)
+    }
+
+    // Pops an array reference whose components satisfy `accepts`
+    fn pop_array(
+        &self,
+        frame: &mut Frame,
+        accepts: impl Fn(&str) -> bool,
+    ) -> Result<Option<String>, String> {
+        let t = frame.pop()?;
+        match &t {
+            VType::Null => Ok(None),
+            VType::Reference(name) if name.starts_with('[') && accepts(&name[1..]) => {
+                Ok(Some(name[1..].to_string()))
+            }
+            _ => Err(format!(
+                "Type {} (current frame, stack[{}]) is not the expected array type",
+                t,
+                frame.stack.len()
+            )),
+        }
+    }
+
+    fn load(&self, frame: &mut Frame, index: usize, expected: &VType) -> Result<(), String> {
+        let t = frame.local(index)?.clone();
+        let valid = match expected {
+            VType::Reference(_) => t.is_reference(),
+            _ => t == *expected,
+        };
+        if !valid || (t.is_wide() && frame.local(index + 1)? != &VType::Top) {
+            return Err(format!(
+                "Bad local variable type: {} (current frame, locals[{}]) is not assignable to {}",
+                t, index, expected
+            ));
+        }
+        frame.push(t);
+        Ok(())
+    }
+
+    fn class_operand(&self, index: u16) -> Result<String, String> {
+        match self.cp.get(index) {
+            Some(Const::Class { name_index, .. }) => Ok(self.cp.resolve(*name_index)),
+            _ => Err(format!("Constant pool index {} is not a class", index)),
+        }
+    }
+
+    // Class, name and descriptor of a field or method reference
+    fn member_operand(&self, index: u16) -> Result<(String, String, String), String> {
+        match self.cp.get(index) {
+            Some(Const::FieldRef {
+                class_index,
+                name_and_type_index,
+                ..
+            })
+            | Some(Const::MethodRef {
+                class_index,
+                name_and_type_index,
+                ..
+            })
+            | Some(Const::InterfaceMethodRef {
+                class_index,
+                name_and_type_index,
+                ..
+            }) => {
+                let (name, desc) = self.cp.name_and_type(*name_and_type_index);
+                Ok((self.cp.class_name(*class_index), name, desc))
+            }
+            Some(Const::InvokeDynamic {
+                name_and_type_index,
+                ..
+            }) => {
+                let (name, desc) = self.cp.name_and_type(*name_and_type_index);
+                Ok((String::new(), name, desc))
+            }
+            _ => Err(format!("Illegal constant pool index {} for member", index)),
+        }
+    }
+
+    fn ldc_type(&self, index: u16, wide: bool) -> Result<VType, String> {
+        let t = match self.cp.get(index) {
+            Some(Const::Integer(_)) => VType::Integer,
+            Some(Const::Float(_)) => VType::Float,
+            Some(Const::Long(_)) => VType::Long,
+            Some(Const::Double(_)) => VType::Double,
+            Some(Const::String { .. }) => VType::Reference("java/lang/String".to_string()),
+            Some(Const::Class { .. }) if self.major_version >= 49 => {
+                VType::Reference("java/lang/Class".to_string())
+            }
+            Some(Const::MethodType { .. }) => {
+                VType::Reference("java/lang/invoke/MethodType".to_string())
+            }
+            Some(Const::MethodHandle { .. }) => {
+                VType::Reference("java/lang/invoke/MethodHandle".to_string())
+            }
+            Some(Const::Dynamic {
+                name_and_type_index,
+                ..
+            }) => {
+                let (_, desc) = self.cp.name_and_type(*name_and_type_index);
+                VType::from_descriptor(&desc)
+                    .ok_or_else(|| format!("Illegal dynamic constant descriptor {}", desc))?
+            }
+            _ => return Err(format!("Invalid index {} in ldc", index)),
+        };
+        if t.is_wide() != wide {
+            return Err(format!("Invalid type {} for ldc at index {}", t, index));
+        }
+        Ok(t)
+    }
+
+    // The class created by the `new` instruction at `pc`
+    fn new_class(&self, pc: usize) -> Result<String, String> {
+        match self.code.get(pc..pc + 3) {
+            Some([bytecode::NEW, hi, lo]) => self.class_operand(u16::from_be_bytes([*hi, *lo])),
+            _ => Err(format!("Expecting new instruction at {}", pc)),
+        }
+    }
+
+    pub fn verification_type(&self, info: &VerificationTypeInfo) -> Result<VType, String> {
+        Ok(match info {
+            VerificationTypeInfo::Top => VType::Top,
+            VerificationTypeInfo::Integer => VType::Integer,
+            VerificationTypeInfo::Float => VType::Float,
+            VerificationTypeInfo::Long => VType::Long,
+            VerificationTypeInfo::Double => VType::Double,
+            VerificationTypeInfo::Null => VType::Null,
+            VerificationTypeInfo::UninitializedThis => VType::UninitializedThis,
+            VerificationTypeInfo::Object(index) => VType::Reference(self.class_operand(*index)?),
+            VerificationTypeInfo::Uninitialized(pc) => {
+                self.new_class(*pc as usize)?;
+                VType::Uninitialized(*pc as usize)
+            }
+        })
+    }
+
+    // Apply the type rule of one instruction to `frame`. Branch targets
+    // and jsr/ret are left to the caller.
+    pub fn execute(&self, frame: &mut Frame, instr: &Instruction) -> Result<(), String> {
+        use bytecode::*;
+        let int = VType::Integer;
+        let opcode = instr.opcode;
+        match opcode {
+            NOP | GOTO | GOTO_W => {}
+            ACONST_NULL => frame.push(VType::Null),
+            ICONST_M1..=ICONST_5 | BIPUSH | SIPUSH => frame.push(int),
+            LCONST_0 | LCONST_1 => frame.push(VType::Long),
+            FCONST_0..=FCONST_2 => frame.push(VType::Float),
+            DCONST_0 | DCONST_1 => frame.push(VType::Double),
+            LDC | LDC_W | LDC2_W => {
+                let Operands::Constant(index) = instr.operands else {
+                    unreachable!()
+                };
+                frame.push(self.ldc_type(index, opcode == LDC2_W)?);
+            }
+            ILOAD..=ALOAD | ILOAD_0..=ALOAD_3 => {
+                let (kind, index) = match instr.operands {
+                    Operands::Local(index) => (opcode - ILOAD, index as usize),
+                    _ => ((opcode - ILOAD_0) / 4, ((opcode - ILOAD_0) % 4) as usize),
+                };
+                let expected = local_type(kind);
+                self.load(frame, index, &expected)?;
+            }
+            ISTORE..=ASTORE | ISTORE_0..=ASTORE_3 => {
+                let (kind, index) = match instr.operands {
+                    Operands::Local(index) => (opcode - ISTORE, index as usize),
+                    _ => ((opcode - ISTORE_0) / 4, ((opcode - ISTORE_0) % 4) as usize),
+                };
+                let t = if kind == 4 {
+                    // Only the type-inference verifier ever sees return addresses
+                    match frame.stack.last() {
+                        Some(VType::ReturnAddress(_)) => frame.pop()?,
+                        _ => self.pop_reference(frame)?,
+                    }
+                } else {
+                    self.pop_expect(frame, &local_type(kind))?
+                };
+                frame.store(index, t)?;
+            }
+            IALOAD..=SALOAD => {
+                self.pop_expect(frame, &int)?;
+                let component = array_component(opcode - IALOAD);
+                let element = self.pop_array(frame, |c| match component {
+                    Some(expected) => expected.contains(&c),
+                    None => c.starts_with('L') || c.starts_with('['),
+                })?;
+                frame.push(match opcode {
+                    LALOAD => VType::Long,
+                    FALOAD => VType::Float,
+                    DALOAD => VType::Double,
+                    AALOAD => match element {
+                        Some(c) => VType::from_descriptor(&c).unwrap_or(VType::Top),
+                        None => VType::Null,
+                    },
+                    _ => VType::Integer,
+                });
+            }
+            IASTORE..=SASTORE => {
+                let component = array_component(opcode - IASTORE);
+                let value = match opcode {
+                    AASTORE => self.pop_reference(frame)?,
+                    BASTORE | CASTORE | SASTORE => self.pop_expect(frame, &int)?,
+                    _ => self.pop_expect(frame, &local_type(opcode - IASTORE))?,
+                };
+                if matches!(value, VType::Uninitialized(_) | VType::UninitializedThis) {
+                    return Err(format!(
+                        "Type {} is not assignable to reference type",
+                        value
+                    ));
+                }
+                self.pop_expect(frame, &int)?;
+                self.pop_array(frame, |c| match component {
+                    Some(expected) => expected.contains(&c),
+                    None => c.starts_with('L') || c.starts_with('['),
+                })?;
+            }
+            POP => {
+                frame.pop_category1()?;
+            }
+            POP2 => {
+                if !frame.pop()?.is_wide() {
+                    frame.pop_category1()?;
+                }
+            }
+            DUP => {
+                let v = frame.pop_category1()?;
+                frame.push(v.clone());
+                frame.push(v);
+            }
+            DUP_X1 => {
+                let v1 = frame.pop_category1()?;
+                let v2 = frame.pop_category1()?;
+                frame.stack.extend([v1.clone(), v2, v1]);
+            }
+            DUP_X2 => {
+                let v1 = frame.pop_category1()?;
+                let v2 = frame.pop()?;
+                if v2.is_wide() {
+                    frame.stack.extend([v1.clone(), v2, v1]);
+                } else {
+                    let v3 = frame.pop_category1()?;
+                    frame.stack.extend([v1.clone(), v3, v2, v1]);
+                }
+            }
+            DUP2 => {
+                let v1 = frame.pop()?;
+                if v1.is_wide() {
+                    frame.stack.extend([v1.clone(), v1]);
+                } else {
+                    let v2 = frame.pop_category1()?;
+                    frame.stack.extend([v2.clone(), v1.clone(), v2, v1]);
+                }
+            }
+            DUP2_X1 => {
+                let v1 = frame.pop()?;
+                if v1.is_wide() {
+                    let v2 = frame.pop_category1()?;
+                    frame.stack.extend([v1.clone(), v2, v1]);
+                } else {
+                    let v2 = frame.pop_category1()?;
+                    let v3 = frame.pop_category1()?;
+                    frame.stack.extend([v2.clone(), v1.clone(), v3, v2, v1]);
+                }
+            }
+            DUP2_X2 => {
+                let v1 = frame.pop()?;
+                let v2 = frame.pop()?;
+                match (v1.is_wide(), v2.is_wide()) {
+                    (true, true) => frame.stack.extend([v1.clone(), v2, v1]),
+                    (true, false) => {
+                        let v3 = frame.pop_category1()?;
+                        frame.stack.extend([v1.clone(), v3, v2, v1]);
+                    }
+                    (false, false) => {
+                        let v3 = frame.pop()?;
+                        if v3.is_wide() {
+                            frame.stack.extend([v2.clone(), v1.clone(), v3, v2, v1]);
+                        } else {
+                            let v4 = frame.pop_category1()?;
+                            frame.stack.extend([v2.clone(), v1.clone(), v4, v3, v2, v1]);
+                        }
+                    }
+                    (false, true) => return Err("Bad type on operand stack in dup2_x2".to_string()),
+                }
+            }
+            SWAP => {
+                let v1 = frame.pop_category1()?;
+                let v2 = frame.pop_category1()?;
+                frame.stack.extend([v1, v2]);
+            }
+            IADD..=DREM | ISHL..=LXOR => {
+                let t = if opcode <= DREM {
+                    local_type((opcode - IADD) % 4)
+                } else {
+                    local_type((opcode - ISHL) % 2)
+                };
+                // Shift distances are always int
+                let rhs = if (ISHL..=LUSHR).contains(&opcode) {
+                    VType::Integer
+                } else {
+                    t.clone()
+                };
+                self.pop_expect(frame, &rhs)?;
+                self.pop_expect(frame, &t)?;
+                frame.push(t);
+            }
+            INEG..=DNEG => {
+                let t = local_type(opcode - INEG);
+                self.pop_expect(frame, &t)?;
+                frame.push(t);
+            }
+            IINC => {
+                let Operands::Iinc(index, _) = instr.operands else {
+                    unreachable!()
+                };
+                if frame.local(index as usize)? != &VType::Integer {
+                    return Err(format!(
+                        "Bad local variable type: locals[{}] is not an integer",
+                        index
+                    ));
+                }
+            }
+            I2L..=I2S => {
+                let (from, to) = match opcode {
+                    I2L => (0, 1),
+                    I2F => (0, 2),
+                    I2D => (0, 3),
+                    L2I => (1, 0),
+                    L2F => (1, 2),
+                    L2D => (1, 3),
+                    F2I => (2, 0),
+                    F2L => (2, 1),
+                    F2D => (2, 3),
+                    D2I => (3, 0),
+                    D2L => (3, 1),
+                    D2F => (3, 2),
+                    _ => (0, 0),
+                };
+                self.pop_expect(frame, &local_type(from))?;
+                frame.push(local_type(to));
+            }
+            LCMP..=DCMPG => {
+                let t = match opcode {
+                    LCMP => VType::Long,
+                    FCMPL | FCMPG => VType::Float,
+                    _ => VType::Double,
+                };
+                self.pop_expect(frame, &t)?;
+                self.pop_expect(frame, &t)?;
+                frame.push(int);
+            }
+            IFEQ..=IFLE | TABLESWITCH | LOOKUPSWITCH => {
+                if let Operands::LookupSwitch { pairs, .. } = &instr.operands {
+                    if pairs.windows(2).any(|w| w[0].0 >= w[1].0) {
+                        return Err("Bad lookupswitch instruction".to_string());
+                    }
+                }
+                self.pop_expect(frame, &int)?;
+            }
+            IF_ICMPEQ..=IF_ICMPLE => {
+                self.pop_expect(frame, &int)?;
+                self.pop_expect(frame, &int)?;
+            }
+            IF_ACMPEQ | IF_ACMPNE => {
+                self.pop_reference(frame)?;
+                self.pop_reference(frame)?;
+            }
+            IFNULL | IFNONNULL | MONITORENTER | MONITOREXIT => {
+                self.pop_reference(frame)?;
+            }
+            IRETURN..=RETURN => match (self.return_type(), opcode) {
+                (None, RETURN) => {
+                    if frame.this_uninit {
+                        return Err(
+                            "Constructor must call super() or this() before return".to_string()
+                        );
+                    }
+                }
+                (None, _) => return Err("Method does not expect a return value".to_string()),
+                (Some(_), RETURN) => return Err("Method expects a return value".to_string()),
+                (Some(t), _) => {
+                    let matches = if opcode == ARETURN {
+                        t.is_reference()
+                    } else {
+                        t == local_type(opcode - IRETURN)
+                    };
+                    if !matches {
+                        return Err("Bad return type".to_string());
+                    }
+                    self.pop_expect(frame, &t)?;
+                }
+            },
+            GETSTATIC..=PUTFIELD => {
+                let Operands::Constant(index) = instr.operands else {
+                    unreachable!()
+                };
+                let (class, name, desc) = self.member_operand(index)?;
+                let t = VType::from_descriptor(&desc)
+                    .ok_or_else(|| format!("Illegal field descriptor {} for {}", desc, name))?;
+                let owner = VType::Reference(class.clone());
+                match opcode {
+                    GETSTATIC => frame.push(t),
+                    PUTSTATIC => {
+                        self.pop_expect(frame, &t)?;
+                    }
+                    GETFIELD => {
+                        let target = self.pop_expect(frame, &owner)?;
+                        self.protected_check(opcode, &class, &name, &desc, &target)?;
+                        frame.push(t);
+                    }
+                    _ => {
+                        self.pop_expect(frame, &t)?;
+                        // Fields of this class may be set before super() is called
+                        let target = frame.pop()?;
+                        let early_store =
+                            target == VType::UninitializedThis && class == self.class_name;
+                        if !early_store && !self.assignable(&target, &owner)? {
+                            return Err(format!(
+                                "Type {} (current frame, stack[{}]) is not assignable to {}",
+                                target,
+                                frame.stack.len(),
+                                owner
+                            ));
+                        }
+                        self.protected_check(opcode, &class, &name, &desc, &target)?;
+                    }
+                }
+            }
+            INVOKEVIRTUAL..=INVOKEDYNAMIC => self.invoke(frame, instr)?,
+            NEW => {
+                let Operands::Constant(index) = instr.operands else {
+                    unreachable!()
+                };
+                if self.class_operand(index)?.starts_with('[') {
+                    return Err("Illegal new instruction on an array class".to_string());
+                }
+                if frame.stack.contains(&VType::Uninitialized(instr.pc))
+                    || frame.locals.contains(&VType::Uninitialized(instr.pc))
+                {
+                    return Err(format!(
+                        "Uninitialized object at {} already exists",
+                        instr.pc
+                    ));
+                }
+                frame.push(VType::Uninitialized(instr.pc));
+            }
+            NEWARRAY => {
+                let Operands::Immediate(atype) = instr.operands else {
+                    unreachable!()
+                };
+                let desc = match atype {
+                    4 => "[Z",
+                    5 => "[C",
+                    6 => "[F",
+                    7 => "[D",
+                    8 => "[B",
+                    9 => "[S",
+                    10 => "[I",
+                    11 => "[J",
+                    _ => return Err(format!("Illegal newarray type {}", atype)),
+                };
+                self.pop_expect(frame, &int)?;
+                frame.push(VType::Reference(desc.to_string()));
+            }
+            ANEWARRAY => {
+                let Operands::Constant(index) = instr.operands else {
+                    unreachable!()
+                };
+                let class = self.class_operand(index)?;
+                self.pop_expect(frame, &int)?;
+                let desc = if class.starts_with('[') {
+                    format!("[{}", class)
+                } else {
+                    format!("[L{};", class)
+                };
+                if desc.bytes().take_while(|b| *b == b'[').count() > 255 {
+                    return Err("Array with too many dimensions".to_string());
+                }
+                frame.push(VType::Reference(desc));
+            }
+            MULTIANEWARRAY => {
+                let Operands::MultiANewArray(index, dimensions) = instr.operands else {
+                    unreachable!()
+                };
+                let class = self.class_operand(index)?;
+                let depth = class.bytes().take_while(|b| *b == b'[').count();
+                if dimensions == 0 || depth < dimensions as usize {
+                    return Err("Illegal dimension in multianewarray".to_string());
+                }
+                for _ in 0..dimensions {
+                    self.pop_expect(frame, &int)?;
+                }
+                frame.push(VType::Reference(class));
+            }
+            ARRAYLENGTH => {
+                self.pop_array(frame, |_| true)?;
+                frame.push(int);
+            }
+            ATHROW => {
+                self.pop_expect(frame, &VType::Reference(THROWABLE.to_string()))?;
+            }
+            CHECKCAST | INSTANCEOF => {
+                let Operands::Constant(index) = instr.operands else {
+                    unreachable!()
+                };
+                let class = self.class_operand(index)?;
+                self.pop_expect(frame, &VType::Reference(OBJECT.to_string()))?;
+                frame.push(if opcode == CHECKCAST {
+                    VType::Reference(class)
+                } else {
+                    int
+                });
+            }
+            _ => {
+                return Err(format!(
+                    "Bad instruction: {} is not allowed here",
+                    bytecode::name(opcode)
+                ))
+            }
+        }
+        if frame.stack_size() > self.max_stack {
+            return Err("Operand stack overflow".to_string());
+        }
+        Ok(())
+    }
+
+    fn invoke(&self, frame: &mut Frame, instr: &Instruction) -> Result<(), String> {
+        let opcode = instr.opcode;
+        let index = match instr.operands {
+            Operands::Constant(index) | Operands::InvokeInterface(index, _) => index,
+            _ => unreachable!(),
+        };
+        let (class, name, desc) = self.member_operand(index)?;
+        let parsed = descriptor::parse_method(&desc)
+            .ok_or_else(|| format!("Illegal method descriptor {} for {}", desc, name))?;
+        if let Operands::InvokeInterface(_, count) = instr.operands {
+            if count as usize != parsed.param_slots() + 1 {
+                return Err("Inconsistent args count operand in invokeinterface".to_string());
+            }
+        }
+        if name.starts_with('<') && (opcode != bytecode::INVOKESPECIAL || name != "<init>") {
+            return Err(format!("Illegal call to internal method {}", name));
+        }
+        for param in parsed.params.iter().rev() {
+            self.pop_expect(frame, &VType::from_field_type(param))?;
+        }
+        let owner = VType::Reference(class.clone());
+        match opcode {
+            bytecode::INVOKESPECIAL if name == "<init>" => {
+                let receiver = frame.pop()?;
+                let initialized = match &receiver {
+                    VType::UninitializedThis => {
+                        if class != self.class_name && Some(&class) != self.super_name.as_ref() {
+                            return Err(format!("Bad <init> method call on {}", class));
+                        }
+                        VType::Reference(self.class_name.clone())
+                    }
+                    VType::Uninitialized(pc) => {
+                        if self.new_class(*pc)? != class {
+                            return Err(format!("Call to wrong <init> method on {}", class));
+                        }
+                        owner
+                    }
+                    _ => {
+                        return Err(format!(
+                            "Type {} (current frame, stack[{}]) is not an uninitialized object",
+                            receiver,
+                            frame.stack.len()
+                        ))
+                    }
+                };
+                frame.initialize(&receiver, &initialized);
+            }
+            bytecode::INVOKESPECIAL => {
+                self.pop_expect(frame, &VType::Reference(self.class_name.clone()))?;
+            }
+            bytecode::INVOKEVIRTUAL => {
+                let target = self.pop_expect(frame, &owner)?;
+                self.protected_check(opcode, &class, &name, &desc, &target)?;
+            }
+            bytecode::INVOKEINTERFACE => {
+                self.pop_expect(frame, &owner)?;
+            }
+            _ => {}
+        }
+        if let Some(ret) = parsed.ret {
+            frame.push(VType::from_field_type(&ret));
+        }
+        Ok(())
+    }
+
+    // JVMS 4.10.1.8: a protected member of a superclass in another package
+    // may only be used on this class or its subclasses
+    fn protected_check(
+        &self,
+        opcode: u8,
+        class: &str,
+        name: &str,
+        desc: &str,
+        target: &VType,
+    ) -> Result<(), String> {
+        if class == self.class_name || class.starts_with('[') {
+            return Ok(());
+        }
+        let mut chain = vec![];
+        let mut current = self.super_name.clone();
+        while let Some(super_name) = current {
+            current = self.hierarchy.super_name(&super_name)?;
+            chain.push(super_name);
+        }
+        if !chain.iter().any(|c| c == class) {
+            return Ok(());
+        }
+        let mut others = false;
+        for c in &chain {
+            if package(c) != package(&self.class_name)
+                && self.hierarchy.is_protected(c, name, desc)? == Some(true)
+            {
+                others = true;
+                break;
+            }
+        }
+        if !others || self.hierarchy.is_protected(class, name, desc)? == Some(false) {
+            return Ok(());
+        }
+        if !self.assignable(target, &VType::Reference(self.class_name.clone()))? {
+            return Err(format!(
+                "Bad access to protected data in {}",
+                bytecode::name(opcode)
+            ));
+        }
+        Ok(())
+    }
+
+    // Frame on entry to each exception handler covering `pc`
+    pub fn handler_frames(&self, pc: usize, frame: &Frame) -> Result<Vec<(usize, Frame)>, String> {
+        let mut frames = vec![];
+        for entry in self.exception_table {
+            if pc < entry.start_pc as usize || pc >= entry.end_pc as usize {
+                continue;
+            }
+            let catch_type = match entry.catch_type {
+                0 => THROWABLE.to_string(),
+                index => self.class_operand(index)?,
+            };
+            if !self.hierarchy.is_java_assignable(&catch_type, THROWABLE)? {
+                return Err(format!(
+                    "Catch type {} is not a subclass of Throwable",
+                    catch_type
+                ));
+            }
+            frames.push((
+                entry.handler_pc as usize,
+                Frame {
+                    locals: frame.locals.clone(),
+                    stack: vec![VType::Reference(catch_type)],
+                    this_uninit: frame.this_uninit,
+                },
+            ));
+        }
+        Ok(frames)
+    }
+}
+
+// Operand kind of typed instructions, in opcode order i, l, f, d, a
+fn local_type(kind: u8) -> VType {
+    match kind {
+        0 => VType::Integer,
+        1 => VType::Long,
+        2 => VType::Float,
+        3 => VType::Double,
+        _ => VType::Reference(OBJECT.to_string()),
+    }
+}
+
+// Accepted component descriptors of typed array instructions, in opcode
+// order i, l, f, d, a, b, c, s. None stands for any reference component.
+fn array_component(kind: u8) -> Option<&'static [&'static str]> {
+    match kind {
+        0 => Some(&["I"]),
+        1 => Some(&["J"]),
+        2 => Some(&["F"]),
+        3 => Some(&["D"]),
+        5 => Some(&["B", "Z"]),
+        6 => Some(&["C"]),
+        7 => Some(&["S"]),
+        _ => None,
+    }
+}
+
+// Verify every method of a class. Version 50 class files that fail type
+// checking get a second chance with type inference, like HotSpot's failover.
+pub fn verify(class: &Class, classpath: &ClassPath) -> Result<(), VerifyError> {
+    let hierarchy = Hierarchy::new(classpath);
+    hierarchy.add(class);
+    let cp = class.const_pool.borrow();
+    for method in &class.methods {
+        if method.flags & (ACC_ABSTRACT | ACC_NATIVE) != 0 {
+            continue;
+        }
+        let Some(Attribute::Code {
+            max_stack,
+            max_locals,
+            code,
+            exception_table,
+            attributes,
+            ..
+        }) = method
+            .attributes
+            .iter()
+            .find(|a| matches!(a, Attribute::Code { .. }))
+        else {
+            continue;
+        };
+        let ctx = MethodContext {
+            hierarchy: &hierarchy,
+            cp: &cp,
+            class_name: class.name(),
+            super_name: class.super_name(),
+            major_version: class.major_version,
+            name: method.name(),
+            descriptor: method.descriptor(),
+            is_static: method.flags & ACC_STATIC != 0,
+            code,
+            max_stack: *max_stack as usize,
+            max_locals: *max_locals as usize,
+            exception_table,
+        };
+        match class.major_version {
+            0..=49 => inference::infer(&ctx)?,
+            50 => {
+                if type_check(&ctx, attributes).is_err() {
+                    inference::infer(&ctx)?;
+                }
+            }
+            _ => type_check(&ctx, attributes)?,
+        }
+    }
+    Ok(())
+}
+
+// Expand the StackMapTable of a method into full frames keyed by offset
+fn stack_map_frames(
+    ctx: &MethodContext,
+    initial: &Frame,
+    attributes: &[Attribute],
+) -> Result<HashMap<usize, Frame>, String> {
+    let mut frames = HashMap::new();
+    let Some(Attribute::StackMapTable { entries }) = attributes
+        .iter()
+        .find(|a| matches!(a, Attribute::StackMapTable { .. }))
+    else {
+        return Ok(frames);
+    };
+    // Frames in the table list long and double once, without their Top
+    let mut locals: Vec<VType> = vec![];
+    let mut i = 0;
+    while i < initial.locals.len() {
+        let t = &initial.locals[i];
+        if *t == VType::Top && initial.locals[i..].iter().all(|t| *t == VType::Top) {
+            break;
+        }
+        locals.push(t.clone());
+        i += if t.is_wide() { 2 } else { 1 };
+    }
+
+    let mut offset: Option<usize> = None;
+    for entry in entries {
+        let pc = match offset {
+            None => entry.offset_delta as usize,
+            Some(prev) => prev + entry.offset_delta as usize + 1,
+        };
+        offset = Some(pc);
+        let mut stack = vec![];
+        match entry.frame_type {
+            0..=63 | 251 => {}
+            64..=127 | 247 => stack.push(ctx.verification_type(&entry.stack[0])?),
+            248..=250 => {
+                let k = 251 - entry.frame_type as usize;
+                if k > locals.len() {
+                    return Err(format!("StackMapTable error: bad chop frame at {}", pc));
+                }
+                locals.truncate(locals.len() - k);
+            }
+            252..=254 => {
+                for info in &entry.locals {
+                    locals.push(ctx.verification_type(info)?);
+                }
+            }
+            _ => {
+                locals = vec![];
+                for info in &entry.locals {
+                    locals.push(ctx.verification_type(info)?);
+                }
+                for info in &entry.stack {
+                    stack.push(ctx.verification_type(info)?);
+                }
+            }
+        }
+        let mut frame = Frame {
+            locals: vec![],
+            stack,
+            this_uninit: locals.contains(&VType::UninitializedThis),
+        };
+        for t in &locals {
+            frame.locals.push(t.clone());
+            if t.is_wide() {
+                frame.locals.push(VType::Top);
+            }
+        }
+        if frame.locals.len() > ctx.max_locals {
+            return Err(format!("StackMapTable error: local size too big at {}", pc));
+        }
+        if frame.stack_size() > ctx.max_stack {
+            return Err(format!("StackMapTable error: stack size too big at {}", pc));
+        }
+        frame.locals.resize(ctx.max_locals, VType::Top);
+        frames.insert(pc, frame);
+    }
+    Ok(frames)
+}
+
+fn type_check(ctx: &MethodContext, attributes: &[Attribute]) -> Result<(), VerifyError> {
+    let initial = ctx.initial_frame().map_err(|e| ctx.error(None, e, None))?;
+    let frames =
+        stack_map_frames(ctx, &initial, attributes).map_err(|e| ctx.error(None, e, None))?;
+    let instructions = bytecode::decode(ctx.code).map_err(|e| ctx.error(None, e, None))?;
+    let starts: Vec<usize> = instructions.iter().map(|i| i.pc).collect();
+    for pc in frames.keys() {
+        if starts.binary_search(pc).is_err() {
+            return Err(ctx.error(
+                None,
+                format!("StackMapTable error: bad offset {}", pc),
+                None,
+            ));
+        }
+    }
+    let check_target = |target: i32, frame: &Frame, pc: usize| -> Result<(), VerifyError> {
+        let target = usize::try_from(target)
+            .ok()
+            .filter(|t| starts.binary_search(t).is_ok())
+            .ok_or_else(|| {
+                ctx.error(
+                    Some(pc),
+                    "Illegal target of jump or branch".to_string(),
+                    Some(frame),
+                )
+            })?;
+        let Some(expected) = frames.get(&target) else {
+            return Err(ctx.error(
+                Some(pc),
+                format!("Expecting a stackmap frame at branch target {}", target),
+                Some(frame),
+            ));
+        };
+        let assignable = ctx
+            .hierarchy
+            .is_frame_assignable(frame, expected)
+            .map_err(|e| ctx.error(Some(pc), e, Some(frame)))?;
+        if !assignable {
+            return Err(ctx.error(
+                Some(pc),
+                format!("Inconsistent stackmap frames at branch target {}", target),
+                Some(frame),
+            ));
+        }
+        Ok(())
+    };
+
+    let mut current = Some(initial);
+    for instr in &instructions {
+        let pc = instr.pc;
+        let mut frame = match (current.take(), frames.get(&pc)) {
+            (Some(frame), Some(expected)) => {
+                check_target(pc as i32, &frame, pc)?;
+                expected.clone()
+            }
+            (None, Some(expected)) => expected.clone(),
+            (Some(frame), None) => frame,
+            (None, None) => {
+                return Err(ctx.error(
+                    Some(pc),
+                    "Expected stackmap frame at this location.".to_string(),
+                    None,
+                ))
+            }
+        };
+        if matches!(
+            instr.opcode,
+            bytecode::JSR | bytecode::JSR_W | bytecode::RET
+        ) {
+            return Err(ctx.error(
+                Some(pc),
+                "Illegal instruction found: jsr and ret require the type-inference verifier"
+                    .to_string(),
+                Some(&frame),
+            ));
+        }
+        let handlers = ctx
+            .handler_frames(pc, &frame)
+            .map_err(|e| ctx.error(Some(pc), e, Some(&frame)))?;
+        for (handler, handler_frame) in handlers {
+            check_target(handler as i32, &handler_frame, pc)?;
+        }
+        let before = frame.clone();
+        ctx.execute(&mut frame, instr)
+            .map_err(|e| ctx.error(Some(pc), e, Some(&before)))?;
+        for target in instr.targets() {
+            check_target(target, &frame, pc)?;
+        }
+        current = if instr.falls_through() {
+            Some(frame)
+        } else {
+            None
+        };
+    }
+    if current.is_some() {
+        return Err(ctx.error(
+            instructions.last().map(|i| i.pc),
+            "Falling off the end of the code".to_string(),
+            None,
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, fs, rc::Rc};
+
+    use super::*;
+    use crate::loader::Field;
+    use crate::visitor::{ClassVisitor, Header};
+    use crate::writer::ClassWriter;
+
+    fn classpath() -> ClassPath {
+        let jre = std::env::temp_dir().join("rustjvm-verifier-test");
+        fs::create_dir_all(jre.join("lib/ext")).unwrap();
+        let jre = jre.to_str().unwrap().to_string();
+        ClassPath::new(Some(jre.clone()), jre).unwrap()
+    }
+
+    // Foo with a single method `static f(I)I` running `code`
+    fn class(code: Vec<u8>) -> Class {
+        let cp = Rc::new(RefCell::new(ConstPool::default()));
+        for c in [
+            Const::Utf8("Foo".to_string()),
+            Const::Class {
+                cp: Rc::downgrade(&cp),
+                name_index: 1,
+            },
+            Const::Utf8("java/lang/Object".to_string()),
+            Const::Class {
+                cp: Rc::downgrade(&cp),
+                name_index: 3,
+            },
+            Const::Utf8("f".to_string()),
+            Const::Utf8("(I)I".to_string()),
+        ] {
+            cp.borrow_mut().push(c);
+        }
+        let method = Field {
+            cp: cp.clone(),
+            flags: ACC_STATIC,
+            name_index: 5,
+            descriptor_index: 6,
+            attributes: vec![Attribute::Code {
+                cp: cp.clone(),
+                max_stack: 2,
+                max_locals: 1,
+                code,
+                exception_table: vec![],
+                attributes: vec![],
+            }],
+        };
+        Class {
+            major_version: 52,
+            const_pool: cp,
+            this_class: 2,
+            super_class: 4,
+            methods: vec![method],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_valid_method() {
+        // iload_0; iconst_1; iadd; ireturn
+        let class = class(vec![0x1a, 0x04, 0x60, 0xac]);
+        assert_eq!(verify(&class, &classpath()), Ok(()));
+    }
+
+    #[test]
+    fn test_bad_return_type() {
+        // aconst_null; ireturn
+        let class = class(vec![0x01, 0xac]);
+        let e = verify(&class, &classpath()).unwrap_err();
+        assert_eq!(e.method, "f(I)I");
+        assert_eq!(e.pc, Some(1));
+        assert_eq!(
+            e.reason,
+            "Type null (current frame, stack[0]) is not assignable to integer"
+        );
+        assert_eq!(e.frame.unwrap().stack, vec![VType::Null]);
+    }
+
+    #[test]
+    fn test_missing_stack_map_frame() {
+        // iload_0; ifeq 5; iconst_1; ireturn; iconst_0; ireturn
+        let class = class(vec![0x1a, 0x99, 0x00, 0x04, 0x04, 0xac, 0x03, 0xac]);
+        let e = verify(&class, &classpath()).unwrap_err();
+        assert_eq!(e.pc, Some(1));
+        assert_eq!(e.reason, "Expecting a stackmap frame at branch target 5");
+    }
+
+    #[test]
+    fn test_stack_overflow() {
+        // iconst_0; iconst_0; iconst_0; ireturn
+        let class = class(vec![0x03, 0x03, 0x03, 0xac]);
+        let e = verify(&class, &classpath()).unwrap_err();
+        assert_eq!(e.pc, Some(2));
+        assert_eq!(e.reason, "Operand stack overflow");
+    }
+
+    #[test]
+    fn test_falling_off_end() {
+        // iload_0; pop
+        let class = class(vec![0x1a, 0x57]);
+        let e = verify(&class, &classpath()).unwrap_err();
+        assert_eq!(e.reason, "Falling off the end of the code");
+    }
+
+    // The same class as a pre-Java 6 file, verified by type inference
+    fn old_class(code: Vec<u8>, locals: u16) -> Class {
+        let mut class = class(code);
+        class.major_version = 49;
+        if let Attribute::Code { max_locals, .. } = &mut class.methods[0].attributes[0] {
+            *max_locals = locals;
+        }
+        class
+    }
+
+    #[test]
+    fn test_inference_branch() {
+        // iload_0; ifeq 6; iconst_1; ireturn; iconst_0; ireturn
+        let class = old_class(vec![0x1a, 0x99, 0x00, 0x05, 0x04, 0xac, 0x03, 0xac], 1);
+        assert_eq!(verify(&class, &classpath()), Ok(()));
+    }
+
+    #[test]
+    fn test_inference_subroutine() {
+        // jsr 5; iload_0; ireturn; astore_1; ret 1
+        let class = old_class(vec![0xa8, 0x00, 0x05, 0x1a, 0xac, 0x4c, 0xa9, 0x01], 2);
+        assert_eq!(verify(&class, &classpath()), Ok(()));
+    }
+
+    #[test]
+    fn test_inference_mismatched_stack() {
+        // iload_0; ifeq 8; fconst_0; goto 9; iconst_0; ireturn
+        let class = old_class(
+            vec![0x1a, 0x99, 0x00, 0x07, 0x0b, 0xa7, 0x00, 0x04, 0x03, 0xac],
+            1,
+        );
+        let e = verify(&class, &classpath()).unwrap_err();
+        assert_eq!(e.reason, "Mismatched stack types at 9");
+    }
+
+    #[test]
+    fn test_inference_bad_ret() {
+        // ret 0
+        let class = old_class(vec![0xa9, 0x00], 1);
+        let e = verify(&class, &classpath()).unwrap_err();
+        assert_eq!(e.pc, Some(0));
+        assert_eq!(e.reason, "Expecting a returnAddress in local 0");
+    }
+
+    // q/Foo extends p/Base, whose field `protected int x` it reads in
+    // `static f(<param>)I` through a reference of type `param`
+    fn subclass(param: &str) -> Class {
+        let mut cw = ClassWriter::new();
+        cw.visit(Header {
+            minor_version: 0,
+            major_version: 52,
+            access: 0x0021,
+            name: "p/Base".to_string(),
+            super_name: Some("java/lang/Object".to_string()),
+            interfaces: vec![],
+            const_pool: Rc::new(RefCell::new(ConstPool::default())),
+        });
+        cw.visit_field(ACC_PROTECTED, "x", "I", vec![]);
+        let path = std::env::temp_dir().join("rustjvm-verifier-test/p/Base.class");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, cw.into_bytes().unwrap()).unwrap();
+
+        let cp = Rc::new(RefCell::new(ConstPool::default()));
+        let weak = Rc::downgrade(&cp);
+        for c in [
+            Const::Utf8("q/Foo".to_string()),
+            Const::Class {
+                cp: weak.clone(),
+                name_index: 1,
+            },
+            Const::Utf8("p/Base".to_string()),
+            Const::Class {
+                cp: weak.clone(),
+                name_index: 3,
+            },
+            Const::Utf8("f".to_string()),
+            Const::Utf8(format!("(L{};)I", param)),
+            Const::Utf8("x".to_string()),
+            Const::Utf8("I".to_string()),
+            Const::NameAndType {
+                cp: weak.clone(),
+                name_index: 7,
+                descriptor_index: 8,
+            },
+            Const::FieldRef {
+                cp: weak,
+                class_index: 4,
+                name_and_type_index: 9,
+            },
+        ] {
+            cp.borrow_mut().push(c);
+        }
+        let method = Field {
+            cp: cp.clone(),
+            flags: ACC_STATIC,
+            name_index: 5,
+            descriptor_index: 6,
+            attributes: vec![Attribute::Code {
+                cp: cp.clone(),
+                max_stack: 1,
+                max_locals: 1,
+                // aload_0; getfield p/Base.x; ireturn
+                code: vec![0x2a, 0xb4, 0x00, 0x0a, 0xac],
+                exception_table: vec![],
+                attributes: vec![],
+            }],
+        };
+        Class {
+            major_version: 52,
+            const_pool: cp,
+            this_class: 2,
+            super_class: 4,
+            methods: vec![method],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_protected_access() {
+        assert_eq!(verify(&subclass("q/Foo"), &classpath()), Ok(()));
+        let e = verify(&subclass("p/Base"), &classpath()).unwrap_err();
+        assert_eq!(e.pc, Some(1));
+        assert_eq!(e.reason, "Bad access to protected data in getfield");
+    }
+}
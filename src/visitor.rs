@@ -0,0 +1,746 @@
+// An event based view of classes in the style of ASM. `accept` replays a
+// parsed class as a sequence of visit calls; visitors may observe, drop,
+// change or add events before passing them on to the next visitor, usually
+// ending in a `writer::ClassWriter` which assembles the result. Code is
+// presented as instructions with symbolic labels instead of offsets, so
+// instructions can be inserted without fixing up branches, exception
+// ranges, debug tables or stack map frames by hand.
+
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{
+    attribute::{Attribute, VerificationTypeInfo},
+    bytecode::{self, Operands},
+    classfile::ConstPool,
+    descriptor::{self, FieldType},
+    loader::Class,
+};
+
+// A position in the code of a method, placed with `visit_label`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Label(usize);
+
+impl Label {
+    pub fn new() -> Label {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        Label(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl Default for Label {
+    fn default() -> Self {
+        Label::new()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Insn {
+    // Any instruction without branch targets. Constant operands are indices
+    // into the pool shared through the `Header`.
+    Plain(u8, Operands),
+    // Conditional branches, goto and jsr, in their short or _w forms
+    Jump(u8, Label),
+    TableSwitch {
+        default: Label,
+        low: i32,
+        targets: Vec<Label>,
+    },
+    LookupSwitch {
+        default: Label,
+        pairs: Vec<(i32, Label)>,
+    },
+}
+
+// A local or stack entry of a frame. As in the StackMapTable, long and
+// double take a single entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameValue {
+    Top,
+    Integer,
+    Float,
+    Double,
+    Long,
+    Null,
+    UninitializedThis,
+    Object(String),
+    // Created by the `new` instruction following the label
+    Uninitialized(Label),
+}
+
+pub struct Header {
+    pub minor_version: u16,
+    pub major_version: u16,
+    pub access: u16,
+    pub name: String,
+    pub super_name: Option<String>,
+    pub interfaces: Vec<String>,
+    pub const_pool: Rc<RefCell<ConstPool>>,
+}
+
+// An entry of the LocalVariableTable, with its LocalVariableTypeTable
+// signature if there is one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalVariable {
+    pub name: String,
+    pub descriptor: String,
+    pub signature: Option<String>,
+    pub start: Label,
+    pub end: Label,
+    pub index: u16,
+}
+
+// Every event is passed on to `next` unless overridden
+pub trait ClassVisitor {
+    fn next(&mut self) -> Option<&mut dyn ClassVisitor> {
+        None
+    }
+
+    fn visit(&mut self, header: Header) {
+        if let Some(next) = self.next() {
+            next.visit(header);
+        }
+    }
+
+    fn visit_field(
+        &mut self,
+        access: u16,
+        name: &str,
+        descriptor: &str,
+        attributes: Vec<Attribute>,
+    ) {
+        if let Some(next) = self.next() {
+            next.visit_field(access, name, descriptor, attributes);
+        }
+    }
+
+    // None drops the method
+    fn visit_method(
+        &mut self,
+        access: u16,
+        name: &str,
+        descriptor: &str,
+    ) -> Option<Box<dyn MethodVisitor>> {
+        self.next()?.visit_method(access, name, descriptor)
+    }
+
+    fn visit_attribute(&mut self, attribute: Attribute) {
+        if let Some(next) = self.next() {
+            next.visit_attribute(attribute);
+        }
+    }
+
+    fn visit_end(&mut self) {
+        if let Some(next) = self.next() {
+            next.visit_end();
+        }
+    }
+}
+
+// Events of a method, in the order attributes, then for methods with code:
+// visit_code, try/catch blocks, labels, line numbers, frames and
+// instructions, local variables, other Code attributes, maxs; and visit_end
+pub trait MethodVisitor {
+    fn next(&mut self) -> Option<&mut dyn MethodVisitor> {
+        None
+    }
+
+    fn visit_attribute(&mut self, attribute: Attribute) {
+        if let Some(next) = self.next() {
+            next.visit_attribute(attribute);
+        }
+    }
+
+    fn visit_code(&mut self) {
+        if let Some(next) = self.next() {
+            next.visit_code();
+        }
+    }
+
+    // A None catch type catches everything, as for finally blocks
+    fn visit_try_catch_block(
+        &mut self,
+        start: Label,
+        end: Label,
+        handler: Label,
+        catch_type: Option<String>,
+    ) {
+        if let Some(next) = self.next() {
+            next.visit_try_catch_block(start, end, handler, catch_type);
+        }
+    }
+
+    fn visit_label(&mut self, label: Label) {
+        if let Some(next) = self.next() {
+            next.visit_label(label);
+        }
+    }
+
+    fn visit_line_number(&mut self, line: u16, start: Label) {
+        if let Some(next) = self.next() {
+            next.visit_line_number(line, start);
+        }
+    }
+
+    // The frame at the next instruction, with all its locals
+    fn visit_frame(&mut self, locals: Vec<FrameValue>, stack: Vec<FrameValue>) {
+        if let Some(next) = self.next() {
+            next.visit_frame(locals, stack);
+        }
+    }
+
+    fn visit_insn(&mut self, insn: Insn) {
+        if let Some(next) = self.next() {
+            next.visit_insn(insn);
+        }
+    }
+
+    fn visit_local_variable(&mut self, local: LocalVariable) {
+        if let Some(next) = self.next() {
+            next.visit_local_variable(local);
+        }
+    }
+
+    // Attributes of the Code attribute other than the line number, local
+    // variable and stack map tables
+    fn visit_code_attribute(&mut self, attribute: Attribute) {
+        if let Some(next) = self.next() {
+            next.visit_code_attribute(attribute);
+        }
+    }
+
+    fn visit_maxs(&mut self, max_stack: u16, max_locals: u16) {
+        if let Some(next) = self.next() {
+            next.visit_maxs(max_stack, max_locals);
+        }
+    }
+
+    fn visit_end(&mut self) {
+        if let Some(next) = self.next() {
+            next.visit_end();
+        }
+    }
+}
+
+// Replay `class` as events on `visitor`
+pub fn accept(class: Class, visitor: &mut dyn ClassVisitor) -> Result<(), String> {
+    let name = class.name();
+    let cp = class.const_pool.clone();
+    visitor.visit(Header {
+        minor_version: class.minor_version,
+        major_version: class.major_version,
+        access: class.flags,
+        name: name.clone(),
+        super_name: class.super_name(),
+        interfaces: class.interface_names(),
+        const_pool: cp.clone(),
+    });
+    for field in class.fields {
+        let (field_name, desc) = (field.name(), field.descriptor());
+        visitor.visit_field(field.flags, &field_name, &desc, field.attributes);
+    }
+    for method in class.methods {
+        let (method_name, desc) = (method.name(), method.descriptor());
+        let Some(mut mv) = visitor.visit_method(method.flags, &method_name, &desc) else {
+            continue;
+        };
+        let mut code = None;
+        for attr in method.attributes {
+            match attr {
+                Attribute::Code { .. } if code.is_none() => code = Some(attr),
+                _ => mv.visit_attribute(attr),
+            }
+        }
+        if let Some(code) = code {
+            let initial = initial_locals(&name, method.flags, &method_name, &desc);
+            accept_code(code, &cp, initial, mv.as_mut())
+                .map_err(|e| format!("{}.{}{}: {}", name, method_name, desc, e))?;
+        }
+        mv.visit_end();
+    }
+    for attr in class.attributes {
+        visitor.visit_attribute(attr);
+    }
+    visitor.visit_end();
+    Ok(())
+}
+
+fn accept_code(
+    code: Attribute,
+    cp: &RefCell<ConstPool>,
+    initial: Vec<FrameValue>,
+    mv: &mut dyn MethodVisitor,
+) -> Result<(), String> {
+    let Attribute::Code {
+        max_stack,
+        max_locals,
+        code,
+        exception_table,
+        attributes,
+        ..
+    } = code
+    else {
+        unreachable!()
+    };
+    // Released before the events, as visitors may add constants
+    let cp = cp.borrow();
+    let instructions = bytecode::decode(&code)?;
+    let boundaries: BTreeSet<usize> = instructions
+        .iter()
+        .map(|i| i.pc)
+        .chain([code.len()])
+        .collect();
+    let mut labels: HashMap<usize, Label> = HashMap::new();
+    let mut label = |pc: usize| -> Result<Label, String> {
+        if !boundaries.contains(&pc) {
+            return Err(format!("Offset {} is not an instruction boundary", pc));
+        }
+        Ok(*labels.entry(pc).or_default())
+    };
+
+    let mut insns = vec![];
+    for instr in &instructions {
+        let target = |t: i32| usize::try_from(t).unwrap_or(usize::MAX);
+        insns.push(match &instr.operands {
+            Operands::Branch(t) => Insn::Jump(instr.opcode, label(target(*t))?),
+            Operands::TableSwitch {
+                default,
+                low,
+                targets,
+                ..
+            } => Insn::TableSwitch {
+                default: label(target(*default))?,
+                low: *low,
+                targets: targets
+                    .iter()
+                    .map(|t| label(target(*t)))
+                    .collect::<Result<_, _>>()?,
+            },
+            Operands::LookupSwitch { default, pairs } => Insn::LookupSwitch {
+                default: label(target(*default))?,
+                pairs: pairs
+                    .iter()
+                    .map(|(key, t)| Ok((*key, label(target(*t))?)))
+                    .collect::<Result<_, String>>()?,
+            },
+            operands => Insn::Plain(instr.opcode, operands.clone()),
+        });
+    }
+
+    let mut try_catch = vec![];
+    for entry in &exception_table {
+        let catch_type = match entry.catch_type {
+            0 => None,
+            index => Some(cp.class_name(index)),
+        };
+        try_catch.push((
+            label(entry.start_pc as usize)?,
+            label(entry.end_pc as usize)?,
+            label(entry.handler_pc as usize)?,
+            catch_type,
+        ));
+    }
+
+    let mut lines: HashMap<usize, Vec<u16>> = HashMap::new();
+    let mut locals = vec![];
+    let mut signatures = HashMap::new();
+    let mut frames = HashMap::new();
+    let mut others = vec![];
+    for attr in attributes {
+        match attr {
+            // Debug information pointing into the middle of instructions is
+            // dropped rather than rejected
+            Attribute::LineNumberTable { line_number_table } => {
+                for entry in line_number_table {
+                    let pc = entry.start_pc as usize;
+                    if boundaries.contains(&pc) && pc < code.len() {
+                        label(pc)?;
+                        lines.entry(pc).or_default().push(entry.line_number);
+                    }
+                }
+            }
+            Attribute::LocalVariableTable {
+                local_variable_table,
+            } => {
+                for entry in local_variable_table {
+                    let start = entry.start_pc as usize;
+                    let end = start + entry.length as usize;
+                    if boundaries.contains(&start) && boundaries.contains(&end) {
+                        locals.push((
+                            LocalVariable {
+                                name: cp.resolve(entry.name_index),
+                                descriptor: cp.resolve(entry.descriptor_index),
+                                signature: None,
+                                start: label(start)?,
+                                end: label(end)?,
+                                index: entry.index,
+                            },
+                            (entry.start_pc, entry.length, entry.index),
+                        ));
+                    }
+                }
+            }
+            Attribute::LocalVariableTypeTable(raw) => {
+                for entry in raw.get(2..).unwrap_or_default().chunks_exact(10) {
+                    let u2 = |i: usize| u16::from_be_bytes([entry[i], entry[i + 1]]);
+                    signatures.insert((u2(0), u2(2), u2(8)), cp.resolve(u2(6)));
+                }
+            }
+            Attribute::StackMapTable { entries } => {
+                let mut current = initial.clone();
+                let mut offset: Option<usize> = None;
+                for entry in entries {
+                    let pc = match offset {
+                        None => entry.offset_delta as usize,
+                        Some(prev) => prev + entry.offset_delta as usize + 1,
+                    };
+                    offset = Some(pc);
+                    let mut value = |t: &VerificationTypeInfo| -> Result<FrameValue, String> {
+                        Ok(match t {
+                            VerificationTypeInfo::Top => FrameValue::Top,
+                            VerificationTypeInfo::Integer => FrameValue::Integer,
+                            VerificationTypeInfo::Float => FrameValue::Float,
+                            VerificationTypeInfo::Double => FrameValue::Double,
+                            VerificationTypeInfo::Long => FrameValue::Long,
+                            VerificationTypeInfo::Null => FrameValue::Null,
+                            VerificationTypeInfo::UninitializedThis => {
+                                FrameValue::UninitializedThis
+                            }
+                            VerificationTypeInfo::Object(index) => {
+                                FrameValue::Object(cp.class_name(*index))
+                            }
+                            VerificationTypeInfo::Uninitialized(offset) => {
+                                FrameValue::Uninitialized(label(*offset as usize)?)
+                            }
+                        })
+                    };
+                    let mut stack = vec![];
+                    match entry.frame_type {
+                        0..=63 | 251 => {}
+                        64..=127 | 247 => stack.push(value(&entry.stack[0])?),
+                        248..=250 => {
+                            let k = 251 - entry.frame_type as usize;
+                            if k > current.len() {
+                                return Err(format!("Bad chop frame at {}", pc));
+                            }
+                            current.truncate(current.len() - k);
+                        }
+                        252..=254 => {
+                            for t in &entry.locals {
+                                current.push(value(t)?);
+                            }
+                        }
+                        _ => {
+                            current = entry
+                                .locals
+                                .iter()
+                                .map(&mut value)
+                                .collect::<Result<_, _>>()?;
+                            stack = entry
+                                .stack
+                                .iter()
+                                .map(&mut value)
+                                .collect::<Result<_, _>>()?;
+                        }
+                    }
+                    label(pc)?;
+                    frames.insert(pc, (current.clone(), stack));
+                }
+            }
+            attr => others.push(attr),
+        }
+    }
+
+    drop(cp);
+    mv.visit_code();
+    for (start, end, handler, catch_type) in try_catch {
+        mv.visit_try_catch_block(start, end, handler, catch_type);
+    }
+    for (instr, insn) in instructions.iter().zip(insns) {
+        let pc = instr.pc;
+        if let Some(label) = labels.get(&pc) {
+            mv.visit_label(*label);
+        }
+        for line in lines.remove(&pc).unwrap_or_default() {
+            mv.visit_line_number(line, labels[&pc]);
+        }
+        if let Some((locals, stack)) = frames.remove(&pc) {
+            mv.visit_frame(locals, stack);
+        }
+        mv.visit_insn(insn);
+    }
+    if let Some(label) = labels.get(&code.len()) {
+        mv.visit_label(*label);
+    }
+    for (mut local, key) in locals {
+        local.signature = signatures.remove(&key);
+        mv.visit_local_variable(local);
+    }
+    for attr in others {
+        mv.visit_code_attribute(attr);
+    }
+    mv.visit_maxs(max_stack, max_locals);
+    Ok(())
+}
+
+// The locals on entry to a method, as in its implicit first frame
+pub fn initial_locals(class_name: &str, access: u16, name: &str, desc: &str) -> Vec<FrameValue> {
+    let mut locals = vec![];
+    if access & 0x0008 == 0 {
+        if name == "<init>" && class_name != "java/lang/Object" {
+            locals.push(FrameValue::UninitializedThis);
+        } else {
+            locals.push(FrameValue::Object(class_name.to_string()));
+        }
+    }
+    for param in descriptor::parse_method(desc)
+        .map(|d| d.params)
+        .unwrap_or_default()
+    {
+        locals.push(match param {
+            FieldType::Byte
+            | FieldType::Char
+            | FieldType::Short
+            | FieldType::Boolean
+            | FieldType::Int => FrameValue::Integer,
+            FieldType::Float => FrameValue::Float,
+            FieldType::Long => FrameValue::Long,
+            FieldType::Double => FrameValue::Double,
+            FieldType::Object(name) => FrameValue::Object(name),
+            array => FrameValue::Object(array.descriptor()),
+        });
+    }
+    locals
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::*;
+    use crate::classfile::Constants;
+    use crate::writer::ClassWriter;
+
+    // Foo.f(I)I: a tableswitch, a try/catch with its handler frame, line
+    // numbers and a local variable with a signature
+    fn class() -> Vec<u8> {
+        let mut cw = ClassWriter::new();
+        let cp = Rc::new(RefCell::new(ConstPool::default()));
+        cw.visit(Header {
+            minor_version: 0,
+            major_version: 52,
+            access: 0x0021,
+            name: "Foo".to_string(),
+            super_name: Some("java/lang/Object".to_string()),
+            interfaces: vec![],
+            const_pool: cp.clone(),
+        });
+        let mut mv = cw.visit_method(0x0009, "f", "(I)I").unwrap();
+        let (start, end, handler, one, other) = (
+            Label::new(),
+            Label::new(),
+            Label::new(),
+            Label::new(),
+            Label::new(),
+        );
+        mv.visit_code();
+        mv.visit_try_catch_block(start, end, handler, Some("java/lang/Exception".to_string()));
+        mv.visit_label(start);
+        mv.visit_line_number(3, start);
+        mv.visit_insn(Insn::Plain(ILOAD_0, Operands::None));
+        mv.visit_insn(Insn::TableSwitch {
+            default: other,
+            low: 1,
+            targets: vec![one],
+        });
+        mv.visit_label(one);
+        mv.visit_frame(vec![FrameValue::Integer], vec![]);
+        mv.visit_insn(Insn::Plain(ICONST_1, Operands::None));
+        mv.visit_label(end);
+        mv.visit_insn(Insn::Plain(IRETURN, Operands::None));
+        mv.visit_label(other);
+        mv.visit_frame(vec![FrameValue::Integer], vec![]);
+        mv.visit_insn(Insn::Plain(ICONST_0, Operands::None));
+        mv.visit_insn(Insn::Plain(IRETURN, Operands::None));
+        mv.visit_label(handler);
+        mv.visit_frame(
+            vec![FrameValue::Integer],
+            vec![FrameValue::Object("java/lang/Exception".to_string())],
+        );
+        mv.visit_insn(Insn::Plain(ATHROW, Operands::None));
+        mv.visit_local_variable(LocalVariable {
+            name: "x".to_string(),
+            descriptor: "I".to_string(),
+            signature: Some("I".to_string()),
+            start,
+            end: handler,
+            index: 0,
+        });
+        mv.visit_maxs(1, 1);
+        mv.visit_end();
+        cw.visit_end();
+        cw.into_bytes().unwrap()
+    }
+
+    fn code(class: &Class) -> &Attribute {
+        &class.methods[0].attributes[0]
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let data = class();
+        let class = Class::parse(&data).unwrap();
+        let Attribute::Code {
+            code,
+            exception_table,
+            attributes,
+            ..
+        } = code(&class)
+        else {
+            panic!("expected Code");
+        };
+        // iload_0, tableswitch padded to offset 4 with one target
+        assert_eq!(code.len(), 1 + 3 + 16 + 2 + 2 + 1);
+        assert_eq!(exception_table[0].handler_pc, 24);
+        let names: Vec<_> = attributes.iter().map(|a| a.name()).collect();
+        assert_eq!(
+            names,
+            [
+                "LineNumberTable",
+                "LocalVariableTable",
+                "LocalVariableTypeTable",
+                "StackMapTable"
+            ]
+        );
+        let mut cw = ClassWriter::new();
+        accept(class, &mut cw).unwrap();
+        assert_eq!(cw.into_bytes().unwrap(), data);
+    }
+
+    // Inserts `nop` at the start of every method
+    struct Probe {
+        next: ClassWriter,
+    }
+
+    struct MethodProbe {
+        next: Box<dyn MethodVisitor>,
+    }
+
+    impl ClassVisitor for Probe {
+        fn next(&mut self) -> Option<&mut dyn ClassVisitor> {
+            Some(&mut self.next)
+        }
+
+        fn visit_method(
+            &mut self,
+            access: u16,
+            name: &str,
+            descriptor: &str,
+        ) -> Option<Box<dyn MethodVisitor>> {
+            let next = self.next.visit_method(access, name, descriptor)?;
+            Some(Box::new(MethodProbe { next }))
+        }
+    }
+
+    impl MethodVisitor for MethodProbe {
+        fn next(&mut self) -> Option<&mut dyn MethodVisitor> {
+            Some(self.next.as_mut())
+        }
+
+        fn visit_code(&mut self) {
+            self.next.visit_code();
+            self.next.visit_insn(Insn::Plain(NOP, Operands::None));
+        }
+    }
+
+    #[test]
+    fn test_insert_instruction() {
+        let mut probe = Probe {
+            next: ClassWriter::new(),
+        };
+        accept(Class::parse(&class()).unwrap(), &mut probe).unwrap();
+        let class = Class::parse(&probe.next.into_bytes().unwrap()).unwrap();
+        let Attribute::Code {
+            code,
+            exception_table,
+            attributes,
+            ..
+        } = code(&class)
+        else {
+            panic!("expected Code");
+        };
+        // Offsets move by one, and the switch loses a byte of padding
+        assert_eq!(&code[..3], [NOP, ILOAD_0, TABLESWITCH]);
+        assert_eq!(code.len(), 1 + 1 + 2 + 16 + 2 + 2 + 1);
+        assert_eq!(exception_table[0].start_pc, 1);
+        assert_eq!(exception_table[0].handler_pc, 24);
+        let Attribute::StackMapTable { entries } = &attributes[3] else {
+            panic!("expected StackMapTable");
+        };
+        assert_eq!(entries[0].offset_delta, 20);
+        let instructions = decode(code).unwrap();
+        assert_eq!(instructions[2].targets(), [22, 20]);
+    }
+
+    #[test]
+    fn test_long_jump() {
+        let mut cw = ClassWriter::new();
+        cw.visit(Header {
+            minor_version: 0,
+            major_version: 49,
+            access: 0x0021,
+            name: "Foo".to_string(),
+            super_name: Some("java/lang/Object".to_string()),
+            interfaces: vec![],
+            const_pool: Rc::new(RefCell::new(ConstPool::default())),
+        });
+        let mut mv = cw.visit_method(0x0009, "f", "()V").unwrap();
+        let (far, near) = (Label::new(), Label::new());
+        mv.visit_code();
+        mv.visit_insn(Insn::Jump(GOTO, far));
+        mv.visit_label(near);
+        for _ in 0..40000 {
+            mv.visit_insn(Insn::Plain(NOP, Operands::None));
+        }
+        mv.visit_label(far);
+        mv.visit_insn(Insn::Jump(IFNULL, near));
+        mv.visit_insn(Insn::Plain(RETURN, Operands::None));
+        mv.visit_end();
+        assert!(cw.into_bytes().unwrap_err().contains("too far"));
+
+        let mut cw = ClassWriter::new();
+        let cp = Rc::new(RefCell::new(ConstPool::default()));
+        cw.visit(Header {
+            minor_version: 0,
+            major_version: 49,
+            access: 0x0021,
+            name: "Foo".to_string(),
+            super_name: None,
+            interfaces: vec![],
+            const_pool: cp.clone(),
+        });
+        let mut mv = cw.visit_method(0x0009, "f", "()V").unwrap();
+        mv.visit_code();
+        mv.visit_insn(Insn::Jump(GOTO, far));
+        for _ in 0..40000 {
+            mv.visit_insn(Insn::Plain(NOP, Operands::None));
+        }
+        mv.visit_label(far);
+        // A constant beyond 255 needs ldc_w
+        for i in 0..300 {
+            cp.integer(i);
+        }
+        mv.visit_insn(Insn::Plain(LDC, Operands::Constant(cp.integer(299))));
+        mv.visit_insn(Insn::Plain(RETURN, Operands::None));
+        mv.visit_end();
+        let class = Class::parse(&cw.into_bytes().unwrap()).unwrap();
+        let Attribute::Code { code, .. } = code(&class) else {
+            panic!("expected Code");
+        };
+        let instructions = decode(code).unwrap();
+        assert_eq!(instructions[0].opcode, GOTO_W);
+        assert_eq!(instructions[0].targets(), [40005]);
+        assert_eq!(instructions[40001].opcode, LDC_W);
+    }
+}
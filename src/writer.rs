@@ -0,0 +1,968 @@
+// Serialization of a parsed class back into the class file format (JVMS 4.1)
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+use crate::{
+    attribute::{
+        Attribute, ExceptionTable, LineNumberTableEntry, LocalVariableTableEntry, StackMapFrame,
+        VerificationTypeInfo,
+    },
+    bytecode,
+    classfile::{Const, ConstPool, Constants},
+    loader::{Class, Field},
+    visitor::{self, ClassVisitor, FrameValue, Header, Insn, Label, LocalVariable, MethodVisitor},
+};
+
+struct Writer<'a> {
+    cp: &'a ConstPool,
+    utf8: HashMap<String, u16>,
+    // Utf8 entries needed for attribute names the pool does not have
+    extra: Vec<String>,
+}
+
+pub fn write(class: &Class) -> Result<Vec<u8>, String> {
+    let cp = class.const_pool.borrow();
+    let mut utf8 = HashMap::new();
+    for index in 1..cp.count() {
+        if let Some(Const::Utf8(s)) = cp.get(index) {
+            utf8.entry(s.clone()).or_insert(index);
+        }
+    }
+    let mut writer = Writer {
+        cp: &cp,
+        utf8,
+        extra: vec![],
+    };
+
+    // The body goes first, as it decides which entries must be added
+    let mut body = vec![];
+    u2(&mut body, class.flags);
+    u2(&mut body, class.this_class);
+    u2(&mut body, class.super_class);
+    u2(&mut body, class.interfaces.len() as u16);
+    for interface in &class.interfaces {
+        u2(&mut body, *interface);
+    }
+    writer.fields(&mut body, &class.fields)?;
+    writer.fields(&mut body, &class.methods)?;
+    writer.attributes(&mut body, &class.attributes)?;
+
+    let mut out = vec![];
+    out.extend(0xcafebabe_u32.to_be_bytes());
+    u2(&mut out, class.minor_version);
+    u2(&mut out, class.major_version);
+    u2(&mut out, writer.next_index()?);
+    for index in 1..cp.count() {
+        if let Some(c) = cp.get(index) {
+            constant(&mut out, c);
+        }
+    }
+    for s in &writer.extra {
+        out.push(1);
+        let bytes = encode_utf8(s);
+        u2(&mut out, bytes.len() as u16);
+        out.extend(bytes);
+    }
+    out.extend(body);
+    Ok(out)
+}
+
+impl Writer<'_> {
+    // The index the next extra entry gets, which is also the count of the
+    // pool so far
+    fn next_index(&self) -> Result<u16, String> {
+        u16::try_from(self.extra.len())
+            .ok()
+            .and_then(|extra| self.cp.count().checked_add(extra))
+            .ok_or_else(|| "Too many constants to name the attributes".to_string())
+    }
+
+    fn utf8_index(&mut self, s: &str) -> Result<u16, String> {
+        if let Some(index) = self.utf8.get(s) {
+            return Ok(*index);
+        }
+        let index = self.next_index()?;
+        // The count written for the pool is one more than the last index
+        if index == u16::MAX {
+            return Err("Too many constants to name the attributes".to_string());
+        }
+        self.extra.push(s.to_string());
+        self.utf8.insert(s.to_string(), index);
+        Ok(index)
+    }
+
+    fn fields(&mut self, out: &mut Vec<u8>, fields: &[Field]) -> Result<(), String> {
+        u2(out, fields.len() as u16);
+        for field in fields {
+            u2(out, field.flags);
+            u2(out, field.name_index);
+            u2(out, field.descriptor_index);
+            self.attributes(out, &field.attributes)?;
+        }
+        Ok(())
+    }
+
+    fn attributes(&mut self, out: &mut Vec<u8>, attributes: &[Attribute]) -> Result<(), String> {
+        u2(out, attributes.len() as u16);
+        for attr in attributes {
+            let info = self.attribute(attr)?;
+            u2(out, self.utf8_index(attr.name())?);
+            out.extend((info.len() as u32).to_be_bytes());
+            out.extend(info);
+        }
+        Ok(())
+    }
+
+    fn attribute(&mut self, attr: &Attribute) -> Result<Vec<u8>, String> {
+        let mut info = vec![];
+        match attr {
+            Attribute::ConstantValue(index) => u2(&mut info, *index),
+            Attribute::Code {
+                max_stack,
+                max_locals,
+                code,
+                exception_table,
+                attributes,
+                ..
+            } => {
+                u2(&mut info, *max_stack);
+                u2(&mut info, *max_locals);
+                info.extend((code.len() as u32).to_be_bytes());
+                info.extend(code);
+                u2(&mut info, exception_table.len() as u16);
+                for entry in exception_table {
+                    u2(&mut info, entry.start_pc);
+                    u2(&mut info, entry.end_pc);
+                    u2(&mut info, entry.handler_pc);
+                    u2(&mut info, entry.catch_type);
+                }
+                self.attributes(&mut info, attributes)?;
+            }
+            Attribute::Exceptions {
+                exception_index_table,
+            } => {
+                u2(&mut info, exception_index_table.len() as u16);
+                for index in exception_index_table {
+                    u2(&mut info, *index);
+                }
+            }
+            Attribute::SourceFile { index, .. } => u2(&mut info, *index),
+            Attribute::LineNumberTable { line_number_table } => {
+                u2(&mut info, line_number_table.len() as u16);
+                for entry in line_number_table {
+                    u2(&mut info, entry.start_pc);
+                    u2(&mut info, entry.line_number);
+                }
+            }
+            Attribute::LocalVariableTable {
+                local_variable_table,
+            } => {
+                u2(&mut info, local_variable_table.len() as u16);
+                for entry in local_variable_table {
+                    u2(&mut info, entry.start_pc);
+                    u2(&mut info, entry.length);
+                    u2(&mut info, entry.name_index);
+                    u2(&mut info, entry.descriptor_index);
+                    u2(&mut info, entry.index);
+                }
+            }
+            Attribute::Synthetic | Attribute::Deprecated => {}
+            Attribute::StackMapTable { entries } => {
+                u2(&mut info, entries.len() as u16);
+                for frame in entries {
+                    stack_map_frame(&mut info, frame);
+                }
+            }
+            Attribute::InnerClasses(raw)
+            | Attribute::EnclosingMethod(raw)
+            | Attribute::Signature(raw)
+            | Attribute::SourceDebugExtension(raw)
+            | Attribute::LocalVariableTypeTable(raw)
+            | Attribute::RuntimeVisibleAnnotations(raw)
+            | Attribute::RuntimeInvisibleAnnotations(raw)
+            | Attribute::RuntimeVisibleParameterAnnotations(raw)
+            | Attribute::RuntimeInvisibleParameterAnnotations(raw)
+            | Attribute::AnnotationDefault(raw)
+            | Attribute::BootstrapMethods(raw)
+            | Attribute::RuntimeVisibleTypeAnnotations(raw)
+            | Attribute::RuntimeInvisibleTypeAnnotations(raw)
+            | Attribute::MethodParameters(raw)
+            | Attribute::Unknown { info: raw, .. } => info.extend(raw),
+        }
+        Ok(info)
+    }
+}
+
+// The end of a visitor chain, building a class from the events it receives.
+// Constants are added to the pool of the visited header, so instructions
+// passed through unchanged keep their operands.
+#[derive(Default)]
+pub struct ClassWriter {
+    class: Option<Class>,
+    methods: Rc<RefCell<Vec<Result<Field, String>>>>,
+}
+
+impl ClassWriter {
+    pub fn new() -> ClassWriter {
+        ClassWriter::default()
+    }
+
+    pub fn into_bytes(self) -> Result<Vec<u8>, String> {
+        let mut class = self.class.ok_or("No class was visited")?;
+        for method in self.methods.take() {
+            class.methods.push(method?);
+        }
+        write(&class)
+    }
+}
+
+impl ClassVisitor for ClassWriter {
+    fn visit(&mut self, header: Header) {
+        let cp = header.const_pool;
+        self.class = Some(Class {
+            minor_version: header.minor_version,
+            major_version: header.major_version,
+            flags: header.access,
+            this_class: cp.class(&header.name),
+            super_class: header.super_name.map_or(0, |name| cp.class(&name)),
+            interfaces: header
+                .interfaces
+                .iter()
+                .map(|name| cp.class(name))
+                .collect(),
+            const_pool: cp,
+            ..Default::default()
+        });
+    }
+
+    fn visit_field(
+        &mut self,
+        access: u16,
+        name: &str,
+        descriptor: &str,
+        attributes: Vec<Attribute>,
+    ) {
+        if let Some(class) = &mut self.class {
+            let cp = class.const_pool.clone();
+            class.fields.push(Field {
+                flags: access,
+                name_index: cp.utf8(name),
+                descriptor_index: cp.utf8(descriptor),
+                cp,
+                attributes,
+            });
+        }
+    }
+
+    fn visit_method(
+        &mut self,
+        access: u16,
+        name: &str,
+        descriptor: &str,
+    ) -> Option<Box<dyn MethodVisitor>> {
+        let class = self.class.as_ref()?;
+        Some(Box::new(MethodWriter {
+            cp: class.const_pool.clone(),
+            initial: visitor::initial_locals(&class.name(), access, name, descriptor),
+            methods: self.methods.clone(),
+            access,
+            name: name.to_string(),
+            descriptor: descriptor.to_string(),
+            attributes: vec![],
+            has_code: false,
+            items: vec![],
+            try_catch: vec![],
+            lines: vec![],
+            locals: vec![],
+            code_attributes: vec![],
+            max_stack: 0,
+            max_locals: 0,
+        }))
+    }
+
+    fn visit_attribute(&mut self, attribute: Attribute) {
+        if let Some(class) = &mut self.class {
+            class.attributes.push(attribute);
+        }
+    }
+}
+
+enum Item {
+    Label(Label),
+    Insn(Insn),
+    Frame(Vec<FrameValue>, Vec<FrameValue>),
+}
+
+// Assembles the code of a method once all of its events are in, and hands
+// the method to its ClassWriter
+pub struct MethodWriter {
+    cp: Rc<RefCell<ConstPool>>,
+    initial: Vec<FrameValue>,
+    methods: Rc<RefCell<Vec<Result<Field, String>>>>,
+    access: u16,
+    name: String,
+    descriptor: String,
+    attributes: Vec<Attribute>,
+    has_code: bool,
+    items: Vec<Item>,
+    try_catch: Vec<(Label, Label, Label, Option<String>)>,
+    lines: Vec<(u16, Label)>,
+    locals: Vec<LocalVariable>,
+    code_attributes: Vec<Attribute>,
+    max_stack: u16,
+    max_locals: u16,
+}
+
+impl MethodVisitor for MethodWriter {
+    fn visit_attribute(&mut self, attribute: Attribute) {
+        self.attributes.push(attribute);
+    }
+
+    fn visit_code(&mut self) {
+        self.has_code = true;
+    }
+
+    fn visit_try_catch_block(
+        &mut self,
+        start: Label,
+        end: Label,
+        handler: Label,
+        catch_type: Option<String>,
+    ) {
+        self.try_catch.push((start, end, handler, catch_type));
+    }
+
+    fn visit_label(&mut self, label: Label) {
+        self.items.push(Item::Label(label));
+    }
+
+    fn visit_line_number(&mut self, line: u16, start: Label) {
+        self.lines.push((line, start));
+    }
+
+    fn visit_frame(&mut self, locals: Vec<FrameValue>, stack: Vec<FrameValue>) {
+        self.items.push(Item::Frame(locals, stack));
+    }
+
+    fn visit_insn(&mut self, insn: Insn) {
+        self.items.push(Item::Insn(insn));
+    }
+
+    fn visit_local_variable(&mut self, local: LocalVariable) {
+        self.locals.push(local);
+    }
+
+    fn visit_code_attribute(&mut self, attribute: Attribute) {
+        self.code_attributes.push(attribute);
+    }
+
+    fn visit_maxs(&mut self, max_stack: u16, max_locals: u16) {
+        self.max_stack = max_stack;
+        self.max_locals = max_locals;
+    }
+
+    fn visit_end(&mut self) {
+        let method = self
+            .method()
+            .map_err(|e| format!("{}{}: {}", self.name, self.descriptor, e));
+        self.methods.borrow_mut().push(method);
+    }
+}
+
+impl MethodWriter {
+    fn method(&mut self) -> Result<Field, String> {
+        let mut attributes = vec![];
+        if self.has_code {
+            attributes.push(self.code()?);
+        }
+        attributes.append(&mut self.attributes);
+        Ok(Field {
+            cp: self.cp.clone(),
+            flags: self.access,
+            name_index: self.cp.utf8(&self.name),
+            descriptor_index: self.cp.utf8(&self.descriptor),
+            attributes,
+        })
+    }
+
+    // Offsets of the labels, and the offset at each item, with the goto and
+    // jsr instructions at `long` in their _w form
+    fn layout(&self, long: &HashSet<usize>) -> Result<(HashMap<Label, usize>, Vec<usize>), String> {
+        let mut labels = HashMap::new();
+        let mut offsets = vec![];
+        let mut pc = 0;
+        let mut scratch = vec![];
+        for (i, item) in self.items.iter().enumerate() {
+            offsets.push(pc);
+            pc += match item {
+                Item::Label(label) => {
+                    if labels.insert(*label, pc).is_some() {
+                        return Err(format!("{:?} placed twice", label));
+                    }
+                    0
+                }
+                Item::Frame(..) => 0,
+                Item::Insn(Insn::Plain(opcode, operands)) => {
+                    scratch.clear();
+                    bytecode::encode(*opcode, operands, &mut scratch)?;
+                    scratch.len()
+                }
+                Item::Insn(Insn::Jump(opcode, _)) => {
+                    if long.contains(&i) || matches!(*opcode, bytecode::GOTO_W | bytecode::JSR_W) {
+                        5
+                    } else {
+                        3
+                    }
+                }
+                Item::Insn(Insn::TableSwitch { targets, .. }) => {
+                    switch_padding(pc) + 13 + 4 * targets.len()
+                }
+                Item::Insn(Insn::LookupSwitch { pairs, .. }) => {
+                    switch_padding(pc) + 9 + 8 * pairs.len()
+                }
+            };
+        }
+        offsets.push(pc);
+        Ok((labels, offsets))
+    }
+
+    fn code(&mut self) -> Result<Attribute, String> {
+        // Grow goto and jsr whose targets are out of reach until none are
+        let mut long = HashSet::new();
+        let (labels, offsets) = loop {
+            let (labels, offsets) = self.layout(&long)?;
+            let mut changed = false;
+            for (i, item) in self.items.iter().enumerate() {
+                let Item::Insn(Insn::Jump(opcode, label)) = item else {
+                    continue;
+                };
+                let target = *labels
+                    .get(label)
+                    .ok_or_else(|| format!("{:?} not placed", label))?;
+                let offset = target as i64 - offsets[i] as i64;
+                if i16::try_from(offset).is_ok() || long.contains(&i) {
+                    continue;
+                }
+                match *opcode {
+                    bytecode::GOTO | bytecode::JSR => {
+                        long.insert(i);
+                        changed = true;
+                    }
+                    bytecode::GOTO_W | bytecode::JSR_W => {}
+                    _ => {
+                        return Err(format!(
+                            "Branch at {} is too far from its target",
+                            offsets[i]
+                        ))
+                    }
+                }
+            }
+            if !changed {
+                break (labels, offsets);
+            }
+        };
+        let pc = |label: &Label| -> Result<usize, String> {
+            labels
+                .get(label)
+                .copied()
+                .ok_or_else(|| format!("{:?} not placed", label))
+        };
+        let offset = |label: &Label, from: usize| -> Result<i32, String> {
+            Ok(pc(label)? as i32 - from as i32)
+        };
+
+        let mut code = vec![];
+        let mut frames = vec![];
+        for (i, item) in self.items.iter().enumerate() {
+            let at = offsets[i];
+            match item {
+                Item::Label(_) => {}
+                Item::Frame(locals, stack) => frames.push((at, locals, stack)),
+                Item::Insn(Insn::Plain(opcode, operands)) => {
+                    bytecode::encode(*opcode, operands, &mut code)?
+                }
+                Item::Insn(Insn::Jump(opcode, label)) => {
+                    let opcode = match *opcode {
+                        bytecode::GOTO if long.contains(&i) => bytecode::GOTO_W,
+                        bytecode::JSR if long.contains(&i) => bytecode::JSR_W,
+                        opcode => opcode,
+                    };
+                    code.push(opcode);
+                    if matches!(opcode, bytecode::GOTO_W | bytecode::JSR_W) {
+                        code.extend(offset(label, at)?.to_be_bytes());
+                    } else {
+                        code.extend((offset(label, at)? as i16).to_be_bytes());
+                    }
+                }
+                Item::Insn(Insn::TableSwitch {
+                    default,
+                    low,
+                    targets,
+                }) => {
+                    code.push(bytecode::TABLESWITCH);
+                    code.extend(vec![0; switch_padding(at)]);
+                    code.extend(offset(default, at)?.to_be_bytes());
+                    code.extend(low.to_be_bytes());
+                    code.extend((low + targets.len() as i32 - 1).to_be_bytes());
+                    for target in targets {
+                        code.extend(offset(target, at)?.to_be_bytes());
+                    }
+                }
+                Item::Insn(Insn::LookupSwitch { default, pairs }) => {
+                    code.push(bytecode::LOOKUPSWITCH);
+                    code.extend(vec![0; switch_padding(at)]);
+                    code.extend(offset(default, at)?.to_be_bytes());
+                    code.extend((pairs.len() as i32).to_be_bytes());
+                    for (key, target) in pairs {
+                        code.extend(key.to_be_bytes());
+                        code.extend(offset(target, at)?.to_be_bytes());
+                    }
+                }
+            }
+        }
+        if code.len() > 65535 {
+            return Err(format!("Code length {} exceeds 65535 bytes", code.len()));
+        }
+
+        let mut exception_table = vec![];
+        for (start, end, handler, catch_type) in &self.try_catch {
+            exception_table.push(ExceptionTable::new(
+                pc(start)? as u16,
+                pc(end)? as u16,
+                pc(handler)? as u16,
+                catch_type.as_ref().map_or(0, |name| self.cp.class(name)),
+            ));
+        }
+
+        let mut attributes = vec![];
+        if !self.lines.is_empty() {
+            let mut line_number_table = vec![];
+            for (line, start) in &self.lines {
+                line_number_table.push(LineNumberTableEntry::new(pc(start)? as u16, *line));
+            }
+            attributes.push(Attribute::LineNumberTable { line_number_table });
+        }
+        if !self.locals.is_empty() {
+            let mut local_variable_table = vec![];
+            let mut types = vec![];
+            for local in &self.locals {
+                let start = pc(&local.start)? as u16;
+                let length = (pc(&local.end)? as u16).wrapping_sub(start);
+                let name_index = self.cp.utf8(&local.name);
+                local_variable_table.push(LocalVariableTableEntry::new(
+                    start,
+                    length,
+                    name_index,
+                    self.cp.utf8(&local.descriptor),
+                    local.index,
+                ));
+                if let Some(signature) = &local.signature {
+                    for value in [
+                        start,
+                        length,
+                        name_index,
+                        self.cp.utf8(signature),
+                        local.index,
+                    ] {
+                        u2(&mut types, value);
+                    }
+                }
+            }
+            attributes.push(Attribute::LocalVariableTable {
+                local_variable_table,
+            });
+            if !types.is_empty() {
+                let mut raw = vec![];
+                u2(&mut raw, types.len() as u16 / 10);
+                raw.extend(types);
+                attributes.push(Attribute::LocalVariableTypeTable(raw));
+            }
+        }
+        if !frames.is_empty() {
+            let mut entries = vec![];
+            let mut previous: Option<usize> = None;
+            let mut locals = &self.initial;
+            for (j, (at, frame_locals, stack)) in frames.iter().enumerate() {
+                // Of several frames at one offset, the last one counts
+                if frames.get(j + 1).is_some_and(|next| next.0 == *at) {
+                    continue;
+                }
+                let delta = match previous {
+                    None => *at,
+                    Some(prev) => at - prev - 1,
+                };
+                previous = Some(*at);
+                let entry = self.frame(locals, frame_locals, stack, delta as u16, &labels)?;
+                entries.push(entry);
+                locals = frame_locals;
+            }
+            attributes.push(Attribute::StackMapTable { entries });
+        }
+        attributes.append(&mut self.code_attributes);
+
+        Ok(Attribute::Code {
+            cp: self.cp.clone(),
+            max_stack: self.max_stack,
+            max_locals: self.max_locals,
+            code,
+            exception_table,
+            attributes,
+        })
+    }
+
+    // The most compact StackMapTable entry for a frame, given the locals of
+    // the previous one
+    fn frame(
+        &self,
+        previous: &[FrameValue],
+        locals: &[FrameValue],
+        stack: &[FrameValue],
+        offset_delta: u16,
+        labels: &HashMap<Label, usize>,
+    ) -> Result<StackMapFrame, String> {
+        let info = |values: &[FrameValue]| -> Result<Vec<VerificationTypeInfo>, String> {
+            values
+                .iter()
+                .map(|value| {
+                    Ok(match value {
+                        FrameValue::Top => VerificationTypeInfo::Top,
+                        FrameValue::Integer => VerificationTypeInfo::Integer,
+                        FrameValue::Float => VerificationTypeInfo::Float,
+                        FrameValue::Double => VerificationTypeInfo::Double,
+                        FrameValue::Long => VerificationTypeInfo::Long,
+                        FrameValue::Null => VerificationTypeInfo::Null,
+                        FrameValue::UninitializedThis => VerificationTypeInfo::UninitializedThis,
+                        FrameValue::Object(name) => {
+                            VerificationTypeInfo::Object(self.cp.class(name))
+                        }
+                        FrameValue::Uninitialized(label) => {
+                            let pc = labels
+                                .get(label)
+                                .ok_or_else(|| format!("{:?} not placed", label))?;
+                            VerificationTypeInfo::Uninitialized(*pc as u16)
+                        }
+                    })
+                })
+                .collect()
+        };
+        let (frame_type, locals, stack) = if locals == previous && stack.is_empty() {
+            (
+                if offset_delta < 64 {
+                    offset_delta as u8
+                } else {
+                    251
+                },
+                vec![],
+                vec![],
+            )
+        } else if locals == previous && stack.len() == 1 {
+            let frame_type = if offset_delta < 64 {
+                64 + offset_delta as u8
+            } else {
+                247
+            };
+            (frame_type, vec![], info(stack)?)
+        } else if stack.is_empty()
+            && locals.len() < previous.len()
+            && previous.len() - locals.len() <= 3
+            && previous.starts_with(locals)
+        {
+            (251 - (previous.len() - locals.len()) as u8, vec![], vec![])
+        } else if stack.is_empty()
+            && locals.len() > previous.len()
+            && locals.len() - previous.len() <= 3
+            && locals.starts_with(previous)
+        {
+            let added = &locals[previous.len()..];
+            (251 + added.len() as u8, info(added)?, vec![])
+        } else {
+            (255, info(locals)?, info(stack)?)
+        };
+        Ok(StackMapFrame {
+            frame_type,
+            offset_delta,
+            locals,
+            stack,
+        })
+    }
+}
+
+// Bytes between a switch opcode at `pc` and its 4-byte aligned operands
+fn switch_padding(pc: usize) -> usize {
+    3 - pc % 4
+}
+
+fn u2(out: &mut Vec<u8>, value: u16) {
+    out.extend(value.to_be_bytes());
+}
+
+fn constant(out: &mut Vec<u8>, c: &Const) {
+    if let Const::Unusable = c {
+        return;
+    }
+    out.push(c.tag());
+    match c {
+        Const::Utf8(s) => {
+            let bytes = encode_utf8(s);
+            u2(out, bytes.len() as u16);
+            out.extend(bytes);
+        }
+        Const::RawUtf8(bytes) => {
+            u2(out, bytes.len() as u16);
+            out.extend(bytes);
+        }
+        Const::Integer(v) => out.extend(v.to_be_bytes()),
+        Const::Float(v) => out.extend(v.to_bits().to_be_bytes()),
+        Const::Long(v) => out.extend(v.to_be_bytes()),
+        Const::Double(v) => out.extend(v.to_bits().to_be_bytes()),
+        Const::Class { name_index, .. }
+        | Const::Module { name_index, .. }
+        | Const::Package { name_index, .. } => u2(out, *name_index),
+        Const::String { string_index, .. } => u2(out, *string_index),
+        Const::FieldRef {
+            class_index,
+            name_and_type_index,
+            ..
+        }
+        | Const::MethodRef {
+            class_index,
+            name_and_type_index,
+            ..
+        }
+        | Const::InterfaceMethodRef {
+            class_index,
+            name_and_type_index,
+            ..
+        } => {
+            u2(out, *class_index);
+            u2(out, *name_and_type_index);
+        }
+        Const::NameAndType {
+            name_index,
+            descriptor_index,
+            ..
+        } => {
+            u2(out, *name_index);
+            u2(out, *descriptor_index);
+        }
+        Const::MethodHandle {
+            reference_kind,
+            reference_index,
+            ..
+        } => {
+            out.push(*reference_kind);
+            u2(out, *reference_index);
+        }
+        Const::MethodType {
+            descriptor_index, ..
+        } => u2(out, *descriptor_index),
+        Const::Dynamic {
+            bootstrap_method_attr_index,
+            name_and_type_index,
+            ..
+        }
+        | Const::InvokeDynamic {
+            bootstrap_method_attr_index,
+            name_and_type_index,
+            ..
+        } => {
+            u2(out, *bootstrap_method_attr_index);
+            u2(out, *name_and_type_index);
+        }
+        Const::Unusable => {}
+    }
+}
+
+fn stack_map_frame(out: &mut Vec<u8>, frame: &StackMapFrame) {
+    out.push(frame.frame_type);
+    match frame.frame_type {
+        0..=63 => {}
+        64..=127 => verification_type(out, &frame.stack[0]),
+        247 => {
+            u2(out, frame.offset_delta);
+            verification_type(out, &frame.stack[0]);
+        }
+        248..=251 => u2(out, frame.offset_delta),
+        252..=254 => {
+            u2(out, frame.offset_delta);
+            for t in &frame.locals {
+                verification_type(out, t);
+            }
+        }
+        _ => {
+            u2(out, frame.offset_delta);
+            u2(out, frame.locals.len() as u16);
+            for t in &frame.locals {
+                verification_type(out, t);
+            }
+            u2(out, frame.stack.len() as u16);
+            for t in &frame.stack {
+                verification_type(out, t);
+            }
+        }
+    }
+}
+
+fn verification_type(out: &mut Vec<u8>, t: &VerificationTypeInfo) {
+    match t {
+        VerificationTypeInfo::Top => out.push(0),
+        VerificationTypeInfo::Integer => out.push(1),
+        VerificationTypeInfo::Float => out.push(2),
+        VerificationTypeInfo::Double => out.push(3),
+        VerificationTypeInfo::Long => out.push(4),
+        VerificationTypeInfo::Null => out.push(5),
+        VerificationTypeInfo::UninitializedThis => out.push(6),
+        VerificationTypeInfo::Object(index) => {
+            out.push(7);
+            u2(out, *index);
+        }
+        VerificationTypeInfo::Uninitialized(offset) => {
+            out.push(8);
+            u2(out, *offset);
+        }
+    }
+}
+
+// Modified UTF-8, the inverse of the decoding done by the loader for any
+// string the loader keeps as a Const::Utf8
+pub(crate) fn encode_utf8(s: &str) -> Vec<u8> {
+    let mut out = vec![];
+    for unit in s.encode_utf16() {
+        match unit {
+            0x01..=0x7f => out.push(unit as u8),
+            0x00 | 0x80..=0x7ff => {
+                out.push(0xc0 | (unit >> 6) as u8);
+                out.push(0x80 | (unit & 0x3f) as u8);
+            }
+            _ => {
+                out.push(0xe0 | (unit >> 12) as u8);
+                out.push(0x80 | (unit >> 6 & 0x3f) as u8);
+                out.push(0x80 | (unit & 0x3f) as u8);
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+    use crate::attribute::LineNumberTableEntry;
+
+    // Foo with a method `f()V` whose code is a bare `return`
+    fn class() -> Class {
+        let cp = Rc::new(RefCell::new(ConstPool::default()));
+        for c in [
+            Const::Utf8("Foo".to_string()),
+            Const::Class {
+                cp: Rc::downgrade(&cp),
+                name_index: 1,
+            },
+            Const::Utf8("java/lang/Object".to_string()),
+            Const::Class {
+                cp: Rc::downgrade(&cp),
+                name_index: 3,
+            },
+            Const::Utf8("f".to_string()),
+            Const::Utf8("()V".to_string()),
+            Const::Long(1 << 40),
+        ] {
+            cp.borrow_mut().push(c);
+        }
+        cp.borrow_mut().push(Const::Unusable);
+        let method = Field {
+            cp: cp.clone(),
+            flags: 0x0001,
+            name_index: 5,
+            descriptor_index: 6,
+            attributes: vec![Attribute::Code {
+                cp: cp.clone(),
+                max_stack: 0,
+                max_locals: 1,
+                code: vec![0xb1],
+                exception_table: vec![],
+                attributes: vec![Attribute::LineNumberTable {
+                    line_number_table: vec![LineNumberTableEntry::new(0, 3)],
+                }],
+            }],
+        };
+        Class {
+            major_version: 52,
+            const_pool: cp,
+            this_class: 2,
+            super_class: 4,
+            methods: vec![method],
+            attributes: vec![Attribute::Unknown {
+                name: "Custom".to_string(),
+                info: vec![1, 2, 3],
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let data = write(&class()).unwrap();
+        let parsed = Class::parse(&data).unwrap();
+        assert_eq!(parsed.name(), "Foo");
+        assert_eq!(parsed.super_name().unwrap(), "java/lang/Object");
+        // The attribute names were appended after the Long and its gap
+        assert_eq!(parsed.const_pool.borrow().count(), 12);
+        let Attribute::Code { attributes, .. } = &parsed.methods[0].attributes[0] else {
+            panic!("expected Code");
+        };
+        assert_eq!(attributes[0].name(), "LineNumberTable");
+        assert!(matches!(
+            &parsed.attributes[0],
+            Attribute::Unknown { name, info } if name == "Custom" && *info == [1, 2, 3]
+        ));
+        assert_eq!(write(&parsed).unwrap(), data);
+    }
+
+    #[test]
+    fn test_round_trip_raw_utf8() {
+        let class = class();
+        // "a\uD800b" and an overlong 'A', neither of which a String keeps
+        for bytes in [b"a\xed\xa0\x80b".to_vec(), b"\xc1\x81".to_vec()] {
+            class.const_pool.borrow_mut().push(Const::RawUtf8(bytes));
+        }
+        let data = write(&class).unwrap();
+        let parsed = Class::parse(&data).unwrap();
+        let cp = parsed.const_pool.borrow();
+        assert!(matches!(cp.get(9), Some(Const::RawUtf8(b)) if b == b"a\xed\xa0\x80b"));
+        assert_eq!(cp.resolve(9), "a\u{fffd}b");
+        assert_eq!(cp.resolve(10), "A");
+        drop(cp);
+        assert_eq!(write(&parsed).unwrap(), data);
+    }
+
+    #[test]
+    fn test_full_pool() {
+        // Room for the three attribute names, the last taking index 65534
+        let class = class();
+        while class.const_pool.borrow().count() < u16::MAX - 3 {
+            class.const_pool.borrow_mut().push(Const::Integer(0));
+        }
+        let parsed = Class::parse(&write(&class).unwrap()).unwrap();
+        assert_eq!(parsed.const_pool.borrow().count(), u16::MAX);
+        // One entry more and the last name has no index left
+        class.const_pool.borrow_mut().push(Const::Integer(0));
+        assert_eq!(
+            write(&class),
+            Err("Too many constants to name the attributes".to_string())
+        );
+    }
+
+    #[test]
+    fn test_encode_utf8() {
+        assert_eq!(encode_utf8("a\0b"), b"a\xc0\x80b");
+        assert_eq!(
+            encode_utf8("\u{1f600}"),
+            [0xed, 0xa0, 0xbd, 0xed, 0xb8, 0x80]
+        );
+    }
+}